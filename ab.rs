@@ -0,0 +1,133 @@
+//
+// sprocketnes/ab.rs
+//
+// Author: Patrick Walton
+//
+
+// A/B audio comparisons, for reviewing changes to the APU by ear and by diff. With `--audio-ab`,
+// the ROM is run headless twice from the same state and movie, once with each of two values of an
+// audio option, and the two WAVs and their difference are written next to the `--wav-out` path:
+//
+//     sprocketnes --frames 1800 --movie play demo.mov --wav-out smb.wav \
+//         --audio-ab mixing=linear,nonlinear smb.nes
+//
+// writes `smb-a.wav`, `smb-b.wav`, and `smb-diff.wav`, which is B minus A. The difference track
+// holds what the change did and nothing else, so it's silent if the change made no difference.
+
+use main::{Options, parse_value_option, run_headless};
+use rom::Rom;
+use wav::WavWriter;
+use wav;
+
+use libc::{int16_t, int32_t};
+use std::cmp;
+use std::num::Float;
+
+// The options that can be compared, without their leading `--`.
+static SETTINGS: [&'static str; 3] = [ "mixing", "revision", "resampler" ];
+
+/// An audio option and the two values to compare.
+#[derive(Clone)]
+pub struct Comparison {
+    pub setting: String,
+    pub a: String,
+    pub b: String,
+}
+
+impl Comparison {
+    /// Parses `<setting>=<a>,<b>`, checking that both values are ones the option takes.
+    pub fn parse(spec: &str) -> Result<Comparison, String> {
+        let (setting, values) = match spec.find('=') {
+            Some(index) => (spec.slice_to(index), spec.slice_from(index + 1)),
+            None => return Err(format!("expected `<setting>=<a>,<b>`, not `{}`", spec)),
+        };
+        if !SETTINGS.iter().any(|&name| name == setting) {
+            return Err(format!("can't compare `{}`; expected one of {}",
+                               setting,
+                               SETTINGS.connect(", ")));
+        }
+        let (a, b) = match values.find(',') {
+            Some(index) => (values.slice_to(index), values.slice_from(index + 1)),
+            None => return Err(format!("expected two values for `{}`, like `a,b`", setting)),
+        };
+
+        let comparison = Comparison {
+            setting: setting.to_string(),
+            a: a.to_string(),
+            b: b.to_string(),
+        };
+        let mut options = Options::default();
+        try!(comparison.apply(&mut options, a));
+        try!(comparison.apply(&mut options, b));
+        Ok(comparison)
+    }
+
+    fn apply(&self, options: &mut Options, value: &str) -> Result<(), String> {
+        let option = format!("--{}", self.setting);
+        parse_value_option(options, option.as_slice(), value)
+    }
+}
+
+// `smb.wav` becomes `smb-a.wav`, and so on.
+fn sibling(path: &Path, suffix: &str) -> Path {
+    let stem = path.filestem_str().unwrap_or("audio");
+    path.with_filename(format!("{}-{}.wav", stem, suffix))
+}
+
+fn read_samples(path: &Path) -> Result<Vec<int16_t>, String> {
+    wav::read_samples(path).map_err(|err| format!("couldn't read {}: {}", path.display(), err))
+}
+
+/// Runs both sides of the comparison and writes the difference, then says how big it is.
+pub fn run(rom: &Rom, options: &Options, comparison: &Comparison) -> Result<(), String> {
+    let wav_path = match options.wav_path {
+        Some(ref path) => Path::new(path.as_slice()),
+        None => return Err("`--audio-ab` needs `--wav-out` to say where to write".to_string()),
+    };
+    let (a_path, b_path, diff_path) = (sibling(&wav_path, "a"),
+                                       sibling(&wav_path, "b"),
+                                       sibling(&wav_path, "diff"));
+
+    for &(value, path) in [ (comparison.a.as_slice(), &a_path),
+                            (comparison.b.as_slice(), &b_path) ].iter() {
+        let mut side = options.clone();
+        try!(comparison.apply(&mut side, value));
+        side.wav_path = Some(path.display().to_string());
+        side.screenshot_path = None;
+        side.mem_profile_path = None;
+        run_headless(rom, &side);
+    }
+
+    let a = try!(read_samples(&a_path));
+    let b = try!(read_samples(&b_path));
+    let len = cmp::max(a.len(), b.len());
+    let diff: Vec<int16_t> = range(0, len).map(|i| {
+        let a = if i < a.len() { a[i] as int32_t } else { 0 };
+        let b = if i < b.len() { b[i] as int32_t } else { 0 };
+        cmp::min(cmp::max(b - a, -32768), 32767) as int16_t
+    }).collect();
+    let mut writer = match WavWriter::create(&diff_path) {
+        Ok(writer) => writer,
+        Err(err) => return Err(format!("couldn't create {}: {}", diff_path.display(), err)),
+    };
+    writer.add_samples(diff.as_slice());
+    writer.finish();
+
+    println!("A: {} (--{} {})", a_path.display(), comparison.setting, comparison.a);
+    println!("B: {} (--{} {})", b_path.display(), comparison.setting, comparison.b);
+    let differing = diff.iter().filter(|&&sample| sample != 0).count();
+    if differing == 0 {
+        println!("The two are identical; {} is silent", diff_path.display());
+        return Ok(());
+    }
+    let peak = diff.iter().map(|&sample| (sample as int32_t).abs()).max().unwrap_or(0);
+    let power = diff.iter().fold(0.0, |sum, &sample| sum + (sample as f64) * (sample as f64));
+    let rms = (power / len as f64).sqrt();
+    println!("B - A: {} ({} of {} samples differ, peak {}, RMS {:.1} dBFS)",
+             diff_path.display(),
+             differing,
+             len,
+             peak,
+             20.0 * (rms / 32768.0).log10());
+    Ok(())
+}
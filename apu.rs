@@ -4,23 +4,35 @@
 // Author: Patrick Walton
 //
 
-use audio::{AudioLock, OutputBuffer};
+use audio::OutputBuffer;
 use audio;
+use log;
+use mapper::Mapper;
 use mem::Mem;
-use speex::Resampler;
-use util::{Save, Xorshift};
+use resample::{Quality, Resampler};
+use resample;
+use util::Save;
 
 use libc::{int16_t, int32_t, uint8_t, uint16_t, uint32_t, uint64_t};
-use std::io::File;
+use std::cell::RefCell;
+use std::cmp;
+use std::mem;
+use std::rc::Rc;
+use std::vec::Vec;
 
 const CYCLES_PER_EVEN_TICK: uint64_t = 7438;
 const CYCLES_PER_ODD_TICK: uint64_t = 7439;
 
 const NES_SAMPLE_RATE: uint32_t = 1789920;   // Actual is 1789800, but this is divisible by 240.
-const OUTPUT_SAMPLE_RATE: uint32_t = 44100;
+pub const OUTPUT_SAMPLE_RATE: uint32_t = 44100;
 const TICK_FREQUENCY: uint32_t = 240;
 const NES_SAMPLES_PER_TICK: uint32_t = NES_SAMPLE_RATE / TICK_FREQUENCY;
 
+/// The number of CPU cycles in one second of emulated time, as measured by the APU's 240 Hz
+/// ticks. Exactly `OUTPUT_SAMPLE_RATE` output samples are produced in this many cycles.
+pub const EMULATED_CYCLES_PER_SECOND: uint64_t =
+    (CYCLES_PER_EVEN_TICK + CYCLES_PER_ODD_TICK) * (TICK_FREQUENCY as uint64_t) / 2;
+
 const PULSE_WAVEFORMS: [uint8_t; 4] = [ 0b01000000, 0b01100000, 0b01111000, 0b10011111 ];
 
 const LENGTH_COUNTERS: [uint8_t; 32] = [
@@ -38,6 +50,52 @@ const NOISE_PERIODS: [uint16_t; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068
 ];
 
+//
+// Hardware revisions
+//
+
+/// The revision of the 2A03 being emulated. They differ in only a few small ways, which most
+/// games never notice.
+#[derive(Clone, PartialEq)]
+pub enum Revision {
+    /// The earliest chips, with no letter after the part number. The noise channel ignores its
+    /// mode bit, so it can't make the short, metallic-sounding noise of later chips.
+    Letterless,
+    /// The 2A03G, which is in the great majority of consoles.
+    RevisionG,
+}
+
+//
+// Mixing
+//
+
+/// How the channels are combined. The 2A03 mixes them through resistors, which makes each channel
+/// quieter the louder the others are; adding them up is simpler, and close enough that most
+/// people can't tell.
+#[derive(Clone, PartialEq)]
+pub enum Mixing {
+    LinearMixing,
+    /// The approximation from http://wiki.nesdev.com/w/index.php/APU_Mixer
+    NonlinearMixing,
+}
+
+// Channel samples are the channel's 4-bit output level times this.
+const LEVEL_SCALE: f32 = 1024.0;
+
+// Non-linear output is between 0 and 1. This makes a lone pulse channel at full volume as loud as
+// it is when mixed linearly, so that switching between the two doesn't change the volume much.
+const NONLINEAR_SCALE: f32 = PULSE_FULL_VOLUME * (8128.0 / 15.0 + 100.0) / 95.88;
+
+// Mixes the pulses, triangle, noise, and DMC, given as samples, the way the 2A03 does.
+fn mix_nonlinear(pulse1: f32, pulse2: f32, triangle: f32, noise: f32, dmc: f32) -> f32 {
+    let pulses = (pulse1 + pulse2) / LEVEL_SCALE;
+    let pulse_out = if pulses == 0.0 { 0.0 } else { 95.88 / (8128.0 / pulses + 100.0) };
+    let tnd = triangle / LEVEL_SCALE / 8227.0 + noise / LEVEL_SCALE / 12241.0 +
+        dmc / LEVEL_SCALE / 22638.0;
+    let tnd_out = if tnd == 0.0 { 0.0 } else { 159.79 / (1.0 / tnd + 100.0) };
+    (pulse_out + tnd_out) * NONLINEAR_SCALE
+}
+
 //
 // Channel lengths
 //
@@ -272,14 +330,21 @@ struct ApuNoise {
     envelope: ApuEnvelope,
     timer: uint16_t,         // The number of ticks per possible waveform change.
     timer_count: uint16_t,   // The number of ticks since the last timer.
-    rng: Xorshift,      // The xorshift RNG. FIXME: This is inaccurate.
+    shift: uint16_t,         // The 15-bit linear feedback shift register. Bit 0 mutes the output.
+    short_mode: bool,        // Take feedback from bit 6 rather than bit 1, for a 93-step sequence.
 }
 
-save_struct!(ApuNoise { envelope, timer, timer_count });
+save_struct!(ApuNoise { envelope, timer, timer_count, shift, short_mode });
 
 impl ApuNoise {
     fn new() -> ApuNoise {
-        ApuNoise { envelope: ApuEnvelope::new(), timer: 0, timer_count: 0, rng: Xorshift::new() }
+        ApuNoise {
+            envelope: ApuEnvelope::new(),
+            timer: 0,
+            timer_count: 0,
+            shift: 1,
+            short_mode: false,
+        }
     }
 }
 
@@ -315,32 +380,65 @@ struct Regs {
     pulses: [ApuPulse; 2],
     triangle: ApuTriangle,
     noise: ApuNoise,
-    status: ApuStatus,  // $4015: APUSTATUS
+    status: ApuStatus,      // $4015: APUSTATUS
+    frame_counter: uint8_t, // $4017: frame sequencer mode and IRQ inhibit
 }
 
 impl Save for Regs {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         self.pulses[0].save(fd);
         self.pulses[1].save(fd);
         self.triangle.save(fd);
         self.noise.save(fd);
         self.status.save(fd);
+        self.frame_counter.save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         self.pulses[0].load(fd);
         self.pulses[1].load(fd);
         self.triangle.load(fd);
         self.noise.load(fd);
         self.status.load(fd);
+        self.frame_counter.load(fd);
     }
 }
 
+impl Regs {
+    fn five_step_mode(&self) -> bool      { (self.frame_counter & 0x80) != 0 }
+    fn frame_irq_inhibited(&self) -> bool { (self.frame_counter & 0x40) != 0 }
+}
+
 //
 // Sample buffers
 //
 
 const SAMPLE_COUNT: uint = 178992;
 
+// Pulse, pulse, triangle, noise, DMC, and the cartridge's expansion audio.
+pub const CHANNEL_COUNT: uint = 6;
+const EXPANSION_CHANNEL: uint = 5;
+
+pub static CHANNEL_NAMES: [&'static str; CHANNEL_COUNT] = [
+    "pulse 1", "pulse 2", "triangle", "noise", "DMC", "expansion"
+];
+
+// The loudest sample one of the pulse channels can produce.
+const PULSE_FULL_VOLUME: f32 = 15360.0;
+
+// Send samples to the device about once a frame.
+const FLUSH_SAMPLE_COUNT: uint = NES_SAMPLES_PER_TICK as uint * 4;
+
+// How full we try to keep the audio device's buffer, and the most that dynamic rate control may
+// speed up or slow down the audio to keep it there.
+const TARGET_FILL_LEVEL: f32 = 0.5;
+const MAX_RATE_ADJUSTMENT: f32 = 0.005;
+
+// Below this, the device's buffer is draining because emulation is behind real time.
+const BEHIND_FILL_LEVEL: f32 = 0.25;
+
+// How long the audio takes to fade over to a newly loaded state's: about 3ms of output.
+const DECLICK_SAMPLES: uint = 128;
+
 struct SampleBuffer {
     samples: [int16_t; SAMPLE_COUNT],
 }
@@ -352,38 +450,70 @@ struct SampleBuffer {
 pub struct Apu {
     regs: Regs,
 
-    sample_buffers: Box<[SampleBuffer; 5]>,
+    sample_buffers: Box<[SampleBuffer; CHANNEL_COUNT]>,
     sample_buffer_offset: uint,
     output_buffer: Option<*mut OutputBuffer>,
-    resampler: Resampler,
+    resampler: Box<Resampler>,
+    mapper: Rc<RefCell<Box<Mapper+Send>>>,
+
+    // Resampled output kept for recording, if requested, and how many samples at its start
+    // predate the request and must be dropped.
+    capture: Option<Vec<int16_t>>,
+    capture_skip: uint,
 
     pub cy: uint64_t,
     pub ticks: uint64_t,
+
+    // The frame sequencer's position, and its IRQ flag, which holds the IRQ line until $4015 is
+    // read or the IRQ is inhibited.
+    frame_step: uint8_t,
+    frame_irq: bool,
+
+    // Set while running frames that will be rewound, whose audio must not be heard.
+    silent: bool,
+
+    // The last output sample, and how much of the fade from it is left after a state load. These
+    // belong to the output rather than the console, so they aren't saved.
+    last_output: int16_t,
+    declick: uint,
+
+    // Channels left out of the mix. This is a listening preference, so it isn't saved.
+    pub muted: [bool; CHANNEL_COUNT],
+
+    pub revision: Revision,
+    pub mixing: Mixing,
 }
 
-save_struct!(Apu { regs, cy, ticks });
+save_struct!(Apu { regs, cy, ticks, frame_step, frame_irq });
 
 impl Mem for Apu {
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
         match addr {
-            0x4015 => *self.regs.status,
+            0x4015 => {
+                let val = (*self.regs.status & 0x1f) | if self.frame_irq { 0x40 } else { 0 };
+                self.frame_irq = false;
+                val
+            }
             _ => 0
         }
     }
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        log!(log::Apu, log::Trace, "${:04X} <- ${:02X}", addr as uint, val as uint);
         match addr {
             0x4000 ... 0x4003 => self.update_pulse(addr, val, 0),
             0x4004 ... 0x4007 => self.update_pulse(addr, val, 1),
             0x4008 ... 0x400b => self.regs.triangle.storeb(addr, val),
             0x400c ... 0x400f => self.update_noise(addr, val),
             0x4015 => self.update_status(val),
+            0x4017 => self.update_frame_counter(val),
             _ => {} // TODO
         }
     }
 }
 
 impl Apu {
-    pub fn new(output_buffer: Option<*mut OutputBuffer>) -> Apu {
+    pub fn new(output_buffer: Option<*mut OutputBuffer>, mapper: Rc<RefCell<Box<Mapper+Send>>>)
+               -> Apu {
         Apu {
             regs: Regs {
                 pulses: [
@@ -399,21 +529,56 @@ impl Apu {
                 triangle: ApuTriangle::new(),
                 noise: ApuNoise::new(),
                 status: ApuStatus{val:0},
+                frame_counter: 0,
             },
 
             sample_buffers: Box::new([
                 SampleBuffer {
                     samples: [ 0, ..SAMPLE_COUNT ]
                 };
-                5
+                CHANNEL_COUNT
             ]),
 
             sample_buffer_offset: 0,
             output_buffer: output_buffer,
-            resampler: Resampler::new(1, NES_SAMPLE_RATE, OUTPUT_SAMPLE_RATE, 0).unwrap(),
+            resampler: resample::create(resample::Adaptive(resample::DEFAULT_SINC_QUALITY),
+                                        NES_SAMPLE_RATE,
+                                        OUTPUT_SAMPLE_RATE),
+            mapper: mapper,
+
+            capture: None,
+            capture_skip: 0,
 
             cy: 0,
             ticks: 0,
+
+            frame_step: 0,
+            frame_irq: false,
+
+            silent: false,
+
+            last_output: 0,
+            declick: 0,
+
+            muted: [ false; CHANNEL_COUNT ],
+
+            revision: RevisionG,
+            mixing: LinearMixing,
+        }
+    }
+
+    pub fn set_resampler(&mut self, quality: Quality) {
+        self.resampler = resample::create(quality, NES_SAMPLE_RATE, OUTPUT_SAMPLE_RATE);
+    }
+
+    /// The APU's IRQ line. The DMC isn't emulated, so only the frame sequencer raises it.
+    pub fn irq_pending(&self) -> bool { self.frame_irq }
+
+    fn update_frame_counter(&mut self, val: uint8_t) {
+        self.regs.frame_counter = val;
+        self.frame_step = 0;
+        if self.regs.frame_irq_inhibited() {
+            self.frame_irq = false;
         }
     }
 
@@ -455,7 +620,7 @@ impl Apu {
         self.regs.noise.envelope.storeb(addr, val);
 
         if (addr & 3) == 2 {
-            // TODO: Mode bit.
+            self.regs.noise.short_mode = (val & 0x80) != 0 && self.revision != Letterless;
             self.regs.noise.timer = NOISE_PERIODS[val as uint & 0xf];
         }
     }
@@ -484,8 +649,19 @@ impl Apu {
     }
 
     fn tick(&mut self) {
+        // The frame sequencer runs four steps with an IRQ at the end, or five with no IRQ:
+        //
+        //     4-step mode: quarter, quarter + half, quarter, quarter + half + IRQ
+        //     5-step mode: quarter, quarter + half, quarter, nothing, quarter + half
+        let five_step_mode = self.regs.five_step_mode();
+        let (quarter, half) = match (five_step_mode, self.frame_step) {
+            (_, 0) | (_, 2) => (true, false),
+            (true, 3) => (false, false),
+            _ => (true, true),
+        };
+
         // 120 Hz operations: length counter and sweep.
-        if self.ticks % 2 == 0 {
+        if half {
             // TODO: Remember that triangle wave has a different length disable bit.
             for i in range(0, 2) {
                 let pulse = &mut self.regs.pulses[i];
@@ -515,19 +691,34 @@ impl Apu {
         }
 
         // 240 Hz operations: envelope and linear counter.
-        self.regs.pulses[0].envelope.tick();
-        self.regs.pulses[1].envelope.tick();
-        self.regs.triangle.tick();
-        self.regs.noise.envelope.tick();
+        if quarter {
+            self.regs.pulses[0].envelope.tick();
+            self.regs.pulses[1].envelope.tick();
+            self.regs.triangle.tick();
+            self.regs.noise.envelope.tick();
+        }
 
         // Fill the sample buffers.
-        self.play_pulse(0, 0);
-        self.play_pulse(1, 1);
-        self.play_triangle(2);
-        self.play_noise(3);
-        self.sample_buffer_offset += NES_SAMPLES_PER_TICK as uint;
+        if !self.silent {
+            self.play_pulse(0, 0);
+            self.play_pulse(1, 1);
+            self.play_triangle(2);
+            self.play_noise(3);
+            self.play_expansion(EXPANSION_CHANNEL);
+            self.sample_buffer_offset += NES_SAMPLES_PER_TICK as uint;
+        }
 
-        // TODO: 60 Hz IRQ.
+        // 60 Hz IRQ.
+        let last_step = if five_step_mode { 4 } else { 3 };
+        if self.frame_step == last_step {
+            if !five_step_mode && !self.regs.frame_irq_inhibited() {
+                log!(log::Apu, log::Debug, "frame IRQ");
+                self.frame_irq = true;
+            }
+            self.frame_step = 0;
+        } else {
+            self.frame_step += 1;
+        }
 
         self.ticks += 1;
     }
@@ -626,41 +817,90 @@ impl Apu {
                 let volume = noise.envelope.sample_volume();
                 let timer = noise.timer;
                 let mut timer_count = noise.timer_count;
-                let mut rng = noise.rng;
-                let mut on = 1;
+                let mut shift = noise.shift;
+                let tap = if noise.short_mode { 6 } else { 1 };
 
                 for dest in buffer.iter_mut() {
                     timer_count += 1;
                     if timer_count >= timer {
                         timer_count = 0;
-                        on = rng.next() & 1;
+                        let feedback = (shift ^ (shift >> tap)) & 1;
+                        shift = (shift >> 1) | (feedback << 14);
                     }
 
-                    *dest = if on == 0 { 0 } else { volume };
+                    *dest = if (shift & 1) != 0 { 0 } else { volume };
                 }
 
                 noise.timer_count = timer_count;
-                noise.rng = rng;
+                noise.shift = shift;
             }
         }
     }
 
+    // The cartridge's sound hardware runs at the CPU clock, so each NES sample is one clock of it.
+    fn play_expansion(&mut self, channel: uint) {
+        let mut mapper = self.mapper.borrow_mut();
+        let (start, end) = (self.sample_buffer_offset,
+                            self.sample_buffer_offset + NES_SAMPLES_PER_TICK as uint);
+        let buffer = self.sample_buffers[channel].samples.slice_mut(start, end);
+        for dest in buffer.iter_mut() {
+            mapper.clock_audio();
+            // VRC6 and VRC7 can reach several times a pulse channel's full volume, more than a
+            // sample holds.
+            let val = (mapper.audio_output() * PULSE_FULL_VOLUME) as int32_t;
+            *dest = cmp::min(cmp::max(val, -32768), 32767) as int16_t;
+        }
+    }
+
+    /// Stops or restarts sound generation. The channels' waveforms don't advance while the APU
+    /// is silent, so this is only for frames nobody will hear: ones that are going to be rewound,
+    /// and those of a bot's console.
+    pub fn set_silent(&mut self, silent: bool) {
+        self.silent = silent;
+    }
+
+    /// Called after a state has been loaded from a file or a movie, but not after run-ahead
+    /// restores a snapshot. The channel buffers aren't part of the state, so they still hold the
+    /// audio from before the load; it's played out, and then the loaded state's audio fades in
+    /// from where it left off, so the jump doesn't click.
+    pub fn state_loaded(&mut self) {
+        self.flush();
+        self.declick = DECLICK_SAMPLES;
+    }
+
     // Resamples and flushes channel buffers to the audio output device if necessary.
     pub fn play_channels(&mut self) {
-        let sample_buffer_length = self.sample_buffers[0].samples.len();
-        if self.sample_buffer_offset < sample_buffer_length {
+        if self.sample_buffer_offset < FLUSH_SAMPLE_COUNT {
             return;
         }
+        self.flush();
+    }
+
+    /// Resamples and flushes however much is in the channel buffers to the audio output device,
+    /// even if that's less than a full buffer. This lets a single frame be heard on its own.
+    pub fn flush(&mut self) {
+        let len = self.sample_buffer_offset;
         self.sample_buffer_offset = 0;
+        if len == 0 {
+            return;
+        }
 
-        // First, mix all sample buffers into the first one.
-        //
-        // FIXME: This should not be a linear mix, for accuracy.
-        for i in range(0, self.sample_buffers[0].samples.len()) {
-            let mut val = 0;
-            for j in range(0, 5) {
-                val += self.sample_buffers[j].samples[i] as int32_t;
+        // First, mix all sample buffers into the first one. The cartridge's sound is added on
+        // after either kind of mixing, since it comes into the console's audio as it is.
+        for i in range(0, len) {
+            let mut samples = [ 0.0f32; CHANNEL_COUNT ];
+            for j in range(0, CHANNEL_COUNT) {
+                if !self.muted[j] {
+                    samples[j] = self.sample_buffers[j].samples[i] as f32;
+                }
             }
+            let mut val = match self.mixing {
+                LinearMixing => samples.iter().take(EXPANSION_CHANNEL).fold(0.0, |a, &b| a + b),
+                NonlinearMixing => {
+                    mix_nonlinear(samples[0], samples[1], samples[2], samples[3], samples[4])
+                }
+            } as int32_t;
+            val += samples[EXPANSION_CHANNEL] as int32_t;
 
             if val > 32767 {
                 val = 32767;
@@ -671,28 +911,110 @@ impl Apu {
             self.sample_buffers[0].samples[i] = val as int16_t;
         }
 
-        if self.output_buffer.is_none() {
+        if self.output_buffer.is_none() && self.capture.is_none() {
             return;
         }
-        let output_buffer = self.output_buffer.unwrap();
 
-        // Wait for the audio callback to catch up if necessary.
-        loop {
-            unsafe {
-                let lock = audio::g_mutex.lock();
-                lock.wait();
-                if (*output_buffer).play_offset == (*output_buffer).samples.len() {
-                    break
+        // Dynamic rate control. The emulated clock and the audio device's never quite agree, so
+        // we play a little faster when the device's buffer is fuller than we'd like, and a little
+        // slower when it's emptier. Half a percent is too little to hear. Captured audio has to
+        // stay at exactly the output rate, though, so the adjustment is held off while capturing.
+        // A buffer well below the target means emulation can't keep up, which the resampler is
+        // told so that it can do less work.
+        match self.output_buffer {
+            None => {}
+            Some(output_buffer) => {
+                let fill = unsafe {
+                    let _lock = audio::g_mutex.lock();
+                    (*output_buffer).fill_level()
+                };
+                self.resampler.set_falling_behind(fill < BEHIND_FILL_LEVEL);
+                let rate = if self.capture.is_some() {
+                    OUTPUT_SAMPLE_RATE
+                } else {
+                    let adjustment = 1.0 + MAX_RATE_ADJUSTMENT * 2.0 * (TARGET_FILL_LEVEL - fill);
+                    (OUTPUT_SAMPLE_RATE as f32 * adjustment) as uint32_t
+                };
+                self.resampler.set_output_rate(rate);
+            }
+        }
+
+        // Resample, leaving room for the rate to have been raised.
+        let capacity = len * (OUTPUT_SAMPLE_RATE as uint) / (NES_SAMPLE_RATE as uint) * 11 / 10;
+        let mut output = Vec::from_elem(capacity + 16, 0i16);
+        let output_len = self.resampler.process(self.sample_buffers[0].samples.slice_to(len),
+                                                output.as_mut_slice());
+        let last = self.last_output as int32_t;
+        for sample in output.slice_to_mut(output_len).iter_mut() {
+            if self.declick == 0 {
+                break;
+            }
+            let progress = (DECLICK_SAMPLES - self.declick) as int32_t;
+            *sample = (last + (*sample as int32_t - last) * progress / DECLICK_SAMPLES as int32_t)
+                      as int16_t;
+            self.declick -= 1;
+        }
+        if output_len > 0 {
+            self.last_output = output[output_len - 1];
+        }
+        let output = output.slice_to(output_len);
+
+        match self.capture {
+            None => {}
+            Some(ref mut capture) => {
+                if self.capture_skip < output.len() {
+                    capture.push_all(output.slice_from(self.capture_skip));
                 }
+                self.capture_skip = 0;
             }
         }
-        let _lock = AudioLock::lock();
+
+        let output_buffer = match self.output_buffer {
+            None => return,
+            Some(output_buffer) => output_buffer,
+        };
+
+        // Wait for the device to play its buffer down to the target level, which paces emulation
+        // to the audio, then queue the new samples.
         unsafe {
-            // Resample and output the audio.
-            let _ = self.resampler.process(0,
-                                           self.sample_buffers[0].samples,
-                                           (*output_buffer).samples);
-            (*output_buffer).play_offset = 0;
+            let lock = audio::g_mutex.lock();
+            let mut queued = 0;
+            loop {
+                if (*output_buffer).fill_level() <= TARGET_FILL_LEVEL {
+                    queued += (*output_buffer).push(output.slice_from(queued));
+                    if queued == output.len() {
+                        break;
+                    }
+                }
+                lock.wait();
+            }
+        }
+    }
+
+    //
+    // Capture
+    //
+
+    /// Starts keeping resampled output for `take_captured()`, beginning at the current emulated
+    /// time.
+    pub fn start_capture(&mut self) {
+        // The samples we've already generated in this buffer will still come out of the
+        // resampler, so skip however many output samples they amount to.
+        self.capture_skip = ((self.sample_buffer_offset as uint64_t) *
+                             (OUTPUT_SAMPLE_RATE as uint64_t) /
+                             (NES_SAMPLE_RATE as uint64_t)) as uint;
+        self.capture = Some(Vec::new());
+    }
+
+    pub fn stop_capture(&mut self) {
+        self.capture = None;
+    }
+
+    /// Returns the samples captured since the last call.
+    pub fn take_captured(&mut self) -> Vec<int16_t> {
+        match self.capture {
+            None => Vec::new(),
+            Some(ref mut capture) => mem::replace(capture, Vec::new()),
         }
     }
 }
@@ -7,7 +7,9 @@
 // TODO: This module is very unsafe. Adding a reader-writer audio lock to SDL would help make it
 // safe.
 
-use libc::{c_int, c_void, uint8_t};
+use log;
+
+use libc::{c_int, c_void, int16_t, uint8_t};
 use sdl2::audio::ll::{ SDL_AudioSpec, AUDIO_S16LSB };
 use sdl2::audio::AudioDevice;
 use std::cmp;
@@ -20,41 +22,66 @@ use std::rt::mutex::{NATIVE_MUTEX_INIT, StaticNativeMutex};
 // The audio callback
 //
 
-const SAMPLE_COUNT: uint = 4410 * 2;
+// The number of samples the device asks for at a time.
+const DEVICE_SAMPLE_COUNT: u16 = 1024;
+
+pub const SAMPLE_COUNT: uint = 8192;
 
 static mut g_audio_device: Option<AudioDevice> = None;
 
 static mut g_output_buffer: Option<*mut OutputBuffer> = None;
 
+// Held while touching the output buffer. The callback signals it whenever it has taken samples.
 pub static mut g_mutex: StaticNativeMutex = NATIVE_MUTEX_INIT;
 
+// A ring buffer of samples waiting to be played.
 pub struct OutputBuffer {
-    pub samples: [uint8_t; SAMPLE_COUNT],
+    pub samples: [int16_t; SAMPLE_COUNT],
     pub play_offset: uint,
+    pub len: uint,          // The number of samples waiting, starting at `play_offset`.
+}
+
+impl OutputBuffer {
+    /// How full the buffer is, from 0 to 1.
+    pub fn fill_level(&self) -> f32 {
+        self.len as f32 / SAMPLE_COUNT as f32
+    }
+
+    /// Queues as many of the samples as there's room for, and returns how many that was.
+    pub fn push(&mut self, samples: &[int16_t]) -> uint {
+        let count = cmp::min(samples.len(), SAMPLE_COUNT - self.len);
+        for i in range(0, count) {
+            self.samples[(self.play_offset + self.len + i) % SAMPLE_COUNT] = samples[i];
+        }
+        self.len += count;
+        count
+    }
 }
 
 extern "C" fn nes_audio_callback(_: *const c_void,
                                  stream: *const uint8_t,
                                  len: c_int) {
     unsafe {
-        let samples: &mut [uint8_t] = mem::transmute(Slice {
-            data: stream,
-            len: len as uint,
+        let samples: &mut [int16_t] = mem::transmute(Slice {
+            data: stream as *const int16_t,
+            len: len as uint / 2,
         });
 
+        let lock = g_mutex.lock();
         let output_buffer: &mut OutputBuffer = mem::transmute(g_output_buffer.unwrap());
-        let play_offset = output_buffer.play_offset;
-        let output_buffer_len = output_buffer.samples.len();
 
+        // If emulation has fallen behind, the rest is silence.
+        let count = cmp::min(samples.len(), output_buffer.len);
         for i in range(0, samples.len()) {
-            if i + play_offset >= output_buffer_len {
-                break;
-            }
-            samples[i] = output_buffer.samples[i + play_offset];
+            samples[i] = if i < count {
+                output_buffer.samples[(output_buffer.play_offset + i) % SAMPLE_COUNT]
+            } else {
+                0
+            };
         }
 
-        let lock = g_mutex.lock();
-        output_buffer.play_offset = cmp::min(play_offset + samples.len(), output_buffer_len);
+        output_buffer.play_offset = (output_buffer.play_offset + count) % SAMPLE_COUNT;
+        output_buffer.len -= count;
         lock.signal();
     }
 }
@@ -65,8 +92,9 @@ extern "C" fn nes_audio_callback(_: *const c_void,
 
 pub fn open() -> Option<*mut OutputBuffer> {
     let output_buffer = Box::new(OutputBuffer {
-        samples: [ 0; 8820 ],
+        samples: [ 0; SAMPLE_COUNT ],
         play_offset: 0,
+        len: 0,
     });
     let output_buffer_ptr: *mut OutputBuffer = unsafe {
         mem::transmute(&*output_buffer)
@@ -82,7 +110,7 @@ pub fn open() -> Option<*mut OutputBuffer> {
         format: AUDIO_S16LSB,
         channels: 1,
         silence: 0,
-        samples: 4410,
+        samples: DEVICE_SAMPLE_COUNT,
         padding: 0,
         size: 0,
         userdata: ptr::null(),
@@ -98,7 +126,7 @@ pub fn open() -> Option<*mut OutputBuffer> {
                 return Some(output_buffer_ptr)
             },
             Err(e) => {
-                println!("Error initializing AudioDevice: {}", e);
+                log!(log::Audio, log::Error, "can't open the audio device: {}", e);
                 return None
             }
         }
@@ -120,28 +148,3 @@ pub fn close() {
         }
     }
 }
-
-pub struct AudioLock;
-
-impl Drop for AudioLock {
-    fn drop(&mut self) {
-        unsafe {
-            match g_audio_device {
-                None => {}
-                Some(audio_device) => audio_device.unlock(),
-            }
-        }
-    }
-}
-
-impl AudioLock {
-    pub fn lock() -> AudioLock {
-        unsafe {
-            match g_audio_device {
-                None => {}
-                Some(audio_device) => audio_device.lock(),
-            }
-        }
-        AudioLock
-    }
-}
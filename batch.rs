@@ -0,0 +1,162 @@
+//
+// sprocketnes/batch.rs
+//
+// Author: Patrick Walton
+//
+
+// Batch runs: lots of headless runs, listed in a JSON file and spread over several threads. This
+// is for testing against a pile of ROMs, or for generating screenshots and audio in bulk. Each job
+// does what `--frames` does on its own:
+//
+//     [
+//         { "rom": "smb.nes", "frames": 600, "screenshot": "smb.png" },
+//         { "rom": "smb.nes", "state": "8-1.sav", "movie": "8-4.mov", "frames": 3600,
+//           "screenshot": "8-4.png", "wav": "8-4.wav" }
+//     ]
+//
+// Only `rom` and `frames` are required; `movie` is played, not recorded. Options given on the
+// command line, like `--revision` or `--palette`, apply to every job.
+//
+// Every job gets a console of its own, so jobs share nothing but the log.
+
+use main::{Options, run_headless};
+use mapper;
+use rom::Rom;
+
+use serialize::json::Json;
+use serialize::json;
+use std::cmp;
+use std::io::File;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUint, SeqCst};
+use std::sync::mpsc::channel;
+use std::task;
+use std::thread::Thread;
+
+fn string_field(job: &Json, name: &str) -> Result<Option<String>, String> {
+    match job.find(name) {
+        None => Ok(None),
+        Some(val) => {
+            match val.as_string() {
+                Some(string) => Ok(Some(string.to_string())),
+                None => Err(format!("`{}` should be a string", name)),
+            }
+        }
+    }
+}
+
+// Makes the options for one job, starting from the ones given on the command line.
+fn parse_job(job: &Json, defaults: &Options) -> Result<Options, String> {
+    if !job.is_object() {
+        return Err("jobs should be objects".to_string());
+    }
+
+    let mut options = defaults.clone();
+    options.rom_path = match try!(string_field(job, "rom")) {
+        Some(path) => path,
+        None => return Err("`rom` is missing".to_string()),
+    };
+    options.frames = match job.find("frames").and_then(|frames| frames.as_u64()) {
+        Some(frames) => Some(frames as uint),
+        None => return Err("`frames` is missing or isn't a number".to_string()),
+    };
+    options.state_path = try!(string_field(job, "state"));
+    options.movie_path = try!(string_field(job, "movie"));
+    options.record_movie = false;
+    options.screenshot_path = try!(string_field(job, "screenshot"));
+    options.wav_path = try!(string_field(job, "wav"));
+
+    // Catch missing files now, rather than an hour into the batch.
+    {
+        let inputs = [ Some(&options.rom_path), options.state_path.as_ref(),
+                       options.movie_path.as_ref() ];
+        for path in inputs.iter().filter_map(|path| *path) {
+            if !Path::new(path.as_slice()).exists() {
+                return Err(format!("{} doesn't exist", path));
+            }
+        }
+    }
+    Ok(options)
+}
+
+/// Reads the jobs from a JSON file. The error says what's wrong, for showing to the user.
+pub fn parse_jobs(path: &Path, defaults: &Options) -> Result<Vec<Options>, String> {
+    let text = match File::open(path).read_to_string() {
+        Ok(text) => text,
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    };
+    let json = match json::from_str(text.as_slice()) {
+        Ok(json) => json,
+        Err(err) => return Err(format!("{} isn't valid JSON: {}", path.display(), err)),
+    };
+    let jobs = match json.as_array() {
+        Some(jobs) => jobs,
+        None => return Err(format!("{} should hold an array of jobs", path.display())),
+    };
+
+    let mut result = Vec::new();
+    for (i, job) in jobs.iter().enumerate() {
+        match parse_job(job, defaults) {
+            Ok(options) => result.push(options),
+            Err(err) => return Err(format!("job {}: {}", i + 1, err)),
+        }
+    }
+    Ok(result)
+}
+
+fn run_job(options: &Options) -> Result<(), String> {
+    let rom = try!(Rom::from_path(&Path::new(options.rom_path.as_slice())));
+    try!(mapper::check_rom(&rom));
+    run_headless(&rom, options);
+    Ok(())
+}
+
+// Runs a job in a task of its own, so that if the emulator panics on it, the failure is reported
+// and the thread goes on to the next job. The panic's message has already gone to stderr.
+fn run_job_catching_panics(options: &Options) -> Result<(), String> {
+    let options = options.clone();
+    match task::try(move || run_job(&options)) {
+        Ok(result) => result,
+        Err(_) => Err("the emulator panicked".to_string()),
+    }
+}
+
+/// Runs the jobs on the given number of threads, printing a line as each one finishes. Returns
+/// the number of jobs that didn't succeed.
+pub fn run(jobs: Vec<Options>, threads: uint) -> uint {
+    let count = jobs.len();
+    let jobs = Arc::new(jobs);
+
+    // Threads take the next job when they finish one, so a few slow jobs don't hold up the rest.
+    let next_job = Arc::new(AtomicUint::new(0));
+    let (sender, receiver) = channel();
+    for _ in range(0, cmp::min(threads, count)) {
+        let jobs = jobs.clone();
+        let next_job = next_job.clone();
+        let sender = sender.clone();
+        Thread::spawn(move || {
+            loop {
+                let index = next_job.fetch_add(1, SeqCst);
+                if index >= jobs.len() {
+                    break;
+                }
+                sender.send((index, run_job_catching_panics(&jobs[index])));
+            }
+        });
+    }
+    drop(sender);
+
+    let mut finished = 0;
+    let mut failed = 0;
+    for (index, result) in receiver.iter() {
+        finished += 1;
+        match result {
+            Ok(()) => println!("[{}/{}] {}: done", finished, count, jobs[index].rom_path),
+            Err(err) => {
+                println!("[{}/{}] {}: {}", finished, count, jobs[index].rom_path, err);
+                failed += 1;
+            }
+        }
+    }
+    failed
+}
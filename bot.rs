@@ -0,0 +1,162 @@
+//
+// sprocketnes/bot.rs
+//
+// Author: Patrick Walton
+//
+
+// An interface for programs that play games: bots that search for good inputs by brute force,
+// learn them by reinforcement, and so on. A bot sees the console's RAM at the end of every frame
+// and chooses the buttons to hold during the next one. The console runs headless, as fast as the
+// host allows, and its state can be snapshotted in memory to try out several futures from the
+// same point.
+//
+// Bots are written in Rust against this module directly, so that nothing like a socket sits
+// between them and the emulator. Nothing in the emulator itself uses it.
+//
+// Nobody hears a bot's console, so its APU is silent, which saves resampling audio that would only
+// be thrown away.
+
+use cpu::Cpu;
+use main::{Options, power_on, step};
+use mapper;
+use mem::MemMap;
+use rom::Rom;
+use rtc::EmulatedTime;
+use util::Snapshot;
+
+use libc::uint8_t;
+
+/// The first controller's buttons, one bit each, in the order the console reads them.
+pub type Buttons = uint8_t;
+
+pub const BUTTON_A: Buttons      = 0x01;
+pub const BUTTON_B: Buttons      = 0x02;
+pub const BUTTON_SELECT: Buttons = 0x04;
+pub const BUTTON_START: Buttons  = 0x08;
+pub const BUTTON_UP: Buttons     = 0x10;
+pub const BUTTON_DOWN: Buttons   = 0x20;
+pub const BUTTON_LEFT: Buttons   = 0x40;
+pub const BUTTON_RIGHT: Buttons  = 0x80;
+
+pub trait Bot {
+    /// Called at the end of every frame with the console's 2K of RAM. Returns the buttons to hold
+    /// during the next frame.
+    fn frame(&mut self, ram: &[uint8_t]) -> Buttons;
+
+    /// Rates how well things are going, judging by the RAM. Higher is better. Only needed for
+    /// `best_input`.
+    fn score(&mut self, _: &[uint8_t]) -> f64 { 0.0 }
+}
+
+/// A console with no window or audio.
+pub struct Console {
+    cpu: Cpu<MemMap>,
+}
+
+impl Console {
+    /// Powers on a console with the ROM in it. The error says why the ROM can't be run.
+    pub fn new(rom: &Rom) -> Result<Console, String> {
+        try!(mapper::check_rom(rom));
+        // The same inputs have to give the same run, whatever the time of day.
+        let mut options = Options::default();
+        options.rtc_source = EmulatedTime;
+        let mut cpu = try!(power_on(rom, None, &options));
+        cpu.mem.apu.set_silent(true);
+        Ok(Console { cpu: cpu })
+    }
+
+    pub fn ram(&self) -> &[uint8_t] {
+        self.cpu.mem.ram.as_slice()
+    }
+
+    /// The last frame, in the PPU's BGR format.
+    pub fn screen(&self) -> &[uint8_t] {
+        self.cpu.mem.ppu.screen.as_slice()
+    }
+
+    /// Runs one frame with the given buttons held.
+    pub fn run_frame(&mut self, buttons: Buttons) {
+        self.cpu.mem.input.set_buttons(buttons);
+        while !step(&mut self.cpu) {}
+    }
+
+    pub fn snapshot(&mut self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        snapshot.take(&mut self.cpu);
+        snapshot
+    }
+
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        snapshot.restore(&mut self.cpu);
+    }
+}
+
+/// Lets the bot play for the given number of frames.
+pub fn run<B>(console: &mut Console, bot: &mut B, frames: uint) where B: Bot {
+    for _ in range(0, frames) {
+        let buttons = bot.frame(console.ram());
+        console.run_frame(buttons);
+    }
+}
+
+/// Tries holding each of the candidate inputs for the given number of frames, starting from the
+/// current state each time, and has the bot score the result. Leaves the console in the state the
+/// best-scoring input led to, and returns that input and its score.
+pub fn best_input<B>(console: &mut Console, bot: &mut B, candidates: &[Buttons], frames: uint)
+                     -> Option<(Buttons, f64)> where B: Bot {
+    let start = console.snapshot();
+    let mut best_state = Snapshot::new();
+    let mut best = None;
+    for &buttons in candidates.iter() {
+        console.restore(&start);
+        for _ in range(0, frames) {
+            console.run_frame(buttons);
+        }
+
+        let score = bot.score(console.ram());
+        let better = match best {
+            None => true,
+            Some((_, best_score)) => score > best_score,
+        };
+        if better {
+            best = Some((buttons, score));
+            best_state.take(&mut console.cpu);
+        }
+    }
+
+    console.restore(if best.is_some() { &best_state } else { &start });
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Console;
+
+    use rom::Rom;
+
+    // A cartridge whose program stores $42 at $0010, then loops forever.
+    fn store_rom() -> Rom {
+        let mut rom = Rom::blank();
+        let program = [
+            0xa9, 0x42,         // LDA #$42
+            0x85, 0x10,         // STA $10
+            0x4c, 0x04, 0x80,   // JMP $8004
+        ];
+        for (i, &byte) in program.iter().enumerate() {
+            rom.prg[i] = byte;
+        }
+        // The reset vector points at $8000.
+        rom.prg[0x7ffc] = 0x00;
+        rom.prg[0x7ffd] = 0x80;
+        rom
+    }
+
+    #[test]
+    fn ram_shows_what_the_frame_did() {
+        let mut console = Console::new(&store_rom()).unwrap();
+        assert_eq!(console.ram().len(), 2048);
+        assert_eq!(console.ram()[0x10], 0);
+        console.run_frame(0);
+        assert_eq!(console.ram()[0x10], 0x42);
+    }
+}
@@ -0,0 +1,37 @@
+//
+// sprocketnes/checksum.rs
+//
+// Author: Patrick Walton
+//
+
+// Checksums of the console's state, one per frame, for noticing when two runs that should be
+// identical have drifted apart. Movies store them next to the input they play, and netplay peers
+// trade them, so a desync is caught on the frame it happens rather than minutes later, when the
+// game has visibly gone its own way.
+//
+// Each frame's checksum is a CRC-32 of the CPU's registers and RAM, continued from the previous
+// frame's. Once two streams differ, they stay different. The PPU and APU are left out to keep it
+// cheap; a difference there affects the game's RAM within a frame or two anyway.
+
+use cpu::Cpu;
+use mem::MemMap;
+use util;
+
+use libc::uint32_t;
+
+pub struct ChecksumStream {
+    checksum: uint32_t,
+}
+
+impl ChecksumStream {
+    pub fn new() -> ChecksumStream {
+        ChecksumStream { checksum: 0 }
+    }
+
+    /// Folds in the state at the end of a frame, and returns the frame's checksum.
+    pub fn add_frame(&mut self, cpu: &Cpu<MemMap>) -> uint32_t {
+        let checksum = util::crc32_update(self.checksum, &cpu.reg_bytes());
+        self.checksum = util::crc32_update(checksum, cpu.mem.ram.as_slice());
+        self.checksum
+    }
+}
@@ -0,0 +1,171 @@
+//
+// sprocketnes/compat.rs
+//
+// Author: Patrick Walton
+//
+
+// The compatibility log. With `--compat-log`, each session adds a line to a file saying which
+// game was played, on which mapper, for how long, and how many times the player flagged a problem
+// with the flag key. Nothing is sent anywhere; `--compat-export` sums the log up per game as JSON,
+// for sending in by hand:
+//
+//     [{"crc":"3FE272FB","name":"smb","mapper":0,"sessions":3,"seconds":5400.2,
+//       "flagged_sessions":1,"flags":2}]
+//
+// The log itself is one JSON object per line, so that a crash mid-write costs one session at
+// most.
+
+use main::NTSC_FRAME_RATE;
+use rom::Rom;
+
+use libc::uint32_t;
+use serialize::json::Json;
+use serialize::json;
+use std::io::{Append, File, IoResult, Write};
+
+// One line of the log, as it's written.
+#[derive(Encodable)]
+struct LogLine {
+    crc: String,
+    name: String,
+    mapper: uint,
+    seconds: f64,
+    flags: uint,
+}
+
+/// One run of one game, recorded when it ends.
+pub struct Session {
+    crc: uint32_t,
+    name: String,
+    mapper: uint,
+    frames: uint,
+    flags: uint,
+}
+
+impl Session {
+    pub fn start(rom: &Rom, name: &str) -> Session {
+        Session {
+            crc: rom.identity_crc32(),
+            name: name.to_string(),
+            mapper: rom.header.mapper_number() as uint,
+            frames: 0,
+            flags: 0,
+        }
+    }
+
+    /// Counts a frame of play. Time spent paused doesn't count.
+    pub fn add_frame(&mut self) {
+        self.frames += 1;
+    }
+
+    /// Notes that the player saw something wrong. Returns how many times they have so far.
+    pub fn flag(&mut self) -> uint {
+        self.flags += 1;
+        self.flags
+    }
+
+    /// Adds the session to the end of the log.
+    pub fn finish(&self, path: &Path) -> IoResult<()> {
+        let line = LogLine {
+            crc: format!("{:08X}", self.crc as uint),
+            name: self.name.clone(),
+            mapper: self.mapper,
+            seconds: self.frames as f64 / NTSC_FRAME_RATE,
+            flags: self.flags,
+        };
+        let mut file = try!(File::open_mode(path, Append, Write));
+        writeln!(file, "{}", json::encode(&line))
+    }
+}
+
+//
+// Exporting
+//
+
+// Everything the log says about one game, as it's exported.
+#[derive(Encodable)]
+struct GameSummary {
+    crc: String,
+    name: String,
+    mapper: u64,
+    sessions: uint,
+    seconds: f64,
+    flagged_sessions: uint,
+    flags: u64,
+}
+
+fn string_field(session: &Json, name: &str) -> Result<String, String> {
+    match session.find(name).and_then(|val| val.as_string()) {
+        Some(string) => Ok(string.to_string()),
+        None => Err(format!("`{}` is missing or isn't a string", name)),
+    }
+}
+
+fn number_field(session: &Json, name: &str) -> Result<f64, String> {
+    match session.find(name).and_then(|val| val.as_f64()) {
+        Some(number) => Ok(number),
+        None => Err(format!("`{}` is missing or isn't a number", name)),
+    }
+}
+
+// Reads one line of the log: the CRC, name, mapper, seconds played, and flags.
+fn parse_session(line: &str) -> Result<(String, String, u64, f64, u64), String> {
+    let session = match json::from_str(line) {
+        Ok(session) => session,
+        Err(err) => return Err(format!("isn't valid JSON: {}", err)),
+    };
+    Ok((try!(string_field(&session, "crc")),
+        try!(string_field(&session, "name")),
+        try!(number_field(&session, "mapper")) as u64,
+        try!(number_field(&session, "seconds")),
+        try!(number_field(&session, "flags")) as u64))
+}
+
+/// Sums up the log per game and writes the result as JSON. Returns the number of games.
+pub fn export(log_path: &Path, out_path: &Path) -> Result<uint, String> {
+    let text = match File::open(log_path).read_to_string() {
+        Ok(text) => text,
+        Err(err) => return Err(format!("couldn't read {}: {}", log_path.display(), err)),
+    };
+
+    let mut games: Vec<GameSummary> = Vec::new();
+    for (i, line) in text.as_slice().lines().enumerate() {
+        if line.trim().len() == 0 {
+            continue;
+        }
+        let (crc, name, mapper, seconds, flags) = match parse_session(line) {
+            Ok(session) => session,
+            Err(err) => return Err(format!("line {} of {} {}", i + 1, log_path.display(), err)),
+        };
+        let index = match games.iter().position(|game| game.crc == crc) {
+            Some(index) => index,
+            None => {
+                games.push(GameSummary {
+                    crc: crc,
+                    name: String::new(),
+                    mapper: mapper,
+                    sessions: 0,
+                    seconds: 0.0,
+                    flagged_sessions: 0,
+                    flags: 0,
+                });
+                games.len() - 1
+            }
+        };
+        let game = &mut games[index];
+        game.name = name;       // The newest file name wins.
+        game.sessions += 1;
+        game.seconds += seconds;
+        game.flags += flags;
+        if flags > 0 {
+            game.flagged_sessions += 1;
+        }
+    }
+    games.sort_by(|a, b| a.name.cmp(&b.name));
+
+    let summary = json::encode(&games);
+    match File::create(out_path).write_line(summary.as_slice()) {
+        Ok(()) => Ok(games.len()),
+        Err(err) => Err(format!("couldn't write {}: {}", out_path.display(), err)),
+    }
+}
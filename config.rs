@@ -0,0 +1,60 @@
+//
+// sprocketnes/config.rs
+//
+// Author: Patrick Walton
+//
+
+// The config file, `sprocketnes.cfg` in the working directory, for settings that should apply to
+// every run, such as the video filters. Each line sets one of the options that take a value, named
+// as on the command line but without the dashes, followed by the value:
+//
+//     # A CRT look
+//     filters crop:8,scale:2,scanlines:60
+//     revision letterless
+//
+// Blank lines and lines starting with `#` are skipped. The file is read before the command line,
+// so options given there win.
+
+use main::{Options, VALUE_OPTIONS, parse_value_option};
+
+use std::io::{File, FileNotFound};
+
+pub const CONFIG_PATH: &'static str = "sprocketnes.cfg";
+
+// Sets one option from a line of the file.
+fn parse_line(options: &mut Options, line: &str) -> Result<(), String> {
+    let (name, val) = match line.find(|c: char| c.is_whitespace()) {
+        Some(index) => (line.slice_to(index), line.slice_from(index).trim()),
+        None => (line, ""),
+    };
+    let option = format!("--{}", name);
+    let option = option.as_slice();
+    // Movies are one-offs, and take two values besides.
+    if option == "--movie" || !VALUE_OPTIONS.iter().any(|&name| name == option) {
+        return Err(format!("`{}` can't be set in the config file", name));
+    }
+    if val.len() == 0 {
+        return Err(format!("`{}` needs a value", name));
+    }
+    parse_value_option(options, option, val)
+}
+
+/// Applies the settings in the config file at `path` to `options`. A missing file sets nothing.
+pub fn load(path: &Path, options: &mut Options) -> Result<(), String> {
+    let text = match File::open(path).read_to_string() {
+        Ok(text) => text,
+        Err(ref err) if err.kind == FileNotFound => return Ok(()),
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    };
+    for (i, line) in text.as_slice().lines().enumerate() {
+        let line = line.trim();
+        if line.len() == 0 || line.starts_with("#") {
+            continue;
+        }
+        match parse_line(options, line) {
+            Ok(()) => {}
+            Err(err) => return Err(format!("line {} of {}: {}", i + 1, path.display(), err)),
+        }
+    }
+    Ok(())
+}
@@ -4,14 +4,12 @@
 // Author: Patrick Walton
 //
 
+use disasm::Disassembler;
+use log;
 use mem::{Mem, MemUtil};
 use util::Save;
 
 use libc::{int8_t, int32_t, uint8_t, uint16_t, uint32_t, uint64_t};
-use std::io::File;
-
-#[cfg(cpuspew)]
-use disasm::Disassembler;
 
 //
 // Constants
@@ -329,6 +327,14 @@ pub struct Cpu<M> {
     pub cy: Cycles,
     regs: Regs,
     pub mem: M,
+
+    // Interrupt lines. Any number of devices can hold IRQ asserted, and the CPU keeps taking IRQs
+    // for as long as one does and the I flag allows it. NMI fires once each time its line is
+    // asserted. The CPU looks at both only between instructions.
+    irq_line: bool,
+    nmi_line: bool,
+    nmi_pending: bool,
+    irq_inhibit: bool,  // The I flag as of the last interrupt poll
 }
 
 // The CPU implements Mem so that it can handle writes to the DMA register.
@@ -346,27 +352,37 @@ impl<M> Mem for Cpu<M> where M: Mem {
 
 // Save state logic.
 impl<M> Save for Cpu<M> where M: Mem + Save {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         self.cy.save(fd);
         self.regs.save(fd);
+        self.irq_line.save(fd);
+        self.nmi_line.save(fd);
+        self.nmi_pending.save(fd);
+        self.irq_inhibit.save(fd);
         self.mem.save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         self.cy.load(fd);
         self.regs.load(fd);
+        self.irq_line.load(fd);
+        self.nmi_line.load(fd);
+        self.nmi_pending.load(fd);
+        self.irq_inhibit.load(fd);
         self.mem.load(fd);
     }
 }
 
 impl<M> Cpu<M> where M: Mem {
-    // Debugging
-    #[cfg(cpuspew)]
+    // Debugging: logs each instruction, nestest.log style, at the trace level.
     fn trace(&mut self) {
+        if !log::enabled(log::Cpu, log::Trace) {
+            return;
+        }
         let mut disassembler = Disassembler {
             pc: self.regs.pc,
             mem: &mut self.mem
         };
-        println!(
+        log!(log::Cpu, log::Trace,
             "{:04X} {:20s} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{}",
             self.regs.pc as uint,
             disassembler.disassemble(),
@@ -378,8 +394,6 @@ impl<M> Cpu<M> where M: Mem {
             self.cy as uint
         );
     }
-    #[cfg(not(cpuspew))]
-    fn trace(&mut self) {}
 
     // Performs DMA to the OAMDATA ($2004) register.
     fn dma(&mut self, hi_addr: uint8_t) {
@@ -753,8 +767,9 @@ impl<M> Cpu<M> where M: Mem {
     fn brk(&mut self) {
         let pc = self.regs.pc;
         self.pushw(pc + 1);
+        // The B flag is how a handler shared with IRQs tells that it was called by BRK.
         let flags = self.regs.flags;
-        self.pushb(flags);    // FIXME: FCEU sets BREAK_FLAG and U_FLAG here, why?
+        self.pushb(flags | BREAK_FLAG);
         self.set_flag(IRQ_FLAG, true);
         self.regs.pc = self.loadw(BRK_VECTOR);
     }
@@ -785,37 +800,82 @@ impl<M> Cpu<M> where M: Mem {
     // No operation
     fn nop(&mut self) {}
 
-    // The main fetch-and-decode routine
+    // Pushes the return address and flags and jumps through the given vector. Unlike BRK, this
+    // pushes the flags with B clear.
+    fn interrupt(&mut self, vector: uint16_t) {
+        let (pc, flags) = (self.regs.pc, self.regs.flags);
+        self.pushw(pc);
+        self.pushb(flags & !BREAK_FLAG);
+        self.set_flag(IRQ_FLAG, true);
+        self.regs.pc = self.loadw(vector);
+        self.irq_inhibit = true;
+        self.cy += 7;
+    }
+
+    // The main fetch-and-decode routine. If an interrupt was pending when the last instruction
+    // finished, this runs the interrupt sequence instead of an instruction.
     pub fn step(&mut self) {
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR);
+            return;
+        }
+        if self.irq_line && !self.irq_inhibit {
+            self.interrupt(BRK_VECTOR);
+            return;
+        }
+
         self.trace();
 
+        let irq_flag = self.get_flag(IRQ_FLAG);
         let op = self.loadb_bump_pc();
         decode_op!(op, self);
 
         self.cy += CYCLE_TABLE[op as uint] as Cycles;
+
+        // CLI, SEI, and PLP change the I flag after the CPU has polled for interrupts, so an IRQ
+        // waiting on CLI gets in one instruction late.
+        self.irq_inhibit = match op {
+            0x28 | 0x58 | 0x78 => irq_flag,
+            _ => self.get_flag(IRQ_FLAG),
+        };
     }
 
     /// External interfaces
     pub fn reset(&mut self) { self.regs.pc = self.loadw(RESET_VECTOR); }
 
-    pub fn nmi(&mut self) {
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(NMI_VECTOR);
-    }
+    /// Sets the level of the shared IRQ line, which is asserted if any device wants an IRQ.
+    pub fn set_irq_line(&mut self, asserted: bool) { self.irq_line = asserted }
 
-    pub fn irq(&mut self) {
-        if self.get_flag(IRQ_FLAG) {
-            return;
+    /// Sets the level of the NMI line. An NMI happens when it goes from clear to asserted.
+    pub fn set_nmi_line(&mut self, asserted: bool) {
+        if asserted && !self.nmi_line {
+            self.nmi_pending = true;
         }
+        self.nmi_line = asserted;
+    }
 
-        let (pc, flags) = (self.regs.pc, self.regs.flags);
-        self.pushw(pc);
-        self.pushb(flags);
-        self.regs.pc = self.loadw(BRK_VECTOR);
+    /// The address of the next instruction.
+    pub fn pc(&self) -> uint16_t { self.regs.pc }
+
+    /// The registers as bytes, in the order they're saved in: A, X, Y, S, P, then PC.
+    pub fn reg_bytes(&self) -> [uint8_t; 7] {
+        let regs = &self.regs;
+        let pc = regs.pc;
+        [ regs.a, regs.x, regs.y, regs.s, regs.flags, pc as uint8_t, (pc >> 8) as uint8_t ]
     }
 
     /// The constructor.
-    pub fn new(mem: M) -> Cpu<M> { Cpu { cy: 0, regs: Regs::new(), mem: mem } }
+    pub fn new(mem: M) -> Cpu<M> {
+        Cpu {
+            cy: 0,
+            regs: Regs::new(),
+            mem: mem,
+
+            irq_line: false,
+            nmi_line: false,
+            nmi_pending: false,
+            irq_inhibit: true,
+        }
+    }
 }
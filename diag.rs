@@ -0,0 +1,211 @@
+//
+// sprocketnes/diag.rs
+//
+// Author: Patrick Walton
+//
+
+// Diagnostic screens, for sorting out a display or input setup without a game:
+//
+// * `--diagnostic pattern` shows all 64 colors of the palette, a gray ramp, and lines marking
+//   the edge of the picture and the 8 pixels of overscan most TVs hide. It goes through the same
+//   palette, filters, and scaling as a game would, so it shows what they do.
+//
+// * `--diagnostic controller` shows which of the controller's buttons are held and the last key
+//   pressed or released, as the emulator sees them. Only the keyboard is read, so there are no
+//   analog axes to show.
+//
+// Escape quits, as it does in a game.
+
+use gfx::Gfx;
+use input::Input;
+use input;
+use osd;
+use ppu;
+
+use libc::uint8_t;
+use sdl2::keyboard;
+use std::io::timer;
+use std::time::Duration;
+
+const SCREEN_WIDTH: uint = 256;
+const SCREEN_HEIGHT: uint = 240;
+
+// Palette entries.
+const BLACK: uint8_t = 0x0f;
+const GRAY: uint8_t = 0x00;
+const WHITE: uint8_t = 0x30;
+const RED: uint8_t = 0x16;
+const GREEN: uint8_t = 0x2a;
+
+// The grays, from black to white.
+const GRAY_RAMP: [uint8_t; 6] = [ 0x0f, 0x2d, 0x00, 0x10, 0x3d, 0x20 ];
+
+// Most TVs hide about this many pixels at each edge.
+const OVERSCAN: uint = 8;
+
+#[derive(Clone)]
+pub enum Diagnostic {
+    TestPattern,
+    ControllerTest,
+}
+
+impl Diagnostic {
+    pub fn parse(name: &str) -> Result<Diagnostic, String> {
+        match name {
+            "pattern" => Ok(TestPattern),
+            "controller" => Ok(ControllerTest),
+            _ => Err(format!("expected `pattern` or `controller`, not `{}`", name)),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match *self {
+            TestPattern => "Test pattern",
+            ControllerTest => "Controller test",
+        }
+    }
+}
+
+// A picture made of palette entries, like the PPU's.
+struct Screen {
+    pixels: Box<[uint8_t; 184320]>,
+    indices: Box<[uint8_t; 61440]>,
+    colors: Vec<uint8_t>,
+}
+
+impl Screen {
+    fn new(colors: Vec<uint8_t>) -> Screen {
+        Screen {
+            pixels: Box::new([ 0; 184320 ]),
+            indices: Box::new([ 0; 61440 ]),
+            colors: colors,
+        }
+    }
+
+    fn fill(&mut self, x: uint, y: uint, width: uint, height: uint, index: uint8_t) {
+        let color = index as uint * 3;
+        for y in range(y, y + height) {
+            for x in range(x, x + width) {
+                let offset = y * SCREEN_WIDTH + x;
+                self.indices[offset] = index;
+                self.pixels[offset * 3 + 0] = self.colors[color + 2];
+                self.pixels[offset * 3 + 1] = self.colors[color + 1];
+                self.pixels[offset * 3 + 2] = self.colors[color + 0];
+            }
+        }
+    }
+
+    // Outlines a rectangle, one pixel wide.
+    fn frame(&mut self, x: uint, y: uint, width: uint, height: uint, index: uint8_t) {
+        self.fill(x, y, width, 1, index);
+        self.fill(x, y + height - 1, width, 1, index);
+        self.fill(x, y, 1, height, index);
+        self.fill(x + width - 1, y, 1, height, index);
+    }
+
+    fn text(&mut self, x: uint, y: uint, string: &str) {
+        osd::draw_text(self.pixels.as_mut_slice(), SCREEN_WIDTH, x as int, y as int, string);
+    }
+}
+
+//
+// Test pattern
+//
+
+const SWATCH_LEFT: uint = 32;
+const SWATCH_TOP: uint = 40;
+const SWATCH_WIDTH: uint = 13;
+const SWATCH_HEIGHT: uint = 22;
+
+const RAMP_TOP: uint = 140;
+const RAMP_HEIGHT: uint = 40;
+
+fn draw_test_pattern(screen: &mut Screen) {
+    screen.fill(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT, BLACK);
+
+    // The palette, a row for each brightness.
+    screen.text(SWATCH_LEFT, SWATCH_TOP - 24, "Palette");
+    for column in range(0u, 16) {
+        let x = SWATCH_LEFT + column * SWATCH_WIDTH;
+        screen.text(x + 3, SWATCH_TOP - 12, format!("{:X}", column).as_slice());
+    }
+    for row in range(0u, 4) {
+        let y = SWATCH_TOP + row * SWATCH_HEIGHT;
+        screen.text(SWATCH_LEFT - 20, y + 6, format!("{}x", row).as_slice());
+        for column in range(0u, 16) {
+            let x = SWATCH_LEFT + column * SWATCH_WIDTH;
+            screen.fill(x, y, SWATCH_WIDTH, SWATCH_HEIGHT, (row * 16 + column) as uint8_t);
+        }
+    }
+
+    // The grays, which show up tinting and crushed blacks or whites.
+    let ramp_width = 16 * SWATCH_WIDTH / GRAY_RAMP.len();
+    for (i, &index) in GRAY_RAMP.iter().enumerate() {
+        screen.fill(SWATCH_LEFT + i * ramp_width, RAMP_TOP, ramp_width, RAMP_HEIGHT, index);
+    }
+
+    // The edge of the picture in red, and the edge of the overscan in white. If the red lines
+    // can't be seen, the display is cutting the picture off.
+    screen.frame(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT, RED);
+    screen.frame(OVERSCAN,
+                 OVERSCAN,
+                 SCREEN_WIDTH - OVERSCAN * 2,
+                 SCREEN_HEIGHT - OVERSCAN * 2,
+                 WHITE);
+    screen.text(SWATCH_LEFT, RAMP_TOP + RAMP_HEIGHT + 8, "White: overscan; red: edge");
+}
+
+//
+// Controller test
+//
+
+const BUTTON_LEFT: uint = 24;
+const BUTTON_TOP: uint = 32;
+const BUTTON_SIZE: uint = 10;
+
+fn draw_controller_test(screen: &mut Screen, input: &Input) {
+    screen.fill(0, 0, SCREEN_WIDTH, SCREEN_HEIGHT, BLACK);
+    screen.text(BUTTON_LEFT, BUTTON_TOP - 20, "Controller 1");
+
+    let buttons = input.buttons();
+    for (i, name) in input::BUTTON_NAMES.iter().enumerate() {
+        let y = BUTTON_TOP + i * (BUTTON_SIZE + 6);
+        let held = (buttons >> i) & 1 != 0;
+        screen.fill(BUTTON_LEFT, y, BUTTON_SIZE, BUTTON_SIZE, if held { GREEN } else { GRAY });
+        screen.text(BUTTON_LEFT + BUTTON_SIZE + 8, y, *name);
+    }
+
+    let y = BUTTON_TOP + 8 * (BUTTON_SIZE + 6) + 8;
+    screen.text(BUTTON_LEFT, y, format!("Buttons, A first: ${:02X}", buttons as uint).as_slice());
+    let last_key = match input.last_key {
+        None => "none yet".to_string(),
+        Some((key, down)) => {
+            format!("{} (code {}), {}",
+                    keyboard::get_key_name(key),
+                    key as int,
+                    if down { "pressed" } else { "released" })
+        }
+    };
+    screen.text(BUTTON_LEFT, y + 16, format!("Last key: {}", last_key).as_slice());
+    screen.text(BUTTON_LEFT, y + 32, "Only the keyboard is read; no axes.");
+}
+
+/// Shows the diagnostic screen until the window is closed or Escape is pressed.
+pub fn run(diagnostic: Diagnostic, mut gfx: Gfx, colors: Option<Vec<uint8_t>>) {
+    gfx.set_title(format!("sprocketnes - {}", diagnostic.title()).as_slice());
+    let mut screen = Screen::new(colors.unwrap_or_else(|| ppu::PALETTE.to_vec()));
+    let mut input = Input::new();
+    loop {
+        match input.check_input() {
+            input::Quit => break,
+            _ => {}
+        }
+        match diagnostic {
+            TestPattern => draw_test_pattern(&mut screen),
+            ControllerTest => draw_controller_test(&mut screen, &input),
+        }
+        gfx.tick();
+        gfx.composite(&mut *screen.pixels, screen.indices.as_slice());
+        timer::sleep(Duration::milliseconds(16));
+    }
+}
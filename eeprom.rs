@@ -0,0 +1,366 @@
+//
+// sprocketnes/eeprom.rs
+//
+// Author: Patrick Walton
+//
+
+// Serial EEPROMs, which some Bandai boards use to keep saved games instead of battery-backed RAM.
+// The game drives the clock (SCL) and data (SDA) lines of an I2C bus through a mapper register and
+// reads SDA back through another.
+//
+// The 24C01 speaks a simplified protocol: after the start condition comes a 7-bit word address
+// and the read/write bit, with no device address, and every byte goes least significant bit
+// first. The 24C02 speaks standard I2C: a device address byte, then the word address for writes,
+// most significant bit first.
+//
+// Writes go straight into the array rather than waiting for the stop condition, and the chip is
+// never busy afterward. No game depends on either.
+//
+// See http://wiki.nesdev.com/w/index.php/Bandai_FCG_board
+
+use util::Save;
+
+use libc::uint8_t;
+
+pub enum Chip {
+    X24C01,     // 128 bytes
+    X24C02,     // 256 bytes
+}
+
+// What the chip expects next.
+#[derive(PartialEq)]
+enum Phase {
+    Standby,    // Waiting for a start condition
+    Command,    // The 24C01's address and read/write bit, or the 24C02's device address
+    Address,    // The 24C02's word address
+    Writing,    // Bytes to store
+    Reading,    // Sending bytes to the game
+}
+
+const DEVICE_CODE: uint8_t = 0xa0;
+
+pub struct Eeprom {
+    chip: Chip,
+    data: Vec<uint8_t>,
+    phase: Phase,
+    address: uint,
+    // The clock edges seen in the current byte: eight for the data, then one for the
+    // acknowledgement.
+    bit: uint,
+    shift: uint8_t,
+    acked: bool,        // Whether the game acknowledged the last byte it read
+    scl: bool,          // The lines as the game last drove them
+    sda: bool,
+    output: bool,       // What the chip drives on SDA; high when it lets go of the line
+}
+
+impl Eeprom {
+    pub fn new(chip: Chip) -> Eeprom {
+        let size = match chip { X24C01 => 128, X24C02 => 256 };
+        Eeprom {
+            chip: chip,
+            data: Vec::from_elem(size, 0xff),
+            phase: Standby,
+            address: 0,
+            bit: 0,
+            shift: 0,
+            acked: false,
+            scl: false,
+            sda: false,
+            output: true,
+        }
+    }
+
+    /// The stored bytes, for keeping them in a file between runs.
+    pub fn contents(&mut self) -> &mut [uint8_t] { self.data.as_mut_slice() }
+
+    /// The level of SDA as the game reads it back.
+    pub fn sda(&self) -> bool { self.output }
+
+    /// Sets the levels the game drives on the clock and data lines.
+    pub fn write(&mut self, scl: bool, sda: bool) {
+        if self.scl && scl && sda != self.sda {
+            // SDA changing while the clock is high marks the start or the end of a transfer.
+            if sda {
+                self.phase = Standby;
+            } else {
+                self.phase = Command;
+                self.bit = 0;
+                self.shift = 0;
+            }
+            self.output = true;
+        } else if !self.scl && scl {
+            self.clock_rising(sda);
+        } else if self.scl && !scl {
+            self.clock_falling();
+        }
+        self.scl = scl;
+        self.sda = sda;
+    }
+
+    fn lsb_first(&self) -> bool {
+        match self.chip { X24C01 => true, X24C02 => false }
+    }
+
+    // The receiver samples SDA while the clock is high.
+    fn clock_rising(&mut self, sda: bool) {
+        match self.phase {
+            Standby => return,
+            Reading => {
+                // After a byte it sent, the chip lets go of SDA, and the game pulls it low to ask
+                // for another. After the command, the chip is the one holding it low.
+                if self.bit == 8 && self.output {
+                    self.acked = !sda;
+                    if self.acked {
+                        self.address = (self.address + 1) % self.data.len();
+                    }
+                }
+            }
+            _ if self.bit < 8 => {
+                let bit = sda as uint8_t;
+                self.shift = if self.lsb_first() {
+                    (self.shift >> 1) | (bit << 7)
+                } else {
+                    (self.shift << 1) | bit
+                };
+            }
+            _ => {}
+        }
+        self.bit += 1;
+    }
+
+    // The transmitter changes SDA while the clock is low.
+    fn clock_falling(&mut self) {
+        if self.bit == 9 {
+            self.bit = 0;
+            if self.phase == Reading && !self.acked {
+                self.phase = Standby;
+            }
+        }
+        self.output = match self.phase {
+            Standby => true,
+            Reading if self.bit < 8 => {
+                let byte = self.data[self.address];
+                let shift = if self.lsb_first() { self.bit } else { 7 - self.bit };
+                (byte >> shift) & 1 != 0
+            }
+            Reading => true,
+            _ if self.bit == 8 => !self.receive_byte(),
+            _ => true,
+        };
+    }
+
+    // Acts on a whole byte from the game. Returns true to acknowledge it.
+    fn receive_byte(&mut self) -> bool {
+        let byte = self.shift;
+        match (self.phase, self.chip) {
+            (Command, X24C01) => {
+                self.address = (byte & 0x7f) as uint;
+                self.start_transfer(byte & 0x80 != 0);
+            }
+            (Command, X24C02) => {
+                // The board ties the chip-select pins low, but the chip answers to any of them.
+                if byte & 0xf0 != DEVICE_CODE {
+                    self.phase = Standby;
+                    return false;
+                }
+                if byte & 1 != 0 {
+                    self.start_transfer(true);
+                } else {
+                    self.phase = Address;
+                }
+            }
+            (Address, _) => {
+                self.address = byte as uint;
+                self.phase = Writing;
+            }
+            (Writing, _) => {
+                self.data[self.address] = byte;
+                // Writes wrap around within a page.
+                let page_size = match self.chip { X24C01 => 4, X24C02 => 8 };
+                self.address = (self.address & !(page_size - 1)) |
+                    ((self.address + 1) & (page_size - 1));
+            }
+            _ => {}
+        }
+        true
+    }
+
+    fn start_transfer(&mut self, read: bool) {
+        if read {
+            self.phase = Reading;
+            self.acked = true;
+        } else {
+            self.phase = Writing;
+        }
+    }
+}
+
+impl Save for Eeprom {
+    fn save(&mut self, fd: &mut Writer) {
+        self.data.as_mut_slice().save(fd);
+        let mut phase: uint8_t = match self.phase {
+            Standby => 0, Command => 1, Address => 2, Writing => 3, Reading => 4
+        };
+        phase.save(fd);
+        self.address.save(fd);
+        self.bit.save(fd);
+        self.shift.save(fd);
+        self.acked.save(fd);
+        self.scl.save(fd);
+        self.sda.save(fd);
+        self.output.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        self.data.as_mut_slice().load(fd);
+        let mut phase: uint8_t = 0;
+        phase.load(fd);
+        self.phase = match phase {
+            1 => Command, 2 => Address, 3 => Writing, 4 => Reading, _ => Standby
+        };
+        self.address.load(fd);
+        self.bit.load(fd);
+        self.shift.load(fd);
+        self.acked.load(fd);
+        self.scl.load(fd);
+        self.sda.load(fd);
+        self.output.load(fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chip, Eeprom, X24C01, X24C02};
+
+    use libc::uint8_t;
+
+    // Drives the bus the way a game's serial routine would.
+    struct Bus {
+        eeprom: Eeprom,
+        lsb_first: bool,
+    }
+
+    impl Bus {
+        fn new(chip: Chip) -> Bus {
+            let lsb_first = match chip { X24C01 => true, X24C02 => false };
+            let mut bus = Bus { eeprom: Eeprom::new(chip), lsb_first: lsb_first };
+            bus.eeprom.write(false, true);
+            bus
+        }
+
+        fn start(&mut self) {
+            self.eeprom.write(false, true);
+            self.eeprom.write(true, true);
+            self.eeprom.write(true, false);
+            self.eeprom.write(false, false);
+        }
+
+        fn stop(&mut self) {
+            self.eeprom.write(false, false);
+            self.eeprom.write(true, false);
+            self.eeprom.write(true, true);
+        }
+
+        // Clocks out one bit and returns SDA as read back while the clock is high.
+        fn clock(&mut self, sda: bool) -> bool {
+            self.eeprom.write(false, sda);
+            self.eeprom.write(true, sda);
+            let level = self.eeprom.sda();
+            self.eeprom.write(false, sda);
+            level
+        }
+
+        // Sends a byte and returns whether the chip acknowledged it.
+        fn send(&mut self, byte: uint8_t) -> bool {
+            for i in range(0, 8u) {
+                let shift = if self.lsb_first { i } else { 7 - i };
+                self.clock((byte >> shift) & 1 != 0);
+            }
+            !self.clock(true)
+        }
+
+        fn receive(&mut self, ack: bool) -> uint8_t {
+            let mut byte = 0;
+            for i in range(0, 8u) {
+                let shift = if self.lsb_first { i } else { 7 - i };
+                byte |= (self.clock(true) as uint8_t) << shift;
+            }
+            self.clock(!ack);
+            byte
+        }
+    }
+
+    #[test]
+    fn x24c01_write_then_read() {
+        let mut bus = Bus::new(X24C01);
+        bus.start();
+        assert!(bus.send(0x12));            // Address $12, write
+        assert!(bus.send(0xab));
+        assert!(bus.send(0xcd));
+        bus.stop();
+
+        bus.start();
+        assert!(bus.send(0x80 | 0x12));     // Address $12, read
+        assert_eq!(bus.receive(true), 0xab);
+        assert_eq!(bus.receive(false), 0xcd);
+        bus.stop();
+
+        assert_eq!(bus.eeprom.contents()[0x12], 0xab);
+        assert_eq!(bus.eeprom.contents()[0x13], 0xcd);
+    }
+
+    #[test]
+    fn x24c02_write_then_read() {
+        let mut bus = Bus::new(X24C02);
+        bus.start();
+        assert!(bus.send(0xa0));
+        assert!(bus.send(0x40));
+        assert!(bus.send(0x5a));
+        bus.stop();
+
+        // A dummy write sets the address for the read.
+        bus.start();
+        assert!(bus.send(0xa0));
+        assert!(bus.send(0x40));
+        bus.start();
+        assert!(bus.send(0xa1));
+        assert_eq!(bus.receive(true), 0x5a);
+        assert_eq!(bus.receive(false), 0xff);
+        bus.stop();
+    }
+
+    #[test]
+    fn x24c02_ignores_other_devices() {
+        let mut bus = Bus::new(X24C02);
+        bus.start();
+        assert!(!bus.send(0x50));
+        assert!(!bus.send(0x00));
+        bus.stop();
+        assert!(bus.eeprom.contents().iter().all(|&byte| byte == 0xff));
+    }
+
+    #[test]
+    fn page_writes_wrap() {
+        let mut bus = Bus::new(X24C02);
+        bus.start();
+        assert!(bus.send(0xa0));
+        assert!(bus.send(0x07));
+        assert!(bus.send(0x01));
+        assert!(bus.send(0x02));
+        bus.stop();
+        assert_eq!(bus.eeprom.contents()[0x07], 0x01);
+        assert_eq!(bus.eeprom.contents()[0x00], 0x02);
+        assert_eq!(bus.eeprom.contents()[0x08], 0xff);
+    }
+
+    #[test]
+    fn stop_ends_a_read() {
+        let mut bus = Bus::new(X24C01);
+        bus.eeprom.contents()[0] = 0x00;
+        bus.start();
+        assert!(bus.send(0x80));
+        bus.stop();
+        // With the transfer over, the chip lets go of SDA.
+        assert!(bus.clock(true));
+    }
+}
@@ -0,0 +1,743 @@
+//
+// sprocketnes/fds.rs
+//
+// Author: Patrick Walton
+//
+
+// The Famicom Disk System: a disk drive that sits under the Famicom, with 32K of RAM for
+// programs, 8K of CHR-RAM, the BIOS, a timer IRQ, and a wavetable sound channel.
+//
+// The BIOS isn't ours to distribute, so it's loaded from `disksys.rom` in the current directory.
+//
+// See http://wiki.nesdev.com/w/index.php/Family_Computer_Disk_System
+
+use mapper::{Horizontal, Mapper, Mirroring, Vertical};
+use ppu::CYCLES_PER_SCANLINE;
+use rom::Rom;
+use util::Save;
+
+use libc::{uint8_t, uint16_t, uint64_t};
+use std::io::File;
+use std::vec::Vec;
+
+const BIOS_PATH: &'static str = "disksys.rom";
+const BIOS_SIZE: uint = 8192;
+
+// The drive moves about 12K bytes a second under the head.
+const CYCLES_PER_BYTE: uint64_t = 149;
+// How long the head takes to go back to the start of the disk.
+const HEAD_RETURN_CYCLES: uint64_t = 50000;
+// How long the disk stays out of the drive when switching sides, so the BIOS notices.
+const SIDE_SWITCH_SCANLINES: uint = 261 * 30;
+
+// The gaps between blocks, which .fds images leave out, in bytes.
+const LEAD_IN_GAP: uint = 28300 / 8;
+const BLOCK_GAP: uint = 976 / 8;
+
+//
+// Disk images
+//
+// The BIOS sees the disk as a stream of bytes: a gap of zeroes, a start mark, a block, and its
+// CRC. Disk images only hold the blocks, so we put the rest back in. The BIOS doesn't learn the
+// CRC is bogus because we never report a CRC error.
+//
+
+// Returns the length of the block at the start of the given data, given the size of the file the
+// last file header block described.
+fn block_length(data: &[uint8_t], file_size: uint) -> Option<uint> {
+    match data[0] {
+        1 => Some(56),              // Disk info
+        2 => Some(2),               // File count
+        3 => Some(16),              // File header
+        4 => Some(1 + file_size),   // File data
+        _ => None,                  // End of the data on this side
+    }
+}
+
+fn raw_side(side: &[uint8_t]) -> Vec<uint8_t> {
+    let mut raw = Vec::from_elem(LEAD_IN_GAP, 0u8);
+    let mut pos = 0;
+    let mut file_size = 0;
+    while pos < side.len() {
+        let len = match block_length(side.slice_from(pos), file_size) {
+            Some(len) if pos + len <= side.len() => len,
+            _ => break,
+        };
+        if side[pos] == 3 {
+            file_size = side[pos + 13] as uint | (side[pos + 14] as uint << 8);
+        }
+
+        raw.push(0x80);
+        raw.push_all(side.slice(pos, pos + len));
+        raw.push(0x4d);
+        raw.push(0x62);
+        raw.grow(BLOCK_GAP, 0u8);
+        pos += len;
+    }
+
+    // Leave room for the game to write new files.
+    if raw.len() < side.len() + LEAD_IN_GAP {
+        let missing = side.len() + LEAD_IN_GAP - raw.len();
+        raw.grow(missing, 0u8);
+    }
+    raw
+}
+
+//
+// The wavetable sound channel
+//
+// See http://wiki.nesdev.com/w/index.php/FDS_audio
+//
+
+// The modulation table entries, as changes to the modulation counter. Entry 4 resets it.
+const MOD_STEPS: [int; 8] = [ 0, 1, 2, 4, 0, -4, -2, -1 ];
+
+// Master volume, as a multiple of 1/36 of full volume.
+const MASTER_VOLUMES: [int; 4] = [ 36, 24, 17, 14 ];
+
+struct FdsEnvelope {
+    speed: uint8_t,
+    gain: uint8_t,
+    increase: bool,
+    disabled: bool,     // In which case the gain is set directly.
+    counter: uint,
+}
+
+save_struct!(FdsEnvelope { speed, gain, increase, disabled, counter });
+
+impl FdsEnvelope {
+    fn new() -> FdsEnvelope {
+        FdsEnvelope { speed: 0, gain: 0, increase: false, disabled: true, counter: 0 }
+    }
+
+    fn storeb(&mut self, val: uint8_t) {
+        self.speed = val & 0x3f;
+        self.increase = (val & 0x40) != 0;
+        self.disabled = (val & 0x80) != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.counter = 0;
+    }
+
+    fn tick(&mut self, master_speed: uint8_t) {
+        if self.disabled {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < 8 * (self.speed as uint + 1) * (master_speed as uint) {
+            return;
+        }
+        self.counter = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+struct FdsAudio {
+    wave: [uint8_t; 64],
+    wave_writable: bool,
+    wave_frequency: uint16_t,
+    wave_halted: bool,
+    wave_accumulator: uint16_t,
+    wave_position: uint,
+
+    mod_table: [uint8_t; 64],
+    mod_table_position: uint,
+    mod_frequency: uint16_t,
+    mod_halted: bool,
+    mod_accumulator: uint16_t,
+    mod_counter: int,           // 7-bit signed
+
+    volume: FdsEnvelope,
+    modulation: FdsEnvelope,
+    envelopes_halted: bool,
+    envelope_speed: uint8_t,    // $408A
+    master_volume: uint,
+
+    // The volume only changes at the start of each pass through the wave.
+    output_gain: uint8_t,
+}
+
+save_struct!(FdsAudio {
+    wave,
+    wave_writable,
+    wave_frequency,
+    wave_halted,
+    wave_accumulator,
+    wave_position,
+    mod_table,
+    mod_table_position,
+    mod_frequency,
+    mod_halted,
+    mod_accumulator,
+    mod_counter,
+    volume,
+    modulation,
+    envelopes_halted,
+    envelope_speed,
+    master_volume,
+    output_gain
+});
+
+impl FdsAudio {
+    fn new() -> FdsAudio {
+        FdsAudio {
+            wave: [ 0; 64 ],
+            wave_writable: false,
+            wave_frequency: 0,
+            wave_halted: true,
+            wave_accumulator: 0,
+            wave_position: 0,
+
+            mod_table: [ 0; 64 ],
+            mod_table_position: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+            mod_counter: 0,
+
+            volume: FdsEnvelope::new(),
+            modulation: FdsEnvelope::new(),
+            envelopes_halted: false,
+            envelope_speed: 0xe8,
+            master_volume: 0,
+
+            output_gain: 0,
+        }
+    }
+
+    fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+        match addr {
+            0x4040 ... 0x407f => self.wave[addr as uint & 0x3f] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.modulation.gain | 0x40,
+            _ => 0x40,
+        }
+    }
+
+    fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr {
+            0x4040 ... 0x407f => {
+                if self.wave_writable {
+                    self.wave[addr as uint & 0x3f] = val & 0x3f;
+                }
+            }
+            0x4080 => self.volume.storeb(val),
+            0x4082 => self.wave_frequency = (self.wave_frequency & 0x0f00) | val as uint16_t,
+            0x4083 => {
+                self.wave_frequency = (self.wave_frequency & 0x00ff) |
+                    ((val as uint16_t & 0x0f) << 8);
+                self.wave_halted = (val & 0x80) != 0;
+                self.envelopes_halted = (val & 0x40) != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                    self.wave_position = 0;
+                }
+            }
+            0x4084 => self.modulation.storeb(val),
+            0x4085 => {
+                let counter = (val & 0x7f) as int;
+                self.mod_counter = if counter >= 64 { counter - 128 } else { counter };
+            }
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0f00) | val as uint16_t,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00ff) |
+                    ((val as uint16_t & 0x0f) << 8);
+                self.mod_halted = (val & 0x80) != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            0x4088 => {
+                // Each write fills two entries, and only while modulation is halted.
+                if self.mod_halted {
+                    self.mod_table[self.mod_table_position] = val & 0x07;
+                    self.mod_table[(self.mod_table_position + 1) & 0x3f] = val & 0x07;
+                    self.mod_table_position = (self.mod_table_position + 2) & 0x3f;
+                }
+            }
+            0x4089 => {
+                self.master_volume = val as uint & 0x03;
+                self.wave_writable = (val & 0x80) != 0;
+            }
+            0x408a => self.envelope_speed = val,
+            _ => {}
+        }
+    }
+
+    // The change to the wave's pitch that the modulation unit makes.
+    fn mod_pitch(&self) -> int {
+        let mut temp = self.mod_counter * (self.modulation.gain as int);
+        let remainder = temp & 0x0f;
+        temp >>= 4;
+        if remainder > 0 && (temp & 0x80) == 0 {
+            temp += if self.mod_counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= self.wave_frequency as int;
+        let remainder = temp & 0x3f;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        temp
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.tick(self.envelope_speed);
+            self.modulation.tick(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency != 0 {
+            let overflowed = self.mod_accumulator > 0xffff - self.mod_frequency;
+            self.mod_accumulator += self.mod_frequency;
+            if overflowed {
+                let entry = self.mod_table[self.mod_table_position] as uint;
+                let counter = if entry == 4 { 0 } else { self.mod_counter + MOD_STEPS[entry] };
+                self.mod_counter = if counter >= 64 {
+                    counter - 128
+                } else if counter < -64 {
+                    counter + 128
+                } else {
+                    counter
+                };
+                self.mod_table_position = (self.mod_table_position + 1) & 0x3f;
+            }
+        }
+
+        if self.wave_halted || self.wave_writable {
+            return;
+        }
+        let pitch = if self.mod_halted {
+            self.wave_frequency as int
+        } else {
+            self.wave_frequency as int + self.mod_pitch()
+        };
+        if pitch <= 0 {
+            return;
+        }
+        let pitch = pitch as uint16_t;
+        let overflowed = self.wave_accumulator > 0xffff - pitch;
+        self.wave_accumulator += pitch;
+        if overflowed {
+            self.wave_position = (self.wave_position + 1) & 0x3f;
+            if self.wave_position == 0 {
+                self.output_gain = self.volume.gain;
+            }
+        }
+    }
+
+    // The output level, from 0 to 63.
+    fn output(&self) -> int {
+        let gain = if self.output_gain > 32 { 32 } else { self.output_gain as int };
+        self.wave[self.wave_position] as int * gain * MASTER_VOLUMES[self.master_volume] / 1152
+    }
+}
+
+//
+// The RAM adapter and disk drive
+//
+
+pub struct Fds {
+    bios: Vec<uint8_t>,
+    prg_ram: Box<[uint8_t; 32768]>,
+    chr_ram: Box<[uint8_t; 8192]>,
+
+    sides: Vec<Vec<uint8_t>>,
+    side: Option<uint>,             // The side in the drive, if any.
+    next_side: Option<uint>,        // The side that goes in once the drive's been empty a while.
+    side_switch_countdown: uint,
+
+    disk_io_enabled: bool,          // $4023
+    sound_io_enabled: bool,
+
+    timer_reload: uint16_t,         // $4020, $4021
+    timer_counter: uint64_t,
+    timer_enabled: bool,            // $4022
+    timer_repeat: bool,
+    timer_irq: bool,
+
+    // $4025
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    gap_ended_by_game: bool,        // Tells the drive to look for the start of a block.
+    disk_irq_enabled: bool,
+
+    // The drive.
+    position: uint,
+    delay: uint64_t,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    read_data: uint8_t,             // $4031
+    write_data: uint8_t,            // $4024
+    byte_transferred: bool,
+    disk_irq: bool,
+
+    audio: FdsAudio,
+}
+
+// The disks are saved too, since games write to them.
+impl Save for Fds {
+    fn save(&mut self, fd: &mut Writer) {
+        self.prg_ram.save(fd);
+        self.chr_ram.save(fd);
+        for side in self.sides.iter_mut() {
+            side.as_mut_slice().save(fd);
+        }
+        self.side.save(fd);
+        self.next_side.save(fd);
+        self.side_switch_countdown.save(fd);
+        self.disk_io_enabled.save(fd);
+        self.sound_io_enabled.save(fd);
+        self.timer_reload.save(fd);
+        self.timer_counter.save(fd);
+        self.timer_enabled.save(fd);
+        self.timer_repeat.save(fd);
+        self.timer_irq.save(fd);
+        self.motor_on.save(fd);
+        self.reset_transfer.save(fd);
+        self.read_mode.save(fd);
+        self.horizontal_mirroring.save(fd);
+        self.gap_ended_by_game.save(fd);
+        self.disk_irq_enabled.save(fd);
+        self.position.save(fd);
+        self.delay.save(fd);
+        self.end_of_head.save(fd);
+        self.scanning.save(fd);
+        self.gap_ended.save(fd);
+        self.read_data.save(fd);
+        self.write_data.save(fd);
+        self.byte_transferred.save(fd);
+        self.disk_irq.save(fd);
+        self.audio.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        self.prg_ram.load(fd);
+        self.chr_ram.load(fd);
+        for side in self.sides.iter_mut() {
+            side.as_mut_slice().load(fd);
+        }
+        self.side.load(fd);
+        self.next_side.load(fd);
+        self.side_switch_countdown.load(fd);
+        self.disk_io_enabled.load(fd);
+        self.sound_io_enabled.load(fd);
+        self.timer_reload.load(fd);
+        self.timer_counter.load(fd);
+        self.timer_enabled.load(fd);
+        self.timer_repeat.load(fd);
+        self.timer_irq.load(fd);
+        self.motor_on.load(fd);
+        self.reset_transfer.load(fd);
+        self.read_mode.load(fd);
+        self.horizontal_mirroring.load(fd);
+        self.gap_ended_by_game.load(fd);
+        self.disk_irq_enabled.load(fd);
+        self.position.load(fd);
+        self.delay.load(fd);
+        self.end_of_head.load(fd);
+        self.scanning.load(fd);
+        self.gap_ended.load(fd);
+        self.read_data.load(fd);
+        self.write_data.load(fd);
+        self.byte_transferred.load(fd);
+        self.disk_irq.load(fd);
+        self.audio.load(fd);
+    }
+}
+
+impl Fds {
+    pub fn new(rom: Box<Rom>) -> Result<Fds, String> {
+        let bios = match File::open(&Path::new(BIOS_PATH)).read_to_end() {
+            Ok(bios) => bios,
+            Err(err) => {
+                return Err(format!("the Famicom Disk System BIOS must be in {}: {}",
+                                   BIOS_PATH,
+                                   err))
+            }
+        };
+        if bios.len() != BIOS_SIZE {
+            return Err(format!("{} isn't a Famicom Disk System BIOS", BIOS_PATH));
+        }
+
+        let sides: Vec<Vec<uint8_t>> = rom.disk_sides.iter()
+                                                     .map(|side| raw_side(side.as_slice()))
+                                                     .collect();
+        let side = if sides.len() > 0 { Some(0) } else { None };
+
+        Ok(Fds {
+            bios: bios,
+            prg_ram: Box::new([ 0; 32768 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+
+            sides: sides,
+            side: side,
+            next_side: None,
+            side_switch_countdown: 0,
+
+            disk_io_enabled: true,
+            sound_io_enabled: true,
+
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_enabled: false,
+            timer_repeat: false,
+            timer_irq: false,
+
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            gap_ended_by_game: false,
+            disk_irq_enabled: false,
+
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            read_data: 0,
+            write_data: 0,
+            byte_transferred: false,
+            disk_irq: false,
+
+            audio: FdsAudio::new(),
+        })
+    }
+
+    fn store_control(&mut self, val: uint8_t) {
+        self.motor_on = (val & 0x01) != 0;
+        self.reset_transfer = (val & 0x02) != 0;
+        self.read_mode = (val & 0x04) != 0;
+        self.horizontal_mirroring = (val & 0x08) != 0;
+        self.gap_ended_by_game = (val & 0x40) != 0;
+        self.disk_irq_enabled = (val & 0x80) != 0;
+        self.disk_irq = false;
+    }
+
+    fn read_status(&mut self) -> uint8_t {
+        let mut val = 0;
+        if self.timer_irq {
+            val |= 0x01;
+        }
+        if self.byte_transferred {
+            val |= 0x02;
+        }
+        if self.end_of_head {
+            val |= 0x40;
+        }
+        self.timer_irq = false;
+        self.byte_transferred = false;
+        self.disk_irq = false;
+        val
+    }
+
+    fn read_drive_status(&self) -> uint8_t {
+        let mut val = 0x40;
+        if self.side.is_none() {
+            val |= 0x01 | 0x02 | 0x04;     // No disk, not ready, not writable
+        } else if !self.scanning {
+            val |= 0x02;
+        }
+        val
+    }
+
+    // Runs the timer for the given number of CPU cycles.
+    fn clock_timer(&mut self, cycles: uint64_t) {
+        if !self.timer_enabled || !self.disk_io_enabled {
+            return;
+        }
+        if self.timer_counter > cycles {
+            self.timer_counter -= cycles;
+            return;
+        }
+        self.timer_irq = true;
+        if self.timer_repeat {
+            self.timer_counter = self.timer_reload as uint64_t;
+        } else {
+            self.timer_enabled = false;
+        }
+    }
+
+    // Runs the drive for the given number of CPU cycles. At most one byte goes past the head per
+    // call, which gives the BIOS a chance to keep up.
+    fn clock_drive(&mut self, cycles: uint64_t) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > cycles {
+            self.delay -= cycles;
+            return;
+        }
+        self.delay = CYCLES_PER_BYTE;
+        self.scanning = true;
+
+        let mut irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let val = self.sides[side][self.position];
+            if !self.gap_ended_by_game {
+                self.gap_ended = false;
+            } else if val != 0 && !self.gap_ended {
+                // The start mark isn't passed on.
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.read_data = val;
+                self.byte_transferred = true;
+                self.disk_irq = self.disk_irq || irq;
+            }
+        } else {
+            let val = if self.gap_ended_by_game { self.write_data } else { 0 };
+            self.sides[side][self.position] = val;
+            self.byte_transferred = true;
+            self.disk_irq = self.disk_irq || irq;
+        }
+
+        self.position += 1;
+        if self.position >= self.sides[side].len() {
+            self.end_of_head = true;
+            self.scanning = false;
+        }
+    }
+
+    fn clock_side_switch(&mut self) {
+        if self.next_side.is_none() {
+            return;
+        }
+        if self.side_switch_countdown > 0 {
+            self.side_switch_countdown -= 1;
+            return;
+        }
+        self.side = self.next_side.take();
+    }
+}
+
+impl Mapper for Fds {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        match addr {
+            0x6000 ... 0xdfff => self.prg_ram[addr as uint - 0x6000],
+            0xe000 ... 0xffff => self.bios[addr as uint - 0xe000],
+            _ => 0,
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr {
+            0x6000 ... 0xdfff => self.prg_ram[addr as uint - 0x6000] = val,
+            _ => {}
+        }
+    }
+
+    fn expansion_loadb(&mut self, addr: uint16_t) -> Option<uint8_t> {
+        match addr {
+            0x4030 if self.disk_io_enabled => Some(self.read_status()),
+            0x4031 if self.disk_io_enabled => {
+                self.byte_transferred = false;
+                self.disk_irq = false;
+                Some(self.read_data)
+            }
+            0x4032 if self.disk_io_enabled => Some(self.read_drive_status()),
+            0x4033 if self.disk_io_enabled => Some(0x80),   // Battery good
+            0x4040 ... 0x409f if self.sound_io_enabled => Some(self.audio.loadb(addr)),
+            _ => None,
+        }
+    }
+
+    fn expansion_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr {
+            0x4023 => {
+                self.disk_io_enabled = (val & 0x01) != 0;
+                self.sound_io_enabled = (val & 0x02) != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            }
+            0x4020 ... 0x4026 if !self.disk_io_enabled => {}
+            0x4020 => self.timer_reload = (self.timer_reload & 0xff00) | val as uint16_t,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00ff) | (val as uint16_t << 8),
+            0x4022 => {
+                self.timer_repeat = (val & 0x01) != 0;
+                self.timer_enabled = (val & 0x02) != 0;
+                self.timer_irq = false;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload as uint64_t;
+                }
+            }
+            0x4024 => {
+                self.write_data = val;
+                self.byte_transferred = false;
+                self.disk_irq = false;
+            }
+            0x4025 => self.store_control(val),
+            0x4040 ... 0x408a if self.sound_io_enabled => self.audio.storeb(addr, val),
+            _ => {}
+        }
+    }
+
+    // Only $4025 sets the mirroring. Everything from $6000 up is RAM, and CHR is RAM too.
+    fn is_banking_register(&self, addr: uint16_t) -> bool { addr == 0x4025 }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring { Horizontal } else { Vertical }
+    }
+
+    // TODO: The timer and the drive really run on every CPU cycle.
+    fn next_scanline(&mut self) {
+        self.clock_side_switch();
+        self.clock_timer(CYCLES_PER_SCANLINE);
+        self.clock_drive(CYCLES_PER_SCANLINE);
+    }
+
+    // Both IRQs stay asserted until acknowledged.
+    fn irq_pending(&self) -> bool { self.timer_irq || self.disk_irq }
+
+    fn clock_audio(&mut self) { self.audio.clock() }
+
+    // At full volume, the wavetable is about as loud as two pulse channels.
+    fn audio_output(&self) -> f32 { self.audio.output() as f32 * 2.0 / 63.0 }
+
+    fn disk_side_count(&self) -> uint { self.sides.len() }
+
+    fn disk_side(&self) -> Option<uint> {
+        if self.next_side.is_some() { self.next_side } else { self.side }
+    }
+
+    fn insert_disk_side(&mut self, side: uint) {
+        self.side = None;
+        self.next_side = Some(side);
+        self.side_switch_countdown = SIDE_SWITCH_SCANLINES;
+    }
+}
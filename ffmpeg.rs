@@ -0,0 +1,156 @@
+//
+// sprocketnes/ffmpeg.rs
+//
+// Author: Patrick Walton
+//
+
+// Records by piping raw video and audio into ffmpeg, which encodes them however its arguments
+// say. The video goes to ffmpeg's stdin and the audio to a second pipe, on file descriptor 3.
+//
+// ffmpeg reads its inputs in whatever order suits it, so it can sit waiting on one pipe while the
+// other is full. Each pipe is written by a thread of its own, fed through a channel, so that the
+// emulator never blocks on either. If a write fails, which usually means ffmpeg has quit, the
+// recording stops with the error.
+//
+// The arguments for the input side are fixed. The rest come from a template, with `{output}`
+// replaced by the output path; the default leaves the codecs up to ffmpeg, which picks them from
+// the output file's extension. The template is split on whitespace, with no quoting.
+
+use apu::OUTPUT_SAMPLE_RATE;
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use record::{Encoder, frame_rate, samples_for_frames};
+
+use libc::{int16_t, uint8_t, uint64_t};
+use std::io::process::{Command, CreatePipe, InheritFd, Process};
+use std::io::PipeStream;
+use std::sync::mpsc::{Receiver, Sender, channel};
+use std::thread::Thread;
+
+pub const DEFAULT_ARGS: &'static str = "-pix_fmt yuv420p {output}";
+
+pub struct FfmpegEncoder {
+    process: Process,
+    // These are dropped at the end, which closes the pipes once the writers have caught up, to
+    // tell ffmpeg there's no more.
+    video: Option<Sender<Vec<uint8_t>>>,
+    audio: Option<Sender<Vec<uint8_t>>>,
+    errors: Receiver<String>,
+    error: Option<String>,      // The first write that failed
+    frames: uint64_t,
+    samples: uint64_t,
+}
+
+// Starts a thread that writes whatever comes through the channel to the pipe, and reports the
+// first failure. After that, the rest is thrown away.
+fn spawn_writer(mut pipe: PipeStream, name: &'static str, errors: Sender<String>)
+                -> Sender<Vec<uint8_t>> {
+    let (sender, receiver) = channel::<Vec<uint8_t>>();
+    Thread::spawn(move || {
+        let mut failed = false;
+        for data in receiver.iter() {
+            if failed {
+                continue;
+            }
+            match pipe.write(data.as_slice()) {
+                Ok(()) => {}
+                Err(err) => {
+                    errors.send(format!("couldn't send the {} to ffmpeg: {}", name, err));
+                    failed = true;
+                }
+            }
+        }
+    });
+    sender
+}
+
+impl FfmpegEncoder {
+    /// Starts ffmpeg. The error says what went wrong, for showing to the user.
+    pub fn spawn(output: &str, template: &str) -> Result<FfmpegEncoder, String> {
+        let (rate, scale) = frame_rate();
+        let mut args = vec![
+            "-loglevel".to_string(), "error".to_string(), "-y".to_string(),
+            "-f".to_string(), "rawvideo".to_string(),
+            "-pixel_format".to_string(), "bgr24".to_string(),
+            "-video_size".to_string(), format!("{}x{}", SCREEN_WIDTH, SCREEN_HEIGHT),
+            "-framerate".to_string(), format!("{}/{}", rate, scale),
+            "-i".to_string(), "pipe:0".to_string(),
+            "-f".to_string(), "s16le".to_string(),
+            "-ar".to_string(), format!("{}", OUTPUT_SAMPLE_RATE),
+            "-ac".to_string(), "1".to_string(),
+            "-i".to_string(), "pipe:3".to_string(),
+        ];
+        for arg in template.split(' ').filter(|arg| arg.len() > 0) {
+            args.push(arg.replace("{output}", output));
+        }
+
+        // ffmpeg's own output goes straight to ours, so that it can't fill up a pipe and stall.
+        let mut process = match Command::new("ffmpeg").args(args.as_slice())
+                                                      .stdin(CreatePipe(true, false))
+                                                      .stdout(InheritFd(1))
+                                                      .stderr(InheritFd(2))
+                                                      .extra_io(CreatePipe(true, false))
+                                                      .spawn() {
+            Ok(process) => process,
+            Err(err) => return Err(format!("couldn't start ffmpeg: {}", err)),
+        };
+        let (error_sender, errors) = channel();
+        let video = spawn_writer(process.stdin.take().unwrap(), "video", error_sender.clone());
+        let audio = spawn_writer(process.extra_io[0].take().unwrap(), "audio", error_sender);
+        Ok(FfmpegEncoder {
+            process: process,
+            video: Some(video),
+            audio: Some(audio),
+            errors: errors,
+            error: None,
+            frames: 0,
+            samples: 0,
+        })
+    }
+}
+
+impl Encoder for FfmpegEncoder {
+    fn add_frame(&mut self, screen: &[uint8_t]) {
+        match self.video {
+            None => {}
+            Some(ref video) => video.send(screen.to_vec()),
+        }
+        self.frames += 1;
+    }
+
+    fn add_audio(&mut self, samples: &[int16_t]) {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples.iter() {
+            bytes.push(sample as uint8_t);
+            bytes.push((sample >> 8) as uint8_t);
+        }
+        match self.audio {
+            None => {}
+            Some(ref audio) => audio.send(bytes),
+        }
+        self.samples += samples.len() as uint64_t;
+    }
+
+    // Pads the audio out to the length of the video, then waits for ffmpeg to finish the file.
+    fn finish(&mut self) {
+        let expected = samples_for_frames(self.frames);
+        if self.samples < expected {
+            let silence = Vec::from_elem((expected - self.samples) as uint, 0 as int16_t);
+            self.add_audio(silence.as_slice());
+        }
+
+        self.video = None;
+        self.audio = None;
+        match self.process.wait() {
+            Ok(ref status) if status.success() => {}
+            Ok(status) => println!("ffmpeg failed: {}", status),
+            Err(err) => println!("ffmpeg failed: {}", err),
+        }
+    }
+
+    fn stopped(&mut self) -> Option<String> {
+        if self.error.is_none() {
+            self.error = self.errors.try_recv().ok();
+        }
+        self.error.clone()
+    }
+}
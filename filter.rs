@@ -0,0 +1,336 @@
+//
+// sprocketnes/filter.rs
+//
+// Author: Patrick Walton
+//
+
+// Video filters: stages that the PPU's picture passes through on its way to the window, such as
+// cutting off the overscan or adding scanlines. Each stage makes a new picture, of a size it says
+// up front, from the last one's, so stages can be chained in any order with `--filters`:
+//
+//     --filters crop:8,scale:2,scanlines:60
+//
+// The first stage also gets the palette index of every pixel, for filters that work from the NES'
+// video signal rather than its colors, as an NTSC filter does. Later stages only see colors.
+//
+// Filters only change what's shown. Screenshots, recordings, and the like take the PPU's picture
+// as it is.
+
+use libc::uint8_t;
+use std::cmp;
+use std::mem;
+
+/// A picture, three bytes per pixel, in the PPU's BGR order.
+pub struct Image {
+    pub width: uint,
+    pub height: uint,
+    pub pixels: Vec<uint8_t>,
+}
+
+impl Image {
+    pub fn new(width: uint, height: uint) -> Image {
+        Image { width: width, height: height, pixels: Vec::from_elem(width * height * 3, 0) }
+    }
+
+    // Makes the image the given size, keeping its buffer if it already is.
+    fn resize(&mut self, width: uint, height: uint) {
+        if self.width != width || self.height != height {
+            *self = Image::new(width, height);
+        }
+    }
+
+    #[inline(always)]
+    pub fn offset(&self, x: uint, y: uint) -> uint {
+        (y * self.width + x) * 3
+    }
+}
+
+/// One stage of the pipeline.
+pub trait VideoFilter {
+    /// The size of the picture the filter makes from one of the given size.
+    fn output_size(&self, width: uint, height: uint) -> (uint, uint);
+
+    /// Filters `input` into `output`, which is already the size `output_size` gave. `indices`
+    /// holds the palette index of each of the input's pixels when the input is the PPU's own
+    /// picture, and is None otherwise.
+    fn apply(&mut self, input: &Image, indices: Option<&[uint8_t]>, output: &mut Image);
+}
+
+//
+// Built-in stages
+//
+
+/// Cuts lines off the top and bottom, and columns off the left and right. Most TVs hid the
+/// outer 8 lines or so, and some games leave garbage there.
+pub struct Crop {
+    pub lines: uint,
+    pub columns: uint,
+}
+
+impl Crop {
+    // How much is really cut off each end of a row or column of the given length. Crops that
+    // would leave nothing leave a pixel.
+    fn cut(amount: uint, length: uint) -> uint {
+        cmp::min(amount, (length - 1) / 2)
+    }
+}
+
+impl VideoFilter for Crop {
+    fn output_size(&self, width: uint, height: uint) -> (uint, uint) {
+        (width - 2 * Crop::cut(self.columns, width), height - 2 * Crop::cut(self.lines, height))
+    }
+
+    fn apply(&mut self, input: &Image, _: Option<&[uint8_t]>, output: &mut Image) {
+        let columns = Crop::cut(self.columns, input.width);
+        let lines = Crop::cut(self.lines, input.height);
+        let row_len = output.width * 3;
+        for y in range(0, output.height) {
+            let src = input.offset(columns, y + lines);
+            let dest = output.offset(0, y);
+            output.pixels.slice_mut(dest, dest + row_len)
+                         .clone_from_slice(input.pixels.slice(src, src + row_len));
+        }
+    }
+}
+
+/// Scales by a whole number, repeating each pixel.
+pub struct Scale {
+    pub factor: uint,
+}
+
+impl VideoFilter for Scale {
+    fn output_size(&self, width: uint, height: uint) -> (uint, uint) {
+        (width * self.factor, height * self.factor)
+    }
+
+    fn apply(&mut self, input: &Image, _: Option<&[uint8_t]>, output: &mut Image) {
+        for y in range(0, output.height) {
+            for x in range(0, output.width) {
+                let src = input.offset(x / self.factor, y / self.factor);
+                let dest = output.offset(x, y);
+                for i in range(0, 3) {
+                    output.pixels[dest + i] = input.pixels[src + i];
+                }
+            }
+        }
+    }
+}
+
+/// Doubles each line, and dims the copy to the given percentage of its brightness, for the dark
+/// gaps between a CRT's lines.
+pub struct Scanlines {
+    pub brightness: uint,
+}
+
+impl VideoFilter for Scanlines {
+    fn output_size(&self, width: uint, height: uint) -> (uint, uint) {
+        (width, height * 2)
+    }
+
+    fn apply(&mut self, input: &Image, _: Option<&[uint8_t]>, output: &mut Image) {
+        let row_len = input.width * 3;
+        for y in range(0, input.height) {
+            let src = input.offset(0, y);
+            let row = input.pixels.slice(src, src + row_len);
+            let dest = output.offset(0, y * 2);
+            output.pixels.slice_mut(dest, dest + row_len).clone_from_slice(row);
+            let dest = output.offset(0, y * 2 + 1);
+            let dimmed = output.pixels.slice_mut(dest, dest + row_len);
+            for (out, &val) in dimmed.iter_mut().zip(row.iter()) {
+                *out = (val as uint * self.brightness / 100) as uint8_t;
+            }
+        }
+    }
+}
+
+//
+// The pipeline
+//
+
+fn parse_number(stage: &str, val: &str, min: uint, max: uint) -> Result<uint, String> {
+    match from_str::<uint>(val) {
+        Some(number) if number >= min && number <= max => Ok(number),
+        _ => Err(format!("`{}` takes a number from {} to {}, not `{}`", stage, min, max, val)),
+    }
+}
+
+// Parses one stage: `crop[:<lines>[:<columns>]]`, `scale:<n>`, or `scanlines[:<percent>]`.
+fn parse_stage(spec: &str) -> Result<Box<VideoFilter>, String> {
+    let mut parts = spec.split(':');
+    let name = parts.next().unwrap_or("");
+    let args: Vec<&str> = parts.collect();
+    let arg = |i: uint| if i < args.len() { Some(args[i]) } else { None };
+    match name {
+        "crop" if args.len() <= 2 => {
+            let lines = match arg(0) {
+                None => 8,
+                Some(val) => try!(parse_number(name, val, 0, 64)),
+            };
+            let columns = match arg(1) {
+                None => 0,
+                Some(val) => try!(parse_number(name, val, 0, 64)),
+            };
+            Ok(Box::new(Crop { lines: lines, columns: columns }) as Box<VideoFilter>)
+        }
+        "scale" if args.len() == 1 => {
+            let factor = try!(parse_number(name, args[0], 1, 4));
+            Ok(Box::new(Scale { factor: factor }) as Box<VideoFilter>)
+        }
+        "scanlines" if args.len() <= 1 => {
+            let brightness = match arg(0) {
+                None => 50,
+                Some(val) => try!(parse_number(name, val, 0, 100)),
+            };
+            Ok(Box::new(Scanlines { brightness: brightness }) as Box<VideoFilter>)
+        }
+        "crop" | "scale" | "scanlines" => Err(format!("wrong number of settings in `{}`", spec)),
+        _ => Err(format!("unknown filter `{}`; expected `crop`, `scale`, or `scanlines`", name)),
+    }
+}
+
+/// A chain of filters, run in order.
+pub struct Pipeline {
+    stages: Vec<Box<VideoFilter>>,
+    images: Vec<Image>,     // What each stage made last time, reused so as not to reallocate
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline { stages: Vec::new(), images: Vec::new() }
+    }
+
+    /// Parses a comma-separated list of stages, such as `crop:8,scale:2,scanlines`.
+    pub fn parse(spec: &str) -> Result<Pipeline, String> {
+        let mut pipeline = Pipeline::new();
+        for stage in spec.split(',') {
+            pipeline.push(try!(parse_stage(stage)));
+        }
+        Ok(pipeline)
+    }
+
+    pub fn push(&mut self, stage: Box<VideoFilter>) {
+        self.stages.push(stage);
+        self.images.push(Image::new(0, 0));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.len() == 0
+    }
+
+    /// The size of the picture the whole pipeline makes from one of the given size.
+    pub fn output_size(&self, width: uint, height: uint) -> (uint, uint) {
+        self.stages.iter().fold((width, height), |(width, height), stage| {
+            stage.output_size(width, height)
+        })
+    }
+
+    /// Runs a picture and its palette indices through every stage, and returns the result. The
+    /// pipeline must have a stage.
+    pub fn run(&mut self, input: &Image, indices: &[uint8_t]) -> &mut Image {
+        let mut images = mem::replace(&mut self.images, Vec::new());
+        for i in range(0, self.stages.len()) {
+            let (done, rest) = images.split_at_mut(i);
+            let (source, indices) = if i == 0 {
+                (input, Some(indices))
+            } else {
+                (&done[i - 1], None)
+            };
+            let (width, height) = self.stages[i].output_size(source.width, source.height);
+            rest[0].resize(width, height);
+            self.stages[i].apply(source, indices, &mut rest[0]);
+        }
+        self.images = images;
+        self.images.last_mut().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Crop, Image, Pipeline, Scale, Scanlines, VideoFilter};
+
+    use libc::uint8_t;
+
+    // A picture whose pixels all differ: blue is the column, green the row, and red 0x80.
+    fn picture(width: uint, height: uint) -> Image {
+        let mut image = Image::new(width, height);
+        for y in range(0, height) {
+            for x in range(0, width) {
+                let offset = image.offset(x, y);
+                image.pixels[offset] = x as uint8_t;
+                image.pixels[offset + 1] = y as uint8_t;
+                image.pixels[offset + 2] = 0x80;
+            }
+        }
+        image
+    }
+
+    fn pixel(image: &Image, x: uint, y: uint) -> (uint8_t, uint8_t, uint8_t) {
+        let offset = image.offset(x, y);
+        (image.pixels[offset], image.pixels[offset + 1], image.pixels[offset + 2])
+    }
+
+    fn apply<F: VideoFilter>(filter: &mut F, input: &Image) -> Image {
+        let (width, height) = filter.output_size(input.width, input.height);
+        let mut output = Image::new(width, height);
+        filter.apply(input, None, &mut output);
+        output
+    }
+
+    #[test]
+    fn crop_cuts_each_edge() {
+        let mut crop = Crop { lines: 8, columns: 4 };
+        assert_eq!(crop.output_size(256, 240), (248, 224));
+        let output = apply(&mut crop, &picture(256, 240));
+        assert_eq!(pixel(&output, 0, 0), (4, 8, 0x80));
+        assert_eq!(pixel(&output, 247, 223), (251, 231, 0x80));
+    }
+
+    #[test]
+    fn crop_leaves_a_pixel() {
+        let mut crop = Crop { lines: 64, columns: 64 };
+        assert_eq!(crop.output_size(5, 4), (1, 2));
+        let output = apply(&mut crop, &picture(5, 4));
+        assert_eq!(pixel(&output, 0, 0), (2, 1, 0x80));
+        assert_eq!(pixel(&output, 0, 1), (2, 2, 0x80));
+    }
+
+    #[test]
+    fn scale_repeats_each_pixel() {
+        let mut scale = Scale { factor: 3 };
+        assert_eq!(scale.output_size(4, 2), (12, 6));
+        let output = apply(&mut scale, &picture(4, 2));
+        for y in range(0u, 6) {
+            for x in range(0u, 12) {
+                assert_eq!(pixel(&output, x, y), ((x / 3) as uint8_t, (y / 3) as uint8_t, 0x80));
+            }
+        }
+    }
+
+    #[test]
+    fn scanlines_dim_every_other_line() {
+        let mut scanlines = Scanlines { brightness: 50 };
+        assert_eq!(scanlines.output_size(4, 3), (4, 6));
+        let output = apply(&mut scanlines, &picture(4, 3));
+        assert_eq!(pixel(&output, 3, 4), (3, 2, 0x80));
+        assert_eq!(pixel(&output, 3, 5), (1, 1, 0x40));
+    }
+
+    #[test]
+    fn pipeline_runs_stages_in_order() {
+        let mut pipeline = Pipeline::parse("crop:1:1,scale:2,scanlines:0").unwrap();
+        assert_eq!(pipeline.output_size(4, 4), (4, 8));
+        let input = picture(4, 4);
+        let output = pipeline.run(&input, Vec::from_elem(16, 0u8).as_slice());
+        assert_eq!((output.width, output.height), (4, 8));
+        assert_eq!(pixel(output, 3, 6), (2, 2, 0x80));
+        assert_eq!(pixel(output, 3, 7), (0, 0, 0));
+    }
+
+    #[test]
+    fn bad_stages_are_errors() {
+        assert!(Pipeline::parse("scale").is_err());
+        assert!(Pipeline::parse("scale:5").is_err());
+        assert!(Pipeline::parse("crop:1:2:3").is_err());
+        assert!(Pipeline::parse("blur").is_err());
+    }
+}
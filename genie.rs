@@ -0,0 +1,212 @@
+//
+// sprocketnes/genie.rs
+//
+// Author: Patrick Walton
+//
+
+// The Game Genie: a cartridge that plugs in between the console and the game. At power-on its own
+// ROM is mapped in front of the game's, so the player can enter codes. When they're done, the
+// Game Genie gets out of the way, except that it replaces what the game reads from up to three
+// PRG addresses, optionally only when the game's own value there matches a given one.
+//
+// The Game Genie's ROM isn't ours to distribute, so it's loaded from `gamegenie.rom` in the
+// current directory: 4K of PRG followed by the 256 bytes of its CHR, which is how FCEUX wants it
+// too.
+//
+// See http://wiki.nesdev.com/w/index.php/Game_Genie
+
+use mapper::{Mapper, Mirroring, NametableMap};
+use rtc::Rtc;
+use util::Save;
+
+use libc::{uint8_t, uint16_t};
+use std::io::File;
+use std::vec::Vec;
+
+const ROM_PATH: &'static str = "gamegenie.rom";
+const PRG_SIZE: uint = 4096;
+const CHR_SIZE: uint = 256;
+
+const CODE_COUNT: uint = 3;
+
+struct Code {
+    addr: uint16_t,
+    compare: uint8_t,
+    replacement: uint8_t,
+}
+
+save_struct!(Code { addr, compare, replacement });
+
+pub struct GameGenie {
+    cartridge: Box<Mapper+Send>,
+    rom: Vec<uint8_t>,
+    codes: [Code; CODE_COUNT],
+    // $8000: bits 1-3 turn on each code's compare, and bits 4-6 turn each code off.
+    control: uint8_t,
+    // Set once the codes are entered and the game is running.
+    passthrough: bool,
+}
+
+impl GameGenie {
+    /// Plugs the Game Genie in front of the cartridge. The error says what's wrong with its ROM,
+    /// for showing to the user.
+    pub fn new(cartridge: Box<Mapper+Send>) -> Result<GameGenie, String> {
+        let rom = match File::open(&Path::new(ROM_PATH)).read_to_end() {
+            Ok(rom) => rom,
+            Err(err) => return Err(format!("couldn't read {}: {}", ROM_PATH, err)),
+        };
+        if rom.len() != PRG_SIZE + CHR_SIZE {
+            return Err(format!("{} isn't a Game Genie ROM", ROM_PATH));
+        }
+
+        Ok(GameGenie {
+            cartridge: cartridge,
+            rom: rom,
+            codes: [ Code { addr: 0, compare: 0, replacement: 0 }; CODE_COUNT ],
+            control: 0,
+            passthrough: false,
+        })
+    }
+
+    fn code_enabled(&self, index: uint) -> bool {
+        (self.control & (0x10 << index)) == 0
+    }
+
+    fn compare_enabled(&self, index: uint) -> bool {
+        (self.control & (0x02 << index)) != 0
+    }
+
+    // $8001-$800C: four registers for each code.
+    fn write_code_register(&mut self, addr: uint16_t, val: uint8_t) {
+        let reg = (addr - 0x8001) as uint;
+        let code = &mut self.codes[reg / 4];
+        match reg % 4 {
+            // The address is always in $8000-$FFFF.
+            0 => code.addr = (code.addr & 0x00ff) | ((val as uint16_t | 0x80) << 8),
+            1 => code.addr = (code.addr & 0xff00) | (val as uint16_t),
+            2 => code.compare = val,
+            _ => code.replacement = val,
+        }
+    }
+}
+
+impl Mapper for GameGenie {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if !self.passthrough && addr >= 0x8000 {
+            return self.rom[addr as uint % PRG_SIZE];
+        }
+
+        let val = self.cartridge.prg_loadb(addr);
+        if self.passthrough {
+            for i in range(0, CODE_COUNT) {
+                let code = &self.codes[i];
+                if code.addr != addr || !self.code_enabled(i) {
+                    continue;
+                }
+                if !self.compare_enabled(i) || code.compare == val {
+                    return code.replacement;
+                }
+            }
+        }
+        val
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if self.passthrough || addr < 0x8000 {
+            self.cartridge.prg_storeb(addr, val);
+            return;
+        }
+        match addr {
+            // The Game Genie's ROM writes the settings, then writes 0 to start the game.
+            0x8000 if val == 0 => self.passthrough = true,
+            0x8000 => self.control = val,
+            0x8001 ... 0x800c => self.write_code_register(addr, val),
+            _ => {}
+        }
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if !self.passthrough {
+            return self.rom[PRG_SIZE + addr as uint % CHR_SIZE];
+        }
+        self.cartridge.chr_loadb(addr)
+    }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if self.passthrough {
+            self.cartridge.chr_storeb(addr, val)
+        }
+    }
+    fn mirroring(&self) -> Mirroring { self.cartridge.mirroring() }
+
+    fn start_scanline(&mut self, scanline: uint16_t) { self.cartridge.start_scanline(scanline) }
+    fn next_scanline(&mut self) { self.cartridge.next_scanline() }
+    fn watches_a12(&self) -> bool { self.cartridge.watches_a12() }
+    fn describe_banks(&self) -> Vec<String> { self.cartridge.describe_banks() }
+    fn a12_rise(&mut self, scanline: uint16_t, dot: uint16_t) {
+        self.cartridge.a12_rise(scanline, dot)
+    }
+    fn irq_pending(&self) -> bool { self.cartridge.irq_pending() }
+
+    fn chr_fetch(&mut self, addr: uint16_t, sprite: bool) -> uint8_t {
+        if !self.passthrough {
+            return self.chr_loadb(addr);
+        }
+        self.cartridge.chr_fetch(addr, sprite)
+    }
+    fn cacheable_chr(&self) -> bool { !self.passthrough || self.cartridge.cacheable_chr() }
+
+    fn arrange_nametables(&self, map: &mut NametableMap) { self.cartridge.arrange_nametables(map) }
+    fn nametable_loadb(&mut self, page: uint, offset: uint16_t) -> uint8_t {
+        self.cartridge.nametable_loadb(page, offset)
+    }
+    fn nametable_storeb(&mut self, page: uint, offset: uint16_t, val: uint8_t) {
+        self.cartridge.nametable_storeb(page, offset, val)
+    }
+    fn chr_rom_loadb(&mut self, offset: uint) -> uint8_t { self.cartridge.chr_rom_loadb(offset) }
+    fn nametable_fetched(&mut self, addr: uint16_t, val: uint8_t) -> uint8_t {
+        self.cartridge.nametable_fetched(addr, val)
+    }
+
+    fn expansion_loadb(&mut self, addr: uint16_t) -> Option<uint8_t> {
+        self.cartridge.expansion_loadb(addr)
+    }
+    fn expansion_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        self.cartridge.expansion_storeb(addr, val)
+    }
+    // Starting the game switches to the cartridge's CHR.
+    fn is_banking_register(&self, addr: uint16_t) -> bool {
+        if self.passthrough { self.cartridge.is_banking_register(addr) } else { addr >= 0x8000 }
+    }
+
+    fn ppu_register_write(&mut self, addr: uint16_t, val: uint8_t) {
+        self.cartridge.ppu_register_write(addr, val)
+    }
+    fn controller_write(&mut self, val: uint8_t) { self.cartridge.controller_write(val) }
+
+    fn clock_audio(&mut self) { self.cartridge.clock_audio() }
+    fn audio_output(&self) -> f32 { self.cartridge.audio_output() }
+
+    fn dip_switch_count(&self) -> uint { self.cartridge.dip_switch_count() }
+    fn dip_switches(&self) -> uint8_t { self.cartridge.dip_switches() }
+    fn set_dip_switches(&mut self, val: uint8_t) { self.cartridge.set_dip_switches(val) }
+
+    fn disk_side_count(&self) -> uint { self.cartridge.disk_side_count() }
+    fn disk_side(&self) -> Option<uint> { self.cartridge.disk_side() }
+    fn insert_disk_side(&mut self, side: uint) { self.cartridge.insert_disk_side(side) }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> { self.cartridge.battery() }
+    fn rtc(&mut self) -> Option<&mut Rtc> { self.cartridge.rtc() }
+}
+
+impl Save for GameGenie {
+    fn save(&mut self, fd: &mut Writer) {
+        self.codes.save(fd);
+        self.control.save(fd);
+        self.passthrough.save(fd);
+        self.cartridge.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        self.codes.load(fd);
+        self.control.load(fd);
+        self.passthrough.load(fd);
+        self.cartridge.load(fd);
+    }
+}
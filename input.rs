@@ -4,13 +4,21 @@
 // Author: Patrick Walton
 //
 
+use apu::CHANNEL_COUNT;
+use log;
 use mem::Mem;
+use util::Save;
 
 use libc::{uint8_t, uint16_t};
 use sdl2::event::{KeyDownEvent, KeyUpEvent, NoEvent, QuitEvent};
 use sdl2::event;
-use sdl2::keycode::{DownKey, EscapeKey, KeyCode, LKey, LeftKey, RShiftKey, ReturnKey, RightKey};
-use sdl2::keycode::{SKey, UpKey, XKey, ZKey};
+use sdl2::keyboard;
+use sdl2::keycode::{BKey, CKey, DKey, DownKey, EscapeKey, F1Key, F2Key, F3Key, F4Key, F12Key};
+use sdl2::keycode::FKey;
+use sdl2::keycode::{KeyCode, LKey, LeftKey};
+use sdl2::keycode::{NKey, Num1Key, Num2Key, Num3Key, Num4Key, Num5Key, Num6Key, PKey};
+use sdl2::keycode::{RShiftKey, ReturnKey, RightKey, SpaceKey, TabKey};
+use sdl2::keycode::{RKey, SKey, UKey, UnknownKey, UpKey, VKey, XKey, ZKey};
 
 //
 // The "strobe state": the order in which the NES reads the buttons.
@@ -25,6 +33,10 @@ const STROBE_STATE_DOWN: uint8_t     = 5;
 const STROBE_STATE_LEFT: uint8_t     = 6;
 const STROBE_STATE_RIGHT: uint8_t    = 7;
 
+pub static BUTTON_NAMES: [&'static str; 8] = [
+    "A", "B", "Select", "Start", "Up", "Down", "Left", "Right"
+];
+
 struct StrobeState{ val: uint8_t }
 
 impl Deref<uint8_t> for StrobeState {
@@ -49,6 +61,10 @@ impl StrobeState {
         }
     }
 
+    fn name(&self) -> &'static str {
+        BUTTON_NAMES[**self as uint]
+    }
+
     fn next(&mut self) {
         *self = StrobeState{val: (**self + 1) & 7};
     }
@@ -58,6 +74,23 @@ impl StrobeState {
     }
 }
 
+//
+// The VS. System's coin slots and service button, as they appear in $4016
+//
+
+pub const VS_SERVICE: uint8_t = 0x04;
+pub const VS_COIN_1: uint8_t  = 0x20;
+pub const VS_COIN_2: uint8_t  = 0x40;
+
+fn vs_button_name(button: uint8_t) -> &'static str {
+    match button {
+        VS_SERVICE => "Service",
+        VS_COIN_1  => "Insert coin (left slot)",
+        VS_COIN_2  => "Insert coin (right slot)",
+        _          => panic!("shouldn't happen")
+    }
+}
+
 //
 // The standard NES game pad state
 //
@@ -75,8 +108,70 @@ pub struct GamePadState {
     strobe_state: StrobeState,
 }
 
+impl GamePadState {
+    fn new() -> GamePadState {
+        GamePadState {
+            left: false,
+            down: false,
+            up: false,
+            right: false,
+            a: false,
+            b: false,
+            select: false,
+            start: false,
+
+            strobe_state: StrobeState{val: STROBE_STATE_A}
+        }
+    }
+
+    /// Sets all of the buttons at once, one bit each, in the order the console reads them: A, B,
+    /// Select, Start, Up, Down, Left, Right.
+    pub fn set_buttons(&mut self, buttons: uint8_t) {
+        for i in range(0, 8) {
+            self.set(StrobeState{val: i}, ((buttons >> i as uint) & 1) != 0);
+        }
+    }
+
+    /// The buttons, in the same form `set_buttons` takes.
+    pub fn buttons(&self) -> uint8_t {
+        let mut buttons = 0;
+        for i in range(0, 8) {
+            if StrobeState{val: i}.get(self) {
+                buttons |= 1 << i as uint;
+            }
+        }
+        buttons
+    }
+
+    fn set(&mut self, button: StrobeState, down: bool) {
+        match *button {
+            STROBE_STATE_A      => self.a = down,
+            STROBE_STATE_B      => self.b = down,
+            STROBE_STATE_SELECT => self.select = down,
+            STROBE_STATE_START  => self.start = down,
+            STROBE_STATE_UP     => self.up = down,
+            STROBE_STATE_DOWN   => self.down = down,
+            STROBE_STATE_LEFT   => self.left = down,
+            STROBE_STATE_RIGHT  => self.right = down,
+            _                   => panic!("shouldn't happen")
+        }
+    }
+}
+
 pub struct Input {
-    pub gamepad_0: GamePadState
+    pub gamepad_0: GamePadState,
+    pub gamepad_1: GamePadState,    // Only used by netplay; the keyboard plays the first one
+    pub bindings: Bindings,
+    // Set while a movie or netplay is driving the first controller and the VS. System's buttons,
+    // so that the keyboard can't interfere. The keys are still tracked in `keyboard_buttons` and
+    // `keyboard_vs_buttons`.
+    pub gamepad_locked: bool,
+    pub keyboard_buttons: uint8_t,
+    pub vs_system: bool,            // Whether the coin slots and service button are there
+    pub vs_buttons: uint8_t,        // Which of them are held down, in $4016's bits
+    pub keyboard_vs_buttons: uint8_t,
+    pub debug_hud_held: bool,
+    pub last_key: Option<(KeyCode, bool)>,  // The last key pressed (true) or released (false)
 }
 
 pub enum InputResult {
@@ -84,53 +179,241 @@ pub enum InputResult {
     Quit,       // Quit the emulator.
     SaveState,  // Save a state.
     LoadState,  // Load a state.
+    UndoLoad,   // Go back to before the last state was loaded.
+    ToggleRecording,    // Start or stop recording video.
+    CycleDipSwitches,   // Step the cartridge's DIP switches to the next setting.
+    PowerCycle,         // Turn the console off and on again.
+    SwitchDiskSide,     // Put the next disk side in the disk drive.
+    TogglePause,        // Pause or resume emulation.
+    AdvanceFrame,       // Pause if necessary, and run a single frame.
+    ToggleHelp,         // Show or hide the list of keys.
+    ToggleFrameRate,    // Show or hide the frame rate counter.
+    ToggleSpriteZero,   // Show or hide the sprite 0 hit overlay.
+    ToggleChannel(uint),    // Mute or unmute one of the APU's channels.
+    FlagProblem,        // Note in the compatibility log that something looks wrong.
+    TakeScreenshot,     // Save the screen as a PNG named after the game and frame.
+}
+
+static TOGGLE_CHANNEL_DESCRIPTIONS: [&'static str; CHANNEL_COUNT] = [
+    "Mute/unmute pulse 1",
+    "Mute/unmute pulse 2",
+    "Mute/unmute triangle",
+    "Mute/unmute noise",
+    "Mute/unmute DMC (not emulated, so always silent)",
+    "Mute/unmute expansion audio",
+];
+
+impl InputResult {
+    fn description(self) -> &'static str {
+        match self {
+            Continue => "Nothing",
+            Quit => "Quit",
+            SaveState => "Save state",
+            LoadState => "Load state",
+            UndoLoad => "Undo loading a state",
+            ToggleRecording => "Start/stop recording",
+            CycleDipSwitches => "Cycle DIP switches",
+            PowerCycle => "Power cycle",
+            SwitchDiskSide => "Switch disk side",
+            TogglePause => "Pause/resume",
+            AdvanceFrame => "Advance one frame",
+            ToggleHelp => "Show/hide this help",
+            ToggleFrameRate => "Show/hide frame rate",
+            ToggleSpriteZero => "Show/hide sprite 0 hit",
+            ToggleChannel(channel) => TOGGLE_CHANNEL_DESCRIPTIONS[channel],
+            FlagProblem => "Flag a problem with this game",
+            TakeScreenshot => "Take a screenshot",
+        }
+    }
+}
+
+//
+// Key bindings
+//
+
+/// Looks up a key by the name SDL gives it, such as `Tab` or `F5`.
+pub fn parse_key(name: &str) -> Result<KeyCode, String> {
+    match keyboard::get_key_from_name(name) {
+        UnknownKey => Err(format!("no key is called `{}`", name)),
+        key => Ok(key),
+    }
+}
+
+pub struct Bindings {
+    pub gamepad_0: Vec<(KeyCode, uint8_t)>,     // Keys for the buttons, by strobe state
+    pub vs: Vec<(KeyCode, uint8_t)>,            // Keys for the VS. System's buttons, by bit
+    pub hotkeys: Vec<(KeyCode, InputResult)>,
+    pub debug_hud: KeyCode,                     // Shows the debug HUD while held
+}
+
+impl Bindings {
+    pub fn default() -> Bindings {
+        Bindings {
+            gamepad_0: vec![
+                (UpKey,     STROBE_STATE_UP),
+                (DownKey,   STROBE_STATE_DOWN),
+                (LeftKey,   STROBE_STATE_LEFT),
+                (RightKey,  STROBE_STATE_RIGHT),
+                (ZKey,      STROBE_STATE_A),
+                (XKey,      STROBE_STATE_B),
+                (RShiftKey, STROBE_STATE_SELECT),
+                (ReturnKey, STROBE_STATE_START),
+            ],
+            vs: vec![
+                (CKey,      VS_COIN_1),
+                (VKey,      VS_COIN_2),
+                (BKey,      VS_SERVICE),
+            ],
+            hotkeys: vec![
+                (F1Key,     ToggleHelp),
+                (F2Key,     ToggleFrameRate),
+                (F3Key,     ToggleSpriteZero),
+                (F4Key,     FlagProblem),
+                (F12Key,    TakeScreenshot),
+                (SpaceKey,  TogglePause),
+                (NKey,      AdvanceFrame),
+                (SKey,      SaveState),
+                (LKey,      LoadState),
+                (UKey,      UndoLoad),
+                (PKey,      PowerCycle),
+                (RKey,      ToggleRecording),
+                (DKey,      CycleDipSwitches),
+                (FKey,      SwitchDiskSide),
+                (Num1Key,   ToggleChannel(0)),
+                (Num2Key,   ToggleChannel(1)),
+                (Num3Key,   ToggleChannel(2)),
+                (Num4Key,   ToggleChannel(3)),
+                (Num5Key,   ToggleChannel(4)),
+                (Num6Key,   ToggleChannel(5)),
+                (EscapeKey, Quit),
+            ],
+            debug_hud: TabKey,
+        }
+    }
+
+    fn button(&self, key: KeyCode) -> Option<StrobeState> {
+        self.gamepad_0.iter()
+                      .find(|&&(bound_key, _)| bound_key == key)
+                      .map(|&(_, button)| StrobeState{val: button})
+    }
+
+    fn vs_button(&self, key: KeyCode) -> Option<uint8_t> {
+        self.vs.iter().find(|&&(bound_key, _)| bound_key == key).map(|&(_, button)| button)
+    }
+
+    fn hotkey(&self, key: KeyCode) -> Option<InputResult> {
+        self.hotkeys.iter().find(|&&(bound_key, _)| bound_key == key).map(|&(_, result)| result)
+    }
+
+    /// Describes every binding, one per line, for the help screen. The VS. System's buttons are
+    /// left out for other consoles.
+    pub fn help_lines(&self, vs_system: bool) -> Vec<String> {
+        let mut lines = vec![ "Controller".to_string() ];
+        for &(key, button) in self.gamepad_0.iter() {
+            let button = StrobeState{val: button};
+            lines.push(format!("  {}: {}", keyboard::get_key_name(key), button.name()));
+        }
+        if vs_system {
+            lines.push("VS. System".to_string());
+            for &(key, button) in self.vs.iter() {
+                let name = vs_button_name(button);
+                lines.push(format!("  {}: {}", keyboard::get_key_name(key), name));
+            }
+        }
+        lines.push("Hotkeys".to_string());
+        for &(key, result) in self.hotkeys.iter() {
+            lines.push(format!("  {}: {}", keyboard::get_key_name(key), result.description()));
+        }
+        lines.push(format!("  {} (hold): Show debug HUD", keyboard::get_key_name(self.debug_hud)));
+        lines
+    }
 }
 
 impl Input {
     pub fn new() -> Input {
         Input {
-            gamepad_0: GamePadState {
-                left: false,
-                down: false,
-                up: false,
-                right: false,
-                a: false,
-                b: false,
-                select: false,
-                start: false,
-
-                strobe_state: StrobeState{val: STROBE_STATE_A}
-            }
+            gamepad_0: GamePadState::new(),
+            gamepad_1: GamePadState::new(),
+            bindings: Bindings::default(),
+            gamepad_locked: false,
+            keyboard_buttons: 0,
+            vs_system: false,
+            vs_buttons: 0,
+            keyboard_vs_buttons: 0,
+            debug_hud_held: false,
+            last_key: None,
         }
     }
 
+    /// Sets all of the first controller's buttons at once. See `GamePadState::set_buttons`.
+    pub fn set_buttons(&mut self, buttons: uint8_t) {
+        self.gamepad_0.set_buttons(buttons);
+    }
+
+    /// The first controller's buttons, in the same form `set_buttons` takes.
+    pub fn buttons(&self) -> uint8_t {
+        self.gamepad_0.buttons()
+    }
+
     fn handle_gamepad_event(&mut self, key: KeyCode, down: bool) {
-        match key {
-            LeftKey   => self.gamepad_0.left   = down,
-            DownKey   => self.gamepad_0.down   = down,
-            UpKey     => self.gamepad_0.up     = down,
-            RightKey  => self.gamepad_0.right  = down,
-            ZKey      => self.gamepad_0.a      = down,
-            XKey      => self.gamepad_0.b      = down,
-            RShiftKey => self.gamepad_0.select = down,
-            ReturnKey => self.gamepad_0.start  = down,
-            _         => {}
+        match self.bindings.vs_button(key) {
+            Some(button) if self.vs_system => {
+                if down {
+                    self.keyboard_vs_buttons |= button;
+                } else {
+                    self.keyboard_vs_buttons &= !button;
+                }
+                if !self.gamepad_locked {
+                    self.vs_buttons = self.keyboard_vs_buttons;
+                }
+                return;
+            }
+            _ => {}
+        }
+
+        match self.bindings.button(key) {
+            Some(button) => {
+                let bit = 1 << *button as uint;
+                if down {
+                    self.keyboard_buttons |= bit;
+                } else {
+                    self.keyboard_buttons &= !bit;
+                }
+                if !self.gamepad_locked {
+                    self.gamepad_0.set(button, down);
+                }
+            }
+            None => {}
         }
     }
 
     pub fn check_input(&mut self) -> InputResult {
         loop {
-            match event::poll_event() {
+            let event = event::poll_event();
+            match event {
+                KeyDownEvent(_, _, key, _, _) => self.last_key = Some((key, true)),
+                KeyUpEvent(_, _, key, _, _) => self.last_key = Some((key, false)),
+                _ => {}
+            }
+            match event {
                 NoEvent => {
                     break
                 }
-                KeyDownEvent(_, _, EscapeKey, _, _) => {
-                    return Quit
+                KeyDownEvent(_, _, key, _, _) if key == self.bindings.debug_hud => {
+                    self.debug_hud_held = true;
+                }
+                KeyUpEvent(_, _, key, _, _) if key == self.bindings.debug_hud => {
+                    self.debug_hud_held = false;
                 }
-                KeyDownEvent(_, _, SKey, _, _) => return SaveState,
-                KeyDownEvent(_, _, LKey, _, _) => return LoadState,
                 KeyDownEvent(_, _, key, _, _) => {
-                    self.handle_gamepad_event(key, true)
+                    match self.bindings.hotkey(key) {
+                        Some(result) => {
+                            log!(log::Input, log::Debug, "{}: {}",
+                                 keyboard::get_key_name(key), result.description());
+                            return result
+                        }
+                        None => self.handle_gamepad_event(key, true),
+                    }
                 }
                 KeyUpEvent(_, _, key, _, _) => self.handle_gamepad_event(key, false),
                 QuitEvent(_) => return Quit,
@@ -141,15 +424,41 @@ impl Input {
     }
 }
 
+/// Handles the window's events while the console isn't running, as while netplay connects, so
+/// that the window doesn't look hung. Returns true if the player closed it or pressed Escape.
+pub fn quit_requested() -> bool {
+    loop {
+        match event::poll_event() {
+            NoEvent => return false,
+            QuitEvent(_) | KeyDownEvent(_, _, EscapeKey, _, _) => return true,
+            _ => {}
+        }
+    }
+}
+
+// Only where each controller's shift register is in reading out the buttons is saved. The
+// buttons themselves are whatever the player is holding when the state is loaded.
+impl Save for Input {
+    fn save(&mut self, fd: &mut Writer) {
+        self.gamepad_0.strobe_state.val.save(fd);
+        self.gamepad_1.strobe_state.val.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        self.gamepad_0.strobe_state.val.load(fd);
+        self.gamepad_1.strobe_state.val.load(fd);
+    }
+}
+
 impl Mem for Input {
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
-        if addr == 0x4016 {
-            let result = self.gamepad_0.strobe_state.get(&self.gamepad_0) as uint8_t;
-            self.gamepad_0.strobe_state.next();
-            result
-        } else {
-            0
-        }
+        let gamepad = match addr {
+            0x4016 => &mut self.gamepad_0,
+            0x4017 => &mut self.gamepad_1,
+            _ => return 0,
+        };
+        let result = gamepad.strobe_state.get(gamepad) as uint8_t;
+        gamepad.strobe_state.next();
+        result
     }
 
     fn storeb(&mut self, addr: uint16_t, _: uint8_t) {
@@ -157,6 +466,7 @@ impl Mem for Input {
             // FIXME: This is not really accurate; you're supposed to not reset until you see
             // 1 strobed than 0. But I doubt this will break anything.
             self.gamepad_0.strobe_state.reset();
+            self.gamepad_1.strobe_state.reset();
         }
     }
 }
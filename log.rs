@@ -0,0 +1,144 @@
+//
+// sprocketnes/log.rs
+//
+// Author: Patrick Walton
+//
+
+// Logging, with a verbosity level for each part of the emulator. Levels are set from the command
+// line with a spec like `ppu=debug,mapper=trace`; a bare level, as in `debug`, applies to every
+// subsystem. Messages go to stderr unless a log file is given.
+//
+// Checking whether a message would be logged is just an atomic load, so the `log!` macro is
+// cheap enough to leave in hot paths like the CPU's instruction loop. The message itself is only
+// formatted if it's going to be written. Batch runs log from several threads at once, so writes
+// take a lock, which keeps their lines from interleaving.
+
+use std::io::File;
+use std::io::stdio;
+use std::rt::mutex::{NATIVE_MUTEX_INIT, StaticNativeMutex};
+use std::sync::atomic::{AtomicUint, INIT_ATOMIC_UINT, Relaxed};
+
+#[derive(PartialEq, PartialOrd)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+static LEVEL_NAMES: [&'static str; 6] = [ "off", "error", "warn", "info", "debug", "trace" ];
+static LEVELS: [Level; 6] = [ Off, Error, Warn, Info, Debug, Trace ];
+
+pub enum Subsystem {
+    Cpu,
+    Ppu,
+    Apu,
+    Mapper,
+    Input,
+    Audio,
+}
+
+const SUBSYSTEM_COUNT: uint = 6;
+
+static SUBSYSTEM_NAMES: [&'static str; SUBSYSTEM_COUNT] = [
+    "cpu", "ppu", "apu", "mapper", "input", "audio"
+];
+static SUBSYSTEMS: [Subsystem; SUBSYSTEM_COUNT] = [ Cpu, Ppu, Apu, Mapper, Input, Audio ];
+
+// Each subsystem's level, as a `Level` cast to a number. They start at `Warn`, which `init` sets.
+static g_levels: [AtomicUint; SUBSYSTEM_COUNT] = [
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+    INIT_ATOMIC_UINT, INIT_ATOMIC_UINT, INIT_ATOMIC_UINT,
+];
+
+static mut g_file: Option<File> = None;
+
+// Held while touching `g_file`, or while writing to stderr.
+static mut g_mutex: StaticNativeMutex = NATIVE_MUTEX_INIT;
+
+fn parse_level(name: &str) -> Result<Level, String> {
+    match LEVEL_NAMES.iter().position(|&level_name| level_name == name) {
+        Some(index) => Ok(LEVELS[index]),
+        None => Err(format!("unknown log level `{}`", name)),
+    }
+}
+
+fn parse_subsystem(name: &str) -> Result<Subsystem, String> {
+    match SUBSYSTEM_NAMES.iter().position(|&subsystem_name| subsystem_name == name) {
+        Some(index) => Ok(SUBSYSTEMS[index]),
+        None => Err(format!("unknown subsystem `{}`", name)),
+    }
+}
+
+/// Sets levels from a comma-separated list of `subsystem=level` pairs or bare levels. Later
+/// entries override earlier ones, so `info,ppu=trace` works as expected.
+pub fn set_levels(spec: &str) -> Result<(), String> {
+    for entry in spec.split(',') {
+        let parts: Vec<&str> = entry.splitn(1, '=').collect();
+        if parts.len() == 1 {
+            let level = try!(parse_level(parts[0]));
+            for subsystem in SUBSYSTEMS.iter() {
+                set_level(*subsystem, level);
+            }
+        } else {
+            let subsystem = try!(parse_subsystem(parts[0]));
+            let level = try!(parse_level(parts[1]));
+            set_level(subsystem, level);
+        }
+    }
+    Ok(())
+}
+
+/// Sets every subsystem to the default level. This has to be called before anything is logged.
+pub fn init() {
+    for subsystem in SUBSYSTEMS.iter() {
+        set_level(*subsystem, Warn);
+    }
+}
+
+pub fn set_level(subsystem: Subsystem, level: Level) {
+    g_levels[subsystem as uint].store(level as uint, Relaxed);
+}
+
+/// Sends messages to a file instead of stderr.
+pub fn set_file(path: &Path) -> Result<(), String> {
+    let file = match File::create(path) {
+        Ok(file) => file,
+        Err(err) => return Err(format!("couldn't create {}: {}", path.display(), err)),
+    };
+    unsafe {
+        let _lock = g_mutex.lock();
+        g_file = Some(file);
+    }
+    Ok(())
+}
+
+pub fn enabled(subsystem: Subsystem, level: Level) -> bool {
+    level as uint <= g_levels[subsystem as uint].load(Relaxed)
+}
+
+pub fn write(subsystem: Subsystem, level: Level, message: &str) {
+    let line = format!("[{} {}] {}",
+                       SUBSYSTEM_NAMES[subsystem as uint],
+                       LEVEL_NAMES[level as uint],
+                       message);
+    unsafe {
+        let _lock = g_mutex.lock();
+        match g_file {
+            Some(ref mut file) => file.write_line(line.as_slice()).unwrap(),
+            None => stdio::stderr().write_line(line.as_slice()).unwrap(),
+        }
+    }
+}
+
+// Logs a message if the subsystem's level allows it. The arguments after the level are as for
+// `format!`.
+macro_rules! log(
+    ($subsystem:expr, $level:expr, $($arg:tt)*) => (
+        if ::log::enabled($subsystem, $level) {
+            ::log::write($subsystem, $level, format!($($arg)*).as_slice());
+        }
+    )
+);
@@ -4,154 +4,1680 @@
 // Author: Patrick Walton
 //
 
-use apu::Apu;
+use ab::Comparison;
+use ab;
+use apu::{Apu, Letterless, LinearMixing, Mixing, NonlinearMixing, Revision, RevisionG};
+use apu;
+use audio::OutputBuffer;
 use audio;
+use batch;
+use compat::Session;
+use compat;
+use config;
 use cpu::Cpu;
+use diag::Diagnostic;
+use diag;
+use ffmpeg::FfmpegEncoder;
+use ffmpeg;
+use filter::Pipeline;
+use genie::GameGenie;
 use gfx::{Gfx, Scale, Scale1x, Scale2x, Scale3x};
 use input::Input;
 use input;
+use log;
 use mapper::Mapper;
 use mapper;
 use mem::MemMap;
+use movie::Movie;
+use movie;
+use netplay::Netplay;
+use netplay;
+use png;
 use ppu::{Oam, Ppu, Vram};
+use ppu;
+use profile::{MemProfiler, Watchpoint, WriteWatch};
+use profile;
+use record::{Encoder, Recorder};
+use resample::Quality;
+use resample;
 use rom::Rom;
-use util::Save;
+use rtc::{ClockSource, EmulatedTime, HostTime};
+use scroll::ScrollDump;
+use state;
+use util::{Save, Snapshot};
 use util;
+use wav::WavWriter;
 
-use libc::{int32_t, uint8_t, uint64_t};
+use libc::{int32_t, uint8_t, uint16_t, uint64_t};
 use std::cell::RefCell;
-use std::io::File;
+use std::cmp;
+use std::io::{File, IoResult};
+use std::io::timer;
 use std::mem;
+use std::os;
 use std::rc::Rc;
 use std::string;
+use std::sync::mpsc::{Receiver, channel};
+use std::thread::Thread;
+use std::time::Duration;
 
-#[cfg(debug)]
-fn record_fps(last_time: &mut uint64_t, frames: &mut uint) {
-    let now = util::current_time_millis();
-    if now >= *last_time + 1000 {
-        println!("{} FPS", *frames);
-        *frames = 0;
-        *last_time = now;
-    } else {
-        *frames += 1;
+//
+// Frame rate reporting
+//
+// Once a second, the window title is set to the game's name and the frame rate, along with the
+// emulation speed when that isn't 100%. The on-screen counter also shows how long frames take to
+// emulate and draw, leaving out the time spent waiting for the audio device to catch up.
+//
+
+pub const NTSC_FRAME_RATE: f64 = 60.0988;
+
+pub const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+
+struct FrameStats {
+    start_time: uint64_t,   // When we started counting, in microseconds
+    frames: uint,
+    busy_time: uint64_t,    // Time spent on the frames we've counted
+    frame_start: uint64_t,  // When the current frame started
+}
+
+impl FrameStats {
+    fn new() -> FrameStats {
+        let now = util::monotonic_time_micros();
+        FrameStats { start_time: now, frames: 0, busy_time: 0, frame_start: now }
+    }
+
+    fn start_frame(&mut self) {
+        self.frame_start = util::monotonic_time_micros();
+    }
+
+    fn end_frame(&mut self) {
+        self.frames += 1;
+        self.busy_time += util::monotonic_time_micros() - self.frame_start;
     }
 }
 
-#[cfg(not(debug))]
-fn record_fps(_: &mut uint64_t, _: &mut uint) {}
+fn update_frame_rate(gfx: &mut Gfx, game: &str, stats: &mut FrameStats) {
+    let now = util::monotonic_time_micros();
+    if now < stats.start_time + 1000000 {
+        return;
+    }
+
+    let fps = stats.frames as f64 * 1000000.0 / (now - stats.start_time) as f64;
+    let speed = (fps * 100.0 / NTSC_FRAME_RATE + 0.5) as uint;
+    let mut title = format!("{} - {:.0} FPS", game, fps);
+    if speed != 100 {
+        title.push_str(format!(" ({}% speed)", speed).as_slice());
+    }
+    gfx.set_title(title.as_slice());
+
+    let frame_time = stats.busy_time as f64 / 1000.0 / stats.frames as f64;
+    gfx.osd.set_frame_rate(fps, frame_time);
+
+    *stats = FrameStats::new();
+}
+
+//
+// Frame pacing
+//
+// Normally the audio device sets the pace, since the APU waits for room in its buffer. Without
+// audio, we sleep off whatever is left of each frame's share of a second instead.
+//
+// The time of day isn't steady: it stops while the machine is suspended and then leaps ahead, and
+// it can be set backwards. So the pacing goes by the monotonic clock, which does neither. We still
+// watch the time of day, and if it disagrees with the monotonic clock, we say that we've resynced,
+// since the jump might otherwise look like something the emulator did.
+//
+
+// If the time of day moves by more or less than the monotonic clock by this much between frames,
+// it jumped.
+const CLOCK_JUMP_MICROS: i64 = 250000;
+
+// How far behind we let ourselves fall before giving up on catching up.
+const MAX_LAG_MICROS: uint64_t = 100000;
+
+struct FramePacer {
+    next_frame: uint64_t,       // When the next frame is due, in monotonic microseconds
+    last_frame: uint64_t,       // When `wait` was last called, by the monotonic clock
+    last_time_of_day: uint64_t, // And by the time of day
+}
+
+impl FramePacer {
+    fn new() -> FramePacer {
+        let now = util::monotonic_time_micros();
+        FramePacer {
+            next_frame: now,
+            last_frame: now,
+            last_time_of_day: util::current_time_micros(),
+        }
+    }
+
+    /// Starts over from the current time, as after a pause.
+    fn reset(&mut self) {
+        *self = FramePacer::new();
+    }
+
+    /// Sleeps until the next frame is due. Returns true if the time of day jumped.
+    fn wait(&mut self) -> bool {
+        let now = util::monotonic_time_micros();
+        let time_of_day = util::current_time_micros();
+        let elapsed = (now - self.last_frame) as i64;
+        let time_of_day_elapsed = time_of_day as i64 - self.last_time_of_day as i64;
+        let jumped = (time_of_day_elapsed - elapsed).abs() > CLOCK_JUMP_MICROS;
+        self.last_frame = now;
+        self.last_time_of_day = time_of_day;
+
+        let frame_micros = (1000000.0 / NTSC_FRAME_RATE) as uint64_t;
+        self.next_frame += frame_micros;
+        if now < self.next_frame {
+            let sleep = cmp::min(self.next_frame - now, frame_micros);
+            timer::sleep(Duration::microseconds(sleep as i64));
+        } else if now > self.next_frame + MAX_LAG_MICROS {
+            // We've fallen well behind. Don't race to catch up.
+            self.next_frame = now;
+        }
+        jumped
+    }
+}
+
+// Formats DIP switch settings the way they're printed on the board: switch 1 first.
+fn dip_switch_string(val: uint8_t, count: uint) -> String {
+    let mut string = String::new();
+    for i in range(0, count) {
+        string.push(if ((val >> i) & 1) != 0 { '1' } else { '0' });
+    }
+    string
+}
 
 //
 // Argument parsing
 //
 
-struct Options {
-    rom_path: String,
-    scale: Scale,
+#[derive(Clone)]
+pub struct Options {
+    pub rom_path: String,
+    pub scale: Scale,
+    pub audio: bool,                     // Play audio, which also sets the pace
+    pub colors: Option<Vec<uint8_t>>,    // The palette to use instead of the built-in one
+    pub state_path: Option<String>,      // State to start from instead of powering on
+    pub frames: Option<uint>,            // Run headless for this many frames, then exit
+    pub screenshot_path: Option<String>, // Where to save the last frame when running headless
+    pub wav_path: Option<String>,        // Where to write all the audio
+    pub scroll_csv_path: Option<String>, // Where to write each line's scroll position
+    pub movie_path: Option<String>,      // Where to play a movie from or record one to
+    pub record_movie: bool,
+    pub sprite_overflow_bug: bool,       // Emulate the PPU's faulty sprite overflow check
+    pub run_ahead: bool,                 // Show each frame's successor, to hide a frame of lag
+    pub revision: Revision,              // The 2A03 revision to emulate
+    pub resampler: Quality,              // How to convert audio to the device's sample rate
+    pub mixing: Mixing,                  // How to combine the APU's channels
+    pub game_genie: bool,                // Plug the game into a Game Genie
+    pub batch_path: Option<String>,      // A file of headless jobs to run instead
+    pub threads: Option<uint>,           // How many batch jobs to run at once
+    pub host_port: Option<u16>,          // Host netplay on this UDP port
+    pub connect_address: Option<String>, // Join the netplay game hosted here
+    pub input_delay: uint,               // Frames of input delay for netplay, when hosting
+    pub ffmpeg_output: Option<String>,   // Record through ffmpeg to this file instead of AVI
+    pub ffmpeg_args: String,             // ffmpeg's output arguments, with `{output}` in them
+    pub mem_profile_path: Option<String>, // Where to write the memory access heatmap
+    pub watchpoints: Vec<Watchpoint>,    // Addresses to stop on when they're accessed
+    pub compat_log_path: Option<String>, // Where to record each session, for compatibility
+    pub compat_export_path: Option<String>, // Where to sum up the compatibility log, instead
+    pub hud_key: Option<String>,         // The key to hold for the debug HUD, by SDL's name
+    pub write_watches: Vec<WriteWatch>,  // Address ranges whose writes are logged to files
+    pub audio_ab: Option<Comparison>,    // Run twice with two audio settings and compare
+    pub filters: Option<String>,         // The video filters to show the picture through
+    pub diagnostic: Option<Diagnostic>,  // A diagnostic screen to show instead of a game
+    pub rtc_source: ClockSource,         // What the cartridge's clock runs from, if it has one
+}
+
+impl Options {
+    pub fn default() -> Options {
+        Options {
+            rom_path: String::new(),
+            scale: Scale1x,
+            audio: true,
+            colors: None,
+            state_path: None,
+            frames: None,
+            screenshot_path: None,
+            wav_path: None,
+            scroll_csv_path: None,
+            movie_path: None,
+            record_movie: false,
+            sprite_overflow_bug: true,
+            run_ahead: false,
+            revision: RevisionG,
+            resampler: resample::Adaptive(resample::DEFAULT_SINC_QUALITY),
+            mixing: LinearMixing,
+            game_genie: false,
+            batch_path: None,
+            threads: None,
+            host_port: None,
+            connect_address: None,
+            input_delay: netplay::DEFAULT_INPUT_DELAY,
+            ffmpeg_output: None,
+            ffmpeg_args: ffmpeg::DEFAULT_ARGS.to_string(),
+            mem_profile_path: None,
+            watchpoints: Vec::new(),
+            compat_log_path: None,
+            compat_export_path: None,
+            hud_key: None,
+            write_watches: Vec::new(),
+            audio_ab: None,
+            filters: None,
+            diagnostic: None,
+            rtc_source: HostTime,
+        }
+    }
 }
 
 fn usage() {
     println!("usage: sprocketnes [options] <path-to-rom>");
-    println!("options:");
-    println!("    -1 scale by 1x (default)");
-    println!("    -2 scale by 2x");
-    println!("    -3 scale by 3x");
+    println!("       sprocketnes --list-mappers   list the supported mappers");
+    println!("       sprocketnes --help           show this message");
+    println!("       sprocketnes --version        show the version, and the versions of the");
+    println!("                                    save state and movie formats");
+    println!("       sprocketnes --diagnostic <screen>");
+    println!("                                    show the `pattern` test pattern or the");
+    println!("                                    `controller` test instead of a game");
+    println!("options (those with a value can also go in sprocketnes.cfg; see config.rs):");
+    println!("    -1, -2, -3           scale by 1x (default), 2x, or 3x");
+    println!("    --scale <n>          the same as -n");
+    println!("    --palette <path>     use the colors in a .pal file");
+    println!("    --filters <stages>   show the picture through a chain of video filters, e.g.");
+    println!("                         `crop:8,scale:2,scanlines`; see filter.rs");
+    println!("    --region <region>    the console to emulate; only `ntsc` is available for now");
+    println!("    --no-audio           don't open an audio device");
+    println!("    --fix-sprite-overflow don't emulate the PPU's sprite overflow bug");
+    println!("    --game-genie         start in the Game Genie, from `gamegenie.rom`");
+    println!("    --run-ahead          cut a frame of input lag by showing the next frame early");
+    println!("    --revision <rev>     emulate the given 2A03 revision: `g` (default) or");
+    println!("                         `letterless`, the earliest");
+    println!("    --resampler <kind>   convert audio with `linear` interpolation, a `sinc`");
+    println!("                         filter of quality `sinc:0` to `sinc:10`, or `adaptive`");
+    println!("                         (default), which is `sinc` unless emulation falls behind");
+    println!("                         real time; `adaptive:<0-10>` sets its sinc quality");
+    println!("    --mixing <kind>      mix the APU's channels `linear`ly (default), or");
+    println!("                         `nonlinear`ly like the console does");
+    println!("    --rtc <source>       run the cartridge's clock, on boards with one, from the");
+    println!("                         `host`'s time (default) or `emulated` time, which counts");
+    println!("                         CPU cycles and is always used headless, by movies, and");
+    println!("                         in netplay");
+    println!("    --state <path>       start from a saved state");
+    println!("    --movie play <path>  play the first controller's input from a movie");
+    println!("    --movie record <path>");
+    println!("                         record the first controller's input to a movie");
+    println!("    --frames <n>         run n frames without a window or audio, then exit");
+    println!("    --screenshot <path>  save the last frame as a PNG when running headless; given");
+    println!("                         a directory, name it after the game and frame");
+    println!("    --wav-out <path>     write the audio to a WAV file");
+    println!("    --audio-ab <setting>=<a>,<b>");
+    println!("                         run headless with each value of `mixing`, `revision`, or");
+    println!("                         `resampler`, and write both WAVs and their difference");
+    println!("                         next to the `--wav-out` path; see ab.rs");
+    println!("    --record-ffmpeg <path>");
+    println!("                         make the record key encode to this file with ffmpeg,");
+    println!("                         instead of writing `recording.avi`");
+    println!("    --ffmpeg-args <args> ffmpeg's output arguments, with `{{output}}` for the path");
+    println!("                         (default: `{}`)", ffmpeg::DEFAULT_ARGS);
+    println!("    --host <port>        host a two-player netplay game on the given UDP port");
+    println!("    --connect <address>  join the netplay game hosted at `host:port`");
+    println!("    --input-delay <n>    frames of netplay input delay, from 1 to 30 (default 2)");
+    println!("    --batch <path>       run the headless jobs listed in a JSON file; see batch.rs");
+    println!("    --threads <n>        run n batch jobs at once (default: one per CPU)");
+    println!("    --scroll-csv <path>  write each line's scroll position to a CSV file");
+    println!("    --mem-profile <path> write a heatmap of the CPU's memory accesses on exit");
+    println!("    --watch <addr>[-<addr>][:r|w|rw]");
+    println!("                         pause when the CPU reads or writes the hexadecimal");
+    println!("                         addresses; can be given more than once");
+    println!("    --watch-writes <addr>[-<addr>]=<path>");
+    println!("                         log every write to the hexadecimal addresses to a file,");
+    println!("                         with the PC and cycle; can be given more than once");
+    println!("    --hud-key <key>      hold this key instead of Tab to show the debug HUD");
+    println!("    --compat-log <path>  add each session to a compatibility log, noting problems");
+    println!("                         flagged with F4");
+    println!("    --compat-export <path>");
+    println!("                         sum up the compatibility log per game as JSON, then exit");
+    println!("    --log <spec>         set log levels, e.g. `ppu=debug,mapper=trace` or `info`");
+    println!("                         (subsystems: cpu, ppu, apu, mapper, input, audio;");
+    println!("                         levels: off, error, warn, info, debug, trace)");
+    println!("    --log-file <path>    write the log to a file instead of stderr");
+    println!("    --trace              log every instruction; the same as `--log cpu=trace`");
+}
+
+fn list_mappers() {
+    println!("Supported mappers:");
+    for &(number, name) in mapper::supported_mappers().iter() {
+        println!("    {:3}  {}", number, name);
+    }
+    println!("Famicom Disk System images (.fds) are supported too.");
+}
+
+// Says what's wrong with the command line and where to find the right options. Returns None so
+// that `parse_args` can return it.
+fn bad_args(message: String) -> Option<Options> {
+    println!("sprocketnes: {}", message);
+    println!("Run `sprocketnes --help` for a list of options.");
+    None
+}
+
+// Options that take a value. These can also be set in the config file.
+pub static VALUE_OPTIONS: [&'static str; 31] = [
+    "--scale", "--palette", "--region", "--revision", "--resampler", "--mixing", "--state",
+    "--frames", "--screenshot", "--wav-out", "--audio-ab", "--scroll-csv", "--log", "--log-file",
+    "--movie", "--batch", "--threads", "--host", "--connect", "--input-delay", "--record-ffmpeg",
+    "--ffmpeg-args", "--mem-profile", "--watch", "--watch-writes", "--compat-log",
+    "--compat-export", "--hud-key", "--filters", "--diagnostic", "--rtc"
+];
+
+pub fn parse_value_option(options: &mut Options, arg: &str, val: &str) -> Result<(), String> {
+    match arg {
+        "--mem-profile" | "--watch" | "--watch-writes" if !profile::ENABLED => {
+            return Err(format!("`{}` needs sprocketnes built with `--features mem-profile`", arg))
+        }
+        "--scale" => {
+            options.scale = match val {
+                "1" => Scale1x,
+                "2" => Scale2x,
+                "3" => Scale3x,
+                _ => return Err(format!("expected 1, 2, or 3, not `{}`", val)),
+            }
+        }
+        "--palette" => options.colors = Some(try!(ppu::load_palette(&Path::new(val)))),
+        "--diagnostic" => options.diagnostic = Some(try!(Diagnostic::parse(val))),
+        "--filters" => {
+            try!(Pipeline::parse(val));
+            options.filters = Some(val.to_string());
+        }
+        "--region" => {
+            // Only NTSC consoles are emulated so far.
+            if val != "ntsc" {
+                return Err(format!("expected `ntsc`, not `{}`", val));
+            }
+        }
+        "--revision" => {
+            options.revision = match val {
+                "g" => RevisionG,
+                "letterless" => Letterless,
+                _ => return Err(format!("expected `g` or `letterless`, not `{}`", val)),
+            }
+        }
+        "--rtc" => {
+            options.rtc_source = match val {
+                "host" => HostTime,
+                "emulated" => EmulatedTime,
+                _ => return Err(format!("expected `host` or `emulated`, not `{}`", val)),
+            }
+        }
+        "--resampler" => {
+            options.resampler = match resample::parse_quality(val) {
+                Some(quality) => quality,
+                None => {
+                    return Err(format!("expected `linear`, `sinc[:<0-10>]`, or \
+                                        `adaptive[:<0-10>]`, not `{}`",
+                                       val))
+                }
+            }
+        }
+        "--mixing" => {
+            options.mixing = match val {
+                "linear" => LinearMixing,
+                "nonlinear" => NonlinearMixing,
+                _ => return Err(format!("expected `linear` or `nonlinear`, not `{}`", val)),
+            }
+        }
+        "--audio-ab" => options.audio_ab = Some(try!(Comparison::parse(val))),
+        "--state" => options.state_path = Some(val.to_string()),
+        "--frames" => {
+            options.frames = match from_str::<uint>(val) {
+                Some(frames) => Some(frames),
+                None => return Err(format!("expected a number of frames, not `{}`", val)),
+            }
+        }
+        "--screenshot" => options.screenshot_path = Some(val.to_string()),
+        "--wav-out" => options.wav_path = Some(val.to_string()),
+        "--scroll-csv" => options.scroll_csv_path = Some(val.to_string()),
+        // The log is global, so there's nothing to keep in the options.
+        "--log" => try!(log::set_levels(val)),
+        "--log-file" => try!(log::set_file(&Path::new(val))),
+        "--batch" => options.batch_path = Some(val.to_string()),
+        "--threads" => {
+            options.threads = match from_str::<uint>(val) {
+                Some(threads) if threads > 0 => Some(threads),
+                _ => return Err(format!("expected a number of threads, not `{}`", val)),
+            }
+        }
+        "--host" => {
+            options.host_port = match from_str::<u16>(val) {
+                Some(port) => Some(port),
+                None => return Err(format!("expected a port number, not `{}`", val)),
+            }
+        }
+        "--connect" => options.connect_address = Some(val.to_string()),
+        "--record-ffmpeg" => options.ffmpeg_output = Some(val.to_string()),
+        "--mem-profile" => options.mem_profile_path = Some(val.to_string()),
+        "--watch" => options.watchpoints.push(try!(Watchpoint::parse(val))),
+        "--watch-writes" => options.write_watches.push(try!(WriteWatch::parse(val))),
+        "--compat-log" => options.compat_log_path = Some(val.to_string()),
+        "--compat-export" => options.compat_export_path = Some(val.to_string()),
+        "--hud-key" => {
+            try!(input::parse_key(val));
+            options.hud_key = Some(val.to_string());
+        }
+        "--ffmpeg-args" => {
+            if !val.contains("{output}") {
+                return Err(format!("the ffmpeg arguments need `{{output}}` in them"));
+            }
+            options.ffmpeg_args = val.to_string();
+        }
+        "--input-delay" => {
+            options.input_delay = match from_str::<uint>(val) {
+                Some(delay) if delay >= 1 && delay <= netplay::MAX_INPUT_DELAY => delay,
+                _ => {
+                    return Err(format!("expected 1 to {} frames, not `{}`",
+                                       netplay::MAX_INPUT_DELAY,
+                                       val))
+                }
+            }
+        }
+        "--movie" => {
+            options.record_movie = match val {
+                "play" => false,
+                "record" => true,
+                _ => return Err(format!("expected `play` or `record`, not `{}`", val)),
+            }
+        }
+        _ => panic!("not an option with a value"),
+    }
+    Ok(())
 }
 
 fn parse_args(argc: int32_t, argv: *const *const uint8_t) -> Option<Options> {
-    let mut options = Options {
-        rom_path: String::new(),
-        scale: Scale1x,
-    };
+    let mut options = Options::default();
+    match config::load(&Path::new(config::CONFIG_PATH), &mut options) {
+        Ok(()) => {}
+        Err(err) => return bad_args(err),
+    }
 
-    for i in range(1, argc as int) {
-        let arg = unsafe {
-            string::raw::from_buf(mem::transmute(*argv.offset(i)))
-        };
+    let args: Vec<String> = range(0, argc as int).map(|i| unsafe {
+        string::raw::from_buf(mem::transmute(*argv.offset(i)))
+    }).collect();
 
-        if "-1" == arg.as_slice() {
+    let mut i = 1;
+    while i < args.len() {
+        let arg = args[i].as_slice();
+
+        if VALUE_OPTIONS.iter().any(|&name| name == arg) {
+            // `--movie` takes a path after the mode.
+            let count = if arg == "--movie" { 2 } else { 1 };
+            if i + count >= args.len() {
+                return bad_args(format!("`{}` needs {}",
+                                        arg,
+                                        if count == 1 { "a value" } else { "two values" }));
+            }
+            match parse_value_option(&mut options, arg, args[i + 1].as_slice()) {
+                Ok(()) => {}
+                Err(err) => return bad_args(format!("{}: {}", arg, err)),
+            }
+            if arg == "--movie" {
+                options.movie_path = Some(args[i + 2].clone());
+            }
+            i += count + 1;
+            continue;
+        }
+
+        if "--help" == arg || "-h" == arg {
+            usage();
+            return None;
+        } else if "--list-mappers" == arg {
+            list_mappers();
+            return None;
+        } else if "--version" == arg {
+            println!("sprocketnes {}", VERSION);
+            println!("Save state format {}", state::STATE_FORMAT);
+            println!("Movie format {}", movie::MOVIE_FORMAT);
+            return None;
+        } else if "-1" == arg {
             options.scale = Scale1x;
-        } else if "-2" == arg.as_slice() {
+        } else if "-2" == arg {
             options.scale = Scale2x;
-        } else if "-3" == arg.as_slice() {
+        } else if "-3" == arg {
             options.scale = Scale3x;
-        } else if arg.as_bytes()[0] == ('-' as uint8_t) {
-            usage();
-            return None;
+        } else if "--no-audio" == arg {
+            options.audio = false;
+        } else if "--trace" == arg {
+            log::set_level(log::Cpu, log::Trace);
+        } else if "--fix-sprite-overflow" == arg {
+            options.sprite_overflow_bug = false;
+        } else if "--run-ahead" == arg {
+            options.run_ahead = true;
+        } else if "--game-genie" == arg {
+            options.game_genie = true;
+        } else if arg.starts_with("-") {
+            return bad_args(format!("unknown option `{}`", arg));
+        } else if options.rom_path.len() != 0 {
+            return bad_args(format!("more than one ROM given: `{}` and `{}`",
+                                    options.rom_path,
+                                    arg));
         } else {
             options.rom_path = arg.to_string();
         }
+        i += 1;
     }
 
-    if options.rom_path.len() == 0 {
-        usage();
-        return None;
+    if options.compat_export_path.is_some() {
+        if options.compat_log_path.is_none() {
+            return bad_args("`--compat-export` needs `--compat-log` to say which log".to_string());
+        }
+        return Some(options);
+    }
+    if options.rom_path.len() == 0 && options.batch_path.is_none() &&
+            options.diagnostic.is_none() {
+        return bad_args("no ROM given".to_string());
+    }
+    if options.audio_ab.is_some() {
+        if options.frames.is_none() || options.wav_path.is_none() {
+            return bad_args("`--audio-ab` needs `--frames` and `--wav-out`".to_string());
+        }
+        if options.record_movie {
+            return bad_args("`--audio-ab` can only play movies, not record them".to_string());
+        }
+    }
+    if options.host_port.is_some() || options.connect_address.is_some() {
+        if options.host_port.is_some() && options.connect_address.is_some() {
+            return bad_args("`--host` and `--connect` can't be used together".to_string());
+        }
+        if options.movie_path.is_some() {
+            return bad_args("movies can't be used with netplay".to_string());
+        }
     }
 
     Some(options)
 }
 
+//
+// Loading and power-on
+//
+
+// Parses the ROM on another thread, so that it's read while we bring up video and audio.
+fn prefetch_rom(path: Path) -> Receiver<Result<Rom, String>> {
+    let (sender, receiver) = channel();
+    Thread::spawn(move || {
+        sender.send(Rom::from_path(&path));
+    });
+    receiver
+}
+
+// Builds a freshly powered-on machine around a copy of the ROM. The parsed ROM is kept around,
+// so power cycling never has to go back to the file.
+pub fn power_on(rom: &Rom, audio_buffer: Option<*mut OutputBuffer>, options: &Options)
+                -> Result<Cpu<MemMap>, String> {
+    let mut mapper: Box<Mapper+Send> = try!(mapper::create_mapper(Box::new(rom.clone())));
+    if options.game_genie {
+        mapper = Box::new(try!(GameGenie::new(mapper))) as Box<Mapper+Send>;
+    }
+    match mapper.rtc() {
+        Some(rtc) => rtc.source = clock_source(options),
+        None => {}
+    }
+    let mapper = Rc::new(RefCell::new(mapper));
+    let mut ppu = Ppu::new(Vram::new(mapper.clone(), rom.header.four_screen()), Oam::new());
+    ppu.sprite_overflow_bug = options.sprite_overflow_bug;
+    let vs_system = rom.header.is_vs_system();
+    match options.colors {
+        None if vs_system => {
+            let colors = ppu::vs_palette(rom.header.vs_ppu());
+            ppu.colors.as_mut_slice().clone_from_slice(colors.as_slice());
+        }
+        None => {}
+        Some(ref colors) => {
+            ppu.colors.as_mut_slice().clone_from_slice(colors.as_slice());
+        }
+    }
+    let mut input = Input::new();
+    input.vs_system = vs_system;
+    match options.hud_key {
+        None => {}
+        Some(ref name) => input.bindings.debug_hud = input::parse_key(name.as_slice()).unwrap(),
+    }
+    let mut apu = Apu::new(audio_buffer, mapper.clone());
+    apu.revision = options.revision;
+    apu.set_resampler(options.resampler);
+    apu.mixing = options.mixing;
+    let mut memmap = MemMap::new(ppu, input, mapper, apu);
+    memmap.rom_crc = rom.identity_crc32();
+    if options.mem_profile_path.is_some() || options.watchpoints.len() > 0 ||
+            options.write_watches.len() > 0 {
+        let profiler = MemProfiler::new(options.watchpoints.clone(), options.write_watches.clone());
+        memmap.profiler = Some(Box::new(profiler));
+    }
+    let mut cpu = Cpu::new(memmap);
+
+    // TODO: Add a flag to not reset for nestest.log
+    cpu.reset();
+    Ok(cpu)
+}
+
+// Runs one instruction and everything that happens alongside it. Returns true when the PPU has
+// just finished a frame.
+pub fn step(cpu: &mut Cpu<MemMap>) -> bool {
+    cpu.step();
+
+    let ppu_result = cpu.mem.ppu.step(cpu.cy);
+    cpu.mem.apu.step(cpu.cy);
+
+    let irq = cpu.mem.irq_line();
+    let nmi = cpu.mem.ppu.nmi_line();
+    cpu.set_irq_line(irq);
+    cpu.set_nmi_line(nmi);
+    ppu_result.new_frame
+}
+
+//
+// Run-ahead
+//
+// Most games take a frame or more to respond to input, on top of the frame it takes to show the
+// result. Once a frame is done, we run the next one too, with the same input, and show that
+// instead; then we rewind to the end of the real frame and carry on with the next input. The
+// player sees the game's response a frame sooner, at the cost of emulating every frame twice.
+//
+
+fn run_ahead(cpu: &mut Cpu<MemMap>, snapshot: &mut Snapshot) {
+    snapshot.take(cpu);
+    cpu.mem.apu.set_silent(true);
+    while !step(cpu) {}
+    cpu.mem.apu.set_silent(false);
+    snapshot.restore(cpu);
+
+    // The frame that set them off hasn't really happened yet.
+    match cpu.mem.profiler {
+        None => {}
+        Some(ref mut profiler) => {
+            drop(profiler.take_hit());
+            profiler.discard_writes();
+        }
+    }
+}
+
+//
+// Debug HUD
+//
+// Held down, the HUD key shows where the PPU is, the CPU's cycle count, the cartridge's banks and
+// the interrupt lines over the game, for a quick look at what's going on around a glitch.
+//
+
+fn on_off(on: bool) -> &'static str {
+    if on { "on" } else { "off" }
+}
+
+fn update_debug_hud(gfx: &mut Gfx, cpu: &Cpu<MemMap>) {
+    gfx.osd.debug_hud = if cpu.mem.input.debug_hud_held {
+        Some(debug_hud_lines(cpu))
+    } else {
+        None
+    };
+}
+
+fn debug_hud_lines(cpu: &Cpu<MemMap>) -> Vec<String> {
+    let (scanline, dot) = cpu.mem.ppu.position();
+    let mapper = cpu.mem.mapper.borrow();
+    let mut lines = vec![
+        format!("Scanline {} dot {}", scanline, dot),
+        format!("CPU cycle {} PC ${:04X}", cpu.cy, cpu.pc() as uint),
+    ];
+    lines.extend(mapper.describe_banks().into_iter());
+    lines.push(format!("IRQ: cartridge {}, APU {}; NMI {}",
+                       on_off(mapper.irq_pending()),
+                       on_off(cpu.mem.apu.irq_pending()),
+                       on_off(cpu.mem.ppu.nmi_line())));
+    lines
+}
+
+//
+// Screenshots
+//
+// Screenshots are named after the game and the frame they show, counted from power on, and carry
+// the ROM's CRC-32, the frame number, the emulator's version and the settings that affect
+// emulation in PNG text chunks. With those and the state or movie that led up to the frame, the
+// same picture can be made again.
+//
+
+fn game_name(options: &Options) -> String {
+    let rom_path = options.rom_path.as_slice();
+    Path::new(rom_path).filestem_str().unwrap_or(rom_path).to_string()
+}
+
+fn screenshot_name(options: &Options, frame: uint64_t) -> String {
+    format!("{}-frame{:06}.png", game_name(options), frame)
+}
+
+// The options that change what the console does, as they'd be given on the command line.
+fn settings_string(options: &Options) -> String {
+    let mut settings = vec![ match options.revision {
+        RevisionG => "--revision g",
+        Letterless => "--revision letterless",
+    }.to_string() ];
+    if options.colors.is_some() {
+        settings.push("--palette <custom>".to_string());
+    }
+    if !options.sprite_overflow_bug {
+        settings.push("--fix-sprite-overflow".to_string());
+    }
+    if options.mixing == NonlinearMixing {
+        settings.push("--mixing nonlinear".to_string());
+    }
+    if options.run_ahead {
+        settings.push("--run-ahead".to_string());
+    }
+    if options.game_genie {
+        settings.push("--game-genie".to_string());
+    }
+    settings.connect(" ")
+}
+
+// Saves the screen, which is of the given frame.
+fn save_screenshot(path: &Path, cpu: &Cpu<MemMap>, frame: uint64_t, rom: &Rom, options: &Options)
+                   -> IoResult<()> {
+    let mut metadata = vec![
+        ("Title", game_name(options)),
+        ("Software", format!("sprocketnes {}", VERSION)),
+        ("ROM CRC-32", format!("{:08X}", rom.identity_crc32() as uint)),
+        ("Frame", frame.to_string()),
+        ("Settings", settings_string(options)),
+    ];
+    match options.state_path {
+        None => {}
+        Some(ref state_path) => metadata.push(("State", state_path.clone())),
+    }
+    match options.movie_path {
+        None => {}
+        Some(ref movie_path) => metadata.push(("Movie", movie_path.clone())),
+    }
+    png::save_screenshot(path, &*cpu.mem.ppu.screen, metadata.as_slice())
+}
+
+//
+// Memory profiling
+//
+
+// Logs the last instruction's writes and describes the watchpoint it set off, if any, given the
+// PC and cycle it started at.
+fn take_watch_hit(cpu: &mut Cpu<MemMap>, pc: uint16_t, cycle: uint64_t) -> Option<String> {
+    let hit = match cpu.mem.profiler {
+        None => return None,
+        Some(ref mut profiler) => {
+            profiler.end_instruction(pc, cycle);
+            profiler.take_hit()
+        }
+    };
+    hit.map(|hit| format!("Watchpoint: PC ${:04X} {}", pc as uint, hit.to_str()))
+}
+
+fn write_mem_profile(cpu: &Cpu<MemMap>, options: &Options) {
+    match (&cpu.mem.profiler, &options.mem_profile_path) {
+        (&Some(ref profiler), &Some(ref path)) => {
+            match profiler.write_report(&Path::new(path.as_slice())) {
+                Ok(()) => {}
+                Err(err) => println!("Couldn't write the memory profile to {}: {}", path, err),
+            }
+        }
+        _ => {}
+    }
+}
+
+//
+// Battery-backed memory
+//
+
+// Saved games are kept next to the ROM, with `.sav` in place of its extension.
+fn battery_path(options: &Options) -> Path {
+    Path::new(options.rom_path.as_slice()).with_extension("sav")
+}
+
+// The clock goes beside it, with `.rtc`.
+fn rtc_path(options: &Options) -> Path {
+    Path::new(options.rom_path.as_slice()).with_extension("rtc")
+}
+
+// Runs whose results have to come out the same every time keep the clock in emulated time.
+fn clock_source(options: &Options) -> ClockSource {
+    if options.frames.is_some() || options.screenshot_path.is_some() ||
+            options.movie_path.is_some() || options.host_port.is_some() ||
+            options.connect_address.is_some() {
+        EmulatedTime
+    } else {
+        options.rtc_source.clone()
+    }
+}
+
+fn load_battery(cpu: &mut Cpu<MemMap>, options: &Options) {
+    let path = battery_path(options);
+    let mut mapper = cpu.mem.mapper.borrow_mut();
+    match mapper.battery() {
+        Some(memory) if path.exists() => {
+            match File::open(&path).read_to_end() {
+                Ok(data) => drop(memory.clone_from_slice(data.as_slice())),
+                Err(err) => {
+                    println!("Couldn't read the saved game from {}: {}", path.display(), err)
+                }
+            }
+        }
+        _ => {}
+    }
+
+    let path = rtc_path(options);
+    let rtc = match mapper.rtc() {
+        Some(rtc) if path.exists() => rtc,
+        _ => return,
+    };
+    let result = match File::open(&path).read_to_end() {
+        Ok(data) => rtc.load_battery_data(data.as_slice()),
+        Err(err) => Err(err.to_string()),
+    };
+    match result {
+        Ok(()) => {}
+        Err(err) => println!("Couldn't read the clock from {}: {}", path.display(), err),
+    }
+}
+
+fn save_battery(cpu: &mut Cpu<MemMap>, options: &Options) {
+    let path = battery_path(options);
+    let mut mapper = cpu.mem.mapper.borrow_mut();
+    match mapper.battery() {
+        None => {}
+        Some(memory) => {
+            match File::create(&path).write(memory) {
+                Ok(()) => {}
+                Err(err) => {
+                    println!("Couldn't write the saved game to {}: {}", path.display(), err)
+                }
+            }
+        }
+    }
+
+    let path = rtc_path(options);
+    let data = match mapper.rtc() {
+        None => return,
+        Some(rtc) => rtc.battery_data(),
+    };
+    match File::create(&path).write(data.as_slice()) {
+        Ok(()) => {}
+        Err(err) => println!("Couldn't write the clock to {}: {}", path.display(), err),
+    }
+}
+
+// Finishes a recording. Its audio is captured for it, unless a WAV file is being written, which it
+// shares the capture with.
+fn stop_recording(mut recorder: Box<Encoder>, cpu: &mut Cpu<MemMap>, writing_wav: bool) {
+    if !writing_wav {
+        cpu.mem.apu.stop_capture();
+    }
+    recorder.finish();
+}
+
+// Loads a saved state, and returns the file's contents, for a movie being recorded.
+fn load_state(cpu: &mut Cpu<MemMap>, path: &Path) -> Result<Vec<uint8_t>, String> {
+    let data = match File::open(path).read_to_end() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    };
+    try!(state::load(data.as_slice(), cpu));
+    Ok(data)
+}
+
+// Starts playing or recording a movie, if asked to.
+fn start_movie(cpu: &mut Cpu<MemMap>, options: &Options) -> Result<Option<Movie>, String> {
+    match options.movie_path {
+        None => Ok(None),
+        Some(ref path) => {
+            let path = Path::new(path.as_slice());
+            let mut movie = if options.record_movie {
+                try!(Movie::record(&path))
+            } else {
+                try!(Movie::play(&path))
+            };
+            try!(movie.start(cpu));
+            Ok(Some(movie))
+        }
+    }
+}
+
+// Connects to the other player, if asked to.
+fn connect_netplay(rom: &Rom, options: &Options) -> Result<Option<Netplay>, String> {
+    if options.host_port.is_none() && options.connect_address.is_none() {
+        return Ok(None);
+    }
+
+    // Both players have to start from the same state, so its contents are compared, not its name.
+    let state_crc = match options.state_path {
+        None => None,
+        Some(ref path) => {
+            let path = Path::new(path.as_slice());
+            match File::open(&path).read_to_end() {
+                Ok(data) => Some(util::crc32(data.as_slice())),
+                Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+            }
+        }
+    };
+    let settings = netplay::Settings {
+        rom_crc: rom.identity_crc32(),
+        state_crc: state_crc,
+        game_genie: options.game_genie,
+        sprite_overflow_bug: options.sprite_overflow_bug,
+    };
+    let netplay = match options.host_port {
+        Some(port) => {
+            try!(Netplay::host(port, settings, options.input_delay, input::quit_requested))
+        }
+        None => {
+            let address = options.connect_address.as_ref().unwrap();
+            try!(Netplay::connect(address.as_slice(), settings, input::quit_requested))
+        }
+    };
+    Ok(Some(netplay))
+}
+
+// Trades buttons with the other player at the end of a frame. If they've gone, we carry on alone.
+fn netplay_end_frame(netplay: &mut Option<Netplay>, cpu: &mut Cpu<MemMap>, gfx: &mut Gfx) {
+    let status = match *netplay {
+        None => return,
+        Some(ref mut netplay) => netplay.end_frame(cpu),
+    };
+    match status {
+        netplay::Running => {}
+        netplay::Desynced(frame) => {
+            gfx.osd.message(format!("Netplay desynced at frame {}", frame));
+        }
+        netplay::Disconnected => {
+            netplay.take().unwrap().stop(&mut cpu.mem.input);
+            gfx.osd.message("The other player disconnected".to_string());
+        }
+    }
+}
+
+// Starts writing scroll positions to a CSV file, if asked to.
+fn create_scroll_dump(options: &Options) -> Result<Option<ScrollDump>, String> {
+    match options.scroll_csv_path {
+        None => Ok(None),
+        Some(ref path) => {
+            match ScrollDump::create(&Path::new(path.as_slice())) {
+                Ok(dump) => Ok(Some(dump)),
+                Err(err) => Err(format!("couldn't create {}: {}", path, err)),
+            }
+        }
+    }
+}
+
+// Opens the window, with the video filters asked for.
+fn create_gfx(options: &Options) -> Gfx {
+    let filters = match options.filters {
+        None => Pipeline::new(),
+        Some(ref spec) => Pipeline::parse(spec.as_slice()).unwrap(),
+    };
+    Gfx::new(options.scale, filters)
+}
+
+// Starts writing audio to a WAV file, if asked to.
+fn create_wav(cpu: &mut Cpu<MemMap>, options: &Options) -> Result<Option<WavWriter>, String> {
+    match options.wav_path {
+        None => Ok(None),
+        Some(ref path) => {
+            match WavWriter::create(&Path::new(path.as_slice())) {
+                Ok(wav) => {
+                    cpu.mem.apu.start_capture();
+                    Ok(Some(wav))
+                }
+                Err(err) => Err(format!("couldn't create {}: {}", path, err)),
+            }
+        }
+    }
+}
+
+//
+// Headless runs
+//
+// These run a fixed number of frames with no window or audio, and optionally save the
+// last frame. Since the same ROM and state always produce the same screenshot, a shell script can
+// compare screenshots to find the frame, or the commit, at which a rendering bug appears.
+//
+
+pub fn run_headless(rom: &Rom, options: &Options) {
+    let mut cpu = match power_on(rom, None, options) {
+        Ok(cpu) => cpu,
+        Err(err) => {
+            println!("Can't power on: {}", err);
+            return;
+        }
+    };
+    match options.state_path {
+        None => {}
+        Some(ref path) => match load_state(&mut cpu, &Path::new(path.as_slice())) {
+            Ok(_) => {}
+            Err(err) => {
+                println!("Can't load the state: {}", err);
+                return;
+            }
+        },
+    }
+    let mut wav = match create_wav(&mut cpu, options) {
+        Ok(wav) => wav,
+        Err(err) => {
+            println!("Can't write the audio: {}", err);
+            return;
+        }
+    };
+    let mut movie = match start_movie(&mut cpu, options) {
+        Ok(movie) => movie,
+        Err(err) => {
+            println!("Can't start the movie: {}", err);
+            return;
+        }
+    };
+    let mut scroll_dump = match create_scroll_dump(options) {
+        Ok(dump) => dump,
+        Err(err) => {
+            println!("Can't write the scroll positions: {}", err);
+            return;
+        }
+    };
+
+    let frames = options.frames.unwrap_or(0);
+    for frame in range(0, frames) {
+        loop {
+            let (pc, cycle) = (cpu.pc(), cpu.cy);
+            let new_frame = step(&mut cpu);
+            match take_watch_hit(&mut cpu, pc, cycle) {
+                None => {}
+                Some(message) => println!("{}: frame {}: {}", options.rom_path, frame, message),
+            }
+            if new_frame {
+                break;
+            }
+        }
+        match scroll_dump {
+            None => {}
+            Some(ref mut scroll_dump) => scroll_dump.add_frame(cpu.mem.ppu.line_scrolls.as_slice()),
+        }
+        match movie {
+            None => {}
+            Some(ref mut movie) => {
+                match movie.end_frame(&mut cpu) {
+                    movie::Desynced(frame) => {
+                        println!("{}: the movie desynced at frame {}", options.rom_path, frame);
+                    }
+                    movie::Failed(err) => println!("{}: {}", options.rom_path, err),
+                    movie::Running | movie::Finished => {}
+                }
+            }
+        }
+
+        // Nothing is listening, but this keeps the channel buffers from filling up.
+        cpu.mem.apu.play_channels();
+        match wav {
+            None => {}
+            Some(ref mut wav) => wav.add_samples(cpu.mem.apu.take_captured().as_slice()),
+        }
+    }
+
+    match wav {
+        None => {}
+        Some(mut wav) => {
+            cpu.mem.apu.flush();
+            wav.add_samples(cpu.mem.apu.take_captured().as_slice());
+            wav.finish();
+        }
+    }
+
+    match options.screenshot_path {
+        None => {}
+        Some(ref path) => {
+            let frame = cpu.mem.ppu.frame;
+            let mut path = Path::new(path.as_slice());
+            if path.is_dir() {
+                path.push(screenshot_name(options, frame));
+            }
+            match save_screenshot(&path, &cpu, frame, rom, options) {
+                Ok(()) => {}
+                Err(err) => println!("Can't save {}: {}", path.display(), err),
+            }
+        }
+    }
+    write_mem_profile(&cpu, options);
+}
+
 //
 // Entry point and main loop
 //
 
 pub fn start(argc: int32_t, argv: *const *const uint8_t) {
+    log::init();
     let options = match parse_args(argc, argv) {
         Some(options) => options,
         None => return,
     };
 
+    match (&options.compat_log_path, &options.compat_export_path) {
+        (&Some(ref log_path), &Some(ref out_path)) => {
+            match compat::export(&Path::new(log_path.as_slice()), &Path::new(out_path.as_slice())) {
+                Ok(count) => println!("Wrote {} games to {}", count, out_path),
+                Err(err) => println!("Can't export the compatibility log: {}", err),
+            }
+            return;
+        }
+        _ => {}
+    }
+
+    match options.diagnostic {
+        None => {}
+        Some(ref diagnostic) => {
+            diag::run(diagnostic.clone(), create_gfx(&options), options.colors.clone());
+            return;
+        }
+    }
+
+    match options.batch_path {
+        None => {}
+        Some(ref path) => {
+            let jobs = match batch::parse_jobs(&Path::new(path.as_slice()), &options) {
+                Ok(jobs) => jobs,
+                Err(err) => {
+                    println!("Can't run the batch: {}", err);
+                    return;
+                }
+            };
+            let count = jobs.len();
+            let failed = batch::run(jobs, options.threads.unwrap_or(os::num_cpus()));
+            println!("Ran {} jobs; {} failed", count, failed);
+            return;
+        }
+    }
+
     let rom_path = options.rom_path.as_slice();
-    let rom = Box::new(Rom::from_path(&Path::new(rom_path)));
-    println!("Loaded ROM:\n{}", rom.header.to_str());
+    if options.frames.is_some() || options.screenshot_path.is_some() {
+        let rom = match Rom::from_path(&Path::new(rom_path)) {
+            Ok(rom) => rom,
+            Err(err) => {
+                println!("Can't load {}: {}", rom_path, err);
+                return;
+            }
+        };
+        match mapper::check_rom(&rom) {
+            Ok(()) => {}
+            Err(err) => {
+                println!("Can't run {}: {}", rom_path, err);
+                return;
+            }
+        }
+        match options.audio_ab {
+            None => {}
+            Some(ref comparison) => {
+                match ab::run(&rom, &options, comparison) {
+                    Ok(()) => {}
+                    Err(err) => println!("Can't compare the audio: {}", err),
+                }
+                return;
+            }
+        }
+        run_headless(&rom, &options);
+        match options.screenshot_path {
+            None => {}
+            Some(ref path) => {
+                println!("Saved screenshot after {} frames to {}",
+                         options.frames.unwrap_or(0),
+                         path);
+            }
+        }
+        return;
+    }
 
-    let mut gfx = Gfx::new(options.scale);
-    let audio_buffer = audio::open();
+    let rom = prefetch_rom(Path::new(rom_path));
 
-    let mapper: Box<Mapper+Send> = mapper::create_mapper(rom);
-    let mapper = Rc::new(RefCell::new(mapper));
-    let ppu = Ppu::new(Vram::new(mapper.clone()), Oam::new());
-    let input = Input::new();
-    let apu = Apu::new(audio_buffer);
-    let memmap = MemMap::new(ppu, input, mapper, apu);
-    let mut cpu = Cpu::new(memmap);
+    let mut gfx = create_gfx(&options);
+    let game = game_name(&options);
+    gfx.set_title(game.as_slice());
+    let audio_buffer = if options.audio { audio::open() } else { None };
 
-    // TODO: Add a flag to not reset for nestest.log
-    cpu.reset();
+    let rom = match rom.recv() {
+        Ok(rom) => rom,
+        Err(err) => {
+            println!("Can't load {}: {}", rom_path, err);
+            audio::close();
+            return;
+        }
+    };
+    println!("Loaded ROM:\n{}", rom.to_str());
+    match mapper::check_rom(&rom) {
+        Ok(()) => {}
+        Err(err) => {
+            println!("Can't run {}: {}", rom_path, err);
+            audio::close();
+            return;
+        }
+    }
 
-    let mut last_time = util::current_time_millis();
-    let mut frames = 0;
+    let mut netplay = match connect_netplay(&rom, &options) {
+        Ok(netplay) => netplay,
+        Err(err) => {
+            println!("Can't start netplay: {}", err);
+            audio::close();
+            return;
+        }
+    };
 
-    loop {
-        cpu.step();
+    // Movies and netplay start from a blank save, so that they play out the same everywhere.
+    let battery = options.movie_path.is_none() && netplay.is_none();
+    let mut cpu = match power_on(&rom, audio_buffer, &options) {
+        Ok(cpu) => cpu,
+        Err(err) => {
+            println!("Can't power on: {}", err);
+            audio::close();
+            return;
+        }
+    };
+    if battery {
+        load_battery(&mut cpu, &options);
+    }
+    match options.state_path {
+        None => {}
+        Some(ref path) => match load_state(&mut cpu, &Path::new(path.as_slice())) {
+            Ok(_) => {}
+            Err(err) => {
+                println!("Can't load the state: {}", err);
+                audio::close();
+                return;
+            }
+        },
+    }
 
-        let ppu_result = cpu.mem.ppu.step(cpu.cy);
-        if ppu_result.vblank_nmi {
-            cpu.nmi();
-        } else if ppu_result.scanline_irq {
-            cpu.irq();
+    let mut wav = match create_wav(&mut cpu, &options) {
+        Ok(wav) => wav,
+        Err(err) => {
+            println!("Can't write the audio: {}", err);
+            audio::close();
+            return;
+        }
+    };
+    let mut movie = match start_movie(&mut cpu, &options) {
+        Ok(movie) => movie,
+        Err(err) => {
+            println!("Can't start the movie: {}", err);
+            audio::close();
+            return;
+        }
+    };
+    let mut scroll_dump = match create_scroll_dump(&options) {
+        Ok(dump) => dump,
+        Err(err) => {
+            println!("Can't write the scroll positions: {}", err);
+            audio::close();
+            return;
         }
+    };
+    match netplay {
+        None => {}
+        Some(ref mut netplay) => netplay.start(&mut cpu.mem.input),
+    }
+
+    let mut stats = FrameStats::new();
+    let mut recorder: Option<Box<Encoder>> = None;
+    let mut paused = false;
+    let mut advancing = false;     // Run one frame, then pause again.
+    let mut snapshot = Snapshot::new();
+    let mut undo_state: Option<Vec<uint8_t>> = None;   // What the last state load replaced
+    let mut pacer = FramePacer::new();
+    let mut session = match options.compat_log_path {
+        None => None,
+        Some(_) => Some(Session::start(&rom, game.as_slice())),
+    };
 
-        cpu.mem.apu.step(cpu.cy);
+    loop {
+        if !paused || advancing {
+            let (pc, cycle) = (cpu.pc(), cpu.cy);
+            let new_frame = step(&mut cpu);
+            match take_watch_hit(&mut cpu, pc, cycle) {
+                None => {}
+                Some(message) => {
+                    // Pausing would put netplay out of step, so it just says so.
+                    println!("{}", message);
+                    if netplay.is_none() {
+                        if !paused {
+                            cpu.mem.apu.flush();
+                            gfx.set_title(format!("{} - Paused", game).as_slice());
+                        }
+                        paused = true;
+                        advancing = false;
+                        gfx.osd.paused = true;
+                    }
+                    gfx.osd.message(message);
+                }
+            }
+            if !new_frame {
+                continue;
+            }
 
-        if ppu_result.new_frame {
+            match movie {
+                None => {}
+                Some(ref mut movie) => {
+                    match movie.end_frame(&mut cpu) {
+                        movie::Running => {}
+                        movie::Finished => gfx.osd.message("Movie finished".to_string()),
+                        movie::Desynced(frame) => {
+                            gfx.osd.message(format!("Movie desynced at frame {}", frame));
+                        }
+                        movie::Failed(err) => gfx.osd.message(format!("Movie stopped: {}", err)),
+                    }
+                }
+            }
+            netplay_end_frame(&mut netplay, &mut cpu, &mut gfx);
+            match session {
+                None => {}
+                Some(ref mut session) => session.add_frame(),
+            }
+            match scroll_dump {
+                None => {}
+                Some(ref mut scroll_dump) => {
+                    scroll_dump.add_frame(cpu.mem.ppu.line_scrolls.as_slice());
+                }
+            }
+
+            gfx.tick();
+            let recorder_stopped = match recorder {
+                None => None,
+                Some(ref mut recorder) => {
+                    recorder.add_frame(&*cpu.mem.ppu.screen);
+                    recorder.stopped()
+                }
+            };
+            match recorder_stopped {
+                None => {}
+                Some(reason) => {
+                    stop_recording(recorder.take().unwrap(), &mut cpu, wav.is_some());
+                    gfx.osd.message(format!("Stopped recording: {}", reason));
+                }
+            }
+            if gfx.osd.sprite_zero.is_some() {
+                gfx.osd.sprite_zero = Some(cpu.mem.ppu.sprite_zero);
+            }
+            if options.run_ahead {
+                run_ahead(&mut cpu, &mut snapshot);
+            }
+            // The display is drawn on a copy, so that screenshots don't pick it up.
+            update_debug_hud(&mut gfx, &cpu);
+            let mut screen = cpu.mem.ppu.screen.clone();
+            gfx.composite(&mut *screen, cpu.mem.ppu.indices.as_slice());
+            if !paused {
+                stats.end_frame();
+                update_frame_rate(&mut gfx, game.as_slice(), &mut stats);
+            }
+            if advancing {
+                // Play just this frame's audio, so it can be heard a frame at a time.
+                cpu.mem.apu.flush();
+                advancing = false;
+            } else {
+                cpu.mem.apu.play_channels();
+            }
+            let samples = cpu.mem.apu.take_captured();
+            match recorder {
+                None => {}
+                Some(ref mut recorder) => recorder.add_audio(samples.as_slice()),
+            }
+            match wav {
+                None => {}
+                Some(ref mut wav) => wav.add_samples(samples.as_slice()),
+            }
+            if audio_buffer.is_none() && pacer.wait() {
+                gfx.osd.message("Resynced after a clock jump".to_string());
+            }
+            stats.start_frame();
+        } else {
+            // Keep the window alive without emulating anything. The status line is drawn on a
+            // copy of the screen so that it can still change.
             gfx.tick();
-            gfx.composite(&mut *cpu.mem.ppu.screen);
-            record_fps(&mut last_time, &mut frames);
-            cpu.mem.apu.play_channels();
+            update_debug_hud(&mut gfx, &cpu);
+            let mut screen = cpu.mem.ppu.screen.clone();
+            gfx.composite(&mut *screen, cpu.mem.ppu.indices.as_slice());
+            timer::sleep(Duration::milliseconds(16));
+            pacer.reset();
+        }
 
-            match cpu.mem.input.check_input() {
-                input::Continue => {}
-                input::Quit => break,
-                input::SaveState => {
-                    cpu.save(&mut File::create(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Saved state".to_string());
+        match cpu.mem.input.check_input() {
+            // These would put the consoles out of step.
+            input::LoadState | input::UndoLoad | input::PowerCycle | input::TogglePause |
+            input::AdvanceFrame | input::CycleDipSwitches | input::SwitchDiskSide
+                    if netplay.is_some() => {
+                gfx.osd.message("Not during netplay".to_string());
+            }
+            input::PowerCycle | input::CycleDipSwitches | input::SwitchDiskSide
+                    if movie.as_ref().map_or(false, |movie| movie.in_progress()) => {
+                gfx.osd.message("Not during a movie".to_string());
+            }
+            input::Continue => {}
+            input::Quit => break,
+            input::SaveState => {
+                // Saved to memory first, so that a full disk is an error rather than a panic.
+                let state = state::save_to_vec(&mut cpu);
+                match File::create(&Path::new("state.sav")).write(state.as_slice()) {
+                    Ok(()) => gfx.osd.message("Saved state".to_string()),
+                    Err(err) => {
+                        println!("Can't save the state: {}", err);
+                        gfx.osd.message("Can't save the state".to_string());
+                    }
+                }
+            }
+            input::LoadState | input::UndoLoad
+                    if movie.as_ref().map_or(false, |movie| movie.is_playing()) => {
+                gfx.osd.message("Not while playing a movie".to_string());
+            }
+            input::LoadState => {
+                // Keep what the load replaces, in case it was a mistake.
+                let before = state::save_to_vec(&mut cpu);
+                match load_state(&mut cpu, &Path::new("state.sav")) {
+                    Ok(state) => {
+                        cpu.mem.apu.state_loaded();
+                        match movie {
+                            None => {}
+                            Some(ref mut movie) => movie.state_loaded(state.as_slice()),
+                        }
+                        undo_state = Some(before);
+                        gfx.osd.message("Loaded state (U to undo)".to_string());
+                    }
+                    Err(err) => {
+                        println!("Can't load the state: {}", err);
+                        gfx.osd.message("Can't load the state".to_string());
+                    }
+                }
+            }
+            input::UndoLoad => {
+                match undo_state.take() {
+                    None => gfx.osd.message("No state load to undo".to_string()),
+                    Some(state) => {
+                        // Keep the state being undone, so that undoing again redoes the load.
+                        undo_state = Some(state::save_to_vec(&mut cpu));
+                        state::load(state.as_slice(), &mut cpu).unwrap();
+                        cpu.mem.apu.state_loaded();
+                        match movie {
+                            None => {}
+                            Some(ref mut movie) => movie.state_loaded(state.as_slice()),
+                        }
+                        gfx.osd.message("Undid the state load (U to redo)".to_string());
+                    }
+                }
+            }
+            input::PowerCycle => {
+                match power_on(&rom, audio_buffer, &options) {
+                    Ok(mut new_cpu) => {
+                        if battery {
+                            save_battery(&mut cpu, &options);
+                            load_battery(&mut new_cpu, &options);
+                        }
+                        new_cpu.mem.profiler = cpu.mem.profiler.take();
+                        new_cpu.mem.apu.muted = cpu.mem.apu.muted;
+                        new_cpu.mem.input.set_buttons(cpu.mem.input.buttons());
+                        new_cpu.mem.input.gamepad_locked = cpu.mem.input.gamepad_locked;
+                        cpu = new_cpu;
+                        if recorder.is_some() || wav.is_some() {
+                            cpu.mem.apu.start_capture();
+                        }
+                        gfx.osd.message("Power cycled".to_string());
+                    }
+                    Err(err) => gfx.osd.message(format!("Can't power cycle: {}", err)),
                 }
-                input::LoadState => {
-                    cpu.load(&mut File::open(&Path::new("state.sav")).unwrap());
-                    gfx.status_line.set("Loaded state".to_string());
+            }
+            input::TogglePause => {
+                paused = !paused;
+                if paused {
+                    // Play out the frames we've shown, so that advancing starts from silence.
+                    cpu.mem.apu.flush();
+                    gfx.set_title(format!("{} - Paused", game).as_slice());
+                } else {
+                    // Don't count the time spent paused.
+                    stats = FrameStats::new();
+                }
+                gfx.osd.paused = paused;
+                gfx.osd.message((if paused { "Paused" } else { "Resumed" }).to_string());
+            }
+            input::AdvanceFrame => {
+                if !paused {
+                    cpu.mem.apu.flush();
+                    gfx.set_title(format!("{} - Paused", game).as_slice());
+                }
+                paused = true;
+                advancing = true;
+                gfx.osd.paused = true;
+            }
+            input::CycleDipSwitches => {
+                let mut mapper = cpu.mem.mapper.borrow_mut();
+                let count = mapper.dip_switch_count();
+                if count == 0 {
+                    gfx.osd.message("This cartridge has no DIP switches".to_string());
+                } else {
+                    let val = (mapper.dip_switches() + 1) & ((1 << count) - 1);
+                    mapper.set_dip_switches(val);
+                    gfx.osd.message(format!("DIP switches: {}",
+                                            dip_switch_string(val, count)));
+                }
+            }
+            input::SwitchDiskSide => {
+                let mut mapper = cpu.mem.mapper.borrow_mut();
+                let count = mapper.disk_side_count();
+                if count == 0 {
+                    gfx.osd.message("There is no disk drive".to_string());
+                } else {
+                    let side = match mapper.disk_side() {
+                        None => 0,
+                        Some(side) => (side + 1) % count,
+                    };
+                    mapper.insert_disk_side(side);
+                    gfx.osd.message(format!("Inserting disk {} side {}",
+                                            side / 2 + 1,
+                                            if side % 2 == 0 { "A" } else { "B" }));
+                }
+            }
+            input::ToggleFrameRate => {
+                gfx.osd.show_frame_rate = !gfx.osd.show_frame_rate;
+            }
+            input::ToggleSpriteZero => {
+                gfx.osd.sprite_zero = match gfx.osd.sprite_zero {
+                    Some(_) => None,
+                    None => Some(cpu.mem.ppu.sprite_zero),
+                };
+            }
+            input::ToggleHelp => {
+                gfx.osd.help = match gfx.osd.help {
+                    Some(_) => None,
+                    None => {
+                        let input = &cpu.mem.input;
+                        Some(input.bindings.help_lines(input.vs_system))
+                    }
+                };
+            }
+            input::ToggleRecording => {
+                // When writing a WAV file, audio is already being captured, and the recording
+                // shares it.
+                match recorder.take() {
+                    Some(recorder) => {
+                        stop_recording(recorder, &mut cpu, wav.is_some());
+                        gfx.osd.message("Stopped recording".to_string());
+                    }
+                    None => {
+                        let (encoder, path) = match options.ffmpeg_output {
+                            None => {
+                                let path = "recording.avi";
+                                let encoder = Recorder::create(&Path::new(path));
+                                (encoder.map(|encoder| Box::new(encoder) as Box<Encoder>), path)
+                            }
+                            Some(ref path) => {
+                                let encoder = FfmpegEncoder::spawn(path.as_slice(),
+                                                                   options.ffmpeg_args.as_slice());
+                                (encoder.map(|encoder| Box::new(encoder) as Box<Encoder>),
+                                 path.as_slice())
+                            }
+                        };
+                        match encoder {
+                            Ok(encoder) => {
+                                recorder = Some(encoder);
+                                if wav.is_none() {
+                                    cpu.mem.apu.start_capture();
+                                }
+                                gfx.osd.message(format!("Recording to {}", path));
+                            }
+                            Err(err) => gfx.osd.message(err),
+                        }
+                    }
                 }
             }
+            input::ToggleChannel(channel) => {
+                let muted = !cpu.mem.apu.muted[channel];
+                cpu.mem.apu.muted[channel] = muted;
+                gfx.osd.message(format!("{} {}",
+                                        if muted { "Muted" } else { "Unmuted" },
+                                        apu::CHANNEL_NAMES[channel]));
+            }
+            input::TakeScreenshot => {
+                // With run-ahead, the screen is a frame ahead of the console.
+                let frame = cpu.mem.ppu.frame + if options.run_ahead { 1 } else { 0 };
+                let path = Path::new(screenshot_name(&options, frame));
+                match save_screenshot(&path, &cpu, frame, &rom, &options) {
+                    Ok(()) => gfx.osd.message(format!("Saved {}", path.display())),
+                    Err(err) => {
+                        gfx.osd.message(format!("Couldn't save {}: {}", path.display(), err));
+                    }
+                }
+            }
+            input::FlagProblem => {
+                match session {
+                    None => {
+                        gfx.osd.message("Start with --compat-log to flag problems".to_string());
+                    }
+                    Some(ref mut session) => {
+                        let flags = session.flag();
+                        gfx.osd.message(format!("Flagged a problem ({} this session)", flags));
+                    }
+                }
+            }
+        }
+    }
+
+    match recorder {
+        None => {}
+        Some(mut recorder) => recorder.finish(),
+    }
+    match wav {
+        None => {}
+        Some(wav) => wav.finish(),
+    }
+    write_mem_profile(&cpu, &options);
+    if battery {
+        save_battery(&mut cpu, &options);
+    }
+    match (&session, &options.compat_log_path) {
+        (&Some(ref session), &Some(ref path)) => {
+            match session.finish(&Path::new(path.as_slice())) {
+                Ok(()) => {}
+                Err(err) => println!("Couldn't write to the compatibility log {}: {}", path, err),
+            }
         }
+        _ => {}
     }
 
     audio::close();
@@ -4,36 +4,389 @@
 // Author: Patrick Walton
 //
 
+use eeprom::{Eeprom, X24C01, X24C02};
+use fds::Fds;
+use ppu::CYCLES_PER_SCANLINE;
+use log;
 use rom::Rom;
-use util;
+use rtc::Rtc;
+use util::Save;
+use vrc7::Vrc7;
 
 use libc::{uint8_t, uint16_t};
+use std::cmp;
 
-#[derive(PartialEq, Eq)]
-pub enum MapperResult {
-    Continue,
-    Irq,
-}
-
-pub trait Mapper {
+// Savestates include the mapper's registers and RAM, but not the ROM, which comes from the file.
+pub trait Mapper: Save {
     fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t;
     fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t);
     fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t;
     fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t);
-    fn next_scanline(&mut self) -> MapperResult;
+    fn mirroring(&self) -> Mirroring;
+
+    // Called at the start of every scanline, with its number, and at the end. Mappers that count
+    // scanlines by watching the PPU's fetches need to know where the frame begins.
+    fn start_scanline(&mut self, _: uint16_t) {}
+    fn next_scanline(&mut self) {}
+
+    // The MMC3 and its relatives count scanlines by watching PPU address line A12 rise as the PPU
+    // moves from background to sprite patterns. Boards that return true from `watches_a12` are
+    // told of each rise, with the scanline and dot it came on, once A12 has been low for long
+    // enough to get past the MMC3's filter.
+    fn watches_a12(&self) -> bool { false }
+    fn a12_rise(&mut self, _: uint16_t, _: uint16_t) {}
+
+    // The cartridge's IRQ line. It stays asserted until the game acknowledges the IRQ in whatever
+    // way the board expects.
+    fn irq_pending(&self) -> bool { false }
+
+    // A pattern fetch made while rendering, as opposed to one through PPUDATA. Some mappers use
+    // separate CHR banks for sprites and background.
+    fn chr_fetch(&mut self, addr: uint16_t, _: bool) -> uint8_t { self.chr_loadb(addr) }
+
+    // Whether the PPU may cache pattern data between CPU writes to the cartridge. Mappers that
+    // switch CHR banks on their own, in response to the PPU's fetches, must return false.
+    fn cacheable_chr(&self) -> bool { true }
+
+    // Decides where each nametable comes from. The PPU asks again whenever the CPU writes to the
+    // cartridge. Most boards only switch between the arrangements `mirroring()` describes, and
+    // four-screen boards have a nametable for every slot, so there's nothing to arrange.
+    fn arrange_nametables(&self, map: &mut NametableMap) {
+        if !map.four_screen {
+            *map = NametableMap::from_mirroring(self.mirroring());
+        }
+    }
+    // The cartridge's own nametable memory, for nametables arranged as `Cartridge` pages, with the
+    // offset into the 1K page.
+    fn nametable_loadb(&mut self, _: uint, _: uint16_t) -> uint8_t { 0 }
+    fn nametable_storeb(&mut self, _: uint, _: uint16_t, _: uint8_t) {}
+    // Reads CHR-ROM by its offset from the start, for nametables arranged as `ChrRom` banks.
+    fn chr_rom_loadb(&mut self, _: uint) -> uint8_t { 0 }
+    // Sees each byte the PPU reads from a nametable, when the arrangement is watched, and returns
+    // what the PPU should see instead.
+    fn nametable_fetched(&mut self, _: uint16_t, val: uint8_t) -> uint8_t { val }
+
+    // Registers and memory in the expansion area, $4020-$5FFF. Reads return None where the
+    // cartridge doesn't drive the bus, leaving it open.
+    fn expansion_loadb(&mut self, _: uint16_t) -> Option<uint8_t> { None }
+    fn expansion_storeb(&mut self, _: uint16_t, _: uint8_t) {}
+
+    // Sees every CPU write to $2000-$2007, as a mapper listening on the CPU bus would.
+    fn ppu_register_write(&mut self, _: uint16_t, _: uint8_t) {}
+
+    // Whether a CPU write to the address can switch CHR banks or rearrange the nametables, which
+    // the PPU has to find out about. Most boards only have registers from $8000 up, and writes
+    // below that, to PRG-RAM, are much too frequent to throw the PPU's caches away for.
+    fn is_banking_register(&self, addr: uint16_t) -> bool { addr >= 0x8000 }
+
+    // Sees every CPU write to $4016, the controller strobe. The VS. System's bank switching hangs
+    // off the same latch.
+    fn controller_write(&mut self, _: uint8_t) {}
+
+    // Expansion audio, as found on some Famicom cartridges. The APU clocks the cartridge's sound
+    // hardware once per CPU cycle and mixes in its output, where 1.0 is as loud as one of the
+    // APU's pulse channels at full volume.
+    fn clock_audio(&mut self) {}
+    fn audio_output(&self) -> f32 { 0.0 }
+
+    // Multicarts, competition carts and VS. System games may have DIP switches on the board.
+    // Most boards don't.
+    fn dip_switch_count(&self) -> uint { 0 }
+    fn dip_switches(&self) -> uint8_t { 0 }
+    fn set_dip_switches(&mut self, _: uint8_t) {}
+
+    // The Famicom Disk System has a disk drive. Inserting a side ejects the disk first and leaves
+    // the drive empty for a moment, so that the BIOS notices the change.
+    fn disk_side_count(&self) -> uint { 0 }
+    fn disk_side(&self) -> Option<uint> { None }
+    fn insert_disk_side(&mut self, _: uint) {}
+
+    // Memory that keeps its contents with the power off, whether battery-backed RAM or an
+    // EEPROM. The main loop fills it from a file named after the ROM at power on and writes it
+    // back on the way out.
+    fn battery(&mut self) -> Option<&mut [uint8_t]> { None }
+
+    // The real-time clock on boards that have one. It's kept beside the battery file, and its
+    // source set from the command line at power on.
+    fn rtc(&mut self) -> Option<&mut Rtc> { None }
+
+    // Describes the banks currently switched in, a line or two for the debug HUD. Boards with
+    // nothing to switch, or that haven't been taught to say, return nothing.
+    fn describe_banks(&self) -> Vec<String> { Vec::new() }
 }
 
-pub fn create_mapper(rom: Box<Rom>) -> Box<Mapper+Send> {
-    match rom.header.ines_mapper() {
+// The ROM should have passed `check_rom` first. Fails if a board needs a file that isn't there,
+// as the disk system needs its BIOS.
+pub fn create_mapper(rom: Box<Rom>) -> Result<Box<Mapper+Send>, String> {
+    Ok(match rom.header.mapper_number() {
         0 => {
             Box::new(Nrom {
                 rom: rom,
             }) as Box<Mapper+Send>
         },
         1 => Box::new(SxRom::new(rom)) as Box<Mapper+Send>,
+        2 => Box::new(UxRom::new(rom, false)) as Box<Mapper+Send>,
+        3 => Box::new(CnRom::new(rom, false)) as Box<Mapper+Send>,
         4 => Box::new(TxRom::new(rom)) as Box<Mapper+Send>,
+        5 => Box::new(ExRom::new(rom)) as Box<Mapper+Send>,
+        7 => Box::new(AxRom::new(rom)) as Box<Mapper+Send>,
+        9 => Box::new(PxRom::new(rom)) as Box<Mapper+Send>,
+        10 => Box::new(FxRom::new(rom)) as Box<Mapper+Send>,
+        16 => Box::new(BandaiFcg::new(rom)) as Box<Mapper+Send>,
+        20 => Box::new(try!(Fds::new(rom))) as Box<Mapper+Send>,
+        24 => Box::new(Vrc6::new(rom, false)) as Box<Mapper+Send>,
+        26 => Box::new(Vrc6::new(rom, true)) as Box<Mapper+Send>,
+        33 => Box::new(TaitoTc0190::new(rom, false)) as Box<Mapper+Send>,
+        34 => Box::new(Bnrom::new(rom)) as Box<Mapper+Send>,
+        48 => Box::new(TaitoTc0190::new(rom, true)) as Box<Mapper+Send>,
+        64 => Box::new(Rambo1::new(rom)) as Box<Mapper+Send>,
+        68 => Box::new(Sunsoft4::new(rom)) as Box<Mapper+Send>,
+        73 => Box::new(Vrc3::new(rom)) as Box<Mapper+Send>,
+        75 => Box::new(Vrc1::new(rom)) as Box<Mapper+Send>,
+        85 => Box::new(Vrc7::new(rom)) as Box<Mapper+Send>,
+        99 => Box::new(VsUnisystem::new(rom)) as Box<Mapper+Send>,
+        105 => Box::new(Nwc::new(rom)) as Box<Mapper+Send>,
+        159 => Box::new(BandaiFcg::new(rom)) as Box<Mapper+Send>,
+        180 => Box::new(UxRom::new(rom, true)) as Box<Mapper+Send>,
+        185 => Box::new(CnRom::new(rom, true)) as Box<Mapper+Send>,
+        202 => Box::new(Multicart150In1::new(rom)) as Box<Mapper+Send>,
         _ => panic!("unsupported mapper")
+    })
+}
+
+pub enum Mirroring {
+    OneScreenLower,
+    OneScreenUpper,
+    Vertical,
+    Horizontal,
+}
+
+//
+// Nametable arrangement
+//
+// The PPU has room for four 1K nametables but only 2K of nametable RAM (CIRAM) of its own, and
+// the cartridge decides what each nametable shows. Usually it's one of the two pages of CIRAM,
+// as the mirroring says, but boards can also supply their own memory, as four-screen boards and
+// the MMC5 do, or point a nametable at CHR-ROM, as the Sunsoft-4 does.
+//
+// Four-screen boards, which the iNES header marks, carry 4K of nametable RAM, one page for each
+// nametable. Nothing on the board ever rearranges it, so the PPU keeps it, rather than every
+// mapper that might be built that way.
+//
+
+/// What one nametable shows.
+pub enum NametableSource {
+    Ciram(uint),        // A 1K page of the console's nametable RAM
+    Cartridge(uint),    // A page of the cartridge's memory, numbered however the mapper likes
+    ChrRom(uint),       // A 1K bank of CHR-ROM, which ignores writes
+    FourScreen(uint),   // A 1K page of a four-screen board's nametable RAM
+}
+
+/// What each of the four nametables shows. The PPU keeps one, and the mapper rearranges it.
+pub struct NametableMap {
+    pub slots: [NametableSource; 4],
+    // Whether the mapper sees every nametable fetch, for the MMC5's extended attributes.
+    pub watched: bool,
+    // Whether the board has four-screen nametable RAM.
+    pub four_screen: bool,
+}
+
+impl NametableMap {
+    pub fn from_mirroring(mirroring: Mirroring) -> NametableMap {
+        let pages = match mirroring {
+            OneScreenLower => [ 0, 0, 0, 0 ],
+            OneScreenUpper => [ 1, 1, 1, 1 ],
+            Vertical       => [ 0, 1, 0, 1 ],
+            Horizontal     => [ 0, 0, 1, 1 ],
+        };
+        NametableMap {
+            slots: [ Ciram(pages[0]), Ciram(pages[1]), Ciram(pages[2]), Ciram(pages[3]) ],
+            watched: false,
+            four_screen: false,
+        }
+    }
+
+    pub fn four_screen() -> NametableMap {
+        NametableMap {
+            slots: [ FourScreen(0), FourScreen(1), FourScreen(2), FourScreen(3) ],
+            watched: false,
+            four_screen: true,
+        }
+    }
+}
+
+//
+// Bus conflicts
+//
+// On discrete-logic boards the bank register sits on the data bus alongside the PRG-ROM, so a
+// write to $8000-$FFFF sees both the CPU and the ROM driving the bus. The result is the logical
+// AND of the written value and the ROM byte at that address. Some games depend on this; others
+// were made for boards with the ROM's output disabled during writes and break if it's emulated.
+//
+// See http://wiki.nesdev.com/w/index.php/Bus_conflict
+//
+
+// Decides whether a UxROM, CNROM, or AxROM board has bus conflicts. For these mappers, NES 2.0
+// submapper 1 means no conflicts and submapper 2 means AND-type conflicts. Failing that, we fall
+// back to what most boards of the type do.
+fn has_bus_conflicts(rom: &Rom, default: bool) -> bool {
+    if rom.header.is_nes2() {
+        match rom.header.submapper() {
+            1 => return false,
+            2 => return true,
+            _ => {}
+        }
+    }
+    default
+}
+
+//
+// Bank switching
+//
+// Games pick banks by writing numbers to the mapper's registers, and nothing stops a buggy game
+// or a bad dump from picking one past the end of the ROM. On real boards the unused high bits of
+// the bank number aren't connected to anything, so the ROM just repeats through the bank numbers.
+// We do the same by wrapping the bank number around the number of banks.
+//
+
+// Returns the number of banks of the given size in the data.
+fn bank_count(data: &[uint8_t], size: uint) -> uint {
+    data.len() / size
+}
+
+// The last bank, which many boards fix at the top of the address space.
+pub fn last_bank(data: &[uint8_t], size: uint) -> uint {
+    cmp::max(bank_count(data, size), 1) - 1
+}
+
+// Reads a byte from a bank. The size must be a power of two.
+pub fn bank_loadb(data: &[uint8_t], bank: uint, size: uint, addr: uint16_t) -> uint8_t {
+    let count = bank_count(data, size);
+    if count == 0 {
+        return 0;
+    }
+    data[(bank % count) * size + (addr as uint & (size - 1))]
+}
+
+//
+// ROM size checks
+//
+// Bank wrapping keeps a bad dump from crashing the emulator, but it also hides the problem. So on
+// load we check the sizes in the header against what the board can actually address, and refuse
+// ROMs that no real cartridge could have held.
+//
+
+struct BoardLimits {
+    mapper: uint8_t,
+    name: &'static str,
+    prg_bank: uint,     // PRG-ROM bank size in bytes
+    prg_min: uint,
+    prg_max: uint,
+    chr_bank: uint,     // CHR-ROM bank size in bytes, or 0 for boards with CHR-RAM
+    chr_max: uint,
+}
+
+static BOARD_LIMITS: [BoardLimits; 26] = [
+    BoardLimits { mapper: 0, name: "NROM", prg_bank: 16384, prg_min: 16384, prg_max: 32768,
+                  chr_bank: 8192, chr_max: 8192 },
+    BoardLimits { mapper: 1, name: "SxROM", prg_bank: 16384, prg_min: 32768, prg_max: 524288,
+                  chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 2, name: "UxROM", prg_bank: 16384, prg_min: 32768, prg_max: 262144,
+                  chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 3, name: "CNROM", prg_bank: 16384, prg_min: 16384, prg_max: 32768,
+                  chr_bank: 8192, chr_max: 32768 },
+    BoardLimits { mapper: 4, name: "TxROM", prg_bank: 8192, prg_min: 32768, prg_max: 524288,
+                  chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 5, name: "ExROM", prg_bank: 8192, prg_min: 16384, prg_max: 1048576,
+                  chr_bank: 1024, chr_max: 1048576 },
+    BoardLimits { mapper: 7, name: "AxROM", prg_bank: 32768, prg_min: 32768, prg_max: 262144,
+                  chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 9, name: "PxROM", prg_bank: 8192, prg_min: 32768, prg_max: 131072,
+                  chr_bank: 4096, chr_max: 131072 },
+    BoardLimits { mapper: 10, name: "FxROM", prg_bank: 16384, prg_min: 32768, prg_max: 262144,
+                  chr_bank: 4096, chr_max: 131072 },
+    BoardLimits { mapper: 16, name: "Bandai FCG", prg_bank: 16384, prg_min: 32768,
+                  prg_max: 262144, chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 24, name: "VRC6a", prg_bank: 8192, prg_min: 32768, prg_max: 262144,
+                  chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 26, name: "VRC6b", prg_bank: 8192, prg_min: 32768, prg_max: 262144,
+                  chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 33, name: "Taito TC0190", prg_bank: 8192, prg_min: 32768,
+                  prg_max: 524288, chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 34, name: "BNROM/NINA-001", prg_bank: 32768, prg_min: 32768,
+                  prg_max: 8388608, chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 48, name: "Taito TC0690", prg_bank: 8192, prg_min: 32768,
+                  prg_max: 524288, chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 64, name: "RAMBO-1", prg_bank: 8192, prg_min: 32768,
+                  prg_max: 262144, chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 68, name: "Sunsoft-4", prg_bank: 16384, prg_min: 32768,
+                  prg_max: 262144, chr_bank: 2048, chr_max: 262144 },
+    BoardLimits { mapper: 73, name: "VRC3", prg_bank: 16384, prg_min: 32768,
+                  prg_max: 131072, chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 75, name: "VRC1", prg_bank: 8192, prg_min: 32768,
+                  prg_max: 131072, chr_bank: 4096, chr_max: 131072 },
+    BoardLimits { mapper: 85, name: "VRC7", prg_bank: 8192, prg_min: 32768,
+                  prg_max: 524288, chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 99, name: "VS. UniSystem", prg_bank: 8192, prg_min: 16384,
+                  prg_max: 40960, chr_bank: 8192, chr_max: 16384 },
+    BoardLimits { mapper: 105, name: "NES-EVENT", prg_bank: 16384, prg_min: 262144,
+                  prg_max: 262144, chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 159, name: "Bandai LZ93D50 with 24C01", prg_bank: 16384,
+                  prg_min: 32768, prg_max: 262144, chr_bank: 1024, chr_max: 262144 },
+    BoardLimits { mapper: 180, name: "UNROM (Crazy Climber)", prg_bank: 16384, prg_min: 32768,
+                  prg_max: 262144, chr_bank: 0, chr_max: 0 },
+    BoardLimits { mapper: 185, name: "CNROM with CHR disable", prg_bank: 16384, prg_min: 16384,
+                  prg_max: 32768, chr_bank: 8192, chr_max: 8192 },
+    BoardLimits { mapper: 202, name: "150-in-1", prg_bank: 16384, prg_min: 16384,
+                  prg_max: 131072, chr_bank: 8192, chr_max: 65536 },
+];
+
+/// The mapper numbers `check_rom` accepts, in order, with the name of the board each one stands
+/// for. Disk images don't have a mapper and aren't included.
+pub fn supported_mappers() -> Vec<(uint8_t, &'static str)> {
+    BOARD_LIMITS.iter().map(|limits| (limits.mapper, limits.name)).collect()
+}
+
+fn check_size(what: &str, len: uint, bank: uint, min: uint, max: uint) -> Result<(), String> {
+    if len < min || len > max || len % bank != 0 {
+        return Err(format!("{} is {}K, but the board takes {}K to {}K in {}K banks",
+                           what,
+                           len / 1024,
+                           min / 1024,
+                           max / 1024,
+                           bank / 1024));
+    }
+    Ok(())
+}
+
+/// Checks that the ROM's mapper is supported and that its PRG-ROM and CHR-ROM sizes are ones the
+/// board could have. The error says what's wrong, for showing to the user.
+pub fn check_rom(rom: &Rom) -> Result<(), String> {
+    let number = rom.header.mapper_number();
+    if rom.is_disk() {
+        if rom.disk_sides.len() == 0 {
+            return Err("the disk image has no disk sides".to_string());
+        }
+        return Ok(());
     }
+
+    let limits = match BOARD_LIMITS.iter().find(|limits| limits.mapper == number) {
+        None => return Err(format!("mapper {} isn't supported", number as uint)),
+        Some(limits) => limits,
+    };
+    let result = check_size("PRG-ROM",
+                            rom.prg.len(),
+                            limits.prg_bank,
+                            limits.prg_min,
+                            limits.prg_max).and_then(|()| {
+        // MMC5 boards come with either CHR-ROM or CHR-RAM.
+        if limits.chr_bank == 0 || (number == 5 && rom.chr.len() == 0) {
+            Ok(())
+        } else {
+            check_size("CHR-ROM", rom.chr.len(), limits.chr_bank, limits.chr_bank, limits.chr_max)
+        }
+    });
+    result.map_err(|err| format!("mapper {} ({}): {}", number as uint, limits.name, err))
 }
 
 //
@@ -47,43 +400,194 @@ pub struct Nrom {
     pub rom: Box<Rom>,
 }
 
+impl Save for Nrom {
+    fn save(&mut self, _: &mut Writer) {}
+    fn load(&mut self, _: &mut Reader) {}
+}
+
 impl Mapper for Nrom {
     fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
         if addr < 0x8000 {
             0u8
-        } else if self.rom.prg.len() > 16384 {
-            self.rom.prg[addr as uint & 0x7fff]
         } else {
-            self.rom.prg[addr as uint & 0x3fff]
+            bank_loadb(self.rom.prg.as_slice(), (addr as uint >> 14) & 1, 16384, addr)
         }
     }
     fn prg_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to PRG-ROM.
-    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t { self.rom.chr[addr as uint] }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        bank_loadb(self.rom.chr.as_slice(), 0, 8192, addr)
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+}
+
+//
+// Mapper 2 (UxROM) and mapper 180
+//
+// Mapper 180 is the board Crazy Climber came on, which turns UxROM around: the first bank is fixed
+// at $8000 and the switchable one is at $C000.
+//
+// See http://wiki.nesdev.com/w/index.php/UxROM
+// and http://wiki.nesdev.com/w/index.php/INES_Mapper_180
+//
+
+pub struct UxRom {
+    rom: Box<Rom>,
+    prg_bank: uint8_t,   // The switchable 16K bank; the other is fixed.
+    bus_conflicts: bool,
+    fixed_first_bank: bool,
+    chr_ram: Box<[uint8_t; 8192]>,
+}
+
+save_struct!(UxRom { prg_bank, chr_ram });
+
+impl UxRom {
+    fn new(rom: Box<Rom>, fixed_first_bank: bool) -> UxRom {
+        let bus_conflicts = has_bus_conflicts(&*rom, true);
+        UxRom {
+            rom: rom,
+            prg_bank: 0,
+            bus_conflicts: bus_conflicts,
+            fixed_first_bank: fixed_first_bank,
+            chr_ram: Box::new([ 0; 8192 ]),
+        }
+    }
+}
+
+impl UxRom {
+    // The 16K banks at $8000 and $C000.
+    fn prg_banks(&self) -> [uint; 2] {
+        if self.fixed_first_bank {
+            [ 0, self.prg_bank as uint ]
+        } else {
+            [ self.prg_bank as uint, last_bank(self.rom.prg.as_slice(), 16384) ]
+        }
+    }
+}
+
+impl Mapper for UxRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            return 0u8;
+        }
+        let bank = self.prg_banks()[(addr as uint >> 14) & 1];
+        bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x8000 {
+            return;
+        }
+        let val = if self.bus_conflicts { val & self.prg_loadb(addr) } else { val };
+        self.prg_bank = val & 0x0f;
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+
+    fn describe_banks(&self) -> Vec<String> {
+        let banks = self.prg_banks();
+        vec![ format!("PRG 16K: {} {}", banks[0], banks[1]) ]
+    }
+}
+
+//
+// Mapper 3 (CNROM) and mapper 185
+//
+// Mapper 185 is CNROM used as copy protection: the register's diodes only connect the CHR-ROM
+// when the game writes the right value, and a game checks that it can't read CHR otherwise. NES
+// 2.0 submappers 4-7 give the value. Without one, we do as other emulators do and treat any
+// value with either of the low bits set, except $13, as the right one.
+//
+// See http://wiki.nesdev.com/w/index.php/CNROM
+// and http://wiki.nesdev.com/w/index.php/INES_Mapper_185
+//
+
+pub struct CnRom {
+    rom: Box<Rom>,
+    chr_bank: uint8_t,   // 8K CHR-ROM bank
+    bus_conflicts: bool,
+    chr_protected: bool,
+    chr_enabled: bool,
+}
+
+save_struct!(CnRom { chr_bank, chr_enabled });
+
+impl CnRom {
+    fn new(rom: Box<Rom>, chr_protected: bool) -> CnRom {
+        let bus_conflicts = has_bus_conflicts(&*rom, true);
+        CnRom {
+            rom: rom,
+            chr_bank: 0,
+            bus_conflicts: bus_conflicts,
+            chr_protected: chr_protected,
+            chr_enabled: !chr_protected,
+        }
+    }
+
+    fn chr_key_matches(&self, val: uint8_t) -> bool {
+        match self.rom.header.submapper() {
+            submapper @ 4 ... 7 if self.rom.header.is_nes2() => (val & 3) == submapper - 4,
+            _ => (val & 3) != 0 && val != 0x13,
+        }
+    }
+}
+
+impl Mapper for CnRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            0u8
+        } else {
+            bank_loadb(self.rom.prg.as_slice(), (addr as uint >> 14) & 1, 16384, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x8000 {
+            return;
+        }
+        let val = if self.bus_conflicts { val & self.prg_loadb(addr) } else { val };
+        if self.chr_protected {
+            self.chr_enabled = self.chr_key_matches(val);
+        } else {
+            self.chr_bank = val & 0x03;
+        }
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        // With the CHR-ROM disconnected, the PPU's data bus floats high.
+        if !self.chr_enabled {
+            return 0xff;
+        }
+        bank_loadb(self.rom.chr.as_slice(), self.chr_bank as uint, 8192, addr)
+    }
     fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
-    fn next_scanline(&mut self) -> MapperResult { Continue }
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+
+    fn describe_banks(&self) -> Vec<String> {
+        if self.chr_enabled {
+            vec![ format!("CHR 8K: {}", self.chr_bank) ]
+        } else {
+            vec![ "CHR: disconnected".to_string() ]
+        }
+    }
 }
 
 //
 // Mapper 1 (SxROM/MMC1)
 //
+// 8K of PRG-RAM at $6000, which is always enabled; battery-backed on boards that say so.
+//
 // See http://wiki.nesdev.com/w/index.php/Nintendo_MMC1
 //
 
 struct SxCtrl{ val: uint8_t }
 
+save_struct!(SxCtrl { val });
+
 impl Deref<uint8_t> for SxCtrl {
     fn deref(&self) -> &uint8_t {
         &self.val
     }
 }
 
-pub enum Mirroring {
-    OneScreenLower,
-    OneScreenUpper,
-    Vertical,
-    Horizontal,
-}
-
 enum SxPrgBankMode {
     Switch32K,      // Switch 32K at $8000, ignore low bit
     FixFirstBank,   // Fix first bank at $8000, switch 16K bank at $C000
@@ -99,6 +603,15 @@ impl SxCtrl {
             _ => panic!("can't happen")
         }
     }
+    fn mirroring(self) -> Mirroring {
+        match *self & 3 {
+            0 => OneScreenLower,
+            1 => OneScreenUpper,
+            2 => Vertical,
+            3 => Horizontal,
+            _ => panic!("can't happen")
+        }
+    }
 }
 
 struct SxRegs {
@@ -106,100 +619,129 @@ struct SxRegs {
     chr_bank_0: uint8_t, // $A000-$BFFF
     chr_bank_1: uint8_t, // $C000-$DFFF
     prg_bank: uint8_t,   // $E000-$FFFF
+    // The internal accumulator.
+    accum: uint8_t,
+    // The write count. At the 5th write, we update the register.
+    write_count: uint8_t,
+}
+
+save_struct!(SxRegs { ctrl, chr_bank_0, chr_bank_1, prg_bank, accum, write_count });
+
+impl SxRegs {
+    fn new() -> SxRegs {
+        SxRegs {
+            ctrl: SxCtrl {
+                val: 3 << 2,
+            },
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+            accum: 0,
+            write_count: 0,
+        }
+    }
+
+    // Handles a write to $8000-$FFFF through the serial port. Returns true if this completed a
+    // write to one of the internal registers.
+    fn storeb(&mut self, addr: uint16_t, val: uint8_t) -> bool {
+        // Check the reset flag.
+        if (val & 0x80) != 0 {
+            self.write_count = 0;
+            self.accum = 0;
+            self.ctrl = SxCtrl{val: *self.ctrl | (3 << 2)};
+            return false;
+        }
+
+        // Write the lowest bit of the value into the right location of the accumulator.
+        self.accum = self.accum | ((val & 1) << (self.write_count as uint));
+
+        self.write_count += 1;
+        if self.write_count < 5 {
+            return false;
+        }
+        self.write_count = 0;
+
+        // Write to the right internal register.
+        if addr <= 0x9fff {
+            self.ctrl = SxCtrl{val: self.accum};
+        } else if addr <= 0xbfff {
+            self.chr_bank_0 = self.accum;
+        } else if addr <= 0xdfff {
+            self.chr_bank_1 = self.accum;
+        } else {
+            self.prg_bank = self.accum;
+        }
+
+        self.accum = 0;
+        true
+    }
 }
 
 pub struct SxRom {
     rom: Box<Rom>,
     regs: SxRegs,
-    // The internal accumulator.
-    accum: uint8_t,
-    // The write count. At the 5th write, we update the register.
-    write_count: uint8_t,
-    //prg_ram: Box<[uint8_t, ..8192]>,
+    prg_ram: Box<[uint8_t; 8192]>,
     chr_ram: Box<[uint8_t; 8192]>,
 }
 
+save_struct!(SxRom { regs, prg_ram, chr_ram });
+
 impl SxRom {
     fn new(rom: Box<Rom>) -> SxRom {
         SxRom {
             rom: rom,
-            regs: SxRegs {
-                ctrl: SxCtrl {
-                    val: 3 << 2,
-                },
-                chr_bank_0: 0,
-                chr_bank_1: 0,
-                prg_bank: 0,
-            },
-            accum: 0,
-            write_count: 0,
-            //prg_ram: box() ([ 0, ..8192 ]),
+            regs: SxRegs::new(),
+            prg_ram: Box::new([ 0; 8192 ]),
             chr_ram: Box::new([ 0; 8192 ]),
         }
     }
+
+    // The 16K banks at $8000 and $C000.
+    fn prg_banks(&self) -> [uint; 2] {
+        let bank = self.regs.prg_bank as uint;
+        match self.regs.ctrl.prg_rom_mode() {
+            Switch32K => [ bank & 0xfe, (bank & 0xfe) | 1 ],
+            FixFirstBank => [ 0, bank ],
+            FixLastBank => [ bank, last_bank(self.rom.prg.as_slice(), 16384) ],
+        }
+    }
 }
 
 impl Mapper for SxRom {
     fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
-        if addr < 0x8000 {
-            0u8
-        } else if addr < 0xc000 {
-            let bank = match self.regs.ctrl.prg_rom_mode() {
-                Switch32K => self.regs.prg_bank & 0xfe,
-                FixFirstBank => 0,
-                FixLastBank => self.regs.prg_bank,
-            };
-            self.rom.prg[(bank as uint * 16384) | ((addr & 0x3fff) as uint)]
-        } else {
-            let bank = match self.regs.ctrl.prg_rom_mode() {
-                Switch32K => (self.regs.prg_bank & 0xfe) | 1,
-                FixFirstBank => self.regs.prg_bank,
-                FixLastBank => (*self.rom).header.prg_rom_size - 1,
-            };
-            self.rom.prg[(bank as uint * 16384) | ((addr & 0x3fff) as uint)]
+        if addr < 0x6000 {
+            return 0u8;
+        } else if addr < 0x8000 {
+            return self.prg_ram[addr as uint & 0x1fff];
         }
+        let bank = self.prg_banks()[(addr as uint >> 14) & 1];
+        bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
     }
 
     fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
-        if addr < 0x8000 {
+        if addr < 0x6000 {
             return;
-        }
-
-        // Check the reset flag.
-        if (val & 0x80) != 0 {
-            self.write_count = 0;
-            self.accum = 0;
-            self.regs.ctrl = SxCtrl{val: *self.regs.ctrl | (3 << 2)};
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff] = val;
             return;
         }
-
-        // Write the lowest bit of the value into the right location of the accumulator.
-        self.accum = self.accum | ((val & 1) << (self.write_count as uint));
-
-        self.write_count += 1;
-        if self.write_count == 5 {
-            self.write_count = 0;
-
-            // Write to the right internal register.
-            if addr <= 0x9fff {
-                self.regs.ctrl = SxCtrl{val: self.accum};
-            } else if addr <= 0xbfff {
-                self.regs.chr_bank_0 = self.accum;
-            } else if addr <= 0xdfff {
-                self.regs.chr_bank_1 = self.accum;
-            } else {
-                self.regs.prg_bank = self.accum;
-            }
-
-            self.accum = 0;
-        }
+        self.regs.storeb(addr, val);
     }
 
     // FIXME: Apparently this mapper can have CHR-ROM as well. Handle this case.
     fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
     fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
 
-    fn next_scanline(&mut self) -> MapperResult { Continue }
+    fn mirroring(&self) -> Mirroring { self.regs.ctrl.mirroring() }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+
+    fn describe_banks(&self) -> Vec<String> {
+        let banks = self.prg_banks();
+        vec![ format!("PRG 16K: {} {}", banks[0], banks[1]) ]
+    }
 }
 
 //
@@ -210,6 +752,8 @@ impl Mapper for SxRom {
 
 struct TxBankSelect{ val: uint8_t }
 
+save_struct!(TxBankSelect { val });
+
 impl Deref<uint8_t> for TxBankSelect {
     fn deref(&self) -> &uint8_t {
         &self.val
@@ -227,12 +771,19 @@ impl TxBankSelect {
         if (*self & 0x40) == 0 { Swappable8000 } else { SwappableC000 }
     }
     fn chr_a12_inversion(self) -> bool { (*self & 0x80) != 0 }
+
+    // The RAMBO-1 has 16 bank registers and a mode with 1K banks in the first 2K pattern slots.
+    fn rambo_bank_update_select(self) -> uint8_t { *self & 0x0f }
+    fn rambo_chr_1k_mode(self) -> bool { (*self & 0x20) != 0 }
 }
 
 struct TxRegs {
     bank_select: TxBankSelect,  // Bank select (0x8000-0x9ffe even)
+    mirroring: uint8_t,         // Mirroring (0xa000-0xbffe even)
 }
 
+save_struct!(TxRegs { bank_select, mirroring });
+
 struct TxRom {
     rom: Box<Rom>,
     regs: TxRegs,
@@ -245,13 +796,26 @@ struct TxRom {
     scanline_counter: uint8_t,
     irq_reload: uint8_t,             // Copied into the scanline counter when it hits zero.
     irq_enabled: bool,
+    irq_pending: bool,
 }
 
+save_struct!(TxRom {
+    regs,
+    prg_ram,
+    chr_banks_2k,
+    chr_banks_1k,
+    prg_banks,
+    scanline_counter,
+    irq_reload,
+    irq_enabled,
+    irq_pending
+});
+
 impl TxRom {
     fn new(rom: Box<Rom>) -> TxRom {
         TxRom {
             rom: rom,
-            regs: TxRegs { bank_select: TxBankSelect{val: 0} },
+            regs: TxRegs { bank_select: TxBankSelect{val: 0}, mirroring: 0 },
             prg_ram: Box::new([ 0; 8192 ]),
 
             chr_banks_2k: [ 0, 0 ],
@@ -261,39 +825,36 @@ impl TxRom {
             scanline_counter: 0,
             irq_reload: 0,
             irq_enabled: false,
+            irq_pending: false,
         }
     }
 
-    fn prg_bank_count(&self) -> uint8_t { self.rom.header.prg_rom_size * 2 }
-}
+    fn prg_bank_count(&self) -> uint { bank_count(self.rom.prg.as_slice(), 8192) }
 
-impl Mapper for TxRom {
+    // The 8K bank at each of $8000, $A000, $C000 and $E000.
+    fn prg_bank(&self, slot: uint) -> uint {
+        match (slot, self.regs.bank_select.prg_bank_mode()) {
+            // $8000-$9FFF and $C000-$DFFF trade places between switchable and fixed to the second
+            // to last bank.
+            (0, Swappable8000) | (2, SwappableC000) => self.prg_banks[0] as uint,
+            (0, SwappableC000) | (2, Swappable8000) => self.prg_bank_count() - 2,
+            // $A000-$BFFF is switchable.
+            (1, _) => self.prg_banks[1] as uint,
+            // $E000-$FFFF is fixed to the last bank.
+            _ => self.prg_bank_count() - 1,
+        }
+    }
+}
+
+impl Mapper for TxRom {
     fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
         if addr < 0x6000 {
             0u8
         } else if addr < 0x8000 {
             self.prg_ram[addr as uint & 0x1fff]
-        } else if addr < 0xa000 {
-            // $8000-$9FFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
-                Swappable8000 => self.prg_banks[0],
-                SwappableC000 => self.prg_bank_count() - 2,
-            };
-            self.rom.prg[(bank as uint * 8192) | (addr as uint & 0x1fff)]
-        } else if addr < 0xc000 {
-            // $A000-$BFFF is switchable.
-            self.rom.prg[(self.prg_banks[1] as uint * 8192) | (addr as uint & 0x1fff)]
-        } else if addr < 0xe000 {
-            // $C000-$DFFF might be switchable or fixed to the second to last bank.
-            let bank = match self.regs.bank_select.prg_bank_mode() {
-                Swappable8000 => self.prg_bank_count() - 2,
-                SwappableC000 => self.prg_banks[0],
-            };
-            self.rom.prg[(bank as uint * 8192) | (addr as uint & 0x1fff)]
         } else {
-            // $E000-$FFFF is fixed to the last bank.
-            let bank = self.prg_bank_count() - 1;
-            self.rom.prg[(bank as uint * 8192) | (addr as uint & 0x1fff)]
+            let bank = self.prg_bank((addr as uint >> 13) & 3);
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
         }
     }
 
@@ -319,18 +880,24 @@ impl Mapper for TxRom {
                 }
             }
         } else if addr < 0xc000 {
-            // TODO: Mirroring and PRG-RAM protect
+            if (addr & 1) == 0 {
+                self.regs.mirroring = val & 1;
+            }
+            // TODO: PRG-RAM protect
         } else if addr < 0xe000 {
             if (addr & 1) == 0 {
                 // IRQ latch.
                 self.irq_reload = val;
             } else {
-                // IRQ reload.
-                self.scanline_counter = self.irq_reload;
+                // IRQ reload. Clearing the counter makes it reload on the next clock.
+                self.scanline_counter = 0;
             }
         } else {
-            // IRQ enable.
+            // IRQ enable. Disabling also acknowledges a pending IRQ.
             self.irq_enabled = (addr & 1) == 1;
+            if !self.irq_enabled {
+                self.irq_pending = false;
+            }
         }
     }
 
@@ -344,10 +911,11 @@ impl Mapper for TxRom {
             (0x1c00 ... 0x1fff, false) | (0x0c00 ... 0x0fff, true) => (self.chr_banks_1k[3], false),
             _ => return 0,
         };
+        // 2K banks are numbered in 1K units, ignoring the low bit.
         if two_kb {
-            self.rom.chr[(bank as uint * 1024) + (addr as uint & 0x7ff)]
+            bank_loadb(self.rom.chr.as_slice(), bank as uint >> 1, 2048, addr)
         } else {
-            self.rom.chr[(bank as uint * 1024) | (addr as uint & 0x3ff)]
+            bank_loadb(self.rom.chr.as_slice(), bank as uint, 1024, addr)
         }
     }
 
@@ -355,19 +923,2196 @@ impl Mapper for TxRom {
         // TODO: CHR-RAM
     }
 
-    fn next_scanline(&mut self) -> MapperResult {
-        if self.scanline_counter != 0 {
+    fn mirroring(&self) -> Mirroring {
+        if self.regs.mirroring == 0 { Vertical } else { Horizontal }
+    }
+
+    // The counter is clocked once per rendered scanline, when the PPU turns from background to
+    // sprite fetches, or the other way around if the background uses the second pattern table.
+    fn watches_a12(&self) -> bool { true }
+    fn a12_rise(&mut self, _: uint16_t, _: uint16_t) {
+        if self.scanline_counter == 0 {
+            self.scanline_counter = self.irq_reload;
+        } else {
             self.scanline_counter -= 1;
-            if self.scanline_counter == 0 {
-                self.scanline_counter = self.irq_reload;
+        }
+        if self.scanline_counter == 0 && self.irq_enabled {
+            log!(log::Mapper, log::Debug, "MMC3 IRQ");
+            self.irq_pending = true;
+        }
+    }
 
-                if self.irq_enabled {
-                    util::debug_print("*** Generated IRQ! ***");
-                    return Irq;
+    fn irq_pending(&self) -> bool { self.irq_pending }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+
+    fn describe_banks(&self) -> Vec<String> {
+        vec![
+            format!("PRG 8K: {} {} {} {}",
+                    self.prg_bank(0), self.prg_bank(1), self.prg_bank(2), self.prg_bank(3)),
+            format!("CHR 2K: {} {} 1K: {} {} {} {}{}",
+                    self.chr_banks_2k[0], self.chr_banks_2k[1],
+                    self.chr_banks_1k[0], self.chr_banks_1k[1],
+                    self.chr_banks_1k[2], self.chr_banks_1k[3],
+                    if self.regs.bank_select.chr_a12_inversion() { " (inverted)" } else { "" }),
+            format!("IRQ counter {} latch {}", self.scanline_counter, self.irq_reload),
+        ]
+    }
+}
+
+
+//
+// Mapper 5 (ExROM/MMC5)
+//
+// Not implemented: the vertical split mode and the sound channels. Boards without CHR-ROM get 8K
+// of CHR-RAM, banked like CHR-ROM would be.
+//
+// See http://wiki.nesdev.com/w/index.php/MMC5
+//
+
+// Pages of the MMC5's own nametable memory.
+const EXRAM_PAGE: uint = 0;
+const FILL_PAGE: uint = 1;
+
+enum ExChrSet {
+    ExChrSetA,      // $5120-$5127: sprites, and everything else in 8x8 sprite mode
+    ExChrSetB,      // $5128-$512B: background in 8x16 sprite mode
+}
+
+save_enum!(ExChrSet { ExChrSetA, ExChrSetB });
+
+pub struct ExRom {
+    rom: Box<Rom>,
+    prg_ram: Box<[uint8_t; 65536]>,
+    exram: Box<[uint8_t; 1024]>,
+    chr_ram: Box<[uint8_t; 8192]>,  // Only used without CHR-ROM
+
+    prg_mode: uint8_t,              // $5100
+    chr_mode: uint8_t,              // $5101
+    prg_ram_protect: [uint8_t; 2],  // $5102, $5103
+    exram_mode: uint8_t,            // $5104
+    nametable_mapping: uint8_t,     // $5105
+    fill_tile: uint8_t,             // $5106
+    fill_attr: uint8_t,             // $5107
+    prg_banks: [uint8_t; 5],        // $5113-$5117
+    chr_banks_a: [uint16_t; 8],     // $5120-$5127
+    chr_banks_b: [uint16_t; 4],     // $5128-$512B
+    chr_upper: uint8_t,             // $5130
+    last_chr_set: ExChrSet,
+
+    irq_target: uint8_t,            // $5203
+    irq_enabled: bool,              // $5204
+    irq_pending: bool,
+    in_frame: bool,
+    scanline_counter: uint8_t,
+    multiplicand: uint8_t,          // $5205
+    multiplier: uint8_t,            // $5206
+
+    // Snooped from the PPU's registers.
+    large_sprites: bool,
+    rendering: bool,
+
+    // The ExRAM byte for the tile the PPU is drawing, in extended attribute mode.
+    ext_attr: uint8_t,
+}
+
+save_struct!(ExRom {
+    prg_ram,
+    exram,
+    prg_mode,
+    chr_mode,
+    prg_ram_protect,
+    exram_mode,
+    nametable_mapping,
+    fill_tile,
+    fill_attr,
+    prg_banks,
+    chr_banks_a,
+    chr_banks_b,
+    chr_upper,
+    last_chr_set,
+    irq_target,
+    irq_enabled,
+    irq_pending,
+    in_frame,
+    scanline_counter,
+    multiplicand,
+    multiplier,
+    large_sprites,
+    rendering,
+    ext_attr,
+    chr_ram
+});
+
+impl ExRom {
+    fn new(rom: Box<Rom>) -> ExRom {
+        ExRom {
+            rom: rom,
+            prg_ram: Box::new([ 0; 65536 ]),
+            exram: Box::new([ 0; 1024 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [ 0, 0 ],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attr: 0,
+            prg_banks: [ 0, 0, 0, 0, 0xff ],
+            chr_banks_a: [ 0, 0, 0, 0, 0, 0, 0, 0 ],
+            chr_banks_b: [ 0, 0, 0, 0 ],
+            chr_upper: 0,
+            last_chr_set: ExChrSetA,
+
+            irq_target: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline_counter: 0,
+            multiplicand: 0xff,
+            multiplier: 0xff,
+
+            large_sprites: false,
+            rendering: false,
+
+            ext_attr: 0,
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect[0] == 2 && self.prg_ram_protect[1] == 1
+    }
+
+    // Returns the register that banks the 8K of $8000-$FFFF containing the given address, and the
+    // size of the bank in 8K units.
+    fn prg_bank_reg(&self, addr: uint16_t) -> (uint8_t, uint) {
+        let slot = (addr as uint - 0x8000) >> 13;
+        match (self.prg_mode & 3, slot) {
+            (0, _) => (self.prg_banks[4] | 0x80, 4),
+            (1, 0) | (1, 1) => (self.prg_banks[2], 2),
+            (1, _) => (self.prg_banks[4] | 0x80, 2),
+            (2, 0) | (2, 1) => (self.prg_banks[2], 2),
+            (2, 2) => (self.prg_banks[3], 1),
+            (2, _) => (self.prg_banks[4] | 0x80, 1),
+            (_, _) => (self.prg_banks[slot + 1] | if slot == 3 { 0x80 } else { 0 }, 1),
+        }
+    }
+
+    // Bit 7 of a bank register selects ROM; otherwise the bank is PRG-RAM. Returns the offset into
+    // PRG-ROM or PRG-RAM and whether it's ROM.
+    fn prg_offset(&self, addr: uint16_t) -> (uint, bool) {
+        let (reg, size) = self.prg_bank_reg(addr);
+        let bank = (reg as uint & 0x7f) & !(size - 1);
+        let offset = (bank << 13) + (addr as uint & ((size << 13) - 1));
+        if (reg & 0x80) != 0 {
+            (offset % self.rom.prg.len(), true)
+        } else {
+            (offset & 0xffff, false)
+        }
+    }
+
+    // CHR-ROM, or the CHR-RAM that takes its place.
+    fn chr(&self) -> &[uint8_t] {
+        if self.rom.chr.len() == 0 {
+            self.chr_ram.as_slice()
+        } else {
+            self.rom.chr.as_slice()
+        }
+    }
+
+    fn chr_offset(&self, addr: uint16_t, set: ExChrSet) -> uint {
+        let addr = addr as uint & 0x1fff;
+        let size = 0x2000 >> (self.chr_mode as uint & 3);
+        let slot = addr / size;
+        let bank = match set {
+            ExChrSetA => self.chr_banks_a[(slot + 1) * (8 >> (self.chr_mode as uint & 3)) - 1],
+            // The B set only covers 4K, which is repeated in both pattern tables.
+            ExChrSetB => match self.chr_mode & 3 {
+                0 | 1 => self.chr_banks_b[3],
+                2 => self.chr_banks_b[(slot & 1) * 2 + 1],
+                _ => self.chr_banks_b[slot & 3],
+            },
+        };
+        ((bank as uint * size) + (addr & (size - 1))) % self.chr().len()
+    }
+
+    fn read_status(&mut self) -> uint8_t {
+        let mut val = 0;
+        if self.irq_pending {
+            val |= 0x80;
+        }
+        if self.in_frame {
+            val |= 0x40;
+        }
+        self.irq_pending = false;
+        val
+    }
+
+    fn store_register(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr {
+            0x5100 => self.prg_mode = val & 3,
+            0x5101 => self.chr_mode = val & 3,
+            0x5102 => self.prg_ram_protect[0] = val & 3,
+            0x5103 => self.prg_ram_protect[1] = val & 3,
+            0x5104 => self.exram_mode = val & 3,
+            0x5105 => self.nametable_mapping = val,
+            0x5106 => self.fill_tile = val,
+            0x5107 => self.fill_attr = val & 3,
+            0x5113 ... 0x5117 => self.prg_banks[addr as uint - 0x5113] = val,
+            0x5120 ... 0x5127 => {
+                self.chr_banks_a[addr as uint - 0x5120] = val as uint16_t |
+                    ((self.chr_upper as uint16_t) << 8);
+                self.last_chr_set = ExChrSetA;
+            }
+            0x5128 ... 0x512b => {
+                self.chr_banks_b[addr as uint - 0x5128] = val as uint16_t |
+                    ((self.chr_upper as uint16_t) << 8);
+                self.last_chr_set = ExChrSetB;
+            }
+            0x5130 => self.chr_upper = val & 3,
+            0x5203 => self.irq_target = val,
+            0x5204 => self.irq_enabled = (val & 0x80) != 0,
+            0x5205 => self.multiplicand = val,
+            0x5206 => self.multiplier = val,
+            _ => {}
+        }
+    }
+
+    // In extended attribute mode, each background tile picks its own 4K CHR bank and palette.
+    fn ext_attr_active(&self) -> bool { self.exram_mode == 1 }
+}
+
+impl Mapper for ExRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            let bank = self.prg_banks[0] as uint & 0x07;
+            self.prg_ram[(bank << 13) | (addr as uint & 0x1fff)]
+        } else {
+            match self.prg_offset(addr) {
+                (offset, true) => self.rom.prg[offset],
+                (offset, false) => self.prg_ram[offset],
+            }
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 || !self.prg_ram_writable() {
+            return;
+        } else if addr < 0x8000 {
+            let bank = self.prg_banks[0] as uint & 0x07;
+            self.prg_ram[(bank << 13) | (addr as uint & 0x1fff)] = val;
+        } else {
+            match self.prg_offset(addr) {
+                (_, true) => {}     // Can't store to PRG-ROM.
+                (offset, false) => self.prg_ram[offset] = val,
+            }
+        }
+    }
+
+    fn expansion_loadb(&mut self, addr: uint16_t) -> Option<uint8_t> {
+        let product = self.multiplicand as uint16_t * self.multiplier as uint16_t;
+        match addr {
+            0x5204 => Some(self.read_status()),
+            0x5205 => Some(product as uint8_t),
+            0x5206 => Some((product >> 8) as uint8_t),
+            // ExRAM is only readable by the CPU in modes 2 and 3.
+            0x5c00 ... 0x5fff if self.exram_mode >= 2 => Some(self.exram[addr as uint & 0x3ff]),
+            _ => None,
+        }
+    }
+
+    fn expansion_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x5000 {
+            return;
+        }
+        if addr < 0x5c00 {
+            self.store_register(addr, val);
+        } else if self.exram_mode != 3 {
+            // Mode 3 makes ExRAM read-only.
+            self.exram[addr as uint & 0x3ff] = val;
+        }
+    }
+
+    // The registers, and ExRAM, which can be nametables.
+    fn is_banking_register(&self, addr: uint16_t) -> bool { addr >= 0x5000 && addr < 0x6000 }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let set = self.last_chr_set;
+        self.chr()[self.chr_offset(addr, set)]
+    }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        // Can't store to CHR-ROM.
+        if self.rom.chr.len() == 0 {
+            let set = self.last_chr_set;
+            let offset = self.chr_offset(addr, set);
+            self.chr_ram[offset] = val;
+        }
+    }
+
+    fn chr_fetch(&mut self, addr: uint16_t, sprite: bool) -> uint8_t {
+        if !sprite && self.ext_attr_active() {
+            let bank = ((self.chr_upper as uint) << 6) | (self.ext_attr as uint & 0x3f);
+            let chr = self.chr();
+            return chr[((bank * 4096) | (addr as uint & 0x0fff)) % chr.len()];
+        }
+
+        // In 8x8 sprite mode, whichever set was written last applies to everything.
+        let set = match (self.large_sprites, sprite) {
+            (true, true) => ExChrSetA,
+            (true, false) => ExChrSetB,
+            (false, _) => self.last_chr_set,
+        };
+        self.chr()[self.chr_offset(addr, set)]
+    }
+
+    // Background tiles can each have their own bank in extended attribute mode.
+    fn cacheable_chr(&self) -> bool { false }
+
+    // $5105 gives each nametable two bits: CIRAM page 0 or 1, ExRAM, or fill mode.
+    fn arrange_nametables(&self, map: &mut NametableMap) {
+        for (table, slot) in map.slots.iter_mut().enumerate() {
+            *slot = match (self.nametable_mapping >> (table * 2)) & 3 {
+                page @ 0 ... 1 => Ciram(page as uint),
+                2 => Cartridge(EXRAM_PAGE),
+                _ => Cartridge(FILL_PAGE),
+            };
+        }
+        map.watched = self.ext_attr_active();
+    }
+
+    fn nametable_loadb(&mut self, page: uint, offset: uint16_t) -> uint8_t {
+        match page {
+            EXRAM_PAGE if self.exram_mode < 2 => self.exram[offset as uint],
+            EXRAM_PAGE => 0,
+            _ if offset >= 0x3c0 => {
+                self.fill_attr | (self.fill_attr << 2) | (self.fill_attr << 4) |
+                    (self.fill_attr << 6)
+            }
+            _ => self.fill_tile,
+        }
+    }
+    fn nametable_storeb(&mut self, page: uint, offset: uint16_t, val: uint8_t) {
+        if page == EXRAM_PAGE && self.exram_mode < 2 {
+            self.exram[offset as uint] = val;
+        }
+    }
+
+    // In extended attribute mode, each tile's ExRAM byte gives its palette, in place of the
+    // attribute table, and its CHR bank.
+    fn nametable_fetched(&mut self, addr: uint16_t, val: uint8_t) -> uint8_t {
+        let offset = addr as uint & 0x3ff;
+        if offset < 0x3c0 {
+            self.ext_attr = self.exram[offset];
+            return val;
+        }
+        let palette = self.ext_attr >> 6;
+        palette | (palette << 2) | (palette << 4) | (palette << 6)
+    }
+
+    // Unused, since `arrange_nametables` takes care of it.
+    fn mirroring(&self) -> Mirroring { Vertical }
+
+    fn ppu_register_write(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr {
+            0x2000 => self.large_sprites = (val & 0x20) != 0,
+            0x2001 => {
+                self.rendering = (val & 0x18) != 0;
+                if !self.rendering {
+                    self.in_frame = false;
                 }
             }
+            _ => {}
+        }
+    }
+
+
+    fn start_scanline(&mut self, scanline: uint16_t) {
+        if !self.rendering || scanline >= 240 {
+            self.in_frame = false;
+            return;
+        }
+
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline_counter = 0;
+            return;
+        }
+
+        self.scanline_counter += 1;
+        if self.scanline_counter == self.irq_target {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq_pending(&self) -> bool { self.irq_pending && self.irq_enabled }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mapper 7 (AxROM)
+//
+// See http://wiki.nesdev.com/w/index.php/AxROM
+//
+
+pub struct AxRom {
+    rom: Box<Rom>,
+    prg_bank: uint8_t,   // 32K bank at $8000
+    one_screen_upper: bool,
+    bus_conflicts: bool,
+    chr_ram: Box<[uint8_t; 8192]>,
+}
+
+save_struct!(AxRom { prg_bank, one_screen_upper, chr_ram });
+
+impl AxRom {
+    fn new(rom: Box<Rom>) -> AxRom {
+        // Only AMROM boards have conflicts, and games written for them also work without.
+        let bus_conflicts = has_bus_conflicts(&*rom, false);
+        AxRom {
+            rom: rom,
+            prg_bank: 0,
+            one_screen_upper: false,
+            bus_conflicts: bus_conflicts,
+            chr_ram: Box::new([ 0; 8192 ]),
+        }
+    }
+}
+
+impl Mapper for AxRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            0u8
+        } else {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 32768, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x8000 {
+            return;
+        }
+        let val = if self.bus_conflicts { val & self.prg_loadb(addr) } else { val };
+        self.prg_bank = val & 0x07;
+        self.one_screen_upper = (val & 0x10) != 0;
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
+    fn mirroring(&self) -> Mirroring {
+        if self.one_screen_upper { OneScreenUpper } else { OneScreenLower }
+    }
+}
+
+//
+// CHR latches shared by MMC2 and MMC4
+//
+// Each 4K pattern table has a latch that selects between two CHR banks. The PPU fetching tile $FD
+// or $FE from a pattern table flips that table's latch, taking effect on the next fetch. This lets
+// a game switch CHR banks partway down the screen without any CPU involvement.
+//
+
+struct ChrLatches {
+    banks: [[uint8_t; 2]; 2],  // 4K banks: [pattern table][latch]; latch 0 is $FD, 1 is $FE
+    latches: [uint; 2],
+}
+
+save_struct!(ChrLatches { banks, latches });
+
+impl ChrLatches {
+    fn new() -> ChrLatches {
+        ChrLatches { banks: [ [ 0, 0 ], [ 0, 0 ] ], latches: [ 1, 1 ] }
+    }
+
+    fn loadb(&self, chr: &[uint8_t], addr: uint16_t) -> uint8_t {
+        let table = (addr as uint >> 12) & 1;
+        bank_loadb(chr, self.banks[table][self.latches[table]] as uint, 4096, addr)
+    }
+
+    // MMC2 triggers latch 0 only on $0FD8 and $0FE8 exactly; MMC4 triggers on the whole eight
+    // byte range like latch 1 does.
+    fn update(&mut self, addr: uint16_t, exact_latch_0: bool) {
+        match addr {
+            0x0fd8 => self.latches[0] = 0,
+            0x0fe8 => self.latches[0] = 1,
+            0x0fd9 ... 0x0fdf if !exact_latch_0 => self.latches[0] = 0,
+            0x0fe9 ... 0x0fef if !exact_latch_0 => self.latches[0] = 1,
+            0x1fd8 ... 0x1fdf => self.latches[1] = 0,
+            0x1fe8 ... 0x1fef => self.latches[1] = 1,
+            _ => {}
+        }
+    }
+
+    // Handles the CHR bank and mirroring registers, which MMC2 and MMC4 share at $B000-$FFFF.
+    fn storeb(&mut self, addr: uint16_t, val: uint8_t, vertical_mirroring: &mut bool) {
+        match addr >> 12 {
+            0xb => self.banks[0][0] = val & 0x1f,
+            0xc => self.banks[0][1] = val & 0x1f,
+            0xd => self.banks[1][0] = val & 0x1f,
+            0xe => self.banks[1][1] = val & 0x1f,
+            0xf => *vertical_mirroring = (val & 1) == 0,
+            _ => {}
+        }
+    }
+}
+
+//
+// Mapper 9 (PxROM/MMC2)
+//
+// See http://wiki.nesdev.com/w/index.php/MMC2
+//
+
+pub struct PxRom {
+    rom: Box<Rom>,
+    prg_bank: uint8_t,   // 8K bank at $8000; $A000-$FFFF is fixed to the last three banks.
+    chr: ChrLatches,
+    vertical_mirroring: bool,
+}
+
+save_struct!(PxRom { prg_bank, chr, vertical_mirroring });
+
+impl PxRom {
+    fn new(rom: Box<Rom>) -> PxRom {
+        PxRom {
+            rom: rom,
+            prg_bank: 0,
+            chr: ChrLatches::new(),
+            vertical_mirroring: true,
+        }
+    }
+}
+
+impl Mapper for PxRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            0u8
+        } else if addr < 0xa000 {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 8192, addr)
+        } else {
+            // The last three banks, unbanked.
+            let first = last_bank(self.rom.prg.as_slice(), 8192) - 2;
+            let bank = first + ((addr as uint - 0xa000) >> 13);
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0xa000 {
+            return;
+        }
+        if addr < 0xb000 {
+            self.prg_bank = val & 0x0f;
+        } else {
+            self.chr.storeb(addr, val, &mut self.vertical_mirroring);
+        }
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let val = self.chr.loadb(self.rom.chr.as_slice(), addr);
+        self.chr.update(addr, true);
+        val
+    }
+
+    // Fetching tile $FD or $FE switches banks.
+    fn cacheable_chr(&self) -> bool { false }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+    fn mirroring(&self) -> Mirroring {
+        if self.vertical_mirroring { Vertical } else { Horizontal }
+    }
+}
+
+//
+// Mapper 10 (FxROM/MMC4)
+//
+// See http://wiki.nesdev.com/w/index.php/MMC4
+//
+
+pub struct FxRom {
+    rom: Box<Rom>,
+    prg_bank: uint8_t,   // 16K bank at $8000; $C000 is fixed to the last bank.
+    prg_ram: Box<[uint8_t; 8192]>,
+    chr: ChrLatches,
+    vertical_mirroring: bool,
+}
+
+save_struct!(FxRom { prg_bank, prg_ram, chr, vertical_mirroring });
+
+impl FxRom {
+    fn new(rom: Box<Rom>) -> FxRom {
+        FxRom {
+            rom: rom,
+            prg_bank: 0,
+            prg_ram: Box::new([ 0; 8192 ]),
+            chr: ChrLatches::new(),
+            vertical_mirroring: true,
         }
-        Continue
     }
 }
 
+impl Mapper for FxRom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff]
+        } else if addr < 0xc000 {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 16384, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 16384);
+            bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff] = val;
+        } else if addr < 0xa000 {
+            // Nothing here.
+        } else if addr < 0xb000 {
+            self.prg_bank = val & 0x0f;
+        } else {
+            self.chr.storeb(addr, val, &mut self.vertical_mirroring);
+        }
+    }
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let val = self.chr.loadb(self.rom.chr.as_slice(), addr);
+        self.chr.update(addr, false);
+        val
+    }
+
+    // Fetching tile $FD or $FE switches banks.
+    fn cacheable_chr(&self) -> bool { false }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+    fn mirroring(&self) -> Mirroring {
+        if self.vertical_mirroring { Vertical } else { Horizontal }
+    }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mappers 16 and 159 (Bandai FCG)
+//
+// Bandai's boards: eight 1K CHR banks, a 16K PRG bank at $8000 with the last bank fixed at
+// $C000, and a 16-bit IRQ counter that counts down CPU cycles. The registers repeat every 16
+// bytes.
+//
+// The original FCG-1 and FCG-2 chips decode the registers at $6000-$7FFF and write the IRQ
+// counter directly. The later LZ93D50 decodes them at $8000-$FFFF, writes a latch that's copied
+// into the counter on enabling, and talks to a serial EEPROM through $800D: a 24C02 on mapper 16
+// boards, a 24C01 on mapper 159. Old iNES headers don't say which chip a mapper 16 game has, so
+// without a submapper we decode both ranges and write the counter and the latch together, which
+// suits games for either.
+//
+// See http://wiki.nesdev.com/w/index.php/Bandai_FCG_board
+//
+
+pub struct BandaiFcg {
+    rom: Box<Rom>,
+    registers_at_6000: bool,
+    registers_at_8000: bool,
+    eeprom: Option<Eeprom>,
+
+    chr_banks: [uint8_t; 8],    // 1K banks
+    prg_bank: uint8_t,          // 16K bank at $8000
+    mirroring: uint8_t,
+    irq_enabled: bool,
+    irq_latch: uint16_t,
+    irq_counter: uint16_t,
+    irq_pending: bool,
+    eeprom_control: uint8_t,    // $800D
+}
+
+impl BandaiFcg {
+    fn new(rom: Box<Rom>) -> BandaiFcg {
+        let (registers_at_6000, registers_at_8000, eeprom) = match rom.header.mapper_number() {
+            159 => (false, true, Some(Eeprom::new(X24C01))),
+            _ if rom.header.is_nes2() && rom.header.submapper() == 4 => (true, false, None),
+            _ if rom.header.is_nes2() && rom.header.submapper() == 5 => {
+                (false, true, Some(Eeprom::new(X24C02)))
+            }
+            _ => (true, true, Some(Eeprom::new(X24C02))),
+        };
+        BandaiFcg {
+            rom: rom,
+            registers_at_6000: registers_at_6000,
+            registers_at_8000: registers_at_8000,
+            eeprom: eeprom,
+
+            chr_banks: [ 0, 0, 0, 0, 0, 0, 0, 0 ],
+            prg_bank: 0,
+            mirroring: 0,
+            irq_enabled: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_pending: false,
+            eeprom_control: 0,
+        }
+    }
+
+    fn store_register(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr & 0x0f {
+            0x0 ... 0x7 => self.chr_banks[addr as uint & 7] = val,
+            0x8 => self.prg_bank = val & 0x0f,
+            0x9 => self.mirroring = val & 3,
+            0xa => {
+                self.irq_enabled = (val & 1) != 0;
+                self.irq_pending = false;
+                if self.registers_at_8000 {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xb | 0xc => {
+                let shift = if (addr & 0x0f) == 0xb { 0 } else { 8 };
+                let mask: uint16_t = 0xff << shift;
+                let val = (val as uint16_t) << shift;
+                if self.registers_at_8000 {
+                    self.irq_latch = (self.irq_latch & !mask) | val;
+                }
+                if self.registers_at_6000 {
+                    self.irq_counter = (self.irq_counter & !mask) | val;
+                }
+            }
+            0xd => {
+                self.eeprom_control = val;
+                match self.eeprom {
+                    None => {}
+                    Some(ref mut eeprom) => eeprom.write((val & 0x20) != 0, (val & 0x40) != 0),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Save for BandaiFcg {
+    fn save(&mut self, fd: &mut Writer) {
+        match self.eeprom {
+            None => {}
+            Some(ref mut eeprom) => eeprom.save(fd),
+        }
+        self.chr_banks.save(fd);
+        self.prg_bank.save(fd);
+        self.mirroring.save(fd);
+        self.irq_enabled.save(fd);
+        self.irq_latch.save(fd);
+        self.irq_counter.save(fd);
+        self.irq_pending.save(fd);
+        self.eeprom_control.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        match self.eeprom {
+            None => {}
+            Some(ref mut eeprom) => eeprom.load(fd),
+        }
+        self.chr_banks.load(fd);
+        self.prg_bank.load(fd);
+        self.mirroring.load(fd);
+        self.irq_enabled.load(fd);
+        self.irq_latch.load(fd);
+        self.irq_counter.load(fd);
+        self.irq_pending.load(fd);
+        self.eeprom_control.load(fd);
+    }
+}
+
+impl Mapper for BandaiFcg {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            // Bit 4 is the EEPROM's data line, when the game has asked to read it.
+            match self.eeprom {
+                Some(ref eeprom) if (self.eeprom_control & 0x80) != 0 => {
+                    (eeprom.sda() as uint8_t) << 4
+                }
+                _ => 0u8,
+            }
+        } else if addr < 0xc000 {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 16384, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 16384);
+            bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if self.is_banking_register(addr) {
+            self.store_register(addr, val);
+        }
+    }
+    fn is_banking_register(&self, addr: uint16_t) -> bool {
+        (addr >= 0x6000 && addr < 0x8000 && self.registers_at_6000) ||
+            (addr >= 0x8000 && self.registers_at_8000)
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let bank = self.chr_banks[(addr as uint >> 10) & 7] as uint;
+        bank_loadb(self.rom.chr.as_slice(), bank, 1024, addr)
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        match self.mirroring {
+            0 => Vertical,
+            1 => Horizontal,
+            2 => OneScreenLower,
+            _ => OneScreenUpper,
+        }
+    }
+
+    // The counter raises an IRQ when it reaches zero and carries on down, wrapping around. We
+    // don't have a per-cycle hook, so it catches up a scanline at a time.
+    fn next_scanline(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        let cycles = CYCLES_PER_SCANLINE as uint16_t;
+        if self.irq_counter < cycles {
+            log!(log::Mapper, log::Debug, "Bandai FCG IRQ");
+            self.irq_pending = true;
+        }
+        self.irq_counter -= cycles;
+    }
+    fn irq_pending(&self) -> bool { self.irq_pending }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        match self.eeprom {
+            Some(ref mut eeprom) if self.rom.header.battery() => Some(eeprom.contents()),
+            _ => None,
+        }
+    }
+}
+
+//
+// IRQ counter shared by the Konami VRCs
+//
+// An 8-bit counter that counts up to $FF and then reloads from the latch and raises an IRQ. It
+// either counts CPU cycles or, through a prescaler, scanlines.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC_IRQ
+//
+
+pub struct VrcIrq {
+    latch: uint8_t,
+    counter: uint8_t,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pub pending: bool,
+}
+
+save_struct!(VrcIrq { latch, counter, enabled, enable_after_ack, cycle_mode, pending });
+
+impl VrcIrq {
+    pub fn new() -> VrcIrq {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn store_latch(&mut self, val: uint8_t) { self.latch = val }
+
+    pub fn store_control(&mut self, val: uint8_t) {
+        self.enable_after_ack = (val & 0x01) != 0;
+        self.enabled = (val & 0x02) != 0;
+        self.cycle_mode = (val & 0x04) != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.enabled = self.enable_after_ack;
+        self.pending = false;
+    }
+
+    // Returns true if the counter overflowed.
+    fn clock(&mut self) -> bool {
+        if self.counter == 0xff {
+            self.counter = self.latch;
+            true
+        } else {
+            self.counter += 1;
+            false
+        }
+    }
+
+    // Runs the counter for one scanline's worth of time. We don't have a per-cycle hook, so in
+    // cycle mode the counter catches up a scanline at a time.
+    pub fn next_scanline(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        if self.cycle_mode {
+            for _ in range(0, CYCLES_PER_SCANLINE) {
+                self.pending = self.clock() || self.pending;
+            }
+        } else {
+            self.pending = self.clock() || self.pending;
+        }
+    }
+}
+
+//
+// VRC6 expansion audio
+//
+// Two pulse channels with eight duty settings and a sawtooth channel built on an accumulator.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC6_audio
+//
+
+struct Vrc6Pulse {
+    volume: uint8_t,
+    duty: uint8_t,
+    constant: bool,     // "Digitized" mode: always output the volume.
+    period: uint16_t,
+    enabled: bool,
+    divider: uint16_t,
+    step: uint8_t,
+}
+
+save_struct!(Vrc6Pulse { volume, duty, constant, period, enabled, divider, step });
+
+impl Vrc6Pulse {
+    fn new() -> Vrc6Pulse {
+        Vrc6Pulse {
+            volume: 0,
+            duty: 0,
+            constant: false,
+            period: 0,
+            enabled: false,
+            divider: 0,
+            step: 15,
+        }
+    }
+
+    fn storeb(&mut self, reg: uint16_t, val: uint8_t) {
+        match reg {
+            0 => {
+                self.constant = (val & 0x80) != 0;
+                self.duty = (val >> 4) & 0x07;
+                self.volume = val & 0x0f;
+            }
+            1 => self.period = (self.period & 0x0f00) | (val as uint16_t),
+            2 => {
+                self.period = (self.period & 0x00ff) | ((val as uint16_t & 0x0f) << 8);
+                self.enabled = (val & 0x80) != 0;
+                if !self.enabled {
+                    self.step = 15;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn clock(&mut self, shift: uint) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider == 0 {
+            self.divider = self.period >> shift;
+            self.step = if self.step == 0 { 15 } else { self.step - 1 };
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> uint8_t {
+        if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+    }
+}
+
+struct Vrc6Saw {
+    rate: uint8_t,
+    period: uint16_t,
+    enabled: bool,
+    divider: uint16_t,
+    step: uint8_t,
+    accumulator: uint8_t,
+}
+
+save_struct!(Vrc6Saw { rate, period, enabled, divider, step, accumulator });
+
+impl Vrc6Saw {
+    fn new() -> Vrc6Saw {
+        Vrc6Saw { rate: 0, period: 0, enabled: false, divider: 0, step: 0, accumulator: 0 }
+    }
+
+    fn storeb(&mut self, reg: uint16_t, val: uint8_t) {
+        match reg {
+            0 => self.rate = val & 0x3f,
+            1 => self.period = (self.period & 0x0f00) | (val as uint16_t),
+            2 => {
+                self.period = (self.period & 0x00ff) | ((val as uint16_t & 0x0f) << 8);
+                self.enabled = (val & 0x80) != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // The accumulator takes the rate on every other step and resets after seven additions.
+    fn clock(&mut self, shift: uint) {
+        if !self.enabled {
+            return;
+        }
+        if self.divider != 0 {
+            self.divider -= 1;
+            return;
+        }
+        self.divider = self.period >> shift;
+        self.step += 1;
+        if self.step == 14 {
+            self.step = 0;
+            self.accumulator = 0;
+        } else if (self.step & 1) == 0 {
+            self.accumulator += self.rate;
+        }
+    }
+
+    fn output(&self) -> uint8_t { self.accumulator >> 3 }
+}
+
+//
+// Mappers 24 and 26 (VRC6)
+//
+// The two differ only in that mapper 26 swaps address lines A0 and A1.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC6
+//
+
+pub struct Vrc6 {
+    rom: Box<Rom>,
+    swap_address_lines: bool,
+    prg_ram: Box<[uint8_t; 8192]>,
+
+    prg_bank_16k: uint8_t,      // $8000-$BFFF
+    prg_bank_8k: uint8_t,       // $C000-$DFFF; $E000 is fixed to the last bank.
+    chr_banks: [uint8_t; 8],    // 1K banks
+    banking_control: uint8_t,   // $B003
+    irq: VrcIrq,
+
+    pulses: [Vrc6Pulse; 2],
+    saw: Vrc6Saw,
+    audio_control: uint8_t,     // $9003
+}
+
+save_struct!(Vrc6 {
+    prg_ram,
+    prg_bank_16k,
+    prg_bank_8k,
+    chr_banks,
+    banking_control,
+    irq,
+    pulses,
+    saw,
+    audio_control
+});
+
+impl Vrc6 {
+    fn new(rom: Box<Rom>, swap_address_lines: bool) -> Vrc6 {
+        Vrc6 {
+            rom: rom,
+            swap_address_lines: swap_address_lines,
+            prg_ram: Box::new([ 0; 8192 ]),
+
+            prg_bank_16k: 0,
+            prg_bank_8k: 0,
+            chr_banks: [ 0, 0, 0, 0, 0, 0, 0, 0 ],
+            banking_control: 0,
+            irq: VrcIrq::new(),
+
+            pulses: [ Vrc6Pulse::new(), Vrc6Pulse::new() ],
+            saw: Vrc6Saw::new(),
+            audio_control: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool { (self.banking_control & 0x80) != 0 }
+}
+
+impl Mapper for Vrc6 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let len = self.rom.prg.len();
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            if self.prg_ram_enabled() { self.prg_ram[addr as uint & 0x1fff] } else { 0u8 }
+        } else if addr < 0xc000 {
+            self.rom.prg[((self.prg_bank_16k as uint * 16384) | (addr as uint & 0x3fff)) % len]
+        } else if addr < 0xe000 {
+            self.rom.prg[((self.prg_bank_8k as uint * 8192) | (addr as uint & 0x1fff)) % len]
+        } else {
+            self.rom.prg[len - 0x2000 + (addr as uint & 0x1fff)]
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[addr as uint & 0x1fff] = val;
+            }
+            return;
+        }
+
+        let addr = if self.swap_address_lines {
+            (addr & 0xfffc) | ((addr & 1) << 1) | ((addr >> 1) & 1)
+        } else {
+            addr
+        };
+        let reg = addr & 3;
+        match addr & 0xf000 {
+            0x8000 => self.prg_bank_16k = val & 0x0f,
+            0x9000 if reg == 3 => self.audio_control = val & 0x07,
+            0x9000 => self.pulses[0].storeb(reg, val),
+            0xa000 => self.pulses[1].storeb(reg, val),
+            0xb000 if reg == 3 => self.banking_control = val,
+            0xb000 => self.saw.storeb(reg, val),
+            0xc000 => self.prg_bank_8k = val & 0x1f,
+            0xd000 => self.chr_banks[reg as uint] = val,
+            0xe000 => self.chr_banks[4 + reg as uint] = val,
+            0xf000 => {
+                match reg {
+                    0 => self.irq.store_latch(val),
+                    1 => self.irq.store_control(val),
+                    2 => self.irq.acknowledge(),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // TODO: The PPU banking modes other than 0, which no licensed game uses.
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let bank = self.chr_banks[(addr as uint >> 10) & 7] as uint;
+        let len = self.rom.chr.len();
+        self.rom.chr[((bank * 1024) | (addr as uint & 0x3ff)) % len]
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        match (self.banking_control >> 2) & 3 {
+            0 => Vertical,
+            1 => Horizontal,
+            2 => OneScreenLower,
+            _ => OneScreenUpper,
+        }
+    }
+
+    fn next_scanline(&mut self) { self.irq.next_scanline() }
+    fn irq_pending(&self) -> bool { self.irq.pending }
+
+    fn clock_audio(&mut self) {
+        // $9003 can halt the channels or speed them up 16 or 256 times.
+        if (self.audio_control & 1) != 0 {
+            return;
+        }
+        let shift = match self.audio_control & 6 {
+            0 => 0,
+            2 => 4,
+            _ => 8,
+        };
+        self.pulses[0].clock(shift);
+        self.pulses[1].clock(shift);
+        self.saw.clock(shift);
+    }
+
+    fn audio_output(&self) -> f32 {
+        let level = self.pulses[0].output() + self.pulses[1].output() + self.saw.output();
+        level as f32 / 15.0
+    }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mapper 34 (BNROM and NINA-001)
+//
+// Two unrelated boards that share a mapper number. BNROM switches 32K of PRG with a write
+// anywhere in $8000-$FFFF, with bus conflicts, and has 8K of CHR-RAM. NINA-001 has 8K of PRG-RAM
+// with three registers over its last bytes: $7FFD picks the 32K PRG bank and $7FFE and $7FFF the
+// two 4K CHR-ROM banks. NES 2.0 headers tell them apart by submapper; otherwise only NINA-001
+// has more than 8K of CHR-ROM.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_034
+//
+
+pub struct Bnrom {
+    rom: Box<Rom>,
+    nina: bool,
+    prg_bank: uint8_t,          // 32K bank at $8000
+    chr_banks: [uint8_t; 2],    // NINA-001's 4K banks
+    prg_ram: Box<[uint8_t; 8192]>,
+    chr_ram: Box<[uint8_t; 8192]>,
+}
+
+save_struct!(Bnrom { prg_bank, chr_banks, prg_ram, chr_ram });
+
+impl Bnrom {
+    fn new(rom: Box<Rom>) -> Bnrom {
+        let nina = match rom.header.submapper() {
+            1 if rom.header.is_nes2() => true,
+            2 if rom.header.is_nes2() => false,
+            _ => rom.chr.len() > 8192,
+        };
+        Bnrom {
+            rom: rom,
+            nina: nina,
+            prg_bank: 0,
+            chr_banks: [ 0, 1 ],
+            prg_ram: Box::new([ 0; 8192 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+        }
+    }
+}
+
+impl Mapper for Bnrom {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            if self.nina { self.prg_ram[addr as uint & 0x1fff] } else { 0u8 }
+        } else {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 32768, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if self.nina {
+            // The registers don't keep the RAM under them from seeing the write too.
+            if addr < 0x8000 {
+                self.prg_ram[addr as uint & 0x1fff] = val;
+            }
+            match addr {
+                0x7ffd => self.prg_bank = val & 1,
+                0x7ffe => self.chr_banks[0] = val & 0x0f,
+                0x7fff => self.chr_banks[1] = val & 0x0f,
+                _ => {}
+            }
+        } else if addr >= 0x8000 {
+            self.prg_bank = val & self.prg_loadb(addr);
+        }
+    }
+    fn is_banking_register(&self, addr: uint16_t) -> bool {
+        if self.nina { addr >= 0x7ffd && addr < 0x8000 } else { addr >= 0x8000 }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if self.nina {
+            let bank = self.chr_banks[(addr as uint >> 12) & 1] as uint;
+            bank_loadb(self.rom.chr.as_slice(), bank, 4096, addr)
+        } else if self.rom.chr.len() == 0 {
+            self.chr_ram[addr as uint]
+        } else {
+            bank_loadb(self.rom.chr.as_slice(), 0, 8192, addr)
+        }
+    }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if !self.nina && self.rom.chr.len() == 0 {
+            self.chr_ram[addr as uint] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mappers 33 and 48 (Taito TC0190 and TC0690)
+//
+// Two 8K PRG banks at $8000 and $A000 with the last 16K fixed, two 2K and four 1K CHR banks.
+// The TC0190 (mapper 33) keeps its mirroring bit in the first PRG bank register. The TC0690
+// (mapper 48) moves it to $E000 and adds a scanline counter like the MMC3's, except that it
+// counts up from the latch and raises the IRQ when it wraps around.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_033
+// and http://wiki.nesdev.com/w/index.php/INES_Mapper_048
+//
+
+pub struct TaitoTc0190 {
+    rom: Box<Rom>,
+    tc0690: bool,
+
+    prg_banks: [uint8_t; 2],        // $8000, $8001
+    chr_banks_2k: [uint8_t; 2],     // $8002, $8003
+    chr_banks_1k: [uint8_t; 4],     // $A000-$A003
+    horizontal_mirroring: bool,
+    irq_latch: uint8_t,             // $C000
+    irq_counter: uint8_t,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+save_struct!(TaitoTc0190 {
+    prg_banks,
+    chr_banks_2k,
+    chr_banks_1k,
+    horizontal_mirroring,
+    irq_latch,
+    irq_counter,
+    irq_enabled,
+    irq_pending
+});
+
+impl TaitoTc0190 {
+    fn new(rom: Box<Rom>, tc0690: bool) -> TaitoTc0190 {
+        TaitoTc0190 {
+            rom: rom,
+            tc0690: tc0690,
+
+            prg_banks: [ 0, 0 ],
+            chr_banks_2k: [ 0, 0 ],
+            chr_banks_1k: [ 0, 0, 0, 0 ],
+            horizontal_mirroring: false,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+}
+
+impl Mapper for TaitoTc0190 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            0u8
+        } else if addr < 0xc000 {
+            let bank = self.prg_banks[(addr as uint >> 13) & 1] as uint;
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        } else {
+            // $C000-$FFFF is fixed to the last 16K.
+            let bank = last_bank(self.rom.prg.as_slice(), 8192) - 1 + ((addr as uint >> 13) & 1);
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr & 0xe003 {
+            0x8000 => {
+                if self.tc0690 {
+                    self.prg_banks[0] = val;
+                } else {
+                    self.prg_banks[0] = val & 0x3f;
+                    self.horizontal_mirroring = (val & 0x40) != 0;
+                }
+            }
+            0x8001 => self.prg_banks[1] = val,
+            0x8002 ... 0x8003 => self.chr_banks_2k[addr as uint & 1] = val,
+            0xa000 ... 0xa003 => self.chr_banks_1k[addr as uint & 3] = val,
+            0xc000 if self.tc0690 => self.irq_latch = val,
+            0xc001 if self.tc0690 => self.irq_counter = self.irq_latch,
+            0xc002 if self.tc0690 => self.irq_enabled = true,
+            0xc003 if self.tc0690 => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            0xe000 if self.tc0690 => self.horizontal_mirroring = (val & 0x40) != 0,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x1000 {
+            let bank = self.chr_banks_2k[(addr as uint >> 11) & 1] as uint;
+            bank_loadb(self.rom.chr.as_slice(), bank, 2048, addr)
+        } else {
+            let bank = self.chr_banks_1k[(addr as uint >> 10) & 3] as uint;
+            bank_loadb(self.rom.chr.as_slice(), bank, 1024, addr)
+        }
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring { Horizontal } else { Vertical }
+    }
+
+    // The TC0690's counter is clocked by A12, as on the MMC3, so it only counts rendered lines.
+    fn watches_a12(&self) -> bool { self.tc0690 }
+    fn a12_rise(&mut self, _: uint16_t, _: uint16_t) {
+        if !self.irq_enabled {
+            return;
+        }
+        self.irq_counter += 1;
+        if self.irq_counter == 0 {
+            log!(log::Mapper, log::Debug, "TC0690 IRQ");
+            self.irq_pending = true;
+        }
+    }
+    fn irq_pending(&self) -> bool { self.irq_pending }
+}
+
+//
+// Mapper 64 (Tengen RAMBO-1)
+//
+// Tengen's take on the MMC3, with the same bank select and data registers, mirroring and IRQ
+// register addresses. It adds a third switchable PRG bank, 1K CHR banks in place of the 2K ones
+// when bit 5 of the bank select is set, and an IRQ counter that can count CPU cycles, divided by
+// four, instead of scanlines. Reloading the counter takes effect on its next clock.
+//
+// See http://wiki.nesdev.com/w/index.php/RAMBO-1
+//
+
+pub struct Rambo1 {
+    rom: Box<Rom>,
+    regs: TxRegs,
+    banks: [uint8_t; 16],   // R0-RF; R0-R5 and R8-R9 are CHR banks, R6, R7 and RF PRG banks.
+
+    irq_latch: uint8_t,     // $C000
+    irq_counter: uint8_t,
+    irq_reload: bool,       // Set by $C001
+    irq_cycle_mode: bool,
+    irq_prescaler: uint8_t,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+save_struct!(Rambo1 {
+    regs,
+    banks,
+    irq_latch,
+    irq_counter,
+    irq_reload,
+    irq_cycle_mode,
+    irq_prescaler,
+    irq_enabled,
+    irq_pending
+});
+
+impl Rambo1 {
+    fn new(rom: Box<Rom>) -> Rambo1 {
+        Rambo1 {
+            rom: rom,
+            regs: TxRegs { bank_select: TxBankSelect{val: 0}, mirroring: 0 },
+            banks: [ 0; 16 ],
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_cycle_mode: false,
+            irq_prescaler: 0,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn clock_irq(&mut self) {
+        if self.irq_reload {
+            self.irq_counter = self.irq_latch + 1;
+            self.irq_reload = false;
+        } else if self.irq_counter == 0 {
+            self.irq_counter = self.irq_latch;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            log!(log::Mapper, log::Debug, "RAMBO-1 IRQ");
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Rambo1 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            return 0u8;
+        }
+        let slot = (addr as uint >> 13) & 3;
+        let bank = match (self.regs.bank_select.prg_bank_mode(), slot) {
+            (_, 3) => last_bank(self.rom.prg.as_slice(), 8192),
+            (Swappable8000, 0) | (SwappableC000, 1) => self.banks[6] as uint,
+            (Swappable8000, 1) | (SwappableC000, 2) => self.banks[7] as uint,
+            _ => self.banks[15] as uint,
+        };
+        bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match (addr & 0xe001, addr < 0x8000) {
+            (_, true) => {}
+            (0x8000, _) => self.regs.bank_select = TxBankSelect{val: val},
+            (0x8001, _) => {
+                let index = self.regs.bank_select.rambo_bank_update_select() as uint;
+                self.banks[index] = val;
+            }
+            (0xa000, _) => self.regs.mirroring = val & 1,
+            (0xc000, _) => self.irq_latch = val,
+            (0xc001, _) => {
+                self.irq_cycle_mode = (val & 1) != 0;
+                self.irq_prescaler = 0;
+                self.irq_reload = true;
+            }
+            (0xe000, _) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (0xe001, _) => self.irq_enabled = true,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        // Inversion swaps the halves of the pattern tables.
+        let addr_in_order = if self.regs.bank_select.chr_a12_inversion() {
+            addr ^ 0x1000
+        } else {
+            addr
+        };
+        let one_kb = self.regs.bank_select.rambo_chr_1k_mode();
+        let (bank, two_kb) = match addr_in_order >> 10 {
+            0 if one_kb => (self.banks[0], false),
+            1 if one_kb => (self.banks[8], false),
+            2 if one_kb => (self.banks[1], false),
+            3 if one_kb => (self.banks[9], false),
+            0 ... 1 => (self.banks[0], true),
+            2 ... 3 => (self.banks[1], true),
+            slot => (self.banks[2 + (slot as uint & 3)], false),
+        };
+        // 2K banks are numbered in 1K units, ignoring the low bit.
+        if two_kb {
+            bank_loadb(self.rom.chr.as_slice(), bank as uint >> 1, 2048, addr)
+        } else {
+            bank_loadb(self.rom.chr.as_slice(), bank as uint, 1024, addr)
+        }
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        if self.regs.mirroring == 0 { Vertical } else { Horizontal }
+    }
+
+    // In scanline mode, the counter is clocked by A12 as on the MMC3.
+    fn watches_a12(&self) -> bool { true }
+    fn a12_rise(&mut self, _: uint16_t, _: uint16_t) {
+        if !self.irq_cycle_mode {
+            self.clock_irq();
+        }
+    }
+
+    // In cycle mode, the counter catches up a scanline at a time, as for the VRCs.
+    fn next_scanline(&mut self) {
+        if !self.irq_cycle_mode {
+            return;
+        }
+        for _ in range(0, CYCLES_PER_SCANLINE) {
+            self.irq_prescaler = (self.irq_prescaler + 1) & 3;
+            if self.irq_prescaler == 0 {
+                self.clock_irq();
+            }
+        }
+    }
+    fn irq_pending(&self) -> bool { self.irq_pending }
+}
+
+//
+// Mapper 68 (Sunsoft-4)
+//
+// Four 2K CHR banks, a 16K PRG bank at $8000 with the last bank fixed at $C000, and 8K of
+// PRG-RAM. Its oddity is that it can put 1K banks of CHR-ROM in place of the console's nametable
+// RAM, which After Burner uses for its backgrounds. The two banks stand in for the two pages of
+// nametable RAM, so the mirroring still decides which one each nametable shows.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_068
+//
+
+pub struct Sunsoft4 {
+    rom: Box<Rom>,
+    prg_ram: Box<[uint8_t; 8192]>,
+
+    chr_banks: [uint8_t; 4],        // $8000-$BFFF: 2K banks
+    nametable_banks: [uint8_t; 2],  // $C000, $D000: 1K banks from the last 128K of CHR-ROM
+    control: uint8_t,               // $E000: mirroring and nametables from CHR-ROM
+    prg_bank: uint8_t,              // $F000: 16K bank at $8000 and PRG-RAM enable
+}
+
+save_struct!(Sunsoft4 { prg_ram, chr_banks, nametable_banks, control, prg_bank });
+
+impl Sunsoft4 {
+    fn new(rom: Box<Rom>) -> Sunsoft4 {
+        Sunsoft4 {
+            rom: rom,
+            prg_ram: Box::new([ 0; 8192 ]),
+
+            chr_banks: [ 0, 0, 0, 0 ],
+            nametable_banks: [ 0, 0 ],
+            control: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool { (self.prg_bank & 0x10) != 0 }
+    fn chr_rom_nametables(&self) -> bool { (self.control & 0x10) != 0 }
+}
+
+impl Mapper for Sunsoft4 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            if self.prg_ram_enabled() { self.prg_ram[addr as uint & 0x1fff] } else { 0u8 }
+        } else if addr < 0xc000 {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint & 0x0f, 16384, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 16384);
+            bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[addr as uint & 0x1fff] = val;
+            }
+            return;
+        }
+        match addr & 0xf000 {
+            0x8000 => self.chr_banks[0] = val,
+            0x9000 => self.chr_banks[1] = val,
+            0xa000 => self.chr_banks[2] = val,
+            0xb000 => self.chr_banks[3] = val,
+            0xc000 => self.nametable_banks[0] = val,
+            0xd000 => self.nametable_banks[1] = val,
+            0xe000 => self.control = val,
+            _ => self.prg_bank = val,
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let bank = self.chr_banks[(addr as uint >> 11) & 3] as uint;
+        bank_loadb(self.rom.chr.as_slice(), bank, 2048, addr)
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 3 {
+            0 => Vertical,
+            1 => Horizontal,
+            2 => OneScreenLower,
+            _ => OneScreenUpper,
+        }
+    }
+
+    // The CHR-ROM banks take the places of the CIRAM pages. They always have the top bit set, so
+    // they come from the last 128K of a 256K CHR-ROM.
+    fn arrange_nametables(&self, map: &mut NametableMap) {
+        *map = NametableMap::from_mirroring(self.mirroring());
+        if !self.chr_rom_nametables() {
+            return;
+        }
+        let banks = bank_count(self.rom.chr.as_slice(), 1024);
+        for slot in map.slots.iter_mut() {
+            let page = match *slot { Ciram(page) => page, _ => 0 };
+            *slot = ChrRom((self.nametable_banks[page] as uint | 0x80) % cmp::max(banks, 1));
+        }
+    }
+    fn chr_rom_loadb(&mut self, offset: uint) -> uint8_t {
+        if offset < self.rom.chr.len() { self.rom.chr[offset] } else { 0 }
+    }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mapper 73 (VRC3)
+//
+// A 16K PRG bank at $8000 with the last bank fixed at $C000, 8K of PRG-RAM, 8K of CHR-RAM and a
+// 16-bit IRQ counter that counts CPU cycles up to $FFFF, then reloads from the latch. In 8-bit
+// mode only the low byte counts and reloads.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC3
+//
+
+pub struct Vrc3 {
+    rom: Box<Rom>,
+    prg_ram: Box<[uint8_t; 8192]>,
+    chr_ram: Box<[uint8_t; 8192]>,
+    prg_bank: uint8_t,          // $F000
+
+    irq_latch: uint16_t,        // $8000-$B000, four bits each
+    irq_counter: uint16_t,
+    irq_enabled: bool,
+    irq_enable_after_ack: bool,
+    irq_8_bit: bool,
+    irq_pending: bool,
+}
+
+save_struct!(Vrc3 {
+    prg_ram,
+    chr_ram,
+    prg_bank,
+    irq_latch,
+    irq_counter,
+    irq_enabled,
+    irq_enable_after_ack,
+    irq_8_bit,
+    irq_pending
+});
+
+impl Vrc3 {
+    fn new(rom: Box<Rom>) -> Vrc3 {
+        Vrc3 {
+            rom: rom,
+            prg_ram: Box::new([ 0; 8192 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+            prg_bank: 0,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_enable_after_ack: false,
+            irq_8_bit: false,
+            irq_pending: false,
+        }
+    }
+
+    // Returns true if the counter overflowed.
+    fn clock_irq(&mut self) -> bool {
+        if self.irq_8_bit {
+            let low = self.irq_counter & 0xff;
+            if low == 0xff {
+                self.irq_counter = (self.irq_counter & 0xff00) | (self.irq_latch & 0xff);
+                return true;
+            }
+            self.irq_counter += 1;
+            false
+        } else if self.irq_counter == 0xffff {
+            self.irq_counter = self.irq_latch;
+            true
+        } else {
+            self.irq_counter += 1;
+            false
+        }
+    }
+}
+
+impl Mapper for Vrc3 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff]
+        } else if addr < 0xc000 {
+            bank_loadb(self.rom.prg.as_slice(), self.prg_bank as uint, 16384, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 16384);
+            bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff] = val;
+            return;
+        }
+        match addr & 0xf000 {
+            0x8000 ... 0xb000 => {
+                let shift = ((addr as uint >> 12) & 3) * 4;
+                self.irq_latch = (self.irq_latch & !(0x0f << shift)) |
+                    ((val as uint16_t & 0x0f) << shift);
+            }
+            0xc000 => {
+                self.irq_enable_after_ack = (val & 0x01) != 0;
+                self.irq_enabled = (val & 0x02) != 0;
+                self.irq_8_bit = (val & 0x04) != 0;
+                self.irq_pending = false;
+                if self.irq_enabled {
+                    self.irq_counter = self.irq_latch;
+                }
+            }
+            0xd000 => {
+                self.irq_enabled = self.irq_enable_after_ack;
+                self.irq_pending = false;
+            }
+            0xf000 => self.prg_bank = val & 0x07,
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
+    fn mirroring(&self) -> Mirroring { self.rom.header.mirroring() }
+
+    // We don't have a per-cycle hook, so the counter catches up a scanline at a time.
+    fn next_scanline(&mut self) {
+        if !self.irq_enabled {
+            return;
+        }
+        for _ in range(0, CYCLES_PER_SCANLINE) {
+            if self.clock_irq() {
+                log!(log::Mapper, log::Debug, "VRC3 IRQ");
+                self.irq_pending = true;
+            }
+        }
+    }
+    fn irq_pending(&self) -> bool { self.irq_pending }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
+
+//
+// Mapper 75 (VRC1)
+//
+// Three 8K PRG banks with the last 8K fixed, and two 4K CHR banks whose fifth bits live in the
+// mirroring register.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC1
+//
+
+pub struct Vrc1 {
+    rom: Box<Rom>,
+    prg_banks: [uint8_t; 3],    // $8000, $A000, $C000
+    chr_banks: [uint8_t; 2],    // $E000 and $F000, with their high bits from $9000
+    horizontal_mirroring: bool,
+}
+
+save_struct!(Vrc1 { prg_banks, chr_banks, horizontal_mirroring });
+
+impl Vrc1 {
+    fn new(rom: Box<Rom>) -> Vrc1 {
+        Vrc1 {
+            rom: rom,
+            prg_banks: [ 0, 0, 0 ],
+            chr_banks: [ 0, 0 ],
+            horizontal_mirroring: false,
+        }
+    }
+}
+
+impl Mapper for Vrc1 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            0u8
+        } else if addr < 0xe000 {
+            let bank = self.prg_banks[(addr as uint - 0x8000) >> 13] as uint;
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 8192);
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        match addr & 0xf000 {
+            0x8000 => self.prg_banks[0] = val & 0x0f,
+            0x9000 => {
+                self.horizontal_mirroring = (val & 0x01) != 0;
+                self.chr_banks[0] = (self.chr_banks[0] & 0x0f) | ((val & 0x02) << 3);
+                self.chr_banks[1] = (self.chr_banks[1] & 0x0f) | ((val & 0x04) << 2);
+            }
+            0xa000 => self.prg_banks[1] = val & 0x0f,
+            0xc000 => self.prg_banks[2] = val & 0x0f,
+            0xe000 => self.chr_banks[0] = (self.chr_banks[0] & 0x10) | (val & 0x0f),
+            0xf000 => self.chr_banks[1] = (self.chr_banks[1] & 0x10) | (val & 0x0f),
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let bank = self.chr_banks[(addr as uint >> 12) & 1] as uint;
+        bank_loadb(self.rom.chr.as_slice(), bank, 4096, addr)
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        if self.horizontal_mirroring { Horizontal } else { Vertical }
+    }
+}
+
+//
+// Mapper 99 (VS. UniSystem)
+//
+// The arcade version of the NES. Games on the simplest board are NROM with a twist: bit 2 of the
+// controller strobe register, $4016, picks one of two 8K CHR banks, and on the few 40K games
+// also swaps the bank at $8000-$9FFF. The mainboard has 2K of work RAM at $6000 and 4K of
+// nametable RAM, for four separate nametables. Its eight DIP switches, read through $4016 and
+// $4017 along with the coin slots, are kept here, since each game has its own settings.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_099
+//
+
+pub struct VsUnisystem {
+    rom: Box<Rom>,
+    bank: uint8_t,      // Bit 2 of the last write to $4016
+    prg_ram: Box<[uint8_t; 2048]>,
+    nametables: Box<[uint8_t; 4096]>,
+    dip_switches: uint8_t,
+}
+
+save_struct!(VsUnisystem { bank, prg_ram, nametables, dip_switches });
+
+impl VsUnisystem {
+    fn new(rom: Box<Rom>) -> VsUnisystem {
+        VsUnisystem {
+            rom: rom,
+            bank: 0,
+            prg_ram: Box::new([ 0; 2048 ]),
+            nametables: Box::new([ 0; 4096 ]),
+            dip_switches: 0,
+        }
+    }
+}
+
+impl Mapper for VsUnisystem {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x7ff]
+        } else if addr < 0xa000 && self.rom.prg.len() > 32768 {
+            // The extra 8K of a 40K game is bank 4, after the usual 32K.
+            bank_loadb(self.rom.prg.as_slice(), self.bank as uint * 4, 8192, addr)
+        } else {
+            bank_loadb(self.rom.prg.as_slice(), (addr as uint >> 13) & 3, 8192, addr)
+        }
+    }
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr >= 0x6000 && addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x7ff] = val;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        bank_loadb(self.rom.chr.as_slice(), self.bank as uint, 8192, addr)
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    // Unused, since the board provides all four nametables.
+    fn mirroring(&self) -> Mirroring { Vertical }
+
+    fn arrange_nametables(&self, map: &mut NametableMap) {
+        map.slots = [ Cartridge(0), Cartridge(1), Cartridge(2), Cartridge(3) ];
+        map.watched = false;
+    }
+    fn nametable_loadb(&mut self, page: uint, offset: uint16_t) -> uint8_t {
+        self.nametables[(page << 10) | offset as uint]
+    }
+    fn nametable_storeb(&mut self, page: uint, offset: uint16_t, val: uint8_t) {
+        self.nametables[(page << 10) | offset as uint] = val;
+    }
+
+    fn controller_write(&mut self, val: uint8_t) { self.bank = (val >> 2) & 1 }
+
+    fn dip_switch_count(&self) -> uint { 8 }
+    fn dip_switches(&self) -> uint8_t { self.dip_switches }
+    fn set_dip_switches(&mut self, val: uint8_t) { self.dip_switches = val }
+}
+
+//
+// Mapper 105 (NES-EVENT, Nintendo World Championships 1990)
+//
+// An MMC1 driving two 128K PRG chips, plus a countdown timer whose length is set by four DIP
+// switches on the board. The timer runs whenever bit 4 of the CHR bank 0 register is clear and
+// raises an IRQ when time's up.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_105
+//
+
+pub struct Nwc {
+    rom: Box<Rom>,
+    regs: SxRegs,
+    prg_ram: Box<[uint8_t; 8192]>,
+    chr_ram: Box<[uint8_t; 8192]>,
+
+    // PRG is locked to the first 32K until the game clears and then sets the IRQ bit.
+    init_state: uint8_t,
+    timer: uint64_t,
+    dip_switches: uint8_t,
+}
+
+save_struct!(Nwc { regs, prg_ram, chr_ram, init_state, timer, dip_switches });
+
+impl Nwc {
+    fn new(rom: Box<Rom>) -> Nwc {
+        Nwc {
+            rom: rom,
+            regs: SxRegs::new(),
+            prg_ram: Box::new([ 0; 8192 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+
+            init_state: 0,
+            timer: 0,
+            dip_switches: 0,
+        }
+    }
+
+    fn timer_running(&self) -> bool { (self.regs.chr_bank_0 & 0x10) == 0 }
+
+    // The timer expires after (16 + DIP) * 2^25 CPU cycles: about five minutes, plus 37.5 seconds
+    // per step of the switches.
+    fn timer_limit(&self) -> uint64_t { (0x10 | self.dip_switches as uint64_t) << 25 }
+
+    // Returns the 16K bank for the given half of the $8000-$FFFF window.
+    fn prg_bank(&self, upper: bool) -> uint {
+        if self.init_state < 2 {
+            return if upper { 1 } else { 0 };
+        }
+
+        let chr_bank_0 = self.regs.chr_bank_0;
+        if (chr_bank_0 & 0x08) == 0 {
+            // First chip: 32K banks.
+            return (chr_bank_0 as uint & 0x06) | (upper as uint);
+        }
+
+        // Second chip: ordinary MMC1 banking.
+        let prg_bank = (self.regs.prg_bank as uint & 0x07) | 0x08;
+        match (self.regs.ctrl.prg_rom_mode(), upper) {
+            (Switch32K, _) => (prg_bank & 0x0e) | (upper as uint),
+            (FixFirstBank, false) => 0x08,
+            (FixFirstBank, true) => prg_bank,
+            (FixLastBank, false) => prg_bank,
+            (FixLastBank, true) => 0x0f,
+        }
+    }
+}
+
+impl Mapper for Nwc {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff]
+        } else {
+            let bank = self.prg_bank(addr >= 0xc000);
+            bank_loadb(self.rom.prg.as_slice(), bank, 16384, addr)
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            self.prg_ram[addr as uint & 0x1fff] = val;
+            return;
+        }
+
+        if !self.regs.storeb(addr, val) {
+            return;
+        }
+
+        let irq_bit = (self.regs.chr_bank_0 & 0x10) != 0;
+        if self.init_state == 0 && !irq_bit {
+            self.init_state = 1;
+        } else if self.init_state == 1 && irq_bit {
+            self.init_state = 2;
+        }
+        if irq_bit {
+            self.timer = 0;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t     { self.chr_ram[addr as uint]       }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) { self.chr_ram[addr as uint] = val }
+
+    fn mirroring(&self) -> Mirroring { self.regs.ctrl.mirroring() }
+
+    fn next_scanline(&mut self) {
+        if self.timer_running() {
+            self.timer = cmp::min(self.timer + CYCLES_PER_SCANLINE, self.timer_limit());
+        }
+    }
+
+    // The IRQ stays asserted until the game acknowledges it by setting the IRQ bit, which resets
+    // the timer.
+    fn irq_pending(&self) -> bool { self.timer_running() && self.timer >= self.timer_limit() }
+
+    fn dip_switch_count(&self) -> uint { 4 }
+    fn dip_switches(&self) -> uint8_t { self.dip_switches }
+    fn set_dip_switches(&mut self, val: uint8_t) { self.dip_switches = val & 0x0f }
+}
+
+//
+// Mapper 202 (150-in-1)
+//
+// A typical pirate multicart: a latch on the address bus picks a 16K PRG bank and a matching 8K
+// CHR bank, and can combine two PRG banks into one NROM-256-style 32K bank.
+//
+// See http://wiki.nesdev.com/w/index.php/INES_Mapper_202
+//
+
+pub struct Multicart150In1 {
+    rom: Box<Rom>,
+    latch: uint16_t,
+}
+
+save_struct!(Multicart150In1 { latch });
+
+impl Multicart150In1 {
+    fn new(rom: Box<Rom>) -> Multicart150In1 {
+        Multicart150In1 {
+            rom: rom,
+            latch: 0,
+        }
+    }
+
+    fn bank(&self) -> uint { (self.latch as uint >> 1) & 0x07 }
+
+    // Address lines 0 and 3 together select 32K mode.
+    fn prg_32k(&self) -> bool { (self.latch & 0x09) == 0x09 }
+}
+
+impl Mapper for Multicart150In1 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x8000 {
+            return 0u8;
+        }
+        let bank = if self.prg_32k() {
+            (self.bank() & 0x06) | ((addr as uint >> 14) & 1)
+        } else {
+            self.bank()
+        };
+        let len = self.rom.prg.len();
+        self.rom.prg[((bank * 16384) | (addr as uint & 0x3fff)) % len]
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, _: uint8_t) {
+        if addr >= 0x8000 {
+            self.latch = addr;
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let len = self.rom.chr.len();
+        self.rom.chr[((self.bank() * 8192) | (addr as uint & 0x1fff)) % len]
+    }
+    fn chr_storeb(&mut self, _: uint16_t, _: uint8_t) {}  // Can't store to CHR-ROM.
+
+    fn mirroring(&self) -> Mirroring {
+        if (self.latch & 1) == 0 { Vertical } else { Horizontal }
+    }
+
+}
@@ -8,11 +8,11 @@ use apu::Apu;
 use input::Input;
 use mapper::Mapper;
 use ppu::Ppu;
+use profile::MemProfiler;
 use util::Save;
 
-use libc::{uint8_t, uint16_t};
+use libc::{uint8_t, uint16_t, uint32_t};
 use std::cell::RefCell;
-use std::io::File;
 use std::rc::Rc;
 
 //
@@ -46,37 +46,80 @@ impl<M> MemUtil for M where M: Mem {
 }
 
 //
-// The NES' paltry 2KB of RAM
+// RAM
+//
+// RAM chips are usually smaller than the address range they're given, and only the address lines
+// they need are wired to them, so they answer at every address in the range, repeating every
+// `size` bytes. The NES' paltry 2KB of work RAM fills $0000-$1FFF four times over this way.
 //
 
-pub struct Ram { pub val: [uint8_t; 0x800] }
+/// The size of the console's work RAM.
+pub const WORK_RAM_SIZE: uint = 0x800;
 
-impl Deref<[uint8_t; 0x800]> for Ram {
-    fn deref(&self) -> &[uint8_t; 0x800] {
-        &self.val
-    }
+/// RAM that repeats through whatever address range it's mapped into.
+pub struct Ram {
+    val: Vec<uint8_t>,
 }
 
-impl DerefMut<[uint8_t; 0x800]> for Ram {
-    fn deref_mut(&mut self) -> &mut [uint8_t; 0x800] {
-        &mut self.val
+impl Ram {
+    /// Makes zero-filled RAM. The size must be a power of two, as it is for any real chip.
+    pub fn new(size: uint) -> Ram {
+        assert!(size != 0 && (size & (size - 1)) == 0, "RAM sizes are powers of two");
+        Ram { val: Vec::from_elem(size, 0) }
+    }
+
+    /// The console's own RAM, at $0000-$1FFF.
+    pub fn work_ram() -> Ram {
+        Ram::new(WORK_RAM_SIZE)
+    }
+
+    pub fn len(&self) -> uint {
+        self.val.len()
+    }
+
+    /// The byte of RAM that an address in its range reaches.
+    #[inline(always)]
+    pub fn offset(&self, addr: uint16_t) -> uint {
+        addr as uint & (self.val.len() - 1)
+    }
+
+    pub fn as_slice(&self) -> &[uint8_t] {
+        self.val.as_slice()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [uint8_t] {
+        self.val.as_mut_slice()
     }
 }
 
 impl Mem for Ram {
-    fn loadb(&mut self, addr: uint16_t) -> uint8_t     { self[addr as uint & 0x7ff] }
-    fn storeb(&mut self, addr: uint16_t, val: uint8_t) { self[addr as uint & 0x7ff] = val }
+    fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let offset = self.offset(addr);
+        self.val[offset]
+    }
+    fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        let offset = self.offset(addr);
+        self.val[offset] = val
+    }
 }
 
 impl Save for Ram {
-    fn save(&mut self, fd: &mut File) {
-        (*self).as_mut_slice().save(fd);
+    fn save(&mut self, fd: &mut Writer) {
+        self.val.as_mut_slice().save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
-        (*self).as_mut_slice().load(fd);
+    fn load(&mut self, fd: &mut Reader) {
+        self.val.as_mut_slice().load(fd);
     }
 }
 
+//
+// The expansion area
+//
+// $4020-$5FFF is free for the cartridge and the Famicom's expansion port to use. Nothing is
+// emulated on the expansion port, so only the mapper answers. Reads it doesn't answer return
+// whatever was last on the data bus.
+//
+
 //
 // The main CPU memory map
 //
@@ -87,6 +130,9 @@ pub struct MemMap {
     pub input: Input,
     pub mapper: Rc<RefCell<Box<Mapper+Send>>>,
     pub apu: Apu,
+    pub data_bus: uint8_t,  // The last value read or written, which is what open bus reads see
+    pub profiler: Option<Box<MemProfiler>>,
+    pub rom_crc: uint32_t,  // The game's `Rom::identity_crc32`, which saved states record
 }
 
 impl MemMap {
@@ -96,51 +142,235 @@ impl MemMap {
                apu: Apu)
                -> MemMap {
         MemMap {
-            ram: Ram {
-                val: [ 0, ..0x800 ]
-            },
+            ram: Ram::work_ram(),
             ppu: ppu,
             input: input,
             mapper: mapper,
             apu: apu,
+            data_bus: 0,
+            profiler: None,
+            rom_crc: 0,
+        }
+    }
+
+    /// The IRQ line, shared by the APU and the cartridge.
+    pub fn irq_line(&self) -> bool {
+        self.apu.irq_pending() || self.mapper.borrow().irq_pending()
+    }
+
+    fn expansion_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        self.mapper.borrow_mut().expansion_loadb(addr).unwrap_or(self.data_bus)
+    }
+
+    fn expansion_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        self.mapper.borrow_mut().expansion_storeb(addr, val);
+    }
+
+    // Lets the PPU know about a write to the cartridge that may have switched CHR banks or
+    // rearranged the nametables.
+    fn cartridge_written(&mut self, addr: uint16_t) {
+        if self.mapper.borrow().is_banking_register(addr) {
+            self.ppu.cartridge_written();
+        }
+    }
+
+    // On the VS. System, the controller ports have more to say: $4016 gives the service button,
+    // the first two DIP switches and the coin slots, and $4017 the other six DIP switches.
+    fn controller_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let val = self.input.loadb(addr);
+        if !self.input.vs_system {
+            return val;
+        }
+        let dip_switches = self.mapper.borrow().dip_switches();
+        if addr == 0x4016 {
+            val | self.input.vs_buttons | ((dip_switches & 0x03) << 3)
+        } else {
+            val | (dip_switches & 0xfc)
+        }
+    }
+
+    // The profiler's hooks, which are empty unless it's built in.
+    #[cfg(feature = "mem-profile")]
+    #[inline(always)]
+    fn profile_read(&mut self, addr: uint16_t, val: uint8_t) {
+        match self.profiler {
+            None => {}
+            Some(ref mut profiler) => profiler.read(addr, val),
+        }
+    }
+
+    #[cfg(not(feature = "mem-profile"))]
+    #[inline(always)]
+    fn profile_read(&mut self, _: uint16_t, _: uint8_t) {}
+
+    #[cfg(feature = "mem-profile")]
+    #[inline(always)]
+    fn profile_write(&mut self, addr: uint16_t, val: uint8_t) {
+        match self.profiler {
+            None => {}
+            Some(ref mut profiler) => profiler.write(addr, val),
         }
     }
+
+    #[cfg(not(feature = "mem-profile"))]
+    #[inline(always)]
+    fn profile_write(&mut self, _: uint16_t, _: uint8_t) {}
 }
 
 impl Mem for MemMap {
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
-        if addr < 0x2000 {
+        let val = if addr < 0x2000 {
             self.ram.loadb(addr)
         } else if addr < 0x4000 {
             self.ppu.loadb(addr)
-        } else if addr == 0x4016 {
-            self.input.loadb(addr)
+        } else if addr == 0x4016 || addr == 0x4017 {
+            self.controller_loadb(addr)
         } else if addr <= 0x4018 {
             self.apu.loadb(addr)
+        } else if addr < 0x4020 {
+            0   // Test mode registers; disabled on retail units.
         } else if addr < 0x6000 {
-            0   // FIXME: I think some mappers use regs in this area?
+            self.expansion_loadb(addr)
         } else {
             let mut mapper = self.mapper.borrow_mut();
             mapper.prg_loadb(addr)
-        }
+        };
+        self.data_bus = val;
+        self.profile_read(addr, val);
+        val
     }
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        self.data_bus = val;
+        self.profile_write(addr, val);
         if addr < 0x2000 {
             self.ram.storeb(addr, val)
         } else if addr < 0x4000 {
             self.ppu.storeb(addr, val)
         } else if addr == 0x4016 {
-            self.input.storeb(addr, val)
+            self.input.storeb(addr, val);
+            self.mapper.borrow_mut().controller_write(val);
+            // Only the VS. System switches banks here.
+            if self.input.vs_system {
+                self.ppu.cartridge_written();
+            }
         } else if addr <= 0x4018 {
             self.apu.storeb(addr, val)
+        } else if addr < 0x4020 {
+            // Nothing. These are test mode registers.
         } else if addr < 0x6000 {
-            // Nothing. FIXME: I think some mappers use regs in this area?
+            self.expansion_storeb(addr, val);
+            self.cartridge_written(addr);
         } else {
-            let mut mapper = self.mapper.borrow_mut();
-            mapper.prg_storeb(addr, val)
+            self.mapper.borrow_mut().prg_storeb(addr, val);
+            self.cartridge_written(addr);
         }
     }
 }
 
-save_struct!(MemMap { ram, ppu, apu });
+impl Save for MemMap {
+    fn save(&mut self, fd: &mut Writer) {
+        self.ram.save(fd);
+        self.ppu.save(fd);
+        self.apu.save(fd);
+        self.data_bus.save(fd);
+        self.input.save(fd);
+        self.mapper.borrow_mut().save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        self.ram.load(fd);
+        self.ppu.load(fd);
+        self.apu.load(fd);
+        self.data_bus.load(fd);
+        self.input.load(fd);
+        self.mapper.borrow_mut().load(fd);
+        self.ppu.cartridge_written();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Mem, MemMap, Ram, WORK_RAM_SIZE};
+
+    use apu::Apu;
+    use input::Input;
+    use mapper::{Mapper, Nrom};
+    use ppu::{Oam, Ppu, Vram};
+    use rom::Rom;
+
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A console with a blank NROM cartridge in it.
+    fn memmap() -> MemMap {
+        let mapper = Box::new(Nrom { rom: Box::new(Rom::blank()) }) as Box<Mapper+Send>;
+        let mapper = Rc::new(RefCell::new(mapper));
+        let ppu = Ppu::new(Vram::new(mapper.clone(), false), Oam::new());
+        let apu = Apu::new(None, mapper.clone());
+        MemMap::new(ppu, Input::new(), mapper, apu)
+    }
+
+    #[test]
+    fn work_ram_repeats_four_times() {
+        let mut mem = memmap();
+        for addr in range(0u, WORK_RAM_SIZE) {
+            mem.storeb(addr as u16, (addr ^ (addr >> 8)) as u8);
+        }
+        for addr in range(0u, 0x2000) {
+            let base = addr % WORK_RAM_SIZE;
+            assert_eq!(mem.loadb(addr as u16), (base ^ (base >> 8)) as u8);
+        }
+    }
+
+    #[test]
+    fn work_ram_writes_through_every_mirror() {
+        let mut mem = memmap();
+        for mirror in range(0u, 4) {
+            let val = 0x10 + mirror as u8;
+            for addr in range(0u, WORK_RAM_SIZE) {
+                mem.storeb((mirror * WORK_RAM_SIZE + addr) as u16, val);
+            }
+            assert!(mem.ram.as_slice().iter().all(|&byte| byte == val));
+        }
+    }
+
+    #[test]
+    fn smaller_ram_repeats_at_its_own_size() {
+        let mut ram = Ram::new(0x400);
+        ram.storeb(0x5123, 0xa5);
+        assert_eq!(ram.offset(0x5123), 0x123);
+        assert_eq!(ram.loadb(0x0123), 0xa5);
+        assert_eq!(ram.loadb(0x7d23), 0xa5);
+        assert_eq!(ram.as_slice()[0x123], 0xa5);
+    }
+
+    #[test]
+    #[should_fail]
+    fn ram_sizes_are_powers_of_two() {
+        Ram::new(0x600);
+    }
 
+    #[test]
+    fn last_mirror_reaches_ppudata() {
+        let mut mem = memmap();
+        mem.storeb(0x3ffe, 0x21);           // PPUADDR
+        mem.storeb(0x3ffe, 0x00);
+        mem.storeb(0x3fff, 0x5a);           // PPUDATA
+
+        mem.loadb(0x2002);
+        mem.storeb(0x2006, 0x21);
+        mem.storeb(0x2006, 0x00);
+        mem.loadb(0x2007);                  // The read buffer's stale byte.
+        assert_eq!(mem.loadb(0x2007), 0x5a);
+    }
+
+    #[test]
+    fn ppu_registers_leave_work_ram_alone() {
+        let mut mem = memmap();
+        for addr in range(0x2000u, 0x4000) {
+            mem.storeb(addr as u16, 0xff);
+            mem.loadb(addr as u16);
+        }
+        assert!(mem.ram.as_slice().iter().all(|&byte| byte == 0));
+    }
+}
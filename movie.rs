@@ -0,0 +1,231 @@
+//
+// sprocketnes/movie.rs
+//
+// Author: Patrick Walton
+//
+
+// Input movies: the first controller's buttons on every frame, recorded from the keyboard and
+// played back later. Emulation is deterministic, so playing a movie back from the same starting
+// point, whether that's power-on or a saved state, repeats the run exactly.
+//
+// A movie starts with a version header (see state.rs), and then is a series of records, each
+// starting with a byte giving its kind:
+//
+// * A frame: the buttons, in the bit order `Input::set_buttons` takes, then the VS. System's coin
+//   and service buttons, in $4016's bits, then the state checksum at the end of the frame, as a
+//   little-endian 32-bit number. Playback compares the checksums, so that a movie that no longer
+//   matches the emulator, or was started from the wrong state, is reported on the frame where
+//   things went wrong.
+//
+// * A state load: the length of the state, as a little-endian 32-bit number, then the state. A
+//   state loaded while recording is kept in the movie and loaded again at the same point during
+//   playback, so that retrying a tricky part doesn't desync the rest. The state is as it was in
+//   the state file, header and all. If it can't be loaded, playback stops there.
+
+use checksum::ChecksumStream;
+use cpu::Cpu;
+use input::Input;
+use mem::MemMap;
+use state;
+
+use libc::{uint8_t, uint16_t, uint32_t};
+use std::io::File;
+
+const MOVIE_MAGIC: &'static [u8] = b"SNMV";
+
+/// The version of the movie format, which goes up whenever the records change. The states in a
+/// movie have versions of their own. Format 1 frames are 6 bytes, counting the byte giving the
+/// record's kind, and format 2 added the VS. System's buttons, for 7. Before formats were
+/// numbered, a frame was a byte of buttons, and then 5 bytes once checksums were added; those
+/// movies have no header, so they're turned away.
+pub const MOVIE_FORMAT: uint16_t = 2;
+
+const FRAME: uint8_t = 0;
+const LOAD_STATE: uint8_t = 1;
+
+const FRAME_SIZE: uint = 7;
+const LOAD_STATE_HEADER_SIZE: uint = 5;
+
+enum Mode {
+    Playing(Vec<uint8_t>),
+    Recording(File),
+}
+
+pub enum MovieStatus {
+    Running,
+    Finished,           // Playback has run out of frames, and the keyboard has the controller back
+    Desynced(uint),     // Playback no longer matches the recording, as of this frame
+    Failed(String),     // Playback has stopped, for the given reason
+}
+
+pub struct Movie {
+    mode: Mode,
+    offset: uint,       // Where the next record to play starts
+    frame: uint,        // Frames since the movie started
+    checksums: ChecksumStream,
+    desynced: bool,     // Only the first desync is reported, since everything after it differs
+}
+
+fn read_le_u32(data: &[uint8_t], offset: uint) -> uint32_t {
+    let mut val = 0;
+    for i in range(0, 4) {
+        val |= (data[offset + i] as uint32_t) << (i * 8);
+    }
+    val
+}
+
+// Gives the keyboard back the controller at the end of playback.
+fn release_input(input: &mut Input) {
+    input.set_buttons(0);
+    input.vs_buttons = 0;
+    input.gamepad_locked = false;
+}
+
+impl Movie {
+    fn new(mode: Mode) -> Movie {
+        Movie { mode: mode, offset: 0, frame: 0, checksums: ChecksumStream::new(), desynced: false }
+    }
+
+    pub fn play(path: &Path) -> Result<Movie, String> {
+        let data = match File::open(path).read_to_end() {
+            Ok(data) => data,
+            Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+        };
+        let start = try!(state::read_header(data.as_slice(), MOVIE_MAGIC, MOVIE_FORMAT, "movie"));
+        let mut movie = Movie::new(Playing(data));
+        movie.offset = start;
+        Ok(movie)
+    }
+
+    pub fn record(path: &Path) -> Result<Movie, String> {
+        let mut file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("couldn't create {}: {}", path.display(), err)),
+        };
+        state::write_header(&mut file, MOVIE_MAGIC, MOVIE_FORMAT);
+        Ok(Movie::new(Recording(file)))
+    }
+
+    pub fn is_playing(&self) -> bool {
+        match self.mode {
+            Playing(_) => true,
+            Recording(_) => false,
+        }
+    }
+
+    /// Whether the movie still decides what happens: always while recording, and until the
+    /// frames run out while playing. Movies only hold buttons and state loads, so nothing else
+    /// that changes the console may happen meanwhile.
+    pub fn in_progress(&self) -> bool {
+        match self.mode {
+            Playing(ref data) => self.offset < data.len(),
+            Recording(_) => true,
+        }
+    }
+
+    /// Called before the first frame. During playback, this takes the controller away from the
+    /// keyboard.
+    pub fn start(&mut self, cpu: &mut Cpu<MemMap>) -> Result<(), String> {
+        if self.is_playing() {
+            cpu.mem.input.gamepad_locked = true;
+            try!(self.play_frame(cpu));
+        }
+        Ok(())
+    }
+
+    /// Called at the end of every frame.
+    pub fn end_frame(&mut self, cpu: &mut Cpu<MemMap>) -> MovieStatus {
+        let checksum = self.checksums.add_frame(cpu);
+        self.frame += 1;
+
+        let mut status = Running;
+        match self.mode {
+            Recording(ref mut file) => {
+                // The keyboard is only read between frames, so these were held for all of it.
+                file.write_u8(FRAME).unwrap();
+                file.write_u8(cpu.mem.input.buttons()).unwrap();
+                file.write_u8(cpu.mem.input.vs_buttons).unwrap();
+                file.write_le_u32(checksum).unwrap();
+                return Running;
+            }
+            Playing(ref data) => {
+                let offset = self.offset;
+                if offset + FRAME_SIZE <= data.len() && data[offset] == FRAME {
+                    self.offset += FRAME_SIZE;
+                    if !self.desynced && read_le_u32(data.as_slice(), offset + 3) != checksum {
+                        self.desynced = true;
+                        status = Desynced(self.frame);
+                    }
+                }
+            }
+        }
+
+        match self.play_frame(cpu) {
+            Ok(true) => {
+                match status {
+                    Running => status = Finished,
+                    _ => {}
+                }
+            }
+            Ok(false) => {}
+            Err(err) => status = Failed(err),
+        }
+        status
+    }
+
+    /// Called after a state has been loaded from a file. While recording, the movie keeps a copy.
+    pub fn state_loaded(&mut self, state: &[uint8_t]) {
+        match self.mode {
+            Recording(ref mut file) => {
+                file.write_u8(LOAD_STATE).unwrap();
+                file.write_le_u32(state.len() as uint32_t).unwrap();
+                file.write(state).unwrap();
+            }
+            Playing(_) => {}
+        }
+    }
+
+    // Loads any states recorded before the next frame, then sets its buttons. Returns true if
+    // there are no more frames. If a state can't be loaded, playback stops.
+    fn play_frame(&mut self, cpu: &mut Cpu<MemMap>) -> Result<bool, String> {
+        let data = match self.mode {
+            Playing(ref data) => data.as_slice(),
+            Recording(_) => return Ok(false),
+        };
+
+        loop {
+            let offset = self.offset;
+            if offset + LOAD_STATE_HEADER_SIZE > data.len() || data[offset] != LOAD_STATE {
+                break;
+            }
+            let start = offset + LOAD_STATE_HEADER_SIZE;
+            let end = start + read_le_u32(data, offset + 1) as uint;
+            if end > data.len() {
+                break;
+            }
+            match state::load(data.slice(start, end), cpu) {
+                Ok(()) => {}
+                Err(err) => {
+                    self.offset = data.len();
+                    release_input(&mut cpu.mem.input);
+                    return Err(format!("can't load the movie's state: {}", err));
+                }
+            }
+            cpu.mem.apu.state_loaded();
+            self.offset = end;
+        }
+
+        let offset = self.offset;
+        let input = &mut cpu.mem.input;
+        if offset + FRAME_SIZE <= data.len() && data[offset] == FRAME {
+            input.set_buttons(data[offset + 1]);
+            input.vs_buttons = data[offset + 2];
+            Ok(false)
+        } else if input.gamepad_locked {
+            release_input(input);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
@@ -9,25 +9,53 @@
 
 extern crate libc;
 extern crate sdl2;
+extern crate serialize;
+extern crate time;
 
 use libc::{int32_t, uint8_t};
 
 // NB: This must be first to pick up the macro definitions. What a botch.
 #[macro_escape]
 pub mod util;
+#[macro_escape]
+pub mod log;
 
+pub mod ab;
 pub mod apu;
 pub mod audio;
+pub mod batch;
+pub mod bot;
+pub mod checksum;
+pub mod compat;
+pub mod config;
 #[macro_escape]
 pub mod cpu;
+pub mod diag;
 pub mod disasm;
+pub mod eeprom;
+pub mod fds;
+pub mod ffmpeg;
+pub mod filter;
+pub mod genie;
 pub mod gfx;
 pub mod input;
 pub mod main;
 pub mod mapper;
 pub mod mem;
+pub mod movie;
+pub mod netplay;
+pub mod osd;
+pub mod png;
 pub mod ppu;
+pub mod profile;
+pub mod record;
+pub mod resample;
 pub mod rom;
+pub mod rtc;
+pub mod scroll;
+pub mod state;
+pub mod vrc7;
+pub mod wav;
 
 // C library support
 pub mod speex;
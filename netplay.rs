@@ -0,0 +1,516 @@
+//
+// sprocketnes/netplay.rs
+//
+// Author: Patrick Walton
+//
+
+// Two-player netplay over UDP. One player hosts and the other connects; the host plays the first
+// controller and the other player the second, each from their own keyboard.
+//
+// Both consoles run the same ROM in lockstep, so the only thing that has to cross the network is
+// each player's buttons, along with the VS. System's coin and service buttons, which both players
+// share. Buttons pressed before frame N are used on frame N plus the input delay,
+// on both consoles, which gives them that many frames to reach the other side. If they haven't
+// arrived in time, the console waits for them. A few frames of delay hides the round trip on most
+// connections.
+//
+// UDP drops packets, so each packet carries all the buttons the other side could still be waiting
+// for, and we send ours again while we wait. Each packet also carries our latest state checksum.
+// If the other side's checksum for a frame doesn't match ours, the consoles have desynced, and we
+// report the frame it happened on.
+//
+// Lockstep only works if both consoles start out the same, so the handshake compares the ROM, the
+// state the game starts from, if any, and whether the Game Genie is plugged in. While waiting on
+// the network, the window's events are still handled, so that the player can give up by closing
+// it.
+
+use checksum::ChecksumStream;
+use cpu::Cpu;
+use input::Input;
+use mem::MemMap;
+use util;
+
+use libc::{uint8_t, uint32_t, uint64_t};
+use std::io::net::ip::{SocketAddr, ToSocketAddr};
+use std::io::net::udp::UdpSocket;
+use std::io::{BufReader, IoError, IoResult, MemWriter, OtherIoError, standard_error};
+
+pub const DEFAULT_INPUT_DELAY: uint = 2;
+pub const MAX_INPUT_DELAY: uint = 30;
+
+// Buttons and checksums are kept for this many frames. It has to cover the furthest apart the two
+// consoles can get, which is a little over twice the input delay.
+const HISTORY: uint = 128;
+
+// How long to wait for a packet before sending ours again, and how long to wait before giving up
+// on the other player, in milliseconds.
+const RESEND_TIME: u64 = 20;
+const HELLO_RESEND_TIME: u64 = 500;
+const DISCONNECT_TIME: u64 = 10000;
+const CONNECT_TIME: u64 = 30000;
+const HOST_TIME: u64 = 600000;
+
+// How often the window's events are handled while connecting, in milliseconds.
+const PUMP_TIME: u64 = 50;
+
+const MAX_PACKET_SIZE: uint = 256;
+
+//
+// Packets
+//
+
+const HELLO: uint8_t = 1;
+const WELCOME: uint8_t = 2;
+const INPUTS: uint8_t = 3;
+
+// A player's buttons on a frame: their controller's, then the VS. System's, in $4016's bits.
+type Buttons = (uint8_t, uint8_t);
+
+/// What both consoles have to agree on before they can run in lockstep.
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    pub rom_crc: uint32_t,              // `Rom::identity_crc32`, which tells disks apart
+    pub state_crc: Option<uint32_t>,    // The CRC of the state file the game starts from
+    pub game_genie: bool,
+    pub sprite_overflow_bug: bool,      // Games can see it in PPUSTATUS
+}
+
+impl Settings {
+    // Says what the other player is playing with that we aren't, if anything.
+    fn difference(&self, other: &Settings) -> Option<&'static str> {
+        if self.rom_crc != other.rom_crc {
+            Some("a different ROM")
+        } else if self.state_crc != other.state_crc {
+            Some("a different starting state")
+        } else if self.game_genie != other.game_genie {
+            Some(if other.game_genie { "the Game Genie" } else { "no Game Genie" })
+        } else if self.sprite_overflow_bug != other.sprite_overflow_bug {
+            Some(if other.sprite_overflow_bug {
+                "the sprite overflow bug"
+            } else {
+                "no sprite overflow bug"
+            })
+        } else {
+            None
+        }
+    }
+
+    fn write(&self, writer: &mut Writer) {
+        writer.write_be_u32(self.rom_crc).unwrap();
+        match self.state_crc {
+            None => writer.write_u8(0).unwrap(),
+            Some(crc) => {
+                writer.write_u8(1).unwrap();
+                writer.write_be_u32(crc).unwrap();
+            }
+        }
+        writer.write_u8(self.game_genie as uint8_t).unwrap();
+        writer.write_u8(self.sprite_overflow_bug as uint8_t).unwrap();
+    }
+
+    fn read(reader: &mut Reader) -> IoResult<Settings> {
+        let rom_crc = try!(reader.read_be_u32());
+        let state_crc = if try!(reader.read_u8()) == 0 {
+            None
+        } else {
+            Some(try!(reader.read_be_u32()))
+        };
+        let game_genie = try!(reader.read_u8()) != 0;
+        let sprite_overflow_bug = try!(reader.read_u8()) != 0;
+        Ok(Settings {
+            rom_crc: rom_crc,
+            state_crc: state_crc,
+            game_genie: game_genie,
+            sprite_overflow_bug: sprite_overflow_bug,
+        })
+    }
+}
+
+enum Packet {
+    Hello(Settings),            // From the player connecting
+    Welcome(Settings, uint),    // The host's answer, with the input delay
+    // The buttons for a run of frames, given by the first, and the checksum of the latest frame
+    // the sender has finished, if any.
+    Inputs(uint, Vec<Buttons>, Option<(uint, uint32_t)>),
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<uint8_t> {
+        let mut writer = MemWriter::new();
+        match *self {
+            Hello(ref settings) => {
+                writer.write_u8(HELLO).unwrap();
+                settings.write(&mut writer);
+            }
+            Welcome(ref settings, delay) => {
+                writer.write_u8(WELCOME).unwrap();
+                settings.write(&mut writer);
+                writer.write_u8(delay as uint8_t).unwrap();
+            }
+            Inputs(first_frame, ref buttons, checksum) => {
+                writer.write_u8(INPUTS).unwrap();
+                writer.write_be_u32(first_frame as uint32_t).unwrap();
+                writer.write_u8(buttons.len() as uint8_t).unwrap();
+                for &(gamepad, vs) in buttons.iter() {
+                    writer.write_u8(gamepad).unwrap();
+                    writer.write_u8(vs).unwrap();
+                }
+                match checksum {
+                    None => writer.write_u8(0).unwrap(),
+                    Some((frame, checksum)) => {
+                        writer.write_u8(1).unwrap();
+                        writer.write_be_u32(frame as uint32_t).unwrap();
+                        writer.write_be_u32(checksum).unwrap();
+                    }
+                }
+            }
+        }
+        writer.into_inner()
+    }
+
+    fn decode(data: &[uint8_t]) -> IoResult<Packet> {
+        let mut reader = BufReader::new(data);
+        match try!(reader.read_u8()) {
+            HELLO => Ok(Hello(try!(Settings::read(&mut reader)))),
+            WELCOME => {
+                let settings = try!(Settings::read(&mut reader));
+                Ok(Welcome(settings, try!(reader.read_u8()) as uint))
+            }
+            INPUTS => {
+                let first_frame = try!(reader.read_be_u32()) as uint;
+                let count = try!(reader.read_u8()) as uint;
+                let mut buttons = Vec::with_capacity(count);
+                for _ in range(0, count) {
+                    let gamepad = try!(reader.read_u8());
+                    buttons.push((gamepad, try!(reader.read_u8())));
+                }
+                let checksum = if try!(reader.read_u8()) == 0 {
+                    None
+                } else {
+                    let frame = try!(reader.read_be_u32()) as uint;
+                    Some((frame, try!(reader.read_be_u32())))
+                };
+                Ok(Inputs(first_frame, buttons, checksum))
+            }
+            _ => Err(standard_error(OtherIoError)),
+        }
+    }
+}
+
+//
+// Per-frame history
+//
+
+// A value for each of the last `HISTORY` frames, or at least for those we've heard about.
+struct History<T> {
+    entries: Vec<Option<(uint, T)>>,
+}
+
+impl<T: Copy + Clone> History<T> {
+    fn new() -> History<T> {
+        History { entries: Vec::from_elem(HISTORY, None) }
+    }
+
+    fn get(&self, frame: uint) -> Option<T> {
+        match self.entries[frame % HISTORY] {
+            Some((entry_frame, val)) if entry_frame == frame => Some(val),
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, frame: uint, val: T) {
+        self.entries[frame % HISTORY] = Some((frame, val));
+    }
+}
+
+//
+// The session
+//
+
+pub enum NetplayStatus {
+    Running,
+    Desynced(uint),     // The consoles stopped matching at the end of this frame
+    Disconnected,       // The other player stopped answering
+}
+
+pub struct Netplay {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    player: uint,               // 0 if we're hosting, 1 if we connected
+    settings: Settings,
+    delay: uint,
+    frame: uint,                // The frame about to run, counting from 0
+
+    local_buttons: History<Buttons>,
+    remote_buttons: History<Buttons>,
+    checksums: ChecksumStream,
+    local_checksums: History<uint32_t>,
+    remote_checksums: History<uint32_t>,
+    last_checksum: Option<(uint, uint32_t)>,
+
+    desynced: bool,             // Only the first desync is reported, since everything after differs
+    desync_frame: Option<uint>, // A desync that hasn't been reported yet
+    last_heard: uint64_t,       // When the last packet from the other player came, in microseconds
+}
+
+fn io_error_string(err: IoError) -> String {
+    format!("{}", err)
+}
+
+impl Netplay {
+    fn new(socket: UdpSocket, peer: SocketAddr, player: uint, settings: Settings, delay: uint)
+           -> Netplay {
+        Netplay {
+            socket: socket,
+            peer: peer,
+            player: player,
+            settings: settings,
+            delay: delay,
+            frame: 0,
+
+            local_buttons: History::new(),
+            remote_buttons: History::new(),
+            checksums: ChecksumStream::new(),
+            local_checksums: History::new(),
+            remote_checksums: History::new(),
+            last_checksum: None,
+
+            desynced: false,
+            desync_frame: None,
+            last_heard: util::current_time_micros(),
+        }
+    }
+
+    /// Waits for another player to connect to the given UDP port. The host decides the input
+    /// delay. `quit_requested` handles the window's events while we wait, and says whether the
+    /// player has given up.
+    pub fn host(port: u16, settings: Settings, delay: uint, quit_requested: fn() -> bool)
+                -> Result<Netplay, String> {
+        let address = format!("0.0.0.0:{}", port);
+        let mut socket = try!(UdpSocket::bind(address.as_slice()).map_err(io_error_string));
+        println!("Waiting for the other player on port {}...", port);
+
+        let start = util::current_time_micros();
+        let mut buf = [ 0; MAX_PACKET_SIZE ];
+        while util::current_time_micros() - start < HOST_TIME * 1000 {
+            if quit_requested() {
+                return Err("gave up waiting for the other player".to_string());
+            }
+            socket.set_read_timeout(Some(PUMP_TIME));
+            let (len, peer) = match socket.recv_from(&mut buf) {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            match Packet::decode(buf.slice_to(len)) {
+                Ok(Hello(ref hello)) => {
+                    // Either way, the other player finds out what we're playing with.
+                    let welcome = Welcome(settings.clone(), delay).encode();
+                    try!(socket.send_to(welcome.as_slice(), peer).map_err(io_error_string));
+                    match settings.difference(hello) {
+                        None => {
+                            println!("{} connected", peer);
+                            return Ok(Netplay::new(socket, peer, 0, settings, delay));
+                        }
+                        Some(difference) => println!("{} tried to play with {}", peer, difference),
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(format!("nobody connected within {} minutes", HOST_TIME / 60000))
+    }
+
+    /// Connects to a host, given as `host:port`. `quit_requested` is as for `host`.
+    pub fn connect(address: &str, settings: Settings, quit_requested: fn() -> bool)
+                   -> Result<Netplay, String> {
+        let peer = try!(address.to_socket_addr().map_err(io_error_string));
+        let mut socket = try!(UdpSocket::bind("0.0.0.0:0").map_err(io_error_string));
+        println!("Connecting to {}...", peer);
+
+        let hello = Hello(settings.clone()).encode();
+        let start = util::current_time_micros();
+        let mut last_hello = None;
+        let mut buf = [ 0; MAX_PACKET_SIZE ];
+        while util::current_time_micros() - start < CONNECT_TIME * 1000 {
+            if quit_requested() {
+                return Err("gave up connecting".to_string());
+            }
+            let now = util::current_time_micros();
+            if last_hello.map_or(true, |sent| now - sent >= HELLO_RESEND_TIME * 1000) {
+                try!(socket.send_to(hello.as_slice(), peer).map_err(io_error_string));
+                last_hello = Some(now);
+            }
+            socket.set_read_timeout(Some(PUMP_TIME));
+            let (len, from) = match socket.recv_from(&mut buf) {
+                Ok(result) => result,
+                Err(_) => continue,
+            };
+            if from != peer {
+                continue;
+            }
+            match Packet::decode(buf.slice_to(len)) {
+                Ok(Welcome(ref host, delay)) => {
+                    match settings.difference(host) {
+                        None => {
+                            println!("Connected, with {} frames of input delay", delay);
+                            return Ok(Netplay::new(socket, peer, 1, settings, delay));
+                        }
+                        Some(difference) => {
+                            return Err(format!("the host is playing with {}", difference));
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(format!("{} didn't answer", peer))
+    }
+
+    /// Called before the first frame. This takes both controllers away from the keyboard.
+    pub fn start(&mut self, input: &mut Input) {
+        input.gamepad_locked = true;
+
+        // Nobody holds anything on the first frame, so this doesn't have to wait.
+        self.advance(input);
+    }
+
+    /// Called at the end of every frame. This sets up the controllers for the next one, which
+    /// means waiting for the other player if they've fallen behind.
+    pub fn end_frame(&mut self, cpu: &mut Cpu<MemMap>) -> NetplayStatus {
+        let checksum = self.checksums.add_frame(cpu);
+        let frame = self.frame;
+        self.local_checksums.set(frame, checksum);
+        self.last_checksum = Some((frame, checksum));
+        self.compare_checksums(frame);
+
+        self.frame += 1;
+        match self.advance(&mut cpu.mem.input) {
+            Running => {}
+            status => return status,
+        }
+        match self.desync_frame.take() {
+            Some(frame) => Desynced(frame),
+            None => Running,
+        }
+    }
+
+    /// Gives the keyboard back the first controller and the VS. System's buttons.
+    pub fn stop(self, input: &mut Input) {
+        input.gamepad_locked = false;
+        input.set_buttons(input.keyboard_buttons);
+        input.gamepad_1.set_buttons(0);
+        input.vs_buttons = input.keyboard_vs_buttons;
+    }
+
+    // The buttons a player holds on a frame. Nobody holds anything during the first few, before
+    // the input delay has passed.
+    fn buttons(&self, history: &History<Buttons>, frame: uint) -> Option<Buttons> {
+        if frame < self.delay {
+            Some((0, 0))
+        } else {
+            history.get(frame)
+        }
+    }
+
+    fn advance(&mut self, input: &mut Input) -> NetplayStatus {
+        let keyboard = (input.keyboard_buttons, input.keyboard_vs_buttons);
+        self.local_buttons.set(self.frame + self.delay, keyboard);
+        self.send_inputs();
+        self.receive(Some(0));
+
+        let remote;
+        loop {
+            match self.buttons(&self.remote_buttons, self.frame) {
+                Some(buttons) => {
+                    remote = buttons;
+                    break;
+                }
+                None => {}
+            }
+            if !self.receive(Some(RESEND_TIME)) {
+                if util::current_time_micros() - self.last_heard > DISCONNECT_TIME * 1000 {
+                    return Disconnected;
+                }
+                self.send_inputs();
+            }
+        }
+        let local = self.buttons(&self.local_buttons, self.frame).unwrap();
+
+        let (first, second) = if self.player == 0 { (local, remote) } else { (remote, local) };
+        let ((first_buttons, first_vs), (second_buttons, second_vs)) = (first, second);
+        input.gamepad_0.set_buttons(first_buttons);
+        input.gamepad_1.set_buttons(second_buttons);
+        input.vs_buttons = first_vs | second_vs;
+        Running
+    }
+
+    // Sends our buttons for every frame the other player might still be waiting on. They can be
+    // as far behind as a frame more than the input delay.
+    fn send_inputs(&mut self) {
+        let last = self.frame + self.delay;
+        let first = if last >= 2 * self.delay + 1 { last - 2 * self.delay - 1 } else { 0 };
+        let buttons = range(first, last + 1).map(|frame| {
+            self.buttons(&self.local_buttons, frame).unwrap_or((0, 0))
+        }).collect();
+        let packet = Inputs(first, buttons, self.last_checksum).encode();
+
+        // If this is lost, it'll be sent again.
+        let _ = self.socket.send_to(packet.as_slice(), self.peer);
+    }
+
+    // Handles the packets that arrive within the timeout. Returns true if any came from the other
+    // player.
+    fn receive(&mut self, mut timeout: Option<u64>) -> bool {
+        let mut heard = false;
+        let mut buf = [ 0; MAX_PACKET_SIZE ];
+        loop {
+            self.socket.set_read_timeout(timeout);
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(result) => result,
+                Err(_) => return heard,
+            };
+            if from != self.peer {
+                continue;
+            }
+            heard = true;
+            self.last_heard = util::current_time_micros();
+
+            match Packet::decode(buf.slice_to(len)) {
+                Ok(Hello(_)) => {
+                    // Our welcome was lost.
+                    let welcome = Welcome(self.settings.clone(), self.delay).encode();
+                    let _ = self.socket.send_to(welcome.as_slice(), self.peer);
+                }
+                Ok(Inputs(first_frame, buttons, checksum)) => {
+                    for (i, &val) in buttons.iter().enumerate() {
+                        self.remote_buttons.set(first_frame + i, val);
+                    }
+                    match checksum {
+                        None => {}
+                        Some((frame, checksum)) => {
+                            self.remote_checksums.set(frame, checksum);
+                            self.compare_checksums(frame);
+                        }
+                    }
+                }
+                Ok(Welcome(..)) | Err(_) => {}
+            }
+
+            // Once something has come in, just collect whatever else is already here.
+            timeout = Some(0);
+        }
+    }
+
+    fn compare_checksums(&mut self, frame: uint) {
+        if self.desynced {
+            return;
+        }
+        match (self.local_checksums.get(frame), self.remote_checksums.get(frame)) {
+            (Some(local), Some(remote)) if local != remote => {
+                self.desynced = true;
+                self.desync_frame = Some(frame + 1);
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,426 @@
+//
+// sprocketnes/osd.rs
+//
+// Author: Patrick Walton
+//
+
+// The on-screen display: text drawn over the emulated picture before it's shown. Anything that
+// needs to tell the player something goes through here: messages like "Saved state", which show
+// for a couple of seconds and slide away, the pause indicator, the frame rate counter, the debug
+// HUD, and the help overlay. The text goes on after the video filters, at whatever size they
+// made, so that cropping can't cut it off. The sprite 0 hit overlay marks the PPU's own pixels,
+// so it goes on before them.
+
+use ppu::SpriteZero;
+
+use libc::uint8_t;
+use std::cmp;
+
+const SCREEN_WIDTH: uint = 256;
+const SCREEN_HEIGHT: uint = 240;
+
+const FONT_HEIGHT: uint = 10;
+const FONT_GLYPH_COUNT: uint = 95;
+const FONT_GLYPH_LENGTH: uint = FONT_GLYPH_COUNT * FONT_HEIGHT;
+
+const STATUS_LINE_PADDING: uint = 6;
+const STATUS_LINE_X: uint = STATUS_LINE_PADDING;
+const STATUS_LINE_HEIGHT: uint = STATUS_LINE_PADDING + FONT_HEIGHT;  // How far it slides to go
+const STATUS_LINE_PAUSE_DURATION: uint = 120;                   // in 1/60 of a second
+
+// The pause indicator and the frame rate go in the corners at the top.
+const CORNER_PADDING: uint = 6;
+
+//
+// PT Ronda Seven
+//
+// (c) Yusuke Kamiyamane, http://pinvoke.com/
+//
+
+const FONT_GLYPHS: [uint8_t; FONT_GLYPH_LENGTH] = [
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,  // ' '
+      0,  64,  64,  64,  64,  64,   0,  64,   0,   0,  // '!'
+      0, 160, 160,   0,   0,   0,   0,   0,   0,   0,  // '"'
+      0,  80,  80, 248,  80, 248,  80,  80,   0,   0,  // '#'
+     32, 112, 168, 160, 112,  40, 168, 112,  32,   0,  // '$'
+      0,  66, 164,  72,  16,  36,  74, 132,   0,   0,  // '%'
+      0,  96, 144, 160,  72, 168, 144, 104,   0,   0,  // '&'
+      0, 128, 128,   0,   0,   0,   0,   0,   0,   0,  // '''
+     32,  64, 128, 128, 128, 128, 128,  64,  32,   0,  // '('
+    128,  64,  32,  32,  32,  32,  32,  64, 128,   0,  // ')'
+      0,  32, 168, 112, 168,  32,   0,   0,   0,   0,  // '*'
+      0,   0,  32,  32, 248,  32,  32,   0,   0,   0,  // '+'
+      0,   0,   0,   0,   0,   0,   0,  64,  64, 128,  // ','
+      0,   0,   0,   0,   0, 224,   0,   0,   0,   0,  // '-'
+      0,   0,   0,   0,   0,   0,   0,  64,   0,   0,  // '.'
+      8,   8,  16,  16,  32,  64,  64, 128, 128,   0,  // '/'
+      0, 112, 136, 136, 136, 136, 136, 112,   0,   0,  // '0'
+      0, 192,  64,  64,  64,  64,  64,  64,   0,   0,  // '1'
+      0, 112, 136,   8,  16,  32,  64, 248,   0,   0,  // '2'
+      0, 112, 136,   8,  48,   8, 136, 112,   0,   0,  // '3'
+      0,  48,  80,  80, 144, 248,  16,  16,   0,   0,  // '4'
+      0, 248, 128, 128, 240,   8, 136, 112,   0,   0,  // '5'
+      0, 112, 136, 128, 240, 136, 136, 112,   0,   0,  // '6'
+      0, 248,   8,  16,  16,  32,  32,  64,   0,   0,  // '7'
+      0, 112, 136, 136, 112, 136, 136, 112,   0,   0,  // '8'
+      0, 112, 136, 136, 120,   8, 136, 112,   0,   0,  // '9'
+      0,   0,   0,  64,   0,   0,   0,  64,   0,   0,  // ':'
+      0,   0,   0,  64,   0,   0,   0,  64,  64, 128,  // ';'
+      0,   0,  32,  64, 128,  64,  32,   0,   0,   0,  // '<'
+      0,   0,   0, 224,   0, 224,   0,   0,   0,   0,  // '='
+      0,   0, 128,  64,  32,  64, 128,   0,   0,   0,  // '>'
+      0, 112, 136,   8,  16,  32,   0,  32,   0,   0,  // '?'
+     60,  66, 157, 165, 165, 173, 149,  66,  56,   0,  // '@'
+      0, 112, 136, 136, 248, 136, 136, 136,   0,   0,  // 'A'
+      0, 240, 136, 136, 240, 136, 136, 240,   0,   0,  // 'B'
+      0, 112, 136, 128, 128, 128, 136, 112,   0,   0,  // 'C'
+      0, 240, 136, 136, 136, 136, 136, 240,   0,   0,  // 'D'
+      0, 248, 128, 128, 240, 128, 128, 248,   0,   0,  // 'E'
+      0, 248, 128, 128, 240, 128, 128, 128,   0,   0,  // 'F'
+      0, 112, 136, 128, 184, 136, 152, 104,   0,   0,  // 'G'
+      0, 136, 136, 136, 248, 136, 136, 136,   0,   0,  // 'H'
+      0, 128, 128, 128, 128, 128, 128, 128,   0,   0,  // 'I'
+      0,  16,  16,  16,  16,  16, 144,  96,   0,   0,  // 'J'
+      0, 136, 144, 160, 192, 160, 144, 136,   0,   0,  // 'K'
+      0, 128, 128, 128, 128, 128, 128, 240,   0,   0,  // 'L'
+      0, 130, 198, 170, 146, 130, 130, 130,   0,   0,  // 'M'
+      0, 136, 200, 168, 168, 168, 152, 136,   0,   0,  // 'N'
+      0, 112, 136, 136, 136, 136, 136, 112,   0,   0,  // 'O'
+      0, 240, 136, 136, 240, 128, 128, 128,   0,   0,  // 'P'
+      0, 112, 136, 136, 136, 136, 136, 112,   8,   0,  // 'Q'
+      0, 240, 136, 136, 240, 160, 144, 136,   0,   0,  // 'R'
+      0, 112, 136, 128, 112,   8, 136, 112,   0,   0,  // 'S'
+      0, 248,  32,  32,  32,  32,  32,  32,   0,   0,  // 'T'
+      0, 136, 136, 136, 136, 136, 136, 112,   0,   0,  // 'U'
+      0, 136, 136,  80,  80,  80,  32,  32,   0,   0,  // 'V'
+      0, 146, 146, 146, 146, 146, 146, 108,   0,   0,  // 'W'
+      0, 136, 136,  80,  32,  80, 136, 136,   0,   0,  // 'X'
+      0, 136, 136,  80,  32,  32,  32,  32,   0,   0,  // 'Y'
+      0, 248,   8,  16,  32,  64, 128, 248,   0,   0,  // 'Z'
+    224, 128, 128, 128, 128, 128, 128, 128, 224,   0,  // '['
+    128, 128,  64,  64,  32,  16,  16,   8,   8,   0,  // '\'
+    224,  32,  32,  32,  32,  32,  32,  32, 224,   0,  // ']'
+      0,  64, 160,   0,   0,   0,   0,   0,   0,   0,  // '^'
+      0,   0,   0,   0,   0,   0,   0, 224,   0,   0,  // '_'
+      0,   0,   0,   0,   0,   0,   0,   0,   0,   0,  // '`'
+      0,   0,   0, 112, 144, 144, 176,  80,   0,   0,  // 'a'
+      0, 128, 128, 160, 208, 144, 144, 224,   0,   0,  // 'b'
+      0,   0,   0,  96, 144, 128, 144,  96,   0,   0,  // 'c'
+      0,  16,  16, 112, 144, 144, 176,  80,   0,   0,  // 'd'
+      0,   0,   0,  96, 144, 240, 128,  96,   0,   0,  // 'e'
+      0,  96, 128, 192, 128, 128, 128, 128,   0,   0,  // 'f'
+      0,   0,   0, 112, 144, 144, 176,  80,  16,  96,  // 'g'
+      0, 128, 128, 160, 208, 144, 144, 144,   0,   0,  // 'h'
+      0, 128,   0, 128, 128, 128, 128, 128,   0,   0,  // 'i'
+      0, 128,   0, 128, 128, 128, 128, 128, 128,   0,  // 'j'
+      0, 128, 128, 144, 160, 192, 160, 144,   0,   0,  // 'k'
+      0, 128, 128, 128, 128, 128, 128, 128,   0,   0,  // 'l'
+      0,   0,   0, 164, 218, 146, 146, 146,   0,   0,  // 'm'
+      0,   0,   0, 160, 208, 144, 144, 144,   0,   0,  // 'n'
+      0,   0,   0,  96, 144, 144, 144,  96,   0,   0,  // 'o'
+      0,   0,   0, 160, 208, 144, 144, 224, 128, 128,  // 'p'
+      0,   0,   0, 112, 144, 144, 176,  80,  16,  16,  // 'q'
+      0,   0,   0, 160, 192, 128, 128, 128,   0,   0,  // 'r'
+      0,   0,   0,  96, 128,  64,  32, 192,   0,   0,  // 's'
+      0,  64,  64, 224,  64,  64,  64,  64,   0,   0,  // 't'
+      0,   0,   0, 144, 144, 144, 176,  80,   0,   0,  // 'u'
+      0,   0,   0, 144, 144, 144, 144,  96,   0,   0,  // 'v'
+      0,   0,   0, 146, 146, 146, 146, 108,   0,   0,  // 'w'
+      0,   0,   0, 144, 144,  96, 144, 144,   0,   0,  // 'x'
+      0,   0,   0, 144, 144, 144, 176,  80,  16,  96,  // 'y'
+      0,   0,   0, 224,  32,  64, 128, 224,   0,   0,  // 'z'
+     32,  64,  64,  64, 128,  64,  64,  64,  32,   0,  // '{'
+    128, 128, 128, 128, 128, 128, 128, 128, 128,   0,  // '|'
+    128,  64,  64,  64,  32,  64,  64,  64, 128,   0,  // '}'
+      0,  80, 160,   0,   0,   0,   0,   0,   0,   0,  // '~'
+];
+
+const FONT_ADVANCES: [uint8_t; FONT_GLYPH_COUNT] = [
+    3 /*   */, 3 /* ! */, 4 /* " */, 6 /* # */, 6 /* $ */, 8 /* % */, 6 /* & */, 2 /* ' */,
+    4 /* ( */, 4 /* ) */, 6 /* * */, 6 /* + */, 3 /* , */, 4 /* - */, 3 /* . */, 5 /* / */,
+    6 /* 0 */, 3 /* 1 */, 6 /* 2 */, 6 /* 3 */, 6 /* 4 */, 6 /* 5 */, 6 /* 6 */, 6 /* 7 */,
+    6 /* 8 */, 6 /* 9 */, 3 /* : */, 3 /* ; */, 4 /* < */, 4 /* = */, 4 /* > */, 6 /* ? */,
+    8 /* @ */, 6 /* A */, 6 /* B */, 6 /* C */, 6 /* D */, 6 /* E */, 6 /* F */, 6 /* G */,
+    6 /* H */, 2 /* I */, 5 /* J */, 6 /* K */, 5 /* L */, 8 /* M */, 6 /* N */, 6 /* O */,
+    6 /* P */, 6 /* Q */, 6 /* R */, 6 /* S */, 6 /* T */, 6 /* U */, 6 /* V */, 8 /* W */,
+    6 /* X */, 6 /* Y */, 6 /* Z */, 4 /* [ */, 6 /* \ */, 4 /* ] */, 4 /* ^ */, 4 /* _ */,
+    3 /* ` */, 5 /* a */, 5 /* b */, 5 /* c */, 5 /* d */, 5 /* e */, 3 /* f */, 5 /* g */,
+    5 /* h */, 2 /* i */, 2 /* j */, 5 /* k */, 2 /* l */, 8 /* m */, 5 /* n */, 5 /* o */,
+    5 /* p */, 5 /* q */, 4 /* r */, 4 /* s */, 4 /* t */, 5 /* u */, 5 /* v */, 8 /* w */,
+    5 /* x */, 5 /* y */, 4 /* z */, 4 /* { */, 2 /* | */, 4 /* } */, 5 /* ~ */,
+];
+
+//
+// Text output
+//
+
+enum GlyphColor {
+    White,
+    Black,
+}
+
+fn draw_glyph(pixels: &mut [uint8_t],
+              surface_width: uint,
+              x: int,
+              y: int,
+              color: GlyphColor,
+              glyph_index: uint) {
+    let color_byte = match color {
+        White => 0xff,
+        Black => 0x00,
+    };
+    for y_index in range(0, 10) {
+        let row = FONT_GLYPHS[glyph_index * 10 + y_index as uint];
+        for x_index in range(0, 8) {
+            if ((row >> (7 - x_index) as uint) & 1) != 0 {
+                for channel in range(0, 3) {
+                    let mut index = (y + y_index) * (surface_width as int) * 3 + (x + x_index) * 3;
+                    index += channel;
+
+                    if index >= 0 && index < pixels.len() as int {
+                        pixels[index as uint] = color_byte;
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_text(pixels: &mut [uint8_t], surface_width: uint, mut x: int, y: int, string: &str) {
+    for i in range(0, string.len()) {
+        let glyph_index = (string.as_bytes()[i] - 32) as uint;
+        if glyph_index < FONT_ADVANCES.len() {
+            draw_glyph(pixels, surface_width, x, y + 1, Black, glyph_index);    // Shadow
+            draw_glyph(pixels, surface_width, x, y, White, glyph_index);        // Main
+            x += FONT_ADVANCES[glyph_index] as int;
+        }
+    }
+}
+
+fn text_width(string: &str) -> uint {
+    string.bytes()
+          .map(|byte| (byte - 32) as uint)
+          .filter(|&glyph_index| glyph_index < FONT_ADVANCES.len())
+          .fold(0, |width, glyph_index| width + FONT_ADVANCES[glyph_index] as uint)
+}
+
+#[derive(PartialEq, Eq)]
+enum StatusLineAnimation {
+    Idle,
+    Pausing(uint),
+    SlidingOut(uint),       // How far it's slid down
+}
+
+struct StatusLineText {
+    string: String,
+    animation: StatusLineAnimation,
+}
+
+impl StatusLineText {
+    fn new() -> StatusLineText {
+        StatusLineText {
+            string: "".to_string(),
+            animation: Idle,
+        }
+    }
+
+    fn set(&mut self, string: String) {
+        self.string = string;
+        self.animation = Pausing(STATUS_LINE_PAUSE_DURATION);
+    }
+
+    fn tick(&mut self) {
+        self.animation = match self.animation {
+            Idle                      => Idle,
+            Pausing(0)                     => SlidingOut(0),
+            Pausing(time)                  => Pausing(time - 1),
+            SlidingOut(STATUS_LINE_HEIGHT) => Idle,
+            SlidingOut(y)                  => SlidingOut(y + 1),
+        }
+    }
+
+    fn render(&self, pixels: &mut [uint8_t], width: uint, height: uint) {
+        if self.animation == Idle {
+            return;
+        }
+        let slid = match self.animation {
+            Idle => panic!(),
+            SlidingOut(y) => y,
+            Pausing(_) => 0,
+        };
+        let y = (height - STATUS_LINE_HEIGHT + slid) as int;
+        draw_text(pixels, width, STATUS_LINE_X as int, y, self.string.as_slice());
+    }
+}
+
+struct StatusLine {
+    text: StatusLineText,
+}
+
+impl StatusLine {
+    fn new() -> StatusLine {
+        StatusLine {
+            text: StatusLineText::new(),
+        }
+    }
+    fn set(&mut self, new_text: String) {
+        self.text.set(new_text);
+    }
+    fn render(&self, pixels: &mut [uint8_t], width: uint, height: uint) {
+        self.text.render(pixels, width, height);
+    }
+}
+
+//
+// Help overlay
+//
+// Dims the screen and lists lines of text over it, in as many columns as it takes.
+//
+
+const HELP_PADDING: uint = 8;
+const HELP_COLUMN_WIDTH: uint = 120;
+
+fn render_help(pixels: &mut [uint8_t], width: uint, height: uint, lines: &[String]) {
+    for pixel in pixels.iter_mut() {
+        *pixel = *pixel / 4;
+    }
+
+    let (mut x, mut y) = (HELP_PADDING, HELP_PADDING);
+    for line in lines.iter() {
+        if y + FONT_HEIGHT > height - HELP_PADDING {
+            x += HELP_COLUMN_WIDTH;
+            y = HELP_PADDING;
+        }
+        draw_text(pixels, width, x as int, y as int, line.as_slice());
+        y += FONT_HEIGHT;
+    }
+}
+
+//
+// Sprite 0 hit overlay
+//
+// Shades sprite 0's bounding box blue and marks the pixel where it first hit the background in
+// red. A single pixel is easy to miss, so the ends of its line are marked too. Games time their
+// status bar splits off the hit, so this shows whether it happened where the game expected.
+//
+
+const HIT_MARKER_WIDTH: uint = 4;
+
+fn render_sprite_zero(pixels: &mut [uint8_t], sprite_zero: &SpriteZero) {
+    let bottom = cmp::min(sprite_zero.y + sprite_zero.height, SCREEN_HEIGHT);
+    let right = cmp::min(sprite_zero.x + 8, SCREEN_WIDTH);
+    for y in range(sprite_zero.y, bottom) {
+        for x in range(sprite_zero.x, right) {
+            // The screen is BGR.
+            let offset = (y * SCREEN_WIDTH + x) * 3;
+            pixels[offset] = pixels[offset] / 2 + 128;
+            pixels[offset + 1] /= 2;
+            pixels[offset + 2] /= 2;
+        }
+    }
+
+    match sprite_zero.hit {
+        None => {}
+        Some((hit_x, hit_y)) => {
+            let markers = range(0, HIT_MARKER_WIDTH).chain(range(SCREEN_WIDTH - HIT_MARKER_WIDTH,
+                                                                 SCREEN_WIDTH));
+            for x in markers.chain(Some(hit_x).into_iter()) {
+                let offset = (hit_y * SCREEN_WIDTH + x) * 3;
+                pixels[offset] = 0;
+                pixels[offset + 1] = 0;
+                pixels[offset + 2] = 255;
+            }
+        }
+    }
+}
+
+//
+// Debug HUD
+//
+// A few lines about the state of the machine, shown while a key is held. It goes in the top left
+// corner, under the frame rate.
+//
+
+fn render_debug_hud(pixels: &mut [uint8_t], width: uint, lines: &[String]) {
+    let mut y = CORNER_PADDING + FONT_HEIGHT;
+    for line in lines.iter() {
+        draw_text(pixels, width, CORNER_PADDING as int, y as int, line.as_slice());
+        y += FONT_HEIGHT;
+    }
+}
+
+//
+// The display as a whole
+//
+
+pub struct Osd {
+    status_line: StatusLine,
+    pub help: Option<Vec<String>>,  // The lines of the help overlay, while it's shown
+    pub paused: bool,
+    pub show_frame_rate: bool,
+    pub sprite_zero: Option<SpriteZero>,    // The last frame's sprite 0, while the overlay is on
+    pub debug_hud: Option<Vec<String>>,     // The lines of the debug HUD, while its key is held
+    frame_rate: String,
+}
+
+impl Osd {
+    pub fn new() -> Osd {
+        Osd {
+            status_line: StatusLine::new(),
+            help: None,
+            paused: false,
+            show_frame_rate: false,
+            sprite_zero: None,
+            debug_hud: None,
+            frame_rate: String::new(),
+        }
+    }
+
+    /// Shows a message for a couple of seconds.
+    pub fn message(&mut self, string: String) {
+        self.status_line.set(string);
+    }
+
+    /// Updates the frame rate counter: frames per second, and the milliseconds each frame took to
+    /// emulate and draw, not counting time spent waiting for the audio device.
+    pub fn set_frame_rate(&mut self, fps: f64, frame_time: f64) {
+        self.frame_rate = format!("{:.1} FPS, {:.1} ms", fps, frame_time);
+    }
+
+    // Called once per frame shown.
+    pub fn tick(&mut self) {
+        self.status_line.text.tick();
+    }
+
+    /// Draws what marks the PPU's picture itself, which has to go on before the filters.
+    pub fn render_picture(&self, pixels: &mut [uint8_t]) {
+        match self.sprite_zero {
+            None => {}
+            Some(ref sprite_zero) => render_sprite_zero(pixels, sprite_zero),
+        }
+    }
+
+    /// Draws the text over a picture of the given size, after the filters.
+    pub fn render(&self, pixels: &mut [uint8_t], width: uint, height: uint) {
+        match self.debug_hud {
+            None => {}
+            Some(ref lines) => render_debug_hud(pixels, width, lines.as_slice()),
+        }
+        match self.help {
+            None => {}
+            Some(ref lines) => render_help(pixels, width, height, lines.as_slice()),
+        }
+        if self.show_frame_rate {
+            draw_text(pixels,
+                      width,
+                      CORNER_PADDING as int,
+                      CORNER_PADDING as int,
+                      self.frame_rate.as_slice());
+        }
+        if self.paused {
+            let x = width - CORNER_PADDING - text_width("Paused");
+            draw_text(pixels, width, x as int, CORNER_PADDING as int, "Paused");
+        }
+        self.status_line.render(pixels, width, height);
+    }
+}
@@ -0,0 +1,116 @@
+//
+// sprocketnes/png.rs
+//
+// Author: Patrick Walton
+//
+
+// Writes screenshots as PNG files.
+//
+// The image data isn't compressed: it goes into "stored" deflate blocks. The files are bigger
+// than they need to be, but any PNG reader can open them, and identical screens always produce
+// byte-for-byte identical files, which is what scripts comparing screenshots want.
+//
+// Whatever the caller knows about where the screen came from goes into text chunks, so that the
+// picture can be made again from the same ROM, frame and settings.
+
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use util;
+
+use libc::{uint8_t, uint32_t};
+use std::io::{File, IoResult};
+use std::vec::Vec;
+
+const SIGNATURE: [u8; 8] = [ 0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a ];
+
+// The most a stored deflate block can hold.
+const MAX_STORED_BLOCK: uint = 65535;
+
+fn adler32(data: &[uint8_t]) -> uint32_t {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data.iter() {
+        a = (a + byte as uint32_t) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+fn push_u32_be(buffer: &mut Vec<uint8_t>, val: uint32_t) {
+    buffer.push((val >> 24) as uint8_t);
+    buffer.push((val >> 16) as uint8_t);
+    buffer.push((val >> 8) as uint8_t);
+    buffer.push(val as uint8_t);
+}
+
+fn write_chunk(file: &mut File, kind: &[u8], data: &[uint8_t]) -> IoResult<()> {
+    try!(file.write_be_u32(data.len() as uint32_t));
+    try!(file.write(kind));
+    try!(file.write(data));
+    file.write_be_u32(util::crc32_update(util::crc32(kind), data))
+}
+
+// Wraps data in a zlib stream without compressing it.
+fn zlib_stored(data: &[uint8_t]) -> Vec<uint8_t> {
+    let mut stream = vec![ 0x78, 0x01 ];
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    loop {
+        let block = match blocks.next() {
+            None => break,
+            Some(block) => block,
+        };
+        let len = block.len() as u16;
+        stream.push(if blocks.peek().is_none() { 1 } else { 0 });
+        stream.push(len as uint8_t);
+        stream.push((len >> 8) as uint8_t);
+        stream.push(!len as uint8_t);
+        stream.push((!len >> 8) as uint8_t);
+        stream.push_all(block);
+    }
+    push_u32_be(&mut stream, adler32(data));
+    stream
+}
+
+// A tEXt chunk's data: the keyword, a zero byte, then the text. Both are meant to be Latin-1, so
+// anything outside ASCII is replaced.
+fn text_chunk(keyword: &str, text: &str) -> Vec<uint8_t> {
+    let mut data: Vec<uint8_t> = keyword.bytes().collect();
+    data.push(0);
+    data.extend(text.chars().map(|c| if (c as u32) < 0x80 { c as uint8_t } else { b'?' }));
+    data
+}
+
+/// Saves a screen, in the PPU's BGR format, as a PNG file, with each of the keyword and text pairs
+/// in a text chunk.
+pub fn save_screenshot(path: &Path, screen: &[uint8_t], metadata: &[(&str, String)])
+                       -> IoResult<()> {
+    // Each row starts with its filter type, which is always "none" here.
+    let mut image = Vec::with_capacity(SCREEN_HEIGHT * (1 + SCREEN_WIDTH * 3));
+    for y in range(0, SCREEN_HEIGHT) {
+        image.push(0);
+        for x in range(0, SCREEN_WIDTH) {
+            let offset = (y * SCREEN_WIDTH + x) * 3;
+            image.push(screen[offset + 2]);
+            image.push(screen[offset + 1]);
+            image.push(screen[offset + 0]);
+        }
+    }
+
+    let mut header = Vec::new();
+    push_u32_be(&mut header, SCREEN_WIDTH as uint32_t);
+    push_u32_be(&mut header, SCREEN_HEIGHT as uint32_t);
+    header.push_all(&[
+        8,  // Bit depth
+        2,  // Color type: RGB
+        0,  // Compression method
+        0,  // Filter method
+        0,  // Interlace method
+    ]);
+
+    let mut file = try!(File::create(path));
+    try!(file.write(&SIGNATURE));
+    try!(write_chunk(&mut file, b"IHDR", header.as_slice()));
+    for &(keyword, ref text) in metadata.iter() {
+        try!(write_chunk(&mut file, b"tEXt", text_chunk(keyword, text.as_slice()).as_slice()));
+    }
+    try!(write_chunk(&mut file, b"IDAT", zlib_stored(image.as_slice()).as_slice()));
+    write_chunk(&mut file, b"IEND", &[])
+}
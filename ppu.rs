@@ -4,12 +4,14 @@
 // Author: Patrick Walton
 //
 
-use mapper::{Irq, Mapper};
+use mapper::{Cartridge, ChrRom, Ciram, FourScreen, Mapper, NametableMap};
+use log;
 use mem::Mem;
 use util::{Save, debug_assert};
 
-use libc::{uint8_t, uint16_t, uint64_t};
+use libc::{uint8_t, uint16_t, uint32_t, uint64_t};
 use std::cell::RefCell;
+use std::cmp;
 use std::io::File;
 use std::rc::Rc;
 
@@ -23,7 +25,26 @@ pub static CYCLES_PER_SCANLINE: uint64_t = 114;   // 29781 cycles per frame, 261
 pub static VBLANK_SCANLINE: uint = 241;
 pub static LAST_SCANLINE: uint = 261;
 
-static PALETTE: [uint8_t; 192] = [
+// The PPU runs three dots per CPU cycle.
+static DOTS_PER_SCANLINE: uint16_t = 342;
+
+// How long the bits of the I/O latch hold their value after being driven: about 600ms.
+static IO_LATCH_DECAY_CYCLES: uint64_t = 1073863;
+
+// We only know where the PPU was when the instruction reading $2002 began. Games poll $2002
+// with four-cycle absolute loads, which read on their last cycle, so we assume the read is this
+// many dots later.
+static STATUS_READ_DELAY_DOTS: int = 9;
+
+// There's no pre-render line of its own, so the last line of VBLANK stands in for it when it comes
+// to the fetches that mappers watch.
+static PRE_RENDER_SCANLINE: uint = LAST_SCANLINE - 1;
+
+// The MMC3 ignores rises of A12 that come less than this many dots after it was last high, so that
+// it counts one per scanline rather than one per pattern fetch.
+static A12_FILTER_DOTS: uint16_t = 8;
+
+pub static PALETTE: [uint8_t; 192] = [
     124,124,124,    0,0,252,        0,0,188,        68,40,188,
     148,0,132,      168,0,32,       168,16,0,       136,20,0,
     80,48,0,        0,120,0,        0,104,0,        0,88,0,
@@ -42,6 +63,67 @@ static PALETTE: [uint8_t; 192] = [
     0,252,252,      248,216,248,    0,0,0,          0,0,0
 ];
 
+/// Reads a palette in the usual `.pal` format: 64 RGB triples. Files that go on to give the colors
+/// under each combination of emphasis bits are accepted too, but only the first 64 are used.
+pub fn load_palette(path: &Path) -> Result<Vec<uint8_t>, String> {
+    let mut data = match File::open(path).read_to_end() {
+        Ok(data) => data,
+        Err(err) => return Err(format!("couldn't read {}: {}", path.display(), err)),
+    };
+    if data.len() != 192 && data.len() != 192 * 8 {
+        return Err(format!("{} is {} bytes, but palettes are 192 bytes, or 1536 with emphasis",
+                           path.display(),
+                           data.len()));
+    }
+    data.truncate(192);
+    Ok(data)
+}
+
+//
+// VS. System palettes
+//
+// The VS. System's PPUs drive an arcade monitor's RGB inputs directly. Most of them share one
+// palette, given here as three bits each of red, green and blue. The four RP2C04s have the same
+// colors, but each one shuffles the order, so that an operator couldn't just swap in another
+// game's ROMs. Their orders aren't built in: like the disk system BIOS, they're read from files,
+// `rp2c04-0001.pal` to `rp2c04-0004.pal`, and the RGB palette stands in when they're missing.
+//
+// The RC2C05s also swap PPUCTRL with PPUMASK and return an ID in PPUSTATUS. That isn't emulated.
+//
+
+static RGB_PALETTE: [uint16_t; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o674, 0o277, 0o000, 0o000, 0o000
+];
+
+/// The palette for a VS. System PPU, given its type from an NES 2.0 header, or None if the header
+/// doesn't say.
+pub fn vs_palette(ppu_type: Option<uint8_t>) -> Vec<uint8_t> {
+    match ppu_type {
+        Some(ppu_type @ 2...5) => {
+            match load_palette(&Path::new(format!("rp2c04-{:04}.pal", ppu_type - 1))) {
+                Ok(colors) => return colors,
+                Err(err) => log!(log::Ppu, log::Warn, "{}; using the RGB palette instead", err),
+            }
+        }
+        _ => {}
+    }
+
+    let mut colors = Vec::with_capacity(192);
+    for &color in RGB_PALETTE.iter() {
+        for &shift in [ 6u, 3, 0 ].iter() {
+            colors.push((((color >> shift) & 7) * 255 / 7) as uint8_t);
+        }
+    }
+    colors
+}
+
 //
 // Registers
 //
@@ -140,7 +222,6 @@ impl DerefMut<uint8_t> for PpuStatus {
 }
 
 impl PpuStatus {
-    // TODO: open bus junk in bits [0,5)
     fn set_sprite_overflow(&mut self, val: bool) {
         *self = if val { PpuStatus{ val: **self | 0x20 } }
         else { PpuStatus{ val: **self & !0x20} }
@@ -149,6 +230,7 @@ impl PpuStatus {
         *self = if val { PpuStatus{ val: **self | 0x40 } }
         else { PpuStatus{ val: **self & !0x40} }
     }
+    fn in_vblank(self) -> bool { (*self & 0x80) != 0 }
     fn set_in_vblank(&mut self, val: bool) {
         *self = if val { PpuStatus{ val: **self | 0x80 } }
         else { PpuStatus{ val: **self & !0x80} }
@@ -192,46 +274,133 @@ enum PpuAddrByte {
 
 save_enum!(PpuAddrByte { Hi, Lo });
 
+//
+// The pattern cache
+//
+// Decoded rows of background tiles, so that a tile's pattern bytes are fetched and taken apart
+// once rather than every time it's drawn. Each row is eight two-bit pixels, leftmost in the top
+// bits. CHR writes and bank switches make the whole cache stale; rather than clearing it, we bump
+// a generation number, and rows stamped with an older one are refetched.
+//
+
+const PATTERN_ROW_COUNT: uint = 512 * 8;    // 512 tiles of 8 rows, in both pattern tables
+
+struct PatternCache {
+    rows: Box<[uint16_t; PATTERN_ROW_COUNT]>,
+    stamps: Box<[uint32_t; PATTERN_ROW_COUNT]>,
+    generation: uint32_t,
+}
+
+impl PatternCache {
+    fn new() -> PatternCache {
+        PatternCache {
+            rows: Box::new([ 0; PATTERN_ROW_COUNT ]),
+            stamps: Box::new([ 0; PATTERN_ROW_COUNT ]),
+            generation: 1,
+        }
+    }
+
+    fn invalidate(&mut self) {
+        self.generation += 1;
+        if self.generation == 0 {
+            // Wrapped around, so old stamps could look current again.
+            for stamp in self.stamps.iter_mut() {
+                *stamp = 0;
+            }
+            self.generation = 1;
+        }
+    }
+}
+
+// Interleaves the two bit planes of a pattern row into eight two-bit pixels.
+fn decode_pattern_row(plane0: uint8_t, plane1: uint8_t) -> uint16_t {
+    let mut row = 0;
+    for bit in range(0u, 8).rev() {
+        let pixel = (((plane1 >> bit) & 1) << 1) | ((plane0 >> bit) & 1);
+        row = (row << 2) | pixel as uint16_t;
+    }
+    row
+}
+
 // PPU VRAM. This implements the same Mem trait that the CPU memory does.
 
+// The PPU's address bus is 14 bits wide, so $4000-$FFFF mirror $0000-$3FFF.
+const VRAM_ADDRESS_MASK: uint16_t = 0x3fff;
+
 pub struct Vram {
     pub mapper: Rc<RefCell<Box<Mapper+Send>>>,
-    pub nametables: [uint8_t; 0x800],  // 2 nametables, 0x400 each. FIXME: Not correct for all mappers.
+    pub nametables: [uint8_t; 0x800],  // 2 nametables, 0x400 each
+    pub four_screen_ram: Box<[uint8_t; 0x1000]>,    // Unused unless the board is four-screen
     pub palette: [uint8_t; 0x20],
+    nametable_map: NametableMap,
+    pattern_cache: PatternCache,
 }
 
 impl Vram {
-    pub fn new(mapper: Rc<RefCell<Box<Mapper+Send>>>) -> Vram {
+    pub fn new(mapper: Rc<RefCell<Box<Mapper+Send>>>, four_screen: bool) -> Vram {
+        let mut nametable_map = if four_screen {
+            NametableMap::four_screen()
+        } else {
+            NametableMap::from_mirroring(mapper.borrow().mirroring())
+        };
+        mapper.borrow().arrange_nametables(&mut nametable_map);
         Vram {
             mapper: mapper,
             nametables: [ 0, ..0x800 ],
-            palette: [ 0, ..0x20 ]
+            four_screen_ram: Box::new([ 0, ..0x1000 ]),
+            palette: [ 0, ..0x20 ],
+            nametable_map: nametable_map,
+            pattern_cache: PatternCache::new(),
         }
     }
+
+    // Asks the mapper where each nametable comes from now.
+    fn arrange_nametables(&mut self) {
+        self.mapper.borrow().arrange_nametables(&mut self.nametable_map);
+    }
 }
 
 impl Mem for Vram {
     #[inline(always)]
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
+        let addr = addr & VRAM_ADDRESS_MASK;
         if addr < 0x2000 {          // Tilesets 0 or 1
             let mut mapper = self.mapper.borrow_mut();
             mapper.chr_loadb(addr)
         } else if addr < 0x3f00 {   // Name table area
-            self.nametables[addr as uint & 0x07ff]
-        } else if addr < 0x4000 {   // Palette area
+            let offset = addr & 0x3ff;
+            let val = match self.nametable_map.slots[(addr as uint >> 10) & 3] {
+                Ciram(page) => self.nametables[(page << 10) | offset as uint],
+                FourScreen(page) => self.four_screen_ram[(page << 10) | offset as uint],
+                Cartridge(page) => self.mapper.borrow_mut().nametable_loadb(page, offset),
+                ChrRom(bank) => {
+                    self.mapper.borrow_mut().chr_rom_loadb((bank << 10) | offset as uint)
+                }
+            };
+            if self.nametable_map.watched {
+                self.mapper.borrow_mut().nametable_fetched(addr, val)
+            } else {
+                val
+            }
+        } else {                    // Palette area
             self.palette[addr as uint & 0x1f]
-        } else {
-            panic!("invalid VRAM read")
         }
     }
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        let addr = addr & VRAM_ADDRESS_MASK;
         if addr < 0x2000 {
+            self.pattern_cache.invalidate();
             let mut mapper = self.mapper.borrow_mut();
             mapper.chr_storeb(addr, val)
         } else if addr < 0x3f00 {           // Name table area
-            let addr = addr & 0x07ff;
-            self.nametables[addr as uint] = val;
-        } else if addr < 0x4000 {   // Palette area
+            let offset = addr & 0x3ff;
+            match self.nametable_map.slots[(addr as uint >> 10) & 3] {
+                Ciram(page) => self.nametables[(page << 10) | offset as uint] = val,
+                FourScreen(page) => self.four_screen_ram[(page << 10) | offset as uint] = val,
+                Cartridge(page) => self.mapper.borrow_mut().nametable_storeb(page, offset, val),
+                ChrRom(_) => {}
+            }
+        } else {                    // Palette area
             let mut addr = addr & 0x1f;
             if addr == 0x10 {
                 addr = 0x00;    // Mirror sprite background color into universal background color.
@@ -241,18 +410,47 @@ impl Mem for Vram {
     }
 }
 
+impl Vram {
+    // Loads a byte of pattern data for rendering. Unlike a load through PPUDATA, this tells the
+    // mapper whether the PPU is fetching sprites or background.
+    #[inline(always)]
+    fn fetch_pattern(&mut self, addr: uint16_t, sprite: bool) -> uint8_t {
+        let mut mapper = self.mapper.borrow_mut();
+        mapper.chr_fetch(addr, sprite)
+    }
+
+    // Returns a row of a background tile, decoded, from the pattern cache. Bit 8 of the tile
+    // index selects the pattern table.
+    fn background_pattern_row(&mut self, tile: uint16_t, y: uint8_t) -> uint16_t {
+        let index = tile as uint * 8 + y as uint;
+        if self.pattern_cache.stamps[index] != self.pattern_cache.generation {
+            let addr = (tile << 4) + (y as uint16_t);
+            let plane0 = self.fetch_pattern(addr, false);
+            let plane1 = self.fetch_pattern(addr + 8, false);
+            self.pattern_cache.rows[index] = decode_pattern_row(plane0, plane1);
+            self.pattern_cache.stamps[index] = self.pattern_cache.generation;
+        }
+        self.pattern_cache.rows[index]
+    }
+}
+
 impl Save for Vram {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         let mut nametables: &mut [uint8_t] = self.nametables;
         nametables.save(fd);
+        let mut four_screen_ram: &mut [uint8_t] = &mut *self.four_screen_ram;
+        four_screen_ram.save(fd);
         let mut palette: &mut [uint8_t] = self.palette;
         palette.save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         let mut nametables: &mut [uint8_t] = self.nametables;
         nametables.load(fd);
+        let mut four_screen_ram: &mut [uint8_t] = &mut *self.four_screen_ram;
+        four_screen_ram.load(fd);
         let mut palette: &mut [uint8_t] = self.palette;
         palette.load(fd);
+        self.pattern_cache.invalidate();
     }
 }
 
@@ -276,16 +474,76 @@ impl Mem for Oam {
 }
 
 impl Save for Oam {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         let mut oam: &mut [uint8_t] = self.oam;
         oam.save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         let mut oam: &mut [uint8_t] = self.oam;
         oam.load(fd);
     }
 }
 
+/// The scroll position a scanline was drawn with, as a position in the 512x480 square of all four
+/// nametables. The PPU records one for each visible line, for debugging split-screen effects.
+pub struct LineScroll {
+    pub x: uint16_t,
+    pub y: uint16_t,
+}
+
+impl LineScroll {
+    /// The nametable the line starts in, numbered as in bits 0-1 of PPUCTRL.
+    pub fn nametable(&self) -> uint16_t { (self.x / 256) | ((self.y / 240) << 1) }
+    pub fn coarse_x(&self) -> uint16_t  { (self.x % 256) / 8 }
+    pub fn fine_x(&self) -> uint16_t    { self.x % 8 }
+    pub fn coarse_y(&self) -> uint16_t  { (self.y % 240) / 8 }
+    pub fn fine_y(&self) -> uint16_t    { self.y % 8 }
+}
+
+/// Where sprite 0 was at the end of a frame's visible lines, and where in the frame it first hit
+/// the background, for the sprite 0 hit overlay.
+pub struct SpriteZero {
+    pub x: uint,
+    pub y: uint,                    // The first line it's on
+    pub height: uint,
+    pub hit: Option<(uint, uint)>,  // The dot and line of the hit, if there was one
+}
+
+// The state of sprite evaluation, which finds the sprites for the next scanline while the
+// current one is drawn.
+struct SpriteEval {
+    sprites: [uint8_t; 8],  // Secondary OAM: indices of the sprites found so far
+    count: uint8_t,
+    index: uint8_t,         // The next sprite in OAM to check
+    byte: uint8_t,          // The byte of that sprite to check as a Y position
+    wait: uint8_t,          // Dots until the next check
+}
+
+impl SpriteEval {
+    fn new() -> SpriteEval {
+        SpriteEval { sprites: [ 0; 8 ], count: 0, index: 0, byte: 0, wait: 0 }
+    }
+}
+
+impl Save for SpriteEval {
+    fn save(&mut self, fd: &mut Writer) {
+        let mut sprites: &mut [uint8_t] = self.sprites;
+        sprites.save(fd);
+        self.count.save(fd);
+        self.index.save(fd);
+        self.byte.save(fd);
+        self.wait.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let mut sprites: &mut [uint8_t] = self.sprites;
+        sprites.load(fd);
+        self.count.load(fd);
+        self.index.load(fd);
+        self.byte.load(fd);
+        self.wait.load(fd);
+    }
+}
+
 struct SpriteStruct {
     x: uint8_t,
     y: uint8_t,
@@ -293,7 +551,7 @@ struct SpriteStruct {
     attribute_byte: uint8_t,
 }
 
-// Specifies the indices of the tiles that make up this sprite.
+// Specifies the indices of the tiles that make up this sprite. Bit 8 selects the pattern table.
 enum SpriteTiles {
     SpriteTiles8x8(uint16_t),
     SpriteTiles8x16(uint16_t, uint16_t)
@@ -303,12 +561,12 @@ impl SpriteStruct {
     fn tiles(&self, ppu: &Ppu) -> SpriteTiles {
         let base = ppu.regs.ctrl.sprite_pattern_table_addr();
         match ppu.regs.ctrl.sprite_size() {
-            SpriteSize8x8 => SpriteTiles8x8(self.tile_index_byte as uint16_t | base),
+            SpriteSize8x8 => SpriteTiles8x8(self.tile_index_byte as uint16_t | (base >> 4)),
             SpriteSize8x16 => {
                 // We ignore the base set in PPUCTRL here.
                 let mut first = (self.tile_index_byte & !1) as uint16_t;
                 if (self.tile_index_byte & 1) != 0 {
-                    first += 0x1000;
+                    first += 0x100;
                 }
                 SpriteTiles8x16(first, first + 1)
             }
@@ -338,6 +596,19 @@ impl SpriteStruct {
     }
 }
 
+// Eight pixels of background, from a single tile, ready to draw. Transparent pixels are None.
+struct BackgroundRun {
+    valid: bool,
+    tile_x: uint16_t,
+    colors: [Option<Rgb>; 8],
+}
+
+impl BackgroundRun {
+    fn new() -> BackgroundRun {
+        BackgroundRun { valid: false, tile_x: 0, colors: [ None; 8 ] }
+    }
+}
+
 // The main PPU structure. This structure is separate from the PPU memory just as the CPU is.
 
 pub struct Ppu {
@@ -346,62 +617,146 @@ pub struct Ppu {
     oam: Oam,
 
     pub screen: Box<[uint8_t; 184320]>,  // 256 * 240 * 3
+    pub indices: Box<[uint8_t; 61440]>,  // The palette index of each pixel on the screen
     scanline: uint16_t,
+    dot: uint16_t,              // The next dot to draw on this scanline
     ppudata_buffer: uint8_t,
 
+    // The I/O latch ("open bus"), and when each of its bits was last driven.
+    io_latch: uint8_t,
+    io_latch_refreshed: [uint64_t; 8],
+
+    // Set when $2002 is read just as VBLANK starts, which keeps the flag and NMI from happening.
+    suppress_vblank: bool,
+
     // NB: These two cannot always be computed from PPUCTRL and PPUSCROLL, because PPUADDR *also*
     // updates the scroll position. This is important to emulate.
     scroll_x: uint16_t,
     scroll_y: uint16_t,
 
-    cy: uint64_t
+    // The scroll position the current scanline is drawn with. Like the hardware, we pick up scroll
+    // changes after the visible part of a scanline, so a change mid-line affects the next line.
+    line_scroll_x: uint16_t,
+    line_scroll_y: uint16_t,
+
+    // The sprites on the current scanline, and the search for those on the next.
+    line_sprites: SpriteEval,
+    sprite_eval: SpriteEval,
+
+    cy: uint64_t,
+
+    // Frames since power on. It's saved, so that a frame number means the same thing whether the
+    // run started from power on, a state, or a movie.
+    pub frame: uint64_t,
+
+    // Whether to emulate the hardware's faulty sprite overflow check, as opposed to setting the
+    // flag whenever there are more than 8 sprites on a scanline.
+    pub sprite_overflow_bug: bool,
+
+    // The scroll position of each visible line of the last frame. Not saved.
+    pub line_scrolls: [LineScroll; 240],
+
+    // Sprite 0 in the last frame, and where it has hit the background in this one. Not saved.
+    pub sprite_zero: SpriteZero,
+    sprite_zero_hit: Option<(uint, uint)>,
+
+    // The RGB color of each of the 64 entries the palette RAM can select. It isn't saved, since
+    // it's a matter of taste rather than part of the console's state.
+    pub colors: [uint8_t; 192],
+
+    // The background is normally drawn a tile at a time, from the pattern cache. A register or
+    // cartridge write in the middle of a scanline could change what the rest of it looks like,
+    // so once that happens we draw the remainder of the line a dot at a time instead.
+    background_run: BackgroundRun,
+    line_dirty: bool,
+
+    // Games sometimes turn rendering off partway through a frame. The PPU's vertical scroll
+    // counter only advances on lines it renders, so each line drawn with rendering off moves the
+    // rest of the background up by one.
+    skipped_lines: uint16_t,
+
+    // Turning rendering off during sprite evaluation corrupts OAM: the next time sprites are
+    // evaluated, the row being read when rendering stopped is overwritten with the first row.
+    oam_corrupt_row: Option<uint>,
+
+    // The level of address line A12 and how many dots it has been low, followed for mappers that
+    // count its rises.
+    a12_watched: bool,
+    a12_high: bool,
+    a12_low_dots: uint16_t,
+}
+
+// The PPU only decodes the low three bits of a CPU address, so its eight registers repeat every
+// 8 bytes from $2000 to $3FFF. Returns which of the eight the address selects.
+#[inline(always)]
+fn register(addr: uint16_t) -> uint16_t {
+    addr & 7
 }
 
 impl Mem for Ppu {
     // Performs a load of the PPU register at the given CPU address.
+    //
+    // Bits that a register doesn't drive come from the I/O latch, which holds whatever was last
+    // on the PPU's data bus.
     fn loadb(&mut self, addr: uint16_t) -> uint8_t {
         debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
-        match addr & 7 {
-            0 => *self.regs.ctrl,
-            1 => *self.regs.mask,
-            2 => self.read_ppustatus(),
-            3 => 0, // OAMADDR is read-only
-            4 => panic!("OAM read unimplemented"),
-            5 => 0, // PPUSCROLL is read-only
-            6 => 0, // PPUADDR is read-only
-            7 => self.read_ppudata(),
-            _ => panic!("can't happen")
-        }
+        let open_bus = self.io_latch();
+        let (val, driven) = match register(addr) {
+            2 => (self.read_ppustatus(), 0xe0),
+            4 => (self.read_oamdata(), 0xff),
+            7 => {
+                // Palette entries are only 6 bits wide.
+                let driven = if (self.regs.addr.val & 0x3fff) >= 0x3f00 { 0x3f } else { 0xff };
+                (self.read_ppudata(), driven)
+            }
+            _ => (0, 0),    // The rest are write-only.
+        };
+        self.refresh_io_latch(val, driven);
+        (val & driven) | (open_bus & !driven)
     }
 
     // Performs a store to the PPU register at the given CPU address.
     fn storeb(&mut self, addr: uint16_t, val: uint8_t) {
         debug_assert(addr >= 0x2000 && addr < 0x4000, "invalid PPU register");
-        match addr & 7 {
+        let register = register(addr);
+        log!(log::Ppu, log::Trace, "${:04X} <- ${:02X} at line {}, dot {}",
+             0x2000 | register as uint, val as uint, self.scanline, self.dot);
+        match register {
+            0 | 5 | 6 if self.scanline < (SCREEN_HEIGHT as uint16_t) && self.rendering() => {
+                log!(log::Ppu, log::Debug, "Mid-frame ${:04X} <- ${:02X} at line {}, dot {}",
+                     0x2000 | register as uint, val as uint, self.scanline, self.dot);
+            }
+            _ => {}
+        }
+        self.refresh_io_latch(val, 0xff);
+        match register {
             0 => self.update_ppuctrl(val),
-            1 => self.regs.mask = PpuMask{val: val},
+            1 => self.update_ppumask(val),
             2 => (),    // PPUSTATUS is read-only
             3 => self.regs.oam_addr = val,
             4 => self.write_oamdata(val),
             5 => self.update_ppuscroll(val),
             6 => self.update_ppuaddr(val),
-            7 => self.write_ppudata(val),
-            _ => panic!("can't happen")
+            _ => self.write_ppudata(val),   // 7 is all that's left.
         }
+
+        // Some mappers watch the CPU bus for writes to the PPU's registers.
+        self.vram.mapper.borrow_mut().ppu_register_write(0x2000 | register, val);
+
+        self.mark_line_dirty();
     }
 }
 
 #[derive(PartialEq, Eq)]
 pub struct StepResult {
     pub new_frame: bool,    // We wrapped around to the next scanline.
-    pub vblank_nmi: bool,   // We entered VBLANK and must generate an NMI.
-    pub scanline_irq: bool, // The mapper wants to execute a scanline IRQ.
 }
 
 struct Rgb {
     r: uint8_t,
     g: uint8_t,
     b: uint8_t,
+    index: uint8_t,     // The palette index the color came from
 }
 
 enum PatternPixelKind {
@@ -426,30 +781,64 @@ enum SpritePriority {
 }
 
 impl Save for Ppu {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         self.regs.save(fd);
         self.vram.save(fd);
         self.oam.save(fd);
         self.scanline.save(fd);
+        self.dot.save(fd);
         self.ppudata_buffer.save(fd);
+        self.io_latch.save(fd);
+        for time in self.io_latch_refreshed.iter_mut() {
+            time.save(fd);
+        }
+        self.suppress_vblank.save(fd);
         self.scroll_x.save(fd);
         self.scroll_y.save(fd);
+        self.line_scroll_x.save(fd);
+        self.line_scroll_y.save(fd);
+        self.line_sprites.save(fd);
+        self.sprite_eval.save(fd);
         self.cy.save(fd);
+        self.skipped_lines.save(fd);
+        self.oam_corrupt_row.save(fd);
+        self.a12_high.save(fd);
+        self.a12_low_dots.save(fd);
+        self.frame.save(fd);
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         self.regs.load(fd);
         self.vram.load(fd);
         self.oam.load(fd);
         self.scanline.load(fd);
+        self.dot.load(fd);
         self.ppudata_buffer.load(fd);
+        self.io_latch.load(fd);
+        for time in self.io_latch_refreshed.iter_mut() {
+            time.load(fd);
+        }
+        self.suppress_vblank.load(fd);
         self.scroll_x.load(fd);
         self.scroll_y.load(fd);
+        self.line_scroll_x.load(fd);
+        self.line_scroll_y.load(fd);
+        self.line_sprites.load(fd);
+        self.sprite_eval.load(fd);
         self.cy.load(fd);
+        self.skipped_lines.load(fd);
+        self.oam_corrupt_row.load(fd);
+        self.a12_high.load(fd);
+        self.a12_low_dots.load(fd);
+        self.frame.load(fd);
+
+        self.background_run = BackgroundRun::new();
+        self.line_dirty = true;
     }
 }
 
 impl Ppu {
     pub fn new(vram: Vram, oam: Oam) -> Ppu {
+        let a12_watched = vram.mapper.borrow().watches_a12();
         Ppu {
             regs: Regs {
                 ctrl: PpuCtrl{val: 0},
@@ -463,13 +852,46 @@ impl Ppu {
             oam: oam,
 
             screen: Box::new([ 0; 184320 ]),
+            indices: Box::new([ 0; 61440 ]),
             scanline: 0,
+            dot: 0,
             ppudata_buffer: 0,
 
+            io_latch: 0,
+            io_latch_refreshed: [ 0; 8 ],
+
+            suppress_vblank: false,
+
             scroll_x: 0,
             scroll_y: 0,
 
-            cy: 0
+            line_scroll_x: 0,
+            line_scroll_y: 0,
+
+            line_sprites: SpriteEval::new(),
+            sprite_eval: SpriteEval::new(),
+
+            cy: 0,
+            frame: 0,
+
+            sprite_overflow_bug: true,
+
+            line_scrolls: [ LineScroll { x: 0, y: 0 }; 240 ],
+
+            sprite_zero: SpriteZero { x: 0, y: 0, height: 8, hit: None },
+            sprite_zero_hit: None,
+
+            colors: PALETTE,
+
+            background_run: BackgroundRun::new(),
+            line_dirty: true,
+
+            skipped_lines: 0,
+            oam_corrupt_row: None,
+
+            a12_watched: a12_watched,
+            a12_high: false,
+            a12_low_dots: 0,
         }
     }
 
@@ -480,9 +902,10 @@ impl Ppu {
     #[inline(always)]
     fn get_color(&self, palette_index: uint8_t) -> Rgb {
         Rgb {
-            r: PALETTE[palette_index as uint * 3 + 2],
-            g: PALETTE[palette_index as uint * 3 + 1],
-            b: PALETTE[palette_index as uint * 3 + 0],
+            r: self.colors[palette_index as uint * 3 + 2],
+            g: self.colors[palette_index as uint * 3 + 1],
+            b: self.colors[palette_index as uint * 3 + 0],
+            index: palette_index,
         }
     }
 
@@ -519,10 +942,24 @@ impl Ppu {
         self.regs.oam_addr += 1;
     }
 
+    fn update_ppumask(&mut self, val: uint8_t) {
+        let was_rendering = self.rendering();
+        self.regs.mask = PpuMask{val: val};
+
+        let evaluating = self.scanline < (SCREEN_HEIGHT as uint16_t) &&
+            self.dot >= 65 && self.dot <= 256;
+        if was_rendering && !self.rendering() && evaluating && self.sprite_eval.index < 64 {
+            // Each 8-byte row of OAM holds two sprites.
+            self.oam_corrupt_row = Some(self.sprite_eval.index as uint / 2);
+        }
+    }
+
     fn update_ppuaddr(&mut self, val: uint8_t) {
         match self.regs.addr.next {
             Hi => {
-                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | ((val as uint16_t) << 8);
+                // The PPU's address bus is 14 bits wide, so the top two bits go nowhere.
+                let hi = (val as uint16_t << 8) & VRAM_ADDRESS_MASK;
+                self.regs.addr.val = (self.regs.addr.val & 0x00ff) | hi;
                 self.regs.addr.next = Lo;
             }
             Lo => {
@@ -540,12 +977,54 @@ impl Ppu {
         }
     }
 
+    // Returns the I/O latch, letting any bits that haven't been driven in a while decay to 0.
+    fn io_latch(&mut self) -> uint8_t {
+        for bit in range(0, 8) {
+            if self.cy >= self.io_latch_refreshed[bit] + IO_LATCH_DECAY_CYCLES {
+                self.io_latch &= !(1 << bit);
+            }
+        }
+        self.io_latch
+    }
+
+    // Updates the bits of the I/O latch that were driven by a register access.
+    fn refresh_io_latch(&mut self, val: uint8_t, driven: uint8_t) {
+        self.io_latch = (self.io_latch & !driven) | (val & driven);
+        for bit in range(0, 8) {
+            if (driven & (1 << bit)) != 0 {
+                self.io_latch_refreshed[bit] = self.cy;
+            }
+        }
+    }
+
     fn read_ppustatus(&mut self) -> uint8_t {
         // Reset latch.
         self.regs.scroll.next = XDir;
         self.regs.addr.next = Hi;
 
-        *self.regs.status
+        let mut status = *self.regs.status;
+        self.regs.status.set_in_vblank(false);
+
+        // Reading just as VBLANK starts races with the flag being set. One dot early reads the flag
+        // as clear and keeps it from being set at all; on the same dot or one later reads it as set
+        // and clears it. Either way, there's no NMI this frame.
+        if self.scanline == (VBLANK_SCANLINE - 1) as uint16_t {
+            let dots_until_vblank = (DOTS_PER_SCANLINE - self.dot) as int - STATUS_READ_DELAY_DOTS;
+            if dots_until_vblank >= -1 && dots_until_vblank <= 1 {
+                if dots_until_vblank <= 0 {
+                    status |= 0x80;
+                }
+                self.suppress_vblank = true;
+            }
+        }
+
+        status
+    }
+
+    fn read_oamdata(&mut self) -> uint8_t {
+        let val = self.oam.loadb(self.regs.oam_addr as uint16_t);
+        // Bits 2-4 of the attribute byte don't exist.
+        if (self.regs.oam_addr & 3) == 2 { val & 0xe3 } else { val }
     }
 
     fn write_ppudata(&mut self, val: uint8_t) {
@@ -554,16 +1033,18 @@ impl Ppu {
     }
 
     fn read_ppudata(&mut self) -> uint8_t {
-        let addr = self.regs.addr.val;
+        let addr = self.regs.addr.val & 0x3fff;
         let val = self.vram.loadb(addr);
         self.regs.addr.val += self.regs.ctrl.vram_addr_increment();
 
-        // Emulate the PPU buffering quirk.
+        // Emulate the PPU buffering quirk. Palette reads skip the buffer, but still fill it with
+        // the nametable byte "underneath" the palette.
         if addr < 0x3f00 {
             let buffered_val = self.ppudata_buffer;
             self.ppudata_buffer = val;
             buffered_val
         } else {
+            self.ppudata_buffer = self.vram.loadb(addr & 0x2fff);
             val
         }
     }
@@ -600,17 +1081,6 @@ impl Ppu {
         }
     }
 
-    #[inline(always)]
-    fn each_sprite<F>(&mut self, f: F)
-        where F: Fn(&mut Ppu, &SpriteStruct, uint8_t) -> bool{
-        for i in range(0, 64) {
-            let sprite = self.make_sprite_info(i as uint16_t);
-            if !f(self, &sprite, i as uint8_t) {
-                return
-            }
-        }
-    }
-
     //
     // Rendering
     //
@@ -620,32 +1090,78 @@ impl Ppu {
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 0] = color.r;
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 1] = color.g;
         self.screen[(y * SCREEN_WIDTH + x) * 3 + 2] = color.b;
+        self.indices[y * SCREEN_WIDTH + x] = color.index;
     }
 
-    // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile.
+    // Returns the color (pre-palette lookup) of pixel (x,y) within the given tile. Bit 8 of the tile
+    // index selects the pattern table.
     #[inline(always)]
     fn get_pattern_pixel(&mut self, kind: PatternPixelKind, tile: uint16_t, x: uint8_t, y: uint8_t) -> uint8_t {
         // Compute the pattern offset.
-        let mut pattern_offset = (tile << 4) + (y as uint16_t);
-        match kind {
-            Background => pattern_offset += self.regs.ctrl.background_pattern_table_addr(),
-            Sprite     => pattern_offset += self.regs.ctrl.sprite_pattern_table_addr(),
-        }
+        let pattern_offset = (tile << 4) + (y as uint16_t);
+        let sprite = match kind {
+            Background => false,
+            Sprite     => true,
+        };
 
         // Determine the color of this pixel.
-        let plane0 = self.vram.loadb(pattern_offset);
-        let plane1 = self.vram.loadb(pattern_offset + 8);
+        let plane0 = self.vram.fetch_pattern(pattern_offset, sprite);
+        let plane1 = self.vram.fetch_pattern(pattern_offset + 8, sprite);
         let bit0 = (plane0 >> ((7 - ((x % 8) as uint8_t)) as uint)) & 1;
         let bit1 = (plane1 >> ((7 - ((x % 8) as uint8_t)) as uint)) & 1;
         (bit1 << 1) | bit0
     }
 
+    // The line of the background this scanline shows, before wrapping around the nametables.
+    fn background_y(&self) -> uint16_t {
+        self.scanline - self.skipped_lines + self.line_scroll_y
+    }
+
+    // Like `get_background_pixel`, but draws from a run of eight pixels that's only recomputed
+    // when we move on to the next tile.
+    #[inline(always)]
+    fn get_run_background_pixel(&mut self, x: uint8_t) -> Option<Rgb> {
+        let x = x as uint16_t + self.line_scroll_x;
+        let tile_x = x / 8;
+        if !self.background_run.valid || self.background_run.tile_x != tile_x {
+            self.fetch_background_run(tile_x);
+        }
+        self.background_run.colors[(x % 8) as uint]
+    }
+
+    fn fetch_background_run(&mut self, tile_x: uint16_t) {
+        let y = self.background_y();
+        let NametableAddr { base, x_index, y_index } = self.nametable_addr(tile_x, y / 8);
+
+        let tile = self.vram.loadb(base + 32 * (y_index as uint16_t) + (x_index as uint16_t));
+        let tile = tile as uint16_t | (self.regs.ctrl.background_pattern_table_addr() >> 4);
+        let row = self.vram.background_pattern_row(tile, (y % 8) as uint8_t);
+
+        let group = y_index / 4 * 8 + x_index / 4;
+        let attr_byte = self.vram.loadb(base + 0x3c0 + (group as uint16_t));
+        let shift = (if x_index % 4 < 2 { 0 } else { 2 }) + (if y_index % 4 < 2 { 0 } else { 4 });
+        let attr_table_color = (attr_byte >> shift) & 0x3;
+
+        for i in range(0u, 8) {
+            let pattern_color = ((row >> (14 - 2 * i)) & 3) as uint8_t;
+            self.background_run.colors[i] = if pattern_color == 0 {
+                None
+            } else {
+                let tile_color = (attr_table_color << 2) | pattern_color;
+                let palette_index = self.vram.loadb(0x3f00 + (tile_color as uint16_t)) & 0x3f;
+                Some(self.get_color(palette_index))
+            };
+        }
+        self.background_run.valid = true;
+        self.background_run.tile_x = tile_x;
+    }
+
     // Returns true if the background was opaque here, false otherwise.
     #[inline(always)]
     fn get_background_pixel(&mut self, x: uint8_t) -> Option<Rgb> {
         // Adjust X and Y to account for scrolling.
-        let x = x as uint16_t + self.scroll_x;
-        let y = self.scanline as uint16_t + self.scroll_y;
+        let x = x as uint16_t + self.line_scroll_x;
+        let y = self.background_y();
 
         // Compute the nametable address, tile index, and pixel offset within that tile.
         let NametableAddr { base, x_index, y_index } = self.nametable_addr(x / 8, y / 8);
@@ -655,7 +1171,8 @@ impl Ppu {
         let tile = self.vram.loadb(base + 32 * (y_index as uint16_t) + (x_index as uint16_t));
 
         // Fetch the pattern color.
-        let pattern_color = self.get_pattern_pixel(Background, tile as uint16_t, xsub, ysub);
+        let tile = tile as uint16_t | (self.regs.ctrl.background_pattern_table_addr() >> 4);
+        let pattern_color = self.get_pattern_pixel(Background, tile, xsub, ysub);
         if pattern_color == 0 {
             return None;    // Transparent.
         }
@@ -677,156 +1194,349 @@ impl Ppu {
         return Some(self.get_color(palette_index));
     }
 
-    fn get_sprite_pixel(&mut self,
-                        visible_sprites: &[Option<uint8_t>; 8],
-                        x: uint8_t,
-                        background_opaque: bool)
-                     -> Option<SpriteColor> {
-        for &visible_sprite_opt in visible_sprites.iter() {
-            match visible_sprite_opt {
-                None => return None,
-                Some(index) => {
-                    let sprite = self.make_sprite_info(index as uint16_t);
-
-                    // Don't need to consider this sprite if we aren't in its bounding box.
-                    if !sprite.in_bounding_box(self, x as uint8_t, self.scanline as uint8_t) {
-                        continue
-                    }
-
-                    let pattern_color;
-                    match sprite.tiles(self) {
-                        // TODO: 8x16 rendering
-                        SpriteTiles8x8(tile) | SpriteTiles8x16(tile, _) => {
-                            let mut x = x - sprite.x;
-                            if sprite.flip_horizontal() { x = 7 - x; }
-
-                            let mut y = self.scanline as uint8_t - sprite.y;
-                            if sprite.flip_vertical() { y = 7 - y; }
-
-                            debug_assert(x < 8, "sprite X miscalculation");
-                            debug_assert(y < 8, "sprite Y miscalculation");
-
-                            pattern_color = self.get_pattern_pixel(Sprite, tile, x, y);
-                        }
-                    }
-
-                    // If the pattern color was zero, this part of the sprite is transparent.
-                    if pattern_color == 0 {
-                        continue
-                    }
-
-                    // OK, so we know this pixel is opaque. Now if this is the first sprite and the
-                    // background was not transparent, set sprite 0 hit.
-                    if index == 0 && background_opaque {
-                        self.regs.status.set_sprite_zero_hit(true);
-                    }
-
-                    // Determine final tile color and do the palette lookup.
-                    let tile_color = (sprite.palette() << 2) | pattern_color;
-                    let palette_index = self.vram.loadb(0x3f00 + (tile_color as uint16_t)) & 0x3f;
-                    let final_color = self.get_color(palette_index);
-
-                    return Some(SpriteColor { priority: sprite.priority(), color: final_color });
+    fn get_sprite_pixel(&mut self, x: uint8_t, background_opaque: bool) -> Option<SpriteColor> {
+        for i in range(0, self.line_sprites.count as uint) {
+            let index = self.line_sprites.sprites[i];
+            let sprite = self.make_sprite_info(index as uint16_t);
+
+            // Don't need to consider this sprite if we aren't in its bounding box.
+            if !sprite.in_bounding_box(self, x as uint8_t, self.scanline as uint8_t) {
+                continue
+            }
+
+            let mut sprite_x = x - sprite.x;
+            if sprite.flip_horizontal() { sprite_x = 7 - sprite_x; }
+            debug_assert(sprite_x < 8, "sprite X miscalculation");
+
+            let y = self.scanline as uint8_t - sprite.y;
+            let (tile, y) = match sprite.tiles(self) {
+                SpriteTiles8x8(tile) => {
+                    debug_assert(y < 8, "sprite Y miscalculation");
+                    (tile, if sprite.flip_vertical() { 7 - y } else { y })
                 }
+                SpriteTiles8x16(top, bottom) => {
+                    debug_assert(y < 16, "sprite Y miscalculation");
+                    // Flipping an 8x16 sprite swaps its two tiles as well.
+                    let y = if sprite.flip_vertical() { 15 - y } else { y };
+                    if y < 8 { (top, y) } else { (bottom, y - 8) }
+                }
+            };
+
+            let pattern_color = self.get_pattern_pixel(Sprite, tile, sprite_x, y);
+
+            // If the pattern color was zero, this part of the sprite is transparent.
+            if pattern_color == 0 {
+                continue
             }
+
+            // OK, so we know this pixel is opaque. Now if this is the first sprite and the
+            // background was not transparent, set sprite 0 hit. The hardware never detects
+            // a hit on the last pixel of the line.
+            if index == 0 && background_opaque && x != 255 {
+                self.regs.status.set_sprite_zero_hit(true);
+                if self.sprite_zero_hit.is_none() {
+                    self.sprite_zero_hit = Some((x as uint, self.scanline as uint));
+                }
+            }
+
+            // Determine final tile color and do the palette lookup.
+            let tile_color = (sprite.palette() << 2) | pattern_color;
+            let palette_index = self.vram.loadb(0x3f00 + (tile_color as uint16_t)) & 0x3f;
+            let final_color = self.get_color(palette_index);
+
+            return Some(SpriteColor { priority: sprite.priority(), color: final_color });
         }
         return None;
     }
 
-    fn compute_visible_sprites(&mut self) -> [Option<uint8_t>; 8] {
-        let mut count = 0;
-        let mut result = [None, ..8];
-        self.each_sprite(|this, sprite, index| {
-            if sprite.on_scanline(this, this.scanline as uint8_t) {
-                if count < 8 {
-                    result[count] = Some(index);
-                    count += 1;
-                    true
-                } else {
-                    this.regs.status.set_sprite_overflow(true);
-                    false
-                }
+    fn sprite_height(&self) -> uint16_t {
+        match self.regs.ctrl.sprite_size() {
+            SpriteSize8x8 => 8,
+            SpriteSize8x16 => 16,
+        }
+    }
+
+    // Whether a sprite with the given OAM Y byte appears on the given scanline.
+    fn sprite_y_in_range(&self, y: uint8_t, scanline: uint16_t) -> bool {
+        let height = self.sprite_height();
+        let top = y as uint16_t + 1;
+        scanline >= top && scanline < top + height
+    }
+
+    // Performs one step of sprite evaluation for the next scanline. Like the hardware, each OAM
+    // entry takes two dots to check, plus six more to copy to secondary OAM if it's in range.
+    fn evaluate_sprites(&mut self) {
+        if self.sprite_eval.wait > 0 {
+            self.sprite_eval.wait -= 1;
+            return;
+        }
+        if self.sprite_eval.index >= 64 {
+            return;
+        }
+
+        let next_scanline = self.scanline + 1;
+        let index = self.sprite_eval.index;
+        if self.sprite_eval.count < 8 {
+            if self.sprite_y_in_range(self.oam.oam[index as uint * 4], next_scanline) {
+                self.sprite_eval.sprites[self.sprite_eval.count as uint] = index;
+                self.sprite_eval.count += 1;
+                self.sprite_eval.wait = 7;
             } else {
-                true
+                self.sprite_eval.wait = 1;
             }
-        });
-        result
+            self.sprite_eval.index += 1;
+            return;
+        }
+
+        // Once secondary OAM is full, the hardware keeps scanning for a ninth sprite to set the
+        // overflow flag. But it increments the byte offset along with the sprite index, so it
+        // checks tile indices, attributes, and X positions as though they were Y positions. This
+        // gives both false positives and false negatives, and some games depend on them.
+        let y = self.oam.oam[index as uint * 4 + self.sprite_eval.byte as uint];
+        if self.sprite_y_in_range(y, next_scanline) {
+            self.regs.status.set_sprite_overflow(true);
+            self.sprite_eval.index = 64;
+            return;
+        }
+        self.sprite_eval.index += 1;
+        self.sprite_eval.wait = 1;
+        if self.sprite_overflow_bug {
+            self.sprite_eval.byte = (self.sprite_eval.byte + 1) & 3;
+        }
     }
 
-    fn render_scanline(&mut self) {
-        // TODO: Scrolling, mirroring
-        let visible_sprites = self.compute_visible_sprites();
+    fn render_pixel(&mut self, x: uint) {
+        let mut background_color = None;
+        if self.regs.mask.show_background() {
+            background_color = if self.line_dirty {
+                self.get_background_pixel(x as uint8_t)
+            } else {
+                self.get_run_background_pixel(x as uint8_t)
+            };
+        }
 
-        let backdrop_color_index = self.vram.loadb(0x3f00) & 0x3f;
-        let backdrop_color = self.get_color(backdrop_color_index);
+        let mut sprite_color = None;
+        if self.regs.mask.show_sprites() {
+            sprite_color = self.get_sprite_pixel(x as uint8_t, background_color.is_some());
+        }
 
-        for x in range(0, SCREEN_WIDTH) {
-            // FIXME: For performance, we shouldn't be recomputing the tile for every pixel.
-            let mut background_color = None;
-            if self.regs.mask.show_background() {
-                background_color = self.get_background_pixel(x as uint8_t);
+        // Combine colors using priority.
+        let color = match (background_color, sprite_color) {
+            (None, None) => {
+                // With rendering off, the PPU shows whichever palette entry the VRAM address
+                // points at, if it's in the palette, rather than the backdrop.
+                let addr = self.regs.addr.val & 0x3fff;
+                let addr = if !self.rendering() && addr >= 0x3f00 { addr } else { 0x3f00 };
+                let backdrop_color_index = self.vram.loadb(addr) & 0x3f;
+                self.get_color(backdrop_color_index)
             }
+            (Some(color), None) => color,
+            (Some(color), Some(SpriteColor { priority: BelowBg, .. })) => color,
+            (None, Some(SpriteColor { priority: BelowBg, color: color })) => color,
+            (_, Some(SpriteColor { priority: AboveBg, color: color })) => color,
+        };
+
+        let scanline = self.scanline;
+        self.putpixel(x, scanline as uint, color);
+    }
+
+    // Runs the PPU for a single dot of the current scanline.
+    fn step_dot(&mut self) {
+        let dot = self.dot;
+        if dot == 0 {
+            let mut mapper = self.vram.mapper.borrow_mut();
+            mapper.start_scanline(self.scanline);
+            self.line_dirty = !mapper.cacheable_chr();
+            self.background_run.valid = false;
+        }
 
-            let mut sprite_color = None;
-            if self.regs.mask.show_sprites() {
-                sprite_color = self.get_sprite_pixel(&visible_sprites,
-                                                     x as uint8_t,
-                                                     background_color.is_some());
+        let visible = self.scanline < (SCREEN_HEIGHT as uint16_t);
+        let rendering = self.rendering();
+        if visible && dot == 0 {
+            let scroll = LineScroll { x: self.line_scroll_x % 512, y: self.background_y() % 480 };
+            self.line_scrolls[self.scanline as uint] = scroll;
+        }
+        if visible && dot >= 1 && dot <= 256 {
+            self.render_pixel((dot - 1) as uint);
+        }
+
+        // Sprite evaluation for the next scanline happens only while rendering is on.
+        if visible && rendering && dot >= 65 && dot <= 256 {
+            if dot == 65 {
+                self.sprite_eval = SpriteEval::new();
+                self.corrupt_oam();
             }
+            self.evaluate_sprites();
+        }
 
-            // Combine colors using priority.
-            let color = match (background_color, sprite_color) {
-                (None, None) => backdrop_color,
-                (Some(color), None) => color,
-                (Some(color), Some(SpriteColor { priority: BelowBg, .. })) => color,
-                (None, Some(SpriteColor { priority: BelowBg, color: color })) => color,
-                (_, Some(SpriteColor { priority: AboveBg, color: color })) => color,
+        if visible && !rendering && dot == 256 {
+            self.skipped_lines += 1;
+        }
+
+        if dot == 257 {
+            self.line_sprites = if visible && rendering {
+                self.sprite_eval
+            } else {
+                SpriteEval::new()
             };
+            self.line_scroll_x = self.scroll_x;
+            self.line_scroll_y = self.scroll_y;
+        }
 
-            let scanline = self.scanline;
-            self.putpixel(x, scanline as uint, color);
+        if self.a12_watched {
+            self.watch_a12(dot);
         }
     }
 
-    fn start_vblank(&mut self, result: &mut StepResult) {
-        self.regs.status.set_in_vblank(true);
+    // The level of A12 during a dot. While rendering, each eight dots of fetches are two from the
+    // nametables, where A12 is low, then two from a pattern table: the background's for the
+    // visible dots and the first two tiles of the next line, and the sprites' in between. With
+    // rendering off, the bus holds the VRAM address.
+    fn a12_level(&self, dot: uint16_t) -> bool {
+        let fetching = self.scanline < (SCREEN_HEIGHT as uint16_t) ||
+            self.scanline == (PRE_RENDER_SCANLINE as uint16_t);
+        if !fetching || !self.rendering() {
+            return (self.regs.addr.val & 0x1000) != 0;
+        }
+        if dot == 0 || dot > 336 || (dot - 1) % 8 < 4 {
+            return false;
+        }
+        if dot < 257 || dot > 320 {
+            return self.regs.ctrl.background_pattern_table_addr() != 0;
+        }
+        match self.regs.ctrl.sprite_size() {
+            SpriteSize8x8 => self.regs.ctrl.sprite_pattern_table_addr() != 0,
+            SpriteSize8x16 => {
+                // Empty slots fetch tile $FF, from the second table.
+                let slot = ((dot - 257) / 8) as uint8_t;
+                if slot >= self.line_sprites.count {
+                    return true;
+                }
+                let index = self.line_sprites.sprites[slot as uint] as uint;
+                (self.oam.oam[index * 4 + 1] & 1) != 0
+            }
+        }
+    }
 
-        // FIXME: Is this correct? Or does it happen on the *next* frame?
-        self.regs.status.set_sprite_zero_hit(false);
+    // Tells the mapper when A12 rises, leaving out the rises that the MMC3's filter ignores.
+    fn watch_a12(&mut self, dot: uint16_t) {
+        let high = self.a12_level(dot);
+        if high && !self.a12_high && self.a12_low_dots >= A12_FILTER_DOTS {
+            self.vram.mapper.borrow_mut().a12_rise(self.scanline, dot);
+        }
+        self.a12_low_dots = if high {
+            0
+        } else {
+            cmp::min(self.a12_low_dots + 1, A12_FILTER_DOTS)
+        };
+        self.a12_high = high;
+    }
 
-        if self.regs.ctrl.vblank_nmi() {
-            result.vblank_nmi = true;
+    fn rendering(&self) -> bool {
+        self.regs.mask.show_background() || self.regs.mask.show_sprites()
+    }
+
+    fn corrupt_oam(&mut self) {
+        match self.oam_corrupt_row.take() {
+            None => {}
+            Some(row) => {
+                for i in range(0, 8) {
+                    self.oam.oam[row * 8 + i] = self.oam.oam[i];
+                }
+            }
         }
     }
 
+    // Switches the rest of the current scanline to the accurate per-dot path, if we're in the
+    // middle of drawing it.
+    fn mark_line_dirty(&mut self) {
+        if self.scanline < (SCREEN_HEIGHT as uint16_t) && self.dot >= 1 && self.dot <= 256 {
+            self.line_dirty = true;
+        }
+    }
+
+    /// Tells the PPU that the CPU wrote to the cartridge, which may have switched CHR banks or
+    /// rearranged the nametables. Also called once the mapper's state is restored.
+    pub fn cartridge_written(&mut self) {
+        self.vram.arrange_nametables();
+        self.vram.pattern_cache.invalidate();
+        self.mark_line_dirty();
+    }
+
+    // Called when the visible lines are done, before the game gets a chance to move sprite 0 for
+    // the next frame.
+    fn record_sprite_zero(&mut self) {
+        let (y, x) = (self.oam.loadb(0), self.oam.loadb(3));
+        self.sprite_zero = SpriteZero {
+            x: x as uint,
+            y: y as uint + 1,
+            height: self.sprite_height() as uint,
+            hit: self.sprite_zero_hit.take(),
+        };
+    }
+
+    fn start_vblank(&mut self) {
+        if self.suppress_vblank {
+            self.suppress_vblank = false;
+            return;
+        }
+
+        self.regs.status.set_in_vblank(true);
+    }
+
+    /// The scanline and dot the PPU has reached.
+    pub fn position(&self) -> (uint16_t, uint16_t) {
+        (self.scanline, self.dot)
+    }
+
+    /// The PPU's NMI output, which is asserted for as long as both the VBLANK flag and NMIs are
+    /// on. Turning on NMIs during VBLANK therefore causes one right away.
+    pub fn nmi_line(&self) -> bool {
+        self.regs.status.in_vblank() && self.regs.ctrl.vblank_nmi()
+    }
+
     #[inline(never)]
     pub fn step(&mut self, run_to_cycle: uint64_t) -> StepResult {
-        let mut result = StepResult { new_frame: false, vblank_nmi: false, scanline_irq: false };
+        let mut result = StepResult { new_frame: false };
         loop {
+            // Catch up on the dots of the current scanline, so that the status flags change at
+            // the right time within it.
             let next_scanline_cycle: uint64_t = self.cy + CYCLES_PER_SCANLINE;
-            if next_scanline_cycle > run_to_cycle {
-                break;
+            let last_dot = if next_scanline_cycle <= run_to_cycle {
+                DOTS_PER_SCANLINE
+            } else if run_to_cycle > self.cy {
+                ((run_to_cycle - self.cy) * 3) as uint16_t
+            } else {
+                0
+            };
+            while self.dot < last_dot {
+                self.step_dot();
+                self.dot += 1;
             }
 
-            if self.scanline < (SCREEN_HEIGHT as uint16_t) {
-                self.render_scanline();
+            if next_scanline_cycle > run_to_cycle {
+                break;
             }
 
             self.scanline += 1;
+            self.dot = 0;
 
             {
                 let mut mapper = self.vram.mapper.borrow_mut();
-                if mapper.next_scanline() == Irq {
-                    result.scanline_irq = true
-                }
+                mapper.next_scanline();
             }
 
             if self.scanline == (VBLANK_SCANLINE as uint16_t) {
-                self.start_vblank(&mut result);
+                self.record_sprite_zero();
+                self.start_vblank();
             } else if self.scanline == (LAST_SCANLINE as uint16_t) {
                 result.new_frame = true;
+                self.frame += 1;
                 self.scanline = 0;
+                self.skipped_lines = 0;
                 self.regs.status.set_in_vblank(false);
+                self.regs.status.set_sprite_zero_hit(false);
+                self.regs.status.set_sprite_overflow(false);
             }
 
             self.cy += CYCLES_PER_SCANLINE;
@@ -837,3 +1547,97 @@ impl Ppu {
         return result;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Oam, Ppu, Vram};
+
+    use mapper::{Mapper, Nrom};
+    use mem::Mem;
+    use rom::Rom;
+
+    use std::cell::RefCell;
+    use std::iter::range_step;
+    use std::rc::Rc;
+
+    // A PPU on an NROM board with blank CHR-ROM.
+    fn ppu() -> Ppu {
+        let mapper = Box::new(Nrom { rom: Box::new(Rom::blank()) }) as Box<Mapper+Send>;
+        Ppu::new(Vram::new(Rc::new(RefCell::new(mapper)), false), Oam::new())
+    }
+
+    #[test]
+    fn every_register_address_is_safe() {
+        let mut ppu = ppu();
+        for addr in range(0x2000u, 0x4000) {
+            ppu.loadb(addr as u16);
+            ppu.storeb(addr as u16, 0);
+        }
+    }
+
+    #[test]
+    fn every_vram_address_is_safe() {
+        let mut ppu = ppu();
+        for addr in range(0u, 0x10000) {
+            ppu.vram.loadb(addr as u16);
+            ppu.vram.storeb(addr as u16, 0);
+        }
+    }
+
+    #[test]
+    fn oam_registers_repeat_every_8_bytes() {
+        let mut ppu = ppu();
+        for base in range_step(0x2000u, 0x4000, 8) {
+            let val = (base >> 3) as u8;
+            ppu.storeb(base as u16 + 3, 0x20);
+            ppu.storeb(base as u16 + 4, val);
+
+            // Read it back through the last mirror.
+            ppu.storeb(0x3ffb, 0x20);
+            assert_eq!(ppu.loadb(0x3ffc), val);
+        }
+    }
+
+    #[test]
+    fn ppudata_repeats_every_8_bytes() {
+        let mut ppu = ppu();
+        for base in range_step(0x2000u, 0x4000, 8) {
+            let base = base as u16;
+            let val = (base >> 3) as u8;
+            ppu.loadb(base + 2);            // Reset the address latch.
+            ppu.storeb(base + 6, 0x21);
+            ppu.storeb(base + 6, 0x00);
+            ppu.storeb(base + 7, val);
+
+            ppu.loadb(0x2002);
+            ppu.storeb(0x2006, 0x21);
+            ppu.storeb(0x2006, 0x00);
+            ppu.loadb(0x2007);              // The read buffer's stale byte.
+            assert_eq!(ppu.loadb(0x2007), val);
+        }
+    }
+
+    #[test]
+    fn write_only_registers_read_open_bus() {
+        let mut ppu = ppu();
+        for base in range_step(0x2000u, 0x4000, 8) {
+            let base = base as u16;
+            ppu.storeb(base + 3, 0x5a);
+            for &register in [ 0u16, 1, 3, 5, 6 ].iter() {
+                assert_eq!(ppu.loadb(base + register), 0x5a);
+            }
+        }
+    }
+
+    #[test]
+    fn ppuaddr_ignores_its_top_two_bits() {
+        let mut ppu = ppu();
+        ppu.storeb(0x2006, 0x7f);           // $7F00 is $3F00 on a 14-bit bus.
+        ppu.storeb(0x2006, 0x00);
+        ppu.storeb(0x2007, 0x15);
+
+        ppu.storeb(0x2006, 0x3f);
+        ppu.storeb(0x2006, 0x00);
+        assert_eq!(ppu.loadb(0x2007), 0x15);
+    }
+}
@@ -0,0 +1,325 @@
+//
+// sprocketnes/profile.rs
+//
+// Author: Patrick Walton
+//
+
+// Instrumentation for the CPU's memory accesses. The profiler counts the reads and writes to every
+// address, for a heatmap report of where a game's bus traffic goes, and checks them against
+// watchpoints, so that emulation can stop when a game touches an address of interest. It can
+// also log every write to a range of addresses to a file, with the instruction that made it, for
+// finding out what keeps overwriting a variable without stopping each time.
+//
+// Reads include the CPU's opcode and operand fetches, so the busiest PRG-ROM addresses are the
+// game's hot loops. The frames emulated for run-ahead are counted too.
+//
+// The profiler is only built in with `cargo build --features mem-profile`, since otherwise every
+// bus access would check for it. Without the feature, the memory map's hooks compile to nothing
+// and the options that need a profiler are turned away.
+
+use libc::{uint8_t, uint16_t, uint64_t};
+use std::cmp;
+use std::io::{BufferedWriter, File, IoResult};
+use std::num::{Float, from_str_radix};
+
+/// Whether this build can profile at all.
+#[cfg(feature = "mem-profile")]
+pub const ENABLED: bool = true;
+#[cfg(not(feature = "mem-profile"))]
+pub const ENABLED: bool = false;
+
+const ADDRESS_COUNT: uint = 0x10000;
+const PAGE_SIZE: uint = 0x100;
+
+// The busiest addresses are listed at the end of the report.
+const BUSIEST_COUNT: uint = 32;
+
+// Heatmap cells, from untouched to busiest.
+static HEAT_LEVELS: &'static [u8] = b" .:-=+*#%@";
+
+static REGIONS: [(&'static str, uint, uint); 6] = [
+    ("RAM", 0x0000, 0x2000),
+    ("PPU registers", 0x2000, 0x4000),
+    ("APU and I/O", 0x4000, 0x4020),
+    ("Expansion area", 0x4020, 0x6000),
+    ("PRG-RAM", 0x6000, 0x8000),
+    ("PRG-ROM", 0x8000, 0x10000),
+];
+
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// A range of addresses to stop on, inclusive at both ends.
+#[derive(Clone)]
+pub struct Watchpoint {
+    pub start: uint16_t,
+    pub end: uint16_t,
+    pub reads: bool,
+    pub writes: bool,
+}
+
+fn parse_address(string: &str) -> Result<uint16_t, String> {
+    let digits = if string.starts_with("$") {
+        string.slice_from(1)
+    } else if string.starts_with("0x") || string.starts_with("0X") {
+        string.slice_from(2)
+    } else {
+        string
+    };
+    match from_str_radix::<uint16_t>(digits, 16) {
+        Some(addr) if digits.len() <= 4 => Ok(addr),
+        _ => Err(format!("expected a hexadecimal address, not `{}`", string)),
+    }
+}
+
+impl Watchpoint {
+    /// Parses a watchpoint in the form `<addr>[-<addr>][:r|w|rw]`, with hexadecimal addresses.
+    /// Without the access kinds, it stops on both reads and writes.
+    pub fn parse(spec: &str) -> Result<Watchpoint, String> {
+        let (range, kinds) = match spec.find(':') {
+            None => (spec, "rw"),
+            Some(index) => (spec.slice_to(index), spec.slice_from(index + 1)),
+        };
+        let (start, end) = try!(parse_range(range));
+        let (reads, writes) = match kinds {
+            "r" => (true, false),
+            "w" => (false, true),
+            "rw" => (true, true),
+            _ => return Err(format!("expected `r`, `w` or `rw`, not `{}`", kinds)),
+        };
+        Ok(Watchpoint { start: start, end: end, reads: reads, writes: writes })
+    }
+
+    fn matches(&self, addr: uint16_t, access: Access) -> bool {
+        let wanted = match access {
+            Read => self.reads,
+            Write => self.writes,
+        };
+        wanted && addr >= self.start && addr <= self.end
+    }
+}
+
+// Parses `<addr>[-<addr>]`, with hexadecimal addresses, into the first and last address.
+fn parse_range(range: &str) -> Result<(uint16_t, uint16_t), String> {
+    let (start, end) = match range.find('-') {
+        None => {
+            let addr = try!(parse_address(range));
+            (addr, addr)
+        }
+        Some(index) => {
+            (try!(parse_address(range.slice_to(index))),
+             try!(parse_address(range.slice_from(index + 1))))
+        }
+    };
+    if start > end {
+        return Err(format!("the range `{}` ends before it starts", range));
+    }
+    Ok((start, end))
+}
+
+/// A range of addresses whose writes are logged to a file, inclusive at both ends.
+#[derive(Clone)]
+pub struct WriteWatch {
+    pub start: uint16_t,
+    pub end: uint16_t,
+    pub path: String,
+}
+
+impl WriteWatch {
+    /// Parses `<addr>[-<addr>]=<path>`, with hexadecimal addresses.
+    pub fn parse(spec: &str) -> Result<WriteWatch, String> {
+        let (range, path) = match spec.find('=') {
+            Some(index) if index + 1 < spec.len() => {
+                (spec.slice_to(index), spec.slice_from(index + 1))
+            }
+            _ => return Err(format!("expected `<addr>[-<addr>]=<path>`, not `{}`", spec)),
+        };
+        let (start, end) = try!(parse_range(range));
+        Ok(WriteWatch { start: start, end: end, path: path.to_string() })
+    }
+}
+
+// The log for one `WriteWatch`. The file is opened on the first write, so that a profiler that's
+// made and thrown away, as on a power cycle, doesn't empty it.
+struct WriteLog {
+    watch: WriteWatch,
+    file: Option<BufferedWriter<File>>,
+    failed: bool,
+}
+
+impl WriteLog {
+    fn log(&mut self, pc: uint16_t, cycle: uint64_t, addr: uint16_t, val: uint8_t) {
+        if self.failed {
+            return;
+        }
+        if self.file.is_none() {
+            match File::create(&Path::new(self.watch.path.as_slice())) {
+                Ok(file) => self.file = Some(BufferedWriter::new(file)),
+                Err(err) => {
+                    println!("Couldn't create the write log {}: {}", self.watch.path, err);
+                    self.failed = true;
+                    return;
+                }
+            }
+        }
+        let result = writeln!(self.file.as_mut().unwrap(),
+                              "cycle {:>12}  PC ${:04X}  wrote ${:02X} to ${:04X}",
+                              cycle,
+                              pc as uint,
+                              val as uint,
+                              addr as uint);
+        match result {
+            Ok(()) => {}
+            Err(err) => {
+                println!("Couldn't write to the write log {}: {}", self.watch.path, err);
+                self.failed = true;
+            }
+        }
+    }
+}
+
+/// An access that set off a watchpoint.
+pub struct WatchHit {
+    pub addr: uint16_t,
+    pub val: uint8_t,
+    pub access: Access,
+}
+
+impl WatchHit {
+    pub fn to_str(&self) -> String {
+        match self.access {
+            Read => format!("read ${:02X} from ${:04X}", self.val as uint, self.addr as uint),
+            Write => format!("wrote ${:02X} to ${:04X}", self.val as uint, self.addr as uint),
+        }
+    }
+}
+
+pub struct MemProfiler {
+    reads: Vec<uint64_t>,
+    writes: Vec<uint64_t>,
+    watchpoints: Vec<Watchpoint>,
+    hit: Option<WatchHit>,  // The first hit since the last `take_hit`
+    write_logs: Vec<WriteLog>,
+    logged_writes: Vec<(uint16_t, uint8_t)>,    // Writes to log, made by the current instruction
+}
+
+impl MemProfiler {
+    pub fn new(watchpoints: Vec<Watchpoint>, write_watches: Vec<WriteWatch>) -> MemProfiler {
+        MemProfiler {
+            reads: Vec::from_elem(ADDRESS_COUNT, 0),
+            writes: Vec::from_elem(ADDRESS_COUNT, 0),
+            watchpoints: watchpoints,
+            hit: None,
+            write_logs: write_watches.into_iter().map(|watch| {
+                WriteLog { watch: watch, file: None, failed: false }
+            }).collect(),
+            logged_writes: Vec::new(),
+        }
+    }
+
+    #[inline]
+    pub fn read(&mut self, addr: uint16_t, val: uint8_t) {
+        self.reads[addr as uint] += 1;
+        self.watch(addr, val, Read);
+    }
+
+    #[inline]
+    pub fn write(&mut self, addr: uint16_t, val: uint8_t) {
+        self.writes[addr as uint] += 1;
+        self.watch(addr, val, Write);
+        if self.write_logs.iter().any(|log| addr >= log.watch.start && addr <= log.watch.end) {
+            self.logged_writes.push((addr, val));
+        }
+    }
+
+    /// Logs the writes the instruction that started at the given PC and cycle made. The memory
+    /// map doesn't know which instruction is running, so the CPU's loop calls this after each.
+    pub fn end_instruction(&mut self, pc: uint16_t, cycle: uint64_t) {
+        for &(addr, val) in self.logged_writes.iter() {
+            for log in self.write_logs.iter_mut() {
+                if addr >= log.watch.start && addr <= log.watch.end {
+                    log.log(pc, cycle, addr, val);
+                }
+            }
+        }
+        self.logged_writes.clear();
+    }
+
+    /// Forgets the writes since the last `end_instruction`, for frames that don't really happen.
+    pub fn discard_writes(&mut self) {
+        self.logged_writes.clear();
+    }
+
+    fn watch(&mut self, addr: uint16_t, val: uint8_t, access: Access) {
+        if self.hit.is_some() || !self.watchpoints.iter().any(|watch| watch.matches(addr, access)) {
+            return;
+        }
+        self.hit = Some(WatchHit { addr: addr, val: val, access: access });
+    }
+
+    /// Returns the first watchpoint hit since the last call, if there was one.
+    pub fn take_hit(&mut self) -> Option<WatchHit> {
+        self.hit.take()
+    }
+
+    /// Writes the heatmap report: totals for each region of the memory map, a map of the
+    /// traffic to each 256-byte page, and the busiest addresses.
+    pub fn write_report(&self, path: &Path) -> IoResult<()> {
+        let mut file = BufferedWriter::new(try!(File::create(path)));
+
+        try!(writeln!(file, "{:<16}{:>16}{:>16}", "Region", "Reads", "Writes"));
+        for &(name, start, end) in REGIONS.iter() {
+            let reads = self.reads.slice(start, end).iter().fold(0, |sum, &count| sum + count);
+            let writes = self.writes.slice(start, end).iter().fold(0, |sum, &count| sum + count);
+            try!(writeln!(file, "{:<16}{:>16}{:>16}", name, reads, writes));
+        }
+
+        try!(write_heatmap(&mut file, "Reads", self.reads.as_slice()));
+        try!(write_heatmap(&mut file, "Writes", self.writes.as_slice()));
+
+        let mut busiest: Vec<(uint, uint64_t)> = range(0, ADDRESS_COUNT).map(|addr| {
+            (addr, self.reads[addr] + self.writes[addr])
+        }).filter(|&(_, total)| total > 0).collect();
+        busiest.sort_by(|&(_, a), &(_, b)| b.cmp(&a));
+        try!(writeln!(file, "\nBusiest addresses:"));
+        for &(addr, _) in busiest.iter().take(BUSIEST_COUNT) {
+            try!(writeln!(file,
+                          "${:04X}{:>16} reads{:>16} writes",
+                          addr,
+                          self.reads[addr],
+                          self.writes[addr]));
+        }
+        Ok(())
+    }
+}
+
+// Draws a map of the accesses to each page, a row of pages to a line. Busier pages get darker
+// characters, on a logarithmic scale up to the busiest page.
+fn write_heatmap(file: &mut Writer, title: &str, counts: &[uint64_t]) -> IoResult<()> {
+    let pages: Vec<uint64_t> = counts.chunks(PAGE_SIZE).map(|page| {
+        page.iter().fold(0, |sum, &count| sum + count)
+    }).collect();
+    let busiest = pages.iter().fold(0, |max, &count| cmp::max(max, count));
+
+    try!(writeln!(file, "\n{} by page (` ` is none, `@` is the most):", title));
+    try!(writeln!(file, "       0123456789ABCDEF"));
+    for (row, row_pages) in pages.chunks(16).enumerate() {
+        let cells: Vec<u8> = row_pages.iter().map(|&count| {
+            let top = HEAT_LEVELS.len() - 1;
+            let level = if count == 0 {
+                0
+            } else if busiest <= 1 {
+                top
+            } else {
+                let heat = ((count as f64).ln() / (busiest as f64).ln()) * (top - 1) as f64;
+                cmp::min(1 + heat as uint, top)
+            };
+            HEAT_LEVELS[level]
+        }).collect();
+        let cells = String::from_utf8(cells).unwrap();
+        try!(writeln!(file, "${:04X}  {}", row * 16 * PAGE_SIZE, cells));
+    }
+    Ok(())
+}
@@ -0,0 +1,316 @@
+//
+// sprocketnes/record.rs
+//
+// Author: Patrick Walton
+//
+
+// Records emulated video and audio to an uncompressed AVI file, or to any other `Encoder`.
+//
+// Synchronization is derived from emulated time, not wall time: every frame is exactly
+// `CYCLES_PER_FRAME` CPU cycles long and the APU produces exactly `OUTPUT_SAMPLE_RATE` samples per
+// `EMULATED_CYCLES_PER_SECOND` cycles, so a frame is always worth the same number of samples no
+// matter how fast or slow the host runs.
+//
+// The AVI's sizes and index offsets are 32 bits, so a recording stops short of 4 GB, about six
+// and a half minutes. The OpenDML extensions that go past that aren't written.
+
+use apu::{EMULATED_CYCLES_PER_SECOND, OUTPUT_SAMPLE_RATE};
+use ppu::{CYCLES_PER_SCANLINE, LAST_SCANLINE, SCREEN_HEIGHT, SCREEN_WIDTH};
+
+use libc::{int16_t, uint8_t, uint32_t, uint64_t};
+use std::io::{File, SeekSet};
+use std::vec::Vec;
+
+const FRAME_SIZE: uint = 256 * 240 * 3;
+
+const AVIF_HASINDEX: uint32_t = 0x10;
+const AVIF_ISINTERLEAVED: uint32_t = 0x100;
+const AVIIF_KEYFRAME: uint32_t = 0x10;
+
+// The largest file whose sizes and offsets fit in 32 bits.
+const MAX_FILE_SIZE: uint64_t = 0xffffffff;
+
+fn cycles_per_frame() -> uint64_t {
+    CYCLES_PER_SCANLINE * (LAST_SCANLINE as uint64_t)
+}
+
+// The video frame rate as a fraction, in (rate, scale) form.
+pub fn frame_rate() -> (uint32_t, uint32_t) {
+    (EMULATED_CYCLES_PER_SECOND as uint32_t, cycles_per_frame() as uint32_t)
+}
+
+/// The number of audio samples that belong to the first `frames` frames.
+pub fn samples_for_frames(frames: uint64_t) -> uint64_t {
+    frames * cycles_per_frame() * (OUTPUT_SAMPLE_RATE as uint64_t) / EMULATED_CYCLES_PER_SECOND
+}
+
+/// Somewhere to send a recording. Frames come in the PPU's BGR format, top row first, and audio
+/// as mono samples at `OUTPUT_SAMPLE_RATE`.
+pub trait Encoder {
+    fn add_frame(&mut self, screen: &[uint8_t]);
+    /// Audio can arrive in bursts, so it doesn't line up with the frames.
+    fn add_audio(&mut self, samples: &[int16_t]);
+    /// Called once, at the end. The audio may be a little short of the video's length.
+    fn finish(&mut self);
+    /// Why the encoder can't take another frame, if it can't, in which case the recording has to
+    /// stop.
+    fn stopped(&mut self) -> Option<String> { None }
+}
+
+struct IndexEntry {
+    id: &'static [u8],
+    offset: uint32_t,
+    size: uint32_t,
+}
+
+pub struct Recorder {
+    file: File,
+    index: Vec<IndexEntry>,
+    movi_start: uint64_t,      // Offset of the 'movi' fourcc.
+    frames: uint64_t,
+    samples: uint64_t,
+    frame: Vec<uint8_t>,       // Scratch space for flipping frames bottom-up.
+
+    // Offsets of header fields that can only be filled in at the end.
+    riff_size_offset: uint64_t,
+    total_frames_offset: uint64_t,
+    video_length_offset: uint64_t,
+    audio_length_offset: uint64_t,
+    movi_size_offset: uint64_t,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Recorder, String> {
+        let file = match File::create(path) {
+            Ok(file) => file,
+            Err(err) => return Err(format!("Couldn't create {}: {}", path.display(), err)),
+        };
+        let mut recorder = Recorder {
+            file: file,
+            index: Vec::new(),
+            movi_start: 0,
+            frames: 0,
+            samples: 0,
+            frame: Vec::from_elem(FRAME_SIZE, 0u8),
+
+            riff_size_offset: 0,
+            total_frames_offset: 0,
+            video_length_offset: 0,
+            audio_length_offset: 0,
+            movi_size_offset: 0,
+        };
+        recorder.write_headers();
+        Ok(recorder)
+    }
+
+    fn tell(&mut self) -> uint64_t {
+        self.file.tell().unwrap()
+    }
+
+    fn write_fourcc(&mut self, fourcc: &[u8]) {
+        self.file.write(fourcc).unwrap();
+    }
+
+    fn write_u32(&mut self, val: uint32_t) {
+        self.file.write_le_u32(val).unwrap();
+    }
+
+    fn write_u16(&mut self, val: u16) {
+        self.file.write_le_u16(val).unwrap();
+    }
+
+    fn patch_u32(&mut self, offset: uint64_t, val: uint32_t) {
+        self.file.seek(offset as i64, SeekSet).unwrap();
+        self.write_u32(val);
+    }
+
+    fn write_headers(&mut self) {
+        let (rate, scale) = frame_rate();
+
+        self.write_fourcc(b"RIFF");
+        self.riff_size_offset = self.tell();
+        self.write_u32(0);
+        self.write_fourcc(b"AVI ");
+
+        self.write_fourcc(b"LIST");
+        self.write_u32(4 + (8 + 56) + (12 + (8 + 56) + (8 + 40)) + (12 + (8 + 56) + (8 + 18)));
+        self.write_fourcc(b"hdrl");
+
+        // Main AVI header.
+        self.write_fourcc(b"avih");
+        self.write_u32(56);
+        self.write_u32((1000000 * (scale as uint64_t) / (rate as uint64_t)) as uint32_t);
+        self.write_u32(0);                                      // Max bytes per second
+        self.write_u32(0);                                      // Padding granularity
+        self.write_u32(AVIF_HASINDEX | AVIF_ISINTERLEAVED);
+        self.total_frames_offset = self.tell();
+        self.write_u32(0);                                      // Total frames
+        self.write_u32(0);                                      // Initial frames
+        self.write_u32(2);                                      // Streams
+        self.write_u32(FRAME_SIZE as uint32_t);                 // Suggested buffer size
+        self.write_u32(SCREEN_WIDTH as uint32_t);
+        self.write_u32(SCREEN_HEIGHT as uint32_t);
+        for _ in range(0, 4) {
+            self.write_u32(0);                                  // Reserved
+        }
+
+        // Video stream.
+        self.write_fourcc(b"LIST");
+        self.write_u32(4 + (8 + 56) + (8 + 40));
+        self.write_fourcc(b"strl");
+        self.write_fourcc(b"strh");
+        self.write_u32(56);
+        self.write_fourcc(b"vids");
+        self.write_fourcc(b"DIB ");
+        self.write_u32(0);                                      // Flags
+        self.write_u16(0);                                      // Priority
+        self.write_u16(0);                                      // Language
+        self.write_u32(0);                                      // Initial frames
+        self.write_u32(scale);
+        self.write_u32(rate);
+        self.write_u32(0);                                      // Start
+        self.video_length_offset = self.tell();
+        self.write_u32(0);                                      // Length
+        self.write_u32(FRAME_SIZE as uint32_t);                 // Suggested buffer size
+        self.write_u32(0xffffffff);                             // Quality
+        self.write_u32(0);                                      // Sample size
+        self.write_u16(0);
+        self.write_u16(0);
+        self.write_u16(SCREEN_WIDTH as u16);
+        self.write_u16(SCREEN_HEIGHT as u16);
+
+        self.write_fourcc(b"strf");
+        self.write_u32(40);
+        self.write_u32(40);                                     // Header size
+        self.write_u32(SCREEN_WIDTH as uint32_t);
+        self.write_u32(SCREEN_HEIGHT as uint32_t);              // Positive: bottom-up rows
+        self.write_u16(1);                                      // Planes
+        self.write_u16(24);                                     // Bits per pixel
+        self.write_u32(0);                                      // BI_RGB
+        self.write_u32(FRAME_SIZE as uint32_t);
+        for _ in range(0, 4) {
+            self.write_u32(0);                                  // Resolution, palette
+        }
+
+        // Audio stream.
+        self.write_fourcc(b"LIST");
+        self.write_u32(4 + (8 + 56) + (8 + 18));
+        self.write_fourcc(b"strl");
+        self.write_fourcc(b"strh");
+        self.write_u32(56);
+        self.write_fourcc(b"auds");
+        self.write_u32(0);                                      // Handler
+        self.write_u32(0);                                      // Flags
+        self.write_u16(0);                                      // Priority
+        self.write_u16(0);                                      // Language
+        self.write_u32(0);                                      // Initial frames
+        self.write_u32(1);                                      // Scale
+        self.write_u32(OUTPUT_SAMPLE_RATE);                     // Rate
+        self.write_u32(0);                                      // Start
+        self.audio_length_offset = self.tell();
+        self.write_u32(0);                                      // Length
+        self.write_u32(OUTPUT_SAMPLE_RATE * 2);                 // Suggested buffer size
+        self.write_u32(0xffffffff);                             // Quality
+        self.write_u32(2);                                      // Sample size
+        for _ in range(0, 4) {
+            self.write_u16(0);                                  // Frame rectangle
+        }
+
+        self.write_fourcc(b"strf");
+        self.write_u32(18);
+        self.write_u16(1);                                      // WAVE_FORMAT_PCM
+        self.write_u16(1);                                      // Channels
+        self.write_u32(OUTPUT_SAMPLE_RATE);
+        self.write_u32(OUTPUT_SAMPLE_RATE * 2);                 // Bytes per second
+        self.write_u16(2);                                      // Block alignment
+        self.write_u16(16);                                     // Bits per sample
+        self.write_u16(0);                                      // Extra size
+
+        self.write_fourcc(b"LIST");
+        self.movi_size_offset = self.tell();
+        self.write_u32(0);
+        self.movi_start = self.tell();
+        self.write_fourcc(b"movi");
+    }
+
+    fn write_chunk(&mut self, id: &'static [u8], data: &[uint8_t]) {
+        let offset = (self.tell() - self.movi_start) as uint32_t;
+        self.write_fourcc(id);
+        self.write_u32(data.len() as uint32_t);
+        self.file.write(data).unwrap();
+        if data.len() % 2 != 0 {
+            self.file.write_u8(0).unwrap();
+        }
+        self.index.push(IndexEntry { id: id, offset: offset, size: data.len() as uint32_t });
+    }
+}
+
+impl Encoder for Recorder {
+    fn add_frame(&mut self, screen: &[uint8_t]) {
+        let stride = SCREEN_WIDTH * 3;
+        for y in range(0, SCREEN_HEIGHT) {
+            let (src, dest) = (y * stride, (SCREEN_HEIGHT - 1 - y) * stride);
+            self.frame.slice_mut(dest, dest + stride).clone_from_slice(screen.slice(src, src + stride));
+        }
+        let frame = self.frame.clone();
+        self.write_chunk(b"00db", frame.as_slice());
+        self.frames += 1;
+    }
+
+    // Players synchronize on the sample count, so bursts are fine.
+    fn add_audio(&mut self, samples: &[int16_t]) {
+        if samples.len() == 0 {
+            return;
+        }
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples.iter() {
+            bytes.push(sample as uint8_t);
+            bytes.push((sample >> 8) as uint8_t);
+        }
+        self.write_chunk(b"01wb", bytes.as_slice());
+        self.samples += samples.len() as uint64_t;
+    }
+
+    // Pads the audio out to the length of the video, writes the index, and fixes up the headers.
+    fn finish(&mut self) {
+        let expected = samples_for_frames(self.frames);
+        if self.samples < expected {
+            let silence = Vec::from_elem((expected - self.samples) as uint, 0 as int16_t);
+            self.add_audio(silence.as_slice());
+        }
+
+        let movi_end = self.tell();
+        self.write_fourcc(b"idx1");
+        let index_len = self.index.len() as uint32_t;
+        self.write_u32(index_len * 16);
+        for i in range(0, self.index.len()) {
+            let (id, offset, size) = (self.index[i].id, self.index[i].offset, self.index[i].size);
+            self.write_fourcc(id);
+            self.write_u32(AVIIF_KEYFRAME);
+            self.write_u32(offset);
+            self.write_u32(size);
+        }
+        let end = self.tell();
+
+        let (frames, samples, riff_size_offset) = (self.frames, self.samples, self.riff_size_offset);
+        self.patch_u32(riff_size_offset, (end - 8) as uint32_t);
+        let offset = self.total_frames_offset;
+        self.patch_u32(offset, frames as uint32_t);
+        let offset = self.video_length_offset;
+        self.patch_u32(offset, frames as uint32_t);
+        let offset = self.audio_length_offset;
+        self.patch_u32(offset, samples as uint32_t);
+        let (offset, movi_start) = (self.movi_size_offset, self.movi_start);
+        self.patch_u32(offset, (movi_end - movi_start) as uint32_t);
+    }
+
+    // Leaves room for another frame, a burst of audio, the padding `finish` adds, and the index.
+    fn stopped(&mut self) -> Option<String> {
+        let index_size = (self.index.len() as uint64_t + 16) * 16;
+        if self.tell() + index_size + 3 * (FRAME_SIZE as uint64_t) > MAX_FILE_SIZE {
+            Some("the file is as big as it can get".to_string())
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,272 @@
+//
+// sprocketnes/resample.rs
+//
+// Author: Patrick Walton
+//
+
+// Converts the APU's output, at nearly the CPU's clock rate, to the audio device's rate.
+//
+// There are two ways to do it. Linear interpolation is cheap but lets the harmonics of the square
+// waves alias down into the audible range, which sounds harsh. Speex's windowed sinc filter
+// removes them first; its quality, from 0 to 10, trades speed for a sharper filter.
+//
+// Either way, the output rate can be changed while running without a click. The APU uses this to
+// keep the audio device's buffer from running dry or overflowing as the emulated and real clocks
+// drift apart.
+//
+// The adaptive resampler, the default, uses the sinc filter until emulation falls behind real
+// time, which the sinc filter's cost may be to blame for, and then linear interpolation until it
+// has caught up, so that slow machines get continuous audio rather than gaps.
+
+use speex;
+
+use libc::{c_int, int16_t, int32_t, uint32_t};
+use std::cmp;
+
+pub trait Resampler {
+    /// Resamples all of `input` into `output`, returning the number of samples written. `output`
+    /// must have room for the input's duration at the output rate, plus a few samples.
+    fn process(&mut self, input: &[int16_t], output: &mut [int16_t]) -> uint;
+    fn set_output_rate(&mut self, out_rate: uint32_t);
+
+    /// Says, before each call to `process` while playing to a device, whether emulation is
+    /// behind real time. Resamplers with a cheaper mode can use it then.
+    fn set_falling_behind(&mut self, _: bool) {}
+}
+
+#[derive(Clone)]
+pub enum Quality {
+    Linear,
+    Sinc(c_int),
+    Adaptive(c_int),    // The sinc filter's quality
+}
+
+pub const DEFAULT_SINC_QUALITY: c_int = 0;
+
+// Parses what follows `sinc` or `adaptive`: nothing, or `:<quality>`.
+fn parse_sinc_quality(rest: &str) -> Option<c_int> {
+    if rest.len() == 0 {
+        return Some(DEFAULT_SINC_QUALITY);
+    }
+    if !rest.starts_with(":") {
+        return None;
+    }
+    match from_str::<c_int>(rest.slice_from(1)) {
+        Some(quality) if quality >= 0 && quality <= 10 => Some(quality),
+        _ => None,
+    }
+}
+
+/// Parses `linear`, `sinc`, `sinc:<quality>`, `adaptive`, or `adaptive:<quality>`.
+pub fn parse_quality(name: &str) -> Option<Quality> {
+    if name == "linear" {
+        return Some(Linear);
+    }
+    if name.starts_with("sinc") {
+        return parse_sinc_quality(name.slice_from(4)).map(Sinc);
+    }
+    if name.starts_with("adaptive") {
+        return parse_sinc_quality(name.slice_from(8)).map(Adaptive);
+    }
+    None
+}
+
+pub fn create(quality: Quality, in_rate: uint32_t, out_rate: uint32_t) -> Box<Resampler> {
+    match quality {
+        Linear => Box::new(LinearResampler::new(in_rate, out_rate)) as Box<Resampler>,
+        Sinc(quality) => {
+            Box::new(SincResampler::new(quality, in_rate, out_rate)) as Box<Resampler>
+        }
+        Adaptive(quality) => {
+            Box::new(AdaptiveResampler::new(quality, in_rate, out_rate)) as Box<Resampler>
+        }
+    }
+}
+
+//
+// Linear interpolation
+//
+
+struct LinearResampler {
+    in_rate: uint32_t,
+    out_rate: uint32_t,
+    // Where the next output sample falls, in input samples from the start of the next input.
+    // Between -1 and 0, it falls between the last sample we were given and the next one.
+    position: f64,
+    last: int16_t,
+}
+
+impl LinearResampler {
+    fn new(in_rate: uint32_t, out_rate: uint32_t) -> LinearResampler {
+        LinearResampler { in_rate: in_rate, out_rate: out_rate, position: 0.0, last: 0 }
+    }
+
+    fn reset(&mut self) {
+        self.position = 0.0;
+    }
+}
+
+impl Resampler for LinearResampler {
+    fn process(&mut self, input: &[int16_t], output: &mut [int16_t]) -> uint {
+        if input.len() == 0 {
+            return 0;
+        }
+
+        let step = self.in_rate as f64 / self.out_rate as f64;
+        let mut written = 0;
+        while written < output.len() {
+            let index = self.position.floor() as int;
+            if index + 1 >= input.len() as int {
+                break;
+            }
+            let a = if index < 0 { self.last } else { input[index as uint] } as f64;
+            let b = input[(index + 1) as uint] as f64;
+            output[written] = (a + (b - a) * (self.position - index as f64)) as int16_t;
+            written += 1;
+            self.position += step;
+        }
+
+        self.last = input[input.len() - 1];
+        self.position -= input.len() as f64;
+        written
+    }
+
+    fn set_output_rate(&mut self, out_rate: uint32_t) {
+        self.out_rate = out_rate;
+    }
+}
+
+//
+// Windowed sinc, courtesy of Speex
+//
+
+struct SincResampler {
+    speex: speex::Resampler,
+    in_rate: uint32_t,
+}
+
+impl SincResampler {
+    fn new(quality: c_int, in_rate: uint32_t, out_rate: uint32_t) -> SincResampler {
+        SincResampler {
+            speex: speex::Resampler::new(1, in_rate, out_rate, quality).unwrap(),
+            in_rate: in_rate,
+        }
+    }
+}
+
+impl Resampler for SincResampler {
+    fn process(&mut self, input: &[int16_t], output: &mut [int16_t]) -> uint {
+        if input.len() == 0 {
+            return 0;
+        }
+        let (_, written) = self.speex.process(0, input, output);
+        written as uint
+    }
+
+    fn set_output_rate(&mut self, out_rate: uint32_t) {
+        self.speex.set_rate(self.in_rate, out_rate);
+    }
+}
+
+//
+// Adaptive: sinc when we can afford it, linear when we can't
+//
+
+// How many buffers in a row, about a frame each, emulation must be behind before we switch to
+// linear interpolation. Any fewer and refilling the device's buffer after a pause would count.
+const BEHIND_BUFFERS: uint = 15;
+
+// How many buffers to wait after switching to linear interpolation before trying the sinc filter
+// again: about 2 seconds at first, doubling each time up to about a minute, so that a machine that
+// can never keep up with the filter settles on interpolation.
+const FIRST_RECOVERY_BUFFERS: uint = 120;
+const MAX_RECOVERY_BUFFERS: uint = 3840;
+
+struct AdaptiveResampler {
+    sinc: SincResampler,
+    linear: LinearResampler,
+    using_sinc: bool,
+    switched: bool,         // Whether to fade over from the other resampler in the next buffer
+    behind: uint,           // How many buffers in a row emulation has been behind
+    caught_up: uint,        // How many buffers in a row it hasn't, while using linear
+    recovery: uint,         // How many buffers to wait before trying the sinc filter again
+    next_recovery: uint,
+}
+
+impl AdaptiveResampler {
+    fn new(quality: c_int, in_rate: uint32_t, out_rate: uint32_t) -> AdaptiveResampler {
+        AdaptiveResampler {
+            sinc: SincResampler::new(quality, in_rate, out_rate),
+            linear: LinearResampler::new(in_rate, out_rate),
+            using_sinc: true,
+            switched: false,
+            behind: 0,
+            caught_up: 0,
+            recovery: FIRST_RECOVERY_BUFFERS,
+            next_recovery: FIRST_RECOVERY_BUFFERS,
+        }
+    }
+
+    // Switches to the other resampler. It hasn't been fed while idle, so it starts over.
+    fn switch(&mut self) {
+        self.using_sinc = !self.using_sinc;
+        if self.using_sinc {
+            self.sinc.speex.reset();
+        } else {
+            self.linear.reset();
+        }
+        self.switched = true;
+        self.behind = 0;
+        self.caught_up = 0;
+    }
+}
+
+impl Resampler for AdaptiveResampler {
+    fn process(&mut self, input: &[int16_t], output: &mut [int16_t]) -> uint {
+        let written = if self.using_sinc {
+            self.sinc.process(input, output)
+        } else {
+            self.linear.process(input, output)
+        };
+        if !self.switched {
+            return written;
+        }
+        self.switched = false;
+
+        // Run the old resampler over this buffer too, and fade from its output to the new one's,
+        // so that there's no click.
+        let mut old = Vec::from_elem(output.len(), 0i16);
+        let old_written = if self.using_sinc {
+            self.linear.process(input, old.as_mut_slice())
+        } else {
+            self.sinc.process(input, old.as_mut_slice())
+        };
+        let fade = cmp::min(written, old_written);
+        for i in range(0, fade) {
+            let (from, to) = (old[i] as int32_t, output[i] as int32_t);
+            output[i] = (from + (to - from) * i as int32_t / fade as int32_t) as int16_t;
+        }
+        written
+    }
+
+    fn set_output_rate(&mut self, out_rate: uint32_t) {
+        self.sinc.set_output_rate(out_rate);
+        self.linear.set_output_rate(out_rate);
+    }
+
+    fn set_falling_behind(&mut self, behind: bool) {
+        if self.using_sinc {
+            self.behind = if behind { self.behind + 1 } else { 0 };
+            if self.behind >= BEHIND_BUFFERS {
+                self.recovery = self.next_recovery;
+                self.next_recovery = cmp::min(self.next_recovery * 2, MAX_RECOVERY_BUFFERS);
+                self.switch();
+            }
+        } else {
+            self.caught_up = if behind { 0 } else { self.caught_up + 1 };
+            if self.caught_up >= self.recovery {
+                self.switch();
+            }
+        }
+    }
+}
@@ -4,63 +4,254 @@
 // Author: Patrick Walton
 //
 
+use mapper::{Horizontal, Mirroring, Vertical};
+use util;
+
+use std::cmp;
 use std::io::File;
 use std::vec::Vec;
 
-use libc::uint8_t;
+use libc::{uint8_t, uint32_t};
+
+const INES_MAGIC: &'static [u8] = b"NES\x1a";
 
+#[derive(Clone)]
 pub struct Rom {
     pub header: INesHeader,
     pub prg: Vec<uint8_t>,         // PRG-ROM
     pub chr: Vec<uint8_t>,         // CHR-ROM
+    pub disk_sides: Vec<Vec<uint8_t>>,  // Famicom Disk System images only
+    pub problems: Vec<DumpProblem>,
 }
 
-impl Rom {
-    fn from_file(file: &mut File) -> Rom {
-        let mut buffer = [ 0, ..16 ];
-        file.read_at_least(buffer.len(), buffer).unwrap();
+//
+// Famicom Disk System images
+//
+// These are either the raw 65500-byte disk sides one after another, or the same with a 16-byte
+// fwNES header in front. Either way we present them as a ROM with no PRG or CHR, using the
+// mapper number NES 2.0 reserves for the disk system.
+//
 
-        let header = INesHeader {
-            magic: [
-                buffer[0],
-                buffer[1],
-                buffer[2],
-                buffer[3],
-            ],
-            prg_rom_size: buffer[4],
-            chr_rom_size: buffer[5],
-            flags_6: buffer[6],
-            flags_7: buffer[7],
-            prg_ram_size: buffer[8],
-            flags_9: buffer[9],
-            flags_10: buffer[10],
-            zero: [ 0, ..5 ]
-        };
+const FDS_HEADER_MAGIC: &'static [u8] = b"FDS\x1a";
+const FDS_DISK_MAGIC: &'static [u8] = b"\x01*NINTENDO-HVC*";
+
+pub const FDS_SIDE_SIZE: uint = 65500;
+pub const FDS_MAPPER: uint8_t = 20;
 
-        assert!(header.magic == [
-            'N' as uint8_t,
-            'E' as uint8_t,
-            'S' as uint8_t,
-            0x1a,
-        ]);
+fn is_disk_image(data: &[uint8_t]) -> bool {
+    data.starts_with(FDS_HEADER_MAGIC) || data.starts_with(FDS_DISK_MAGIC)
+}
+
+//
+// Dump verification
+//
+// Bad dumps are a major source of "emulator bug" reports, so we check the file against its header
+// on load and say what looks wrong.
+//
+
+#[derive(Clone)]
+pub enum DumpProblem {
+    Truncated(uint, uint),          // (expected bytes, actual bytes); the rest is zero-filled
+    TrailingData(uint),             // Bytes left over after CHR-ROM
+    TrailingPadding(uint, uint8_t), // Leftover bytes that are all one value
+    ShortDiskSide(uint, uint),      // (side, actual bytes); the rest is zero-filled
+    MirroredPrg(uint),              // PRG-ROM is the given number of KB repeated
+    MirroredChr(uint),              // CHR-ROM is the given number of KB repeated
+    NoPrg,                          // The header claims no PRG-ROM at all
+}
 
+impl DumpProblem {
+    pub fn to_str(&self) -> String {
+        match *self {
+            Truncated(expected, actual) => {
+                format!("underdump: header says {} bytes of ROM, file has {}", expected, actual)
+            }
+            TrailingData(len) => format!("overdump: {} extra bytes after CHR-ROM", len),
+            TrailingPadding(len, val) => {
+                format!("overdump: {} bytes of ${:02X} padding after CHR-ROM", len, val as uint)
+            }
+            MirroredPrg(kb) => format!("PRG-ROM is the same {}K repeated; header may be wrong", kb),
+            MirroredChr(kb) => format!("CHR-ROM is the same {}K repeated; header may be wrong", kb),
+            NoPrg => "header claims no PRG-ROM".to_string(),
+            ShortDiskSide(side, actual) => {
+                format!("underdump: disk side {} has {} of {} bytes", side, actual, FDS_SIDE_SIZE)
+            }
+        }
+    }
+}
+
+// Returns the size of the smallest block that the data is made of repeated copies of, if any.
+fn repeated_block_size(data: &[uint8_t], min_size: uint) -> Option<uint> {
+    let mut size = data.len() / 2;
+    let mut result = None;
+    while size >= min_size && data.len() % size == 0 {
+        let first = data.slice_to(size);
+        if !range(1, data.len() / size).all(|i| data.slice(i * size, (i + 1) * size) == first) {
+            break;
+        }
+        result = Some(size);
+        size /= 2;
+    }
+    result
+}
+
+fn check_dump(header: &INesHeader, data: &[uint8_t], prg: &[uint8_t], chr: &[uint8_t])
+              -> Vec<DumpProblem> {
+    let mut problems = Vec::new();
+
+    if header.prg_rom_size == 0 {
+        problems.push(NoPrg);
+    }
+
+    let expected = prg.len() + chr.len();
+    if data.len() < expected {
+        problems.push(Truncated(expected, data.len()));
+    } else if data.len() > expected {
+        let trailing = data.slice_from(expected);
+        if trailing.iter().all(|&byte| byte == trailing[0]) {
+            problems.push(TrailingPadding(trailing.len(), trailing[0]));
+        } else {
+            problems.push(TrailingData(trailing.len()));
+        }
+    }
+
+    match repeated_block_size(prg, 16384) {
+        None => {}
+        Some(size) => problems.push(MirroredPrg(size / 1024)),
+    }
+    match repeated_block_size(chr, 8192) {
+        None => {}
+        Some(size) => problems.push(MirroredChr(size / 1024)),
+    }
+
+    problems
+}
+
+impl Rom {
+    fn from_data(data: Vec<uint8_t>) -> Result<Rom, String> {
+        if is_disk_image(data.as_slice()) {
+            return Ok(Rom::from_disk_image(data.as_slice()));
+        }
+        if data.len() < 16 {
+            return Err("the file is too short for an iNES header".to_string());
+        }
+        if data.slice_to(4) != INES_MAGIC {
+            return Err("not an iNES or FDS image".to_string());
+        }
+
+        let header = INesHeader::from_bytes(data.slice_to(16));
+        let mut data = data.slice_from(16).to_vec();
+        if header.trainer() {
+            // We don't support trainers, but we mustn't mistake one for PRG-ROM either.
+            data = data.slice_from(cmp::min(512, data.len())).to_vec();
+        }
+
+        // Missing data is zero-filled so that a truncated ROM at least gets as far as it can.
         let mut prg_rom = Vec::from_elem(header.prg_rom_size as uint * 16384, 0u8);
-        file.read_at_least(prg_rom.len(), prg_rom.as_mut_slice()).unwrap();
         let mut chr_rom = Vec::from_elem(header.chr_rom_size as uint * 8192, 0u8);
-        file.read_at_least(chr_rom.len(), chr_rom.as_mut_slice()).unwrap();
+        let prg_len = cmp::min(prg_rom.len(), data.len());
+        prg_rom.slice_to_mut(prg_len).clone_from_slice(data.slice_to(prg_len));
+        let chr_len = cmp::min(chr_rom.len(), data.len() - prg_len);
+        chr_rom.slice_to_mut(chr_len).clone_from_slice(data.slice(prg_len, prg_len + chr_len));
 
-        Rom {
+        let problems = check_dump(&header, data.as_slice(), prg_rom.as_slice(), chr_rom.as_slice());
+
+        Ok(Rom {
             header: header,
             prg: prg_rom,
             chr: chr_rom,
+            disk_sides: Vec::new(),
+            problems: problems,
+        })
+    }
+
+    fn from_disk_image(data: &[uint8_t]) -> Rom {
+        let data = if data.starts_with(FDS_HEADER_MAGIC) {
+            data.slice_from(cmp::min(16, data.len()))
+        } else {
+            data
+        };
+
+        let mut sides = Vec::new();
+        let mut problems = Vec::new();
+        for chunk in data.chunks(FDS_SIDE_SIZE) {
+            let mut side = chunk.to_vec();
+            if side.len() < FDS_SIDE_SIZE {
+                problems.push(ShortDiskSide(sides.len(), side.len()));
+                let missing = FDS_SIDE_SIZE - side.len();
+                side.grow(missing, 0u8);
+            }
+            sides.push(side);
+        }
+
+        Rom {
+            header: INesHeader::for_disk(),
+            prg: Vec::new(),
+            chr: Vec::new(),
+            disk_sides: sides,
+            problems: problems,
         }
     }
 
-    pub fn from_path(path: &Path) -> Rom {
-        Rom::from_file(&mut File::open(path).unwrap())
+    pub fn is_disk(&self) -> bool {
+        self.header.mapper_number() == FDS_MAPPER
+    }
+
+    pub fn from_path(path: &Path) -> Result<Rom, String> {
+        match File::open(path).read_to_end() {
+            Ok(data) => Rom::from_data(data),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+
+    /// A 32K NROM cartridge with blank PRG-ROM and CHR-ROM, for tests that need a console.
+    #[cfg(test)]
+    pub fn blank() -> Rom {
+        let header = [ 'N' as uint8_t, 'E' as uint8_t, 'S' as uint8_t, 0x1a, 2, 1, 0, 0,
+                       0, 0, 0, 0, 0, 0, 0, 0 ];
+        Rom {
+            header: INesHeader::from_bytes(header.as_slice()),
+            prg: Vec::from_elem(32768, 0),
+            chr: Vec::from_elem(8192, 0),
+            disk_sides: Vec::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// The CRC-32 of PRG-ROM followed by CHR-ROM, as used by ROM databases.
+    pub fn crc32(&self) -> uint32_t {
+        util::crc32_update(util::crc32(self.prg.as_slice()), self.chr.as_slice())
+    }
+
+    /// A CRC-32 that tells games apart: `crc32` for cartridges, and one of the disks' contents for
+    /// disk images, which have no PRG-ROM.
+    pub fn identity_crc32(&self) -> uint32_t {
+        if !self.is_disk() {
+            return self.crc32();
+        }
+        self.disk_sides.iter().fold(0, |crc, side| util::crc32_update(crc, side.as_slice()))
+    }
+
+    /// The header summary followed by the results of checking the dump.
+    pub fn to_str(&self) -> String {
+        let mut string = if self.is_disk() {
+            format!("Famicom Disk System image\nDisk sides: {}", self.disk_sides.len())
+        } else {
+            let mut string = self.header.to_str();
+            string.push_str(format!("\nCRC-32: {:08X}", self.crc32() as uint).as_slice());
+            string
+        };
+        if self.problems.len() == 0 {
+            string.push_str("\nDump: OK");
+        }
+        for problem in self.problems.iter() {
+            string.push_str(format!("\nDump: {}", problem.to_str()).as_slice());
+        }
+        string
     }
 }
 
+#[derive(Clone)]
 pub struct INesHeader {
     pub magic: [uint8_t; 4],   // 'N' 'E' 'S' '\x1a'
     pub prg_rom_size: uint8_t,   // number of 16K units of PRG-ROM
@@ -70,19 +261,92 @@ pub struct INesHeader {
     pub prg_ram_size: uint8_t,   // number of 8K units of PRG-RAM
     pub flags_9: uint8_t,
     pub flags_10: uint8_t,
-    pub zero: [uint8_t; 5],    // always zero
+    pub zero: [uint8_t; 5],    // always zero, except in NES 2.0 headers and bad dumps
 }
 
 impl INesHeader {
+    fn from_bytes(buffer: &[uint8_t]) -> INesHeader {
+        assert!(buffer.len() == 16, "file too short for an iNES header");
+        let header = INesHeader {
+            magic: [
+                buffer[0],
+                buffer[1],
+                buffer[2],
+                buffer[3],
+            ],
+            prg_rom_size: buffer[4],
+            chr_rom_size: buffer[5],
+            flags_6: buffer[6],
+            flags_7: buffer[7],
+            prg_ram_size: buffer[8],
+            flags_9: buffer[9],
+            flags_10: buffer[10],
+            zero: [ buffer[11], buffer[12], buffer[13], buffer[14], buffer[15] ]
+        };
+
+        assert!(header.magic.as_slice() == INES_MAGIC);
+
+        header
+    }
+
+    // The header we make up for Famicom Disk System images.
+    fn for_disk() -> INesHeader {
+        INesHeader {
+            magic: [ 'N' as uint8_t, 'E' as uint8_t, 'S' as uint8_t, 0x1a ],
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            flags_6: (FDS_MAPPER & 0x0f) << 4,
+            flags_7: FDS_MAPPER & 0xf0,
+            prg_ram_size: 0,
+            flags_9: 0,
+            flags_10: 0,
+            zero: [ 0, 0, 0, 0, 0 ],
+        }
+    }
+
     pub fn mapper(&self) -> uint8_t {
         (self.flags_7 & 0xf0) | (self.flags_6 >> 4)
     }
     pub fn ines_mapper(&self) -> uint8_t {
         self.flags_6 >> 4
     }
+    // The mapper number to use. Old dumps often have junk (e.g. "DiskDude!") from byte 7 onward,
+    // in which case only the low nibble can be trusted.
+    pub fn mapper_number(&self) -> uint8_t {
+        if self.is_nes2() || self.zero.iter().all(|&byte| byte == 0) {
+            self.mapper()
+        } else {
+            self.ines_mapper()
+        }
+    }
     pub fn trainer(&self) -> bool {
         (self.flags_6 & 0x04) != 0
     }
+    pub fn battery(&self) -> bool {
+        (self.flags_6 & 0x02) != 0
+    }
+    pub fn four_screen(&self) -> bool {
+        (self.flags_6 & 0x08) != 0
+    }
+    pub fn mirroring(&self) -> Mirroring {
+        if (self.flags_6 & 0x01) == 0 { Horizontal } else { Vertical }
+    }
+    pub fn is_nes2(&self) -> bool {
+        (self.flags_7 & 0x0c) == 0x08
+    }
+    // Old dumps of mapper 99 games don't always set the VS. System bit, but no other console
+    // used that board.
+    pub fn is_vs_system(&self) -> bool {
+        (self.flags_7 & 0x01) != 0 || self.mapper_number() == 99
+    }
+    // Which PPU a VS. System game expects, which decides its palette. Only NES 2.0 headers say.
+    pub fn vs_ppu(&self) -> Option<uint8_t> {
+        if self.is_nes2() { Some(self.zero[2] & 0x0f) } else { None }
+    }
+    // Only meaningful for NES 2.0 headers, where byte 8 is no longer the PRG-RAM size.
+    pub fn submapper(&self) -> uint8_t {
+        self.prg_ram_size >> 4
+    }
 
     pub fn to_str(&self) -> String {
         (format!("PRG-ROM size: {}\nCHR-ROM size: {}\nMapper: {}/{}\nTrainer: {}",
@@ -0,0 +1,317 @@
+//
+// sprocketnes/rtc.rs
+//
+// Author: Patrick Walton
+//
+
+// Real-time clocks, for boards that keep the date and time. The clock counts seconds since the
+// Unix epoch and shows them to the game as BCD registers, laid out like those of the common RTC
+// chips: seconds, minutes, hours (24-hour), day, month, year (two digits, from 2000), and weekday
+// (0 is Sunday). A board with a clock maps the registers wherever the chip sits, and ticks the
+// clock once per CPU cycle from `clock_audio`, which is called every cycle whether or not the
+// board makes sound.
+//
+// The clock runs from one of two sources:
+//
+// * Host time: the computer's clock, plus however far the game has set it forward or back. This
+//   is how the real thing behaves, time passing with the power off.
+//
+// * Emulated time: a count of CPU cycles from a fixed starting point, so that the same run always
+//   sees the same time. Headless runs, movies and netplay always use this, since the time of day
+//   would make them come out differently every time. So do bots.
+//
+// The clock is kept with the battery-backed memory, in a file next to the ROM named like the save
+// but ending in `.rtc`, and boards save it in their states.
+
+use util::Save;
+use util;
+
+use libc::{uint8_t, uint64_t};
+use std::io::{BufReader, MemWriter};
+
+/// The NTSC CPU's clock rate.
+const CPU_CYCLES_PER_SECOND: uint = 1789773;
+
+/// Where emulated time starts: 2000-01-01 00:00:00.
+const EMULATED_EPOCH: i64 = 946684800;
+
+const SECONDS_PER_DAY: i64 = 86400;
+
+/// The length of the `.rtc` file.
+pub const BATTERY_SIZE: uint = 24;
+
+#[derive(Clone, PartialEq, Show)]
+pub enum ClockSource {
+    HostTime,
+    EmulatedTime,
+}
+
+/// A moment, broken down as the registers show it.
+#[derive(Clone, PartialEq, Show)]
+pub struct Time {
+    pub year: i64,
+    pub month: uint,        // 1-12
+    pub day: uint,          // 1-31
+    pub hour: uint,
+    pub minute: uint,
+    pub second: uint,
+    pub weekday: uint,      // 0 is Sunday
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar. Out-of-range days and months
+// carry over into the next month or year.
+// See http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: i64, month: uint, day: uint) -> i64 {
+    let (month, day) = (month as i64, day as i64);
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+// The inverse of `days_from_civil`: the year, month and day.
+fn civil_from_days(days: i64) -> (i64, uint, uint) {
+    let days = days + 719468;
+    let era = (if days >= 0 { days } else { days - 146096 }) / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as uint, day as uint)
+}
+
+impl Time {
+    pub fn from_seconds(seconds: i64) -> Time {
+        // Round toward negative infinity, so that times before 1970 come out right too.
+        let days = (if seconds >= 0 { seconds } else { seconds - SECONDS_PER_DAY + 1 }) /
+            SECONDS_PER_DAY;
+        let of_day = (seconds - days * SECONDS_PER_DAY) as uint;
+        let (year, month, day) = civil_from_days(days);
+        Time {
+            year: year,
+            month: month,
+            day: day,
+            hour: of_day / 3600,
+            minute: of_day / 60 % 60,
+            second: of_day % 60,
+            weekday: ((days % 7 + 11) % 7) as uint,     // 1970-01-01 was a Thursday.
+        }
+    }
+
+    /// Seconds since the epoch. The weekday is ignored, since the date decides it.
+    pub fn to_seconds(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY +
+            (self.hour * 3600 + self.minute * 60 + self.second) as i64
+    }
+}
+
+fn to_bcd(val: uint) -> uint8_t {
+    ((val / 10 % 10) << 4 | val % 10) as uint8_t
+}
+
+fn from_bcd(val: uint8_t) -> uint {
+    (val >> 4) as uint * 10 + (val & 0x0f) as uint
+}
+
+pub struct Rtc {
+    pub source: ClockSource,
+    offset: i64,        // Host time: how far the game has set the clock from the host's
+    seconds: i64,       // Emulated time: seconds since the epoch
+    cycles: uint,       // Emulated time: CPU cycles into the current second
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc { source: EmulatedTime, offset: 0, seconds: EMULATED_EPOCH, cycles: 0 }
+    }
+
+    fn host_seconds() -> i64 {
+        (util::current_time_micros() / 1000000) as i64
+    }
+
+    /// Seconds since the epoch, as the clock has it.
+    pub fn now(&self) -> i64 {
+        match self.source {
+            HostTime => Rtc::host_seconds() + self.offset,
+            EmulatedTime => self.seconds,
+        }
+    }
+
+    pub fn time(&self) -> Time {
+        Time::from_seconds(self.now())
+    }
+
+    pub fn set_time(&mut self, time: &Time) {
+        let seconds = time.to_seconds();
+        match self.source {
+            HostTime => self.offset = seconds - Rtc::host_seconds(),
+            EmulatedTime => {
+                self.seconds = seconds;
+                self.cycles = 0;
+            }
+        }
+    }
+
+    /// Called once per CPU cycle.
+    #[inline]
+    pub fn clock(&mut self) {
+        if self.source != EmulatedTime {
+            return;
+        }
+        self.cycles += 1;
+        if self.cycles == CPU_CYCLES_PER_SECOND {
+            self.cycles = 0;
+            self.seconds += 1;
+        }
+    }
+
+    /// Reads one of the registers, in BCD.
+    pub fn register(&self, index: uint) -> uint8_t {
+        let time = self.time();
+        match index {
+            0 => to_bcd(time.second),
+            1 => to_bcd(time.minute),
+            2 => to_bcd(time.hour),
+            3 => to_bcd(time.day),
+            4 => to_bcd(time.month),
+            5 => to_bcd((time.year - 2000) as uint),
+            6 => time.weekday as uint8_t,
+            _ => 0,
+        }
+    }
+
+    /// Sets one of the registers, in BCD. The weekday follows from the date, so writes to it are
+    /// ignored.
+    pub fn set_register(&mut self, index: uint, val: uint8_t) {
+        let mut time = self.time();
+        let val = from_bcd(val);
+        match index {
+            0 => time.second = val,
+            1 => time.minute = val,
+            2 => time.hour = val,
+            3 => time.day = val,
+            4 => time.month = val,
+            5 => time.year = 2000 + val as i64,
+            _ => return,
+        }
+        self.set_time(&time);
+    }
+
+    /// The contents of the `.rtc` file.
+    pub fn battery_data(&mut self) -> Vec<uint8_t> {
+        let mut writer = MemWriter::new();
+        self.save(&mut writer);
+        writer.into_inner()
+    }
+
+    /// Restores the clock from the contents of the `.rtc` file.
+    pub fn load_battery_data(&mut self, data: &[uint8_t]) -> Result<(), String> {
+        if data.len() != BATTERY_SIZE {
+            return Err(format!("expected {} bytes, not {}", BATTERY_SIZE, data.len()));
+        }
+        self.load(&mut BufReader::new(data));
+        Ok(())
+    }
+}
+
+// The source is how this run of the emulator keeps time, not part of the clock, so it isn't saved.
+impl Save for Rtc {
+    fn save(&mut self, fd: &mut Writer) {
+        let mut offset = self.offset as uint64_t;
+        offset.save(fd);
+        let mut seconds = self.seconds as uint64_t;
+        seconds.save(fd);
+        self.cycles.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let mut offset: uint64_t = 0;
+        offset.load(fd);
+        self.offset = offset as i64;
+        let mut seconds: uint64_t = 0;
+        seconds.load(fd);
+        self.seconds = seconds as i64;
+        self.cycles.load(fd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CPU_CYCLES_PER_SECOND, EMULATED_EPOCH, HostTime, Rtc, Time};
+
+    fn time(year: i64, month: uint, day: uint, hour: uint, minute: uint, second: uint,
+            weekday: uint) -> Time {
+        Time {
+            year: year,
+            month: month,
+            day: day,
+            hour: hour,
+            minute: minute,
+            second: second,
+            weekday: weekday,
+        }
+    }
+
+    #[test]
+    fn dates_convert_both_ways() {
+        let cases = [
+            (0, time(1970, 1, 1, 0, 0, 0, 4)),
+            (951782400, time(2000, 2, 29, 0, 0, 0, 2)),
+            (1234567890, time(2009, 2, 13, 23, 31, 30, 5)),
+            (-1, time(1969, 12, 31, 23, 59, 59, 3)),
+        ];
+        for &(seconds, ref expected) in cases.iter() {
+            assert_eq!(Time::from_seconds(seconds), *expected);
+            assert_eq!(expected.to_seconds(), seconds);
+        }
+    }
+
+    #[test]
+    fn emulated_time_follows_the_cpu() {
+        let mut rtc = Rtc::new();
+        assert_eq!(rtc.now(), EMULATED_EPOCH);
+        for _ in range(0, CPU_CYCLES_PER_SECOND - 1) {
+            rtc.clock();
+        }
+        assert_eq!(rtc.now(), EMULATED_EPOCH);
+        rtc.clock();
+        assert_eq!(rtc.now(), EMULATED_EPOCH + 1);
+    }
+
+    #[test]
+    fn registers_are_bcd() {
+        let mut rtc = Rtc::new();
+        rtc.set_time(&time(2015, 12, 31, 23, 59, 58, 0));
+        assert_eq!(rtc.register(0), 0x58);
+        assert_eq!(rtc.register(2), 0x23);
+        assert_eq!(rtc.register(4), 0x12);
+        assert_eq!(rtc.register(5), 0x15);
+        assert_eq!(rtc.register(6), 4);         // A Thursday
+
+        rtc.set_register(3, 0x24);
+        assert_eq!(rtc.time(), time(2015, 12, 24, 23, 59, 58, 4));
+    }
+
+    #[test]
+    fn host_time_keeps_the_games_setting() {
+        let mut rtc = Rtc::new();
+        rtc.source = HostTime;
+        let target = time(2001, 6, 15, 12, 0, 0, 5);
+        rtc.set_time(&target);
+        let mut restored = Rtc::new();
+        restored.source = HostTime;
+        restored.load_battery_data(rtc.battery_data().as_slice()).unwrap();
+        // The host's clock may tick over in between.
+        assert!(restored.now() - target.to_seconds() <= 1);
+    }
+
+    #[test]
+    fn short_battery_files_are_refused() {
+        assert!(Rtc::new().load_battery_data([ 0u8; 8 ].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+//
+// sprocketnes/scroll.rs
+//
+// Author: Patrick Walton
+//
+
+// Writes the scroll position of every visible scanline to a CSV file, a frame at a time, for
+// debugging status bars and other split-screen effects. Each row gives the position both as a
+// point in the 512x480 square of nametables and broken down the way the PPU's registers hold it.
+// Lines that don't carry on from the one above, which is where a game has changed the scroll
+// mid-frame, are marked as splits.
+//
+// To see which writes caused a split, `--log ppu=debug` logs every write to PPUCTRL, PPUSCROLL,
+// and PPUADDR made while a frame is being drawn, along with the line and dot it landed on.
+
+use ppu::LineScroll;
+
+use std::io::{BufferedWriter, File, IoResult};
+
+pub struct ScrollDump {
+    file: BufferedWriter<File>,
+    frame: uint,
+}
+
+impl ScrollDump {
+    pub fn create(path: &Path) -> IoResult<ScrollDump> {
+        let mut file = BufferedWriter::new(try!(File::create(path)));
+        try!(file.write_line("frame,line,x,y,nametable,coarse_x,fine_x,coarse_y,fine_y,split"));
+        Ok(ScrollDump { file: file, frame: 0 })
+    }
+
+    pub fn add_frame(&mut self, lines: &[LineScroll]) {
+        for (line, scroll) in lines.iter().enumerate() {
+            let split = line > 0 && {
+                let above = &lines[line - 1];
+                scroll.x != above.x || scroll.y != (above.y + 1) % 480
+            };
+            self.file.write_line(format!("{},{},{},{},{},{},{},{},{},{}",
+                                         self.frame,
+                                         line,
+                                         scroll.x,
+                                         scroll.y,
+                                         scroll.nametable(),
+                                         scroll.coarse_x(),
+                                         scroll.fine_x(),
+                                         scroll.coarse_y(),
+                                         scroll.fine_y(),
+                                         if split { 1u } else { 0 }).as_slice()).unwrap();
+        }
+        self.frame += 1;
+    }
+}
@@ -4,8 +4,7 @@
 // Author: Patrick Walton
 //
 
-use libc::{c_int, c_void, int16_t, uint8_t, uint32_t};
-use std::mem::transmute;
+use libc::{c_int, c_void, int16_t, uint32_t};
 use std::ptr::null;
 
 type SpeexResamplerState = c_void;
@@ -26,6 +25,11 @@ extern {
                                    out: *const int16_t,
                                    out_len: *mut uint32_t)
                                    -> c_int;
+    fn speex_resampler_set_rate(st: *const SpeexResamplerState,
+                                in_rate: uint32_t,
+                                out_rate: uint32_t)
+                                -> c_int;
+    fn speex_resampler_reset_mem(st: *const SpeexResamplerState) -> c_int;
 }
 
 pub struct Resampler {
@@ -52,24 +56,39 @@ impl Resampler {
         }
     }
 
-    pub fn process(&self, channel_index: uint32_t, input: &[int16_t], out: &mut [uint8_t])
+    pub fn process(&self, channel_index: uint32_t, input: &[int16_t], out: &mut [int16_t])
                    -> (uint32_t, uint32_t) {
         unsafe {
             assert!(input.len() <= 0xffffffff);
-            assert!(out.len() / 2 <= 0xffffffff);
-            let (in_len, out_len) = (input.len() as uint32_t, out.len() as uint32_t / 2);
-            let mut in_len = in_len;
-            let mut out_len = out_len;
+            assert!(out.len() <= 0xffffffff);
+            let mut in_len = input.len() as uint32_t;
+            let mut out_len = out.len() as uint32_t;
             let err = speex_resampler_process_int(self.speex_resampler,
                                                   channel_index,
                                                   &input[0],
                                                   &mut in_len,
-                                                  transmute(&out[0]),
+                                                  &out[0],
                                                   &mut out_len);
             assert!(err == 0);
             (in_len, out_len)
         }
     }
+
+    /// Changes the rates without losing the filter's state, so there's no click.
+    pub fn set_rate(&self, in_rate: uint32_t, out_rate: uint32_t) {
+        unsafe {
+            let err = speex_resampler_set_rate(self.speex_resampler, in_rate, out_rate);
+            assert!(err == 0);
+        }
+    }
+
+    /// Forgets the input the filter has seen, as if starting over.
+    pub fn reset(&self) {
+        unsafe {
+            let err = speex_resampler_reset_mem(self.speex_resampler);
+            assert!(err == 0);
+        }
+    }
 }
 
 impl Drop for Resampler {
@@ -0,0 +1,129 @@
+//
+// sprocketnes/state.rs
+//
+// Author: Patrick Walton
+//
+
+// Version headers for the files the emulator writes for itself to read back: saved states and
+// movies. Every `Save` implementation writes its fields in order with nothing to say what they
+// are, so a state from a different version of the emulator would load as garbage, or crash. The
+// header says which format the rest of the file is in, and which version of the emulator wrote
+// it, so that a file we can't read is turned away with a message saying why:
+//
+// * 4 bytes of magic, saying what kind of file it is.
+// * The format version, as a little-endian 16-bit number.
+// * The emulator's version, as a byte giving its length and then the string.
+//
+// Files from before headers were added are turned away like any other format we can't read, since
+// there's no telling which layout they're in.
+//
+// Saved states go on to give the game's CRC-32, as `Rom::identity_crc32` has it, as a
+// little-endian 32-bit number, since a state only makes sense for the game that saved it. Their
+// body has to be as long as the game's state is, too, since the `Save` implementations read it
+// without checking.
+//
+// States kept in memory, for run-ahead and netplay, never outlive the emulator, so they don't
+// have a header.
+
+use cpu::Cpu;
+use main::VERSION;
+use mem::MemMap;
+use util::Save;
+
+use libc::{uint8_t, uint16_t};
+use std::io::{BufReader, MemWriter};
+
+const STATE_MAGIC: &'static [u8] = b"SNST";
+
+/// The version of the layout `Save` gives the console's state. This goes up whenever a change to
+/// any `Save` implementation moves the fields around. Format 2 added the MMC1's PRG-RAM, 3
+/// four-screen nametable RAM, and 4 the game's CRC-32.
+pub const STATE_FORMAT: uint16_t = 4;
+
+/// Writes a header for a file of the given kind and format.
+pub fn write_header(fd: &mut Writer, magic: &[uint8_t], format: uint16_t) {
+    fd.write(magic).unwrap();
+    fd.write_le_u16(format).unwrap();
+    fd.write_u8(VERSION.len() as uint8_t).unwrap();
+    fd.write(VERSION.as_bytes()).unwrap();
+}
+
+/// Checks the header at the start of `data`, and returns its length. `kind` names the kind of
+/// file, for the error message.
+pub fn read_header(data: &[uint8_t], magic: &[uint8_t], format: uint16_t, kind: &str)
+                   -> Result<uint, String> {
+    if !data.starts_with(magic) {
+        return Err(format!("the {} is from a version of sprocketnes from before {} formats were \
+                            numbered, or isn't a {} at all, but this is sprocketnes {}, which \
+                            can only read format {}",
+                           kind, kind, kind, VERSION, format));
+    }
+    let version_offset = magic.len() + 3;
+    if data.len() < version_offset {
+        return Err(format!("the {} is cut off in its header", kind));
+    }
+    let file_format = data[magic.len()] as uint16_t | (data[magic.len() + 1] as uint16_t << 8);
+    let version_end = version_offset + data[version_offset - 1] as uint;
+    if data.len() < version_end {
+        return Err(format!("the {} is cut off in its header", kind));
+    }
+    let version = String::from_utf8_lossy(data.slice(version_offset, version_end));
+
+    if file_format > format {
+        return Err(format!("the {} is from sprocketnes {}, which writes {} format {}, but this \
+                            is sprocketnes {}, which can only read format {}; try a newer \
+                            version",
+                           kind, version, kind, file_format, VERSION, format));
+    }
+    if file_format < format {
+        return Err(format!("the {} is from sprocketnes {}, which writes {} format {}, but this \
+                            is sprocketnes {}, which can only read format {}",
+                           kind, version, kind, file_format, VERSION, format));
+    }
+    Ok(version_end)
+}
+
+/// Saves a state, with its header.
+pub fn save(fd: &mut Writer, cpu: &mut Cpu<MemMap>) {
+    write_header(fd, STATE_MAGIC, STATE_FORMAT);
+    fd.write_le_u32(cpu.mem.rom_crc).unwrap();
+    cpu.save(fd);
+}
+
+/// Saves a state, with its header, to memory.
+pub fn save_to_vec(cpu: &mut Cpu<MemMap>) -> Vec<uint8_t> {
+    let mut writer = MemWriter::new();
+    save(&mut writer, cpu);
+    writer.into_inner()
+}
+
+/// Loads a state saved by `save`, if it's for the game that's running.
+pub fn load(data: &[uint8_t], cpu: &mut Cpu<MemMap>) -> Result<(), String> {
+    let start = try!(read_header(data, STATE_MAGIC, STATE_FORMAT, "state"));
+    let rom_crc = match BufReader::new(data.slice_from(start)).read_le_u32() {
+        Ok(rom_crc) => rom_crc,
+        Err(_) => return Err("the state is cut off in its header".to_string()),
+    };
+    if rom_crc != cpu.mem.rom_crc {
+        return Err(format!("the state is for a different game, with the CRC-32 {:08X}, not {:08X}",
+                           rom_crc as uint,
+                           cpu.mem.rom_crc as uint));
+    }
+
+    // The running console's own state is the length the body should be.
+    let body = data.slice_from(start + 4);
+    let mut writer = MemWriter::new();
+    cpu.save(&mut writer);
+    let length = writer.into_inner().len();
+    if body.len() < length {
+        return Err(format!("the state is cut off, with {} of the {} bytes it needs",
+                           body.len(),
+                           length));
+    }
+    if body.len() > length {
+        return Err(format!("the state has {} more bytes than this game's state",
+                           body.len() - length));
+    }
+    cpu.load(&mut BufReader::new(body));
+    Ok(())
+}
@@ -7,8 +7,10 @@
 #![allow(improper_ctypes)]
 
 use libc::{c_int, c_void, time_t, uint8_t, uint16_t, uint32_t, uint64_t};
-use std::io::File;
+use std::io::{BufReader, MemWriter};
+use std::mem;
 use std::ptr::null;
+use time;
 
 //
 // A tiny custom serialization infrastructure, used for savestates.
@@ -18,15 +20,15 @@ use std::ptr::null;
 //
 
 pub trait Save {
-    fn save(&mut self, fd: &mut File);
-    fn load(&mut self, fd: &mut File);
+    fn save(&mut self, fd: &mut Writer);
+    fn load(&mut self, fd: &mut Reader);
 }
 
 impl Save for uint8_t {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         fd.write([ *self ]).unwrap();
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         let mut buf = [ 0 ];
         fd.read_at_least(buf.len(), buf).unwrap();
         *self = buf[0];
@@ -34,10 +36,10 @@ impl Save for uint8_t {
 }
 
 impl Save for uint16_t {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         fd.write([ *self as uint8_t, (*self >> 8) as uint8_t ]).unwrap();
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         let mut buf = [ 0, 0 ];
         fd.read_at_least(buf.len(), buf).unwrap();
         *self = (buf[0] as uint16_t) | ((buf[1] as uint16_t) << 8);
@@ -45,14 +47,14 @@ impl Save for uint16_t {
 }
 
 impl Save for uint64_t {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         let mut buf = [ 0, ..8 ];
         for i in 0..8 {
             buf[i] = ((*self) >> (i * 8)) as uint8_t;
         }
         fd.write(buf).unwrap();
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         let mut buf = [ 0, ..8 ];
         fd.read_at_least(buf.len(), buf).unwrap();
         *self = 0;
@@ -63,31 +65,107 @@ impl Save for uint64_t {
 }
 
 impl<'a> Save for &'a mut [uint8_t] {
-    fn save(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) {
         fd.write(*self).unwrap();
     }
-    fn load(&mut self, fd: &mut File) {
+    fn load(&mut self, fd: &mut Reader) {
         fd.read_at_least(self.len(), *self).unwrap();
     }
 }
 
 impl Save for bool {
-    fn save(&mut self, fd: &mut File) { fd.write([ if *self { 0 } else { 1 } ]).unwrap(); }
-    fn load(&mut self, fd: &mut File) {
+    fn save(&mut self, fd: &mut Writer) { fd.write([ if *self { 1 } else { 0 } ]).unwrap(); }
+    fn load(&mut self, fd: &mut Reader) {
         let mut val: [uint8_t; 1] = [ 0 ];
         fd.read_at_least(val.len(), val).unwrap();
         *self = val[0] != 0
     }
 }
 
+// Machine-sized integers are saved as 64 bits, so that states move between 32- and 64-bit builds.
+impl Save for uint {
+    fn save(&mut self, fd: &mut Writer) {
+        let mut val = *self as uint64_t;
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let mut val: uint64_t = 0;
+        val.load(fd);
+        *self = val as uint
+    }
+}
+
+impl Save for int {
+    fn save(&mut self, fd: &mut Writer) {
+        let mut val = *self as uint64_t;
+        val.save(fd)
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let mut val: uint64_t = 0;
+        val.load(fd);
+        *self = val as int
+    }
+}
+
+impl Save for Option<uint> {
+    fn save(&mut self, fd: &mut Writer) {
+        let (mut present, mut val) = match *self { None => (false, 0), Some(val) => (true, val) };
+        present.save(fd);
+        val.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let (mut present, mut val) = (false, 0u);
+        present.load(fd);
+        val.load(fd);
+        *self = if present { Some(val) } else { None }
+    }
+}
+
+impl<T> Save for Box<T> where T: Save {
+    fn save(&mut self, fd: &mut Writer) { (**self).save(fd) }
+    fn load(&mut self, fd: &mut Reader) { (**self).load(fd) }
+}
+
+// Small arrays of anything, one element at a time.
+macro_rules! save_arrays(
+    ($($len:expr),*) => ($(
+        impl<T> Save for [T; $len] where T: Save {
+            fn save(&mut self, fd: &mut Writer) {
+                for val in self.iter_mut() {
+                    val.save(fd);
+                }
+            }
+            fn load(&mut self, fd: &mut Reader) {
+                for val in self.iter_mut() {
+                    val.load(fd);
+                }
+            }
+        }
+    )*)
+);
+
+save_arrays!(2, 3, 4, 5, 6, 8, 16);
+
+// Big arrays of bytes, such as cartridge RAM, all at once.
+macro_rules! save_byte_arrays(
+    ($($len:expr),*) => ($(
+        impl Save for [uint8_t; $len] {
+            fn save(&mut self, fd: &mut Writer) { self.as_mut_slice().save(fd) }
+            fn load(&mut self, fd: &mut Reader) { self.as_mut_slice().load(fd) }
+        }
+    )*)
+);
+
+save_byte_arrays!(64, 1024, 2048, 4096, 8192, 32768, 65536);
+
 // A convenience macro to save and load entire structs.
 macro_rules! save_struct(
     ($name:ident { $($field:ident),* }) => (
         impl Save for $name {
-            fn save(&mut self, fd: &mut File) {
+            fn save(&mut self, fd: &mut Writer) {
                 $(self.$field.save(fd);)*
             }
-            fn load(&mut self, fd: &mut File) {
+            fn load(&mut self, fd: &mut Reader) {
                 $(self.$field.load(fd);)*
             }
         }
@@ -97,11 +175,11 @@ macro_rules! save_struct(
 macro_rules! save_enum(
     ($name:ident { $val_0:ident, $val_1:ident }) => (
         impl Save for $name {
-            fn save(&mut self, fd: &mut File) {
+            fn save(&mut self, fd: &mut Writer) {
                 let mut val: uint8_t = match *self { $val_0 => 0, $val_1 => 1 };
                 val.save(fd)
             }
-            fn load(&mut self, fd: &mut File) {
+            fn load(&mut self, fd: &mut Reader) {
                 let mut val: uint8_t = 0;
                 val.load(fd);
                 *self = if val == 0 { $val_0 } else { $val_1 };
@@ -111,29 +189,55 @@ macro_rules! save_enum(
 );
 
 //
-// Random number generation
+// Snapshots
+//
+// A savestate kept in memory. Taking and restoring one is cheap enough to do every frame, which
+// run-ahead does.
 //
 
-pub struct Xorshift {
-    pub x: uint32_t,
-    pub y: uint32_t,
-    pub z: uint32_t,
-    pub w: uint32_t,
+pub struct Snapshot {
+    data: Vec<uint8_t>,
 }
 
-impl Xorshift {
-    pub fn new() -> Xorshift {
-        Xorshift { x: 123456789, y: 362436069, z: 521288629, w: 88675123 }
+impl Snapshot {
+    pub fn new() -> Snapshot {
+        Snapshot { data: Vec::new() }
     }
 
-    pub fn next(&mut self) -> uint32_t {
-        let t = self.x ^ (self.x << 11);
-        self.x = self.y; self.y = self.z; self.z = self.w;
-        self.w = self.w ^ (self.w >> 19) ^ (t ^ (t >> 8));
-        self.w
+    /// Replaces the snapshot with the current state, reusing the memory of the old one.
+    pub fn take<S>(&mut self, state: &mut S) where S: Save {
+        let mut data = mem::replace(&mut self.data, Vec::new());
+        data.clear();
+        let mut writer = MemWriter::from_vec(data);
+        state.save(&mut writer);
+        self.data = writer.into_inner();
+    }
+
+    pub fn restore<S>(&self, state: &mut S) where S: Save {
+        state.load(&mut BufReader::new(self.data.as_slice()));
     }
 }
 
+//
+// CRC-32, as used by ROM databases and PNG
+//
+
+pub fn crc32(data: &[uint8_t]) -> uint32_t {
+    crc32_update(0, data)
+}
+
+// Continues a CRC-32 computation over more data, given the CRC of everything before it.
+pub fn crc32_update(crc: uint32_t, data: &[uint8_t]) -> uint32_t {
+    let mut crc = !crc;
+    for &byte in data.iter() {
+        crc ^= byte as uint32_t;
+        for _ in range(0, 8) {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
 //
 // Simple assertions
 //
@@ -148,14 +252,6 @@ pub fn debug_assert(cond: bool, msg: &str) {
 #[cfg(not(debug))]
 pub fn debug_assert(_: bool, _: &str) {}
 
-#[cfg(debug)]
-pub fn debug_print(msg: &str) {
-    println!("{}", msg);
-}
-
-#[cfg(not(debug))]
-pub fn debug_print(_: &str) {}
-
 //
 // Bindings for `gettimeofday(2)`
 //
@@ -170,10 +266,16 @@ extern {
     fn gettimeofday(tp: *mut timeval, tzp: *const c_void) -> c_int;
 }
 
-pub fn current_time_millis() -> uint64_t {
+pub fn current_time_micros() -> uint64_t {
     unsafe {
         let mut tv = timeval { tv_sec: 0, tv_usec: 0 };
         gettimeofday(&mut tv, null());
-        (tv.tv_sec as uint64_t) * 1000 + (tv.tv_usec as uint64_t) / 1000
+        (tv.tv_sec as uint64_t) * 1000000 + (tv.tv_usec as uint64_t)
     }
 }
+
+/// Microseconds from some fixed point, on a clock that never jumps, unlike the time of day. This
+/// is for measuring how long things take.
+pub fn monotonic_time_micros() -> uint64_t {
+    time::precise_time_ns() / 1000
+}
@@ -0,0 +1,609 @@
+//
+// sprocketnes/vrc7.rs
+//
+// Author: Patrick Walton
+//
+
+// Konami's VRC7 (mapper 85): three switchable 8K PRG banks with the last fixed, eight 1K CHR
+// banks, 8K of PRG-RAM, the VRC IRQ counter, and an FM synthesizer derived from Yamaha's YM2413,
+// which gets most of this file.
+//
+// Boards wire the chip's register select line to either A4 (VRC7a, Lagrange Point) or A3
+// (VRC7b, Tiny Toon Adventures 2), so we accept both. Lagrange Point has CHR-RAM, which the CHR
+// banks select from just like ROM.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC7
+
+use mapper::{Horizontal, Mapper, Mirroring, OneScreenLower, OneScreenUpper, Vertical, VrcIrq};
+use mapper::{bank_loadb, last_bank};
+use rom::Rom;
+use util::Save;
+
+use libc::{uint8_t, uint16_t, uint64_t};
+use std::cmp;
+use std::f32::consts::PI;
+use std::num::Float;
+
+//
+// FM audio
+//
+// Six channels, each a pair of sine operators: a modulator, which can feed back into itself, bends
+// the phase of a carrier, which is what we hear. Instead of the YM2413's registers for each
+// operator, a channel picks one of 15 instruments built into the chip, or the one custom
+// instrument the game defines in registers $00-$07, and sets a frequency and a volume.
+//
+// The chip makes a sample every 36 CPU cycles. We follow its structure but not its arithmetic:
+// the operators work in floating point with attenuation in decibels, where the chip uses log-sine
+// and exponent tables, and the envelope times and LFOs are approximations from the YM2413's
+// datasheet. Games can't tell, since they can't read anything back from the chip.
+//
+// See http://wiki.nesdev.com/w/index.php/VRC7_audio
+//
+
+const CYCLES_PER_SAMPLE: uint = 36;
+const SAMPLE_RATE: f32 = 49716.0;
+
+const CHANNEL_COUNT: uint = 6;
+
+// One cycle of an operator's phase. The chip's phase counters are 19 bits.
+const PHASE_CYCLE: uint = 1 << 19;
+
+// The envelope covers 48dB, and an operator further down than that is silent.
+const ENVELOPE_RANGE: f32 = 48.0;
+const SILENT: f32 = 96.0;
+// An attack stops at this attenuation, since it approaches 0dB exponentially.
+const ATTACK_END: f32 = 0.1;
+
+// How long an attack and a full decay take at effective rate 4. Each step of four in the rate
+// halves them.
+const ATTACK_SECONDS: f32 = 2.826;
+const DECAY_SECONDS: f32 = 19.64;
+
+// The LFOs: tremolo for operators with AM set, vibrato for those with VIB set.
+const AM_HZ: f32 = 3.7;
+const AM_DEPTH: f32 = 4.8;          // dB
+const VIB_HZ: f32 = 6.4;
+const VIB_DEPTH: f32 = 0.008;       // Fraction of the frequency, about 14 cents
+
+// How far a full-scale modulator moves the carrier's phase, in cycles.
+const MODULATION_DEPTH: f32 = 2.0;
+
+// A full-volume channel against a full-volume APU pulse channel.
+const CHANNEL_LEVEL: f32 = 0.75;
+
+// Frequency multipliers, doubled so that they're whole numbers.
+static MULTIPLIERS: [uint; 16] = [ 1, 2, 4, 6, 8, 10, 12, 14, 16, 18, 20, 20, 24, 24, 30, 30 ];
+
+// Key scaling attenuation in dB for octave 7, by the top four bits of the frequency number. Each
+// octave lower takes 6dB off. This is for the steepest setting; the others are fractions of it.
+static KEY_SCALE_LEVELS: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25,
+    36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25, 42.0,
+];
+static KEY_SCALE_FRACTIONS: [f32; 4] = [ 0.0, 0.25, 0.5, 1.0 ];
+
+// The instruments built into the VRC7, in the same layout as the custom instrument's registers.
+static INSTRUMENTS: [[uint8_t; 8]; 15] = [
+    [ 0x03, 0x21, 0x05, 0x06, 0xe8, 0x81, 0x42, 0x27 ],     // Buzzy bell
+    [ 0x13, 0x41, 0x14, 0x0d, 0xd8, 0xf6, 0x23, 0x12 ],     // Guitar
+    [ 0x11, 0x11, 0x08, 0x08, 0xfa, 0xb2, 0x20, 0x12 ],     // Wurly
+    [ 0x31, 0x61, 0x0c, 0x07, 0xa8, 0x64, 0x61, 0x27 ],     // Flute
+    [ 0x32, 0x21, 0x1e, 0x06, 0xe1, 0x76, 0x01, 0x28 ],     // Clarinet
+    [ 0x02, 0x01, 0x06, 0x00, 0xa3, 0xe2, 0xf4, 0xf4 ],     // Synth
+    [ 0x21, 0x61, 0x1d, 0x07, 0x82, 0x81, 0x11, 0x07 ],     // Trumpet
+    [ 0x23, 0x21, 0x22, 0x17, 0xa2, 0x72, 0x01, 0x17 ],     // Organ
+    [ 0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01 ],     // Bells
+    [ 0xb5, 0x01, 0x0f, 0x0f, 0xa8, 0xa5, 0x51, 0x02 ],     // Vibes
+    [ 0x17, 0xc1, 0x24, 0x07, 0xf8, 0xf8, 0x22, 0x12 ],     // Vibraphone
+    [ 0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16 ],     // Tutti
+    [ 0x01, 0x02, 0xd3, 0x05, 0xc9, 0x95, 0x03, 0x02 ],     // Fretless
+    [ 0x61, 0x63, 0x0c, 0x00, 0x94, 0xc0, 0x33, 0xf6 ],     // Synth bass
+    [ 0x21, 0x72, 0x0d, 0x00, 0xc1, 0xd5, 0x56, 0x06 ],     // Sweep
+];
+
+// What an operator takes from its instrument. `index` is 0 for the modulator, 1 for the carrier.
+struct OperatorPatch {
+    am: bool,
+    vib: bool,
+    sustained: bool,        // Holds at the sustain level instead of carrying on into release
+    key_scale_rate: bool,
+    multiplier: uint,
+    key_scale_level: uint,
+    half_sine: bool,        // Cuts off the negative half of the wave
+    attack: uint,
+    decay: uint,
+    sustain_level: uint,
+    release: uint,
+}
+
+impl OperatorPatch {
+    fn new(instrument: &[uint8_t], index: uint) -> OperatorPatch {
+        let flags = instrument[index];
+        OperatorPatch {
+            am: (flags & 0x80) != 0,
+            vib: (flags & 0x40) != 0,
+            sustained: (flags & 0x20) != 0,
+            key_scale_rate: (flags & 0x10) != 0,
+            multiplier: MULTIPLIERS[flags as uint & 0x0f],
+            key_scale_level: (instrument[2 + index] >> 6) as uint,
+            half_sine: (instrument[3] & (0x08 << index)) != 0,
+            attack: (instrument[4 + index] >> 4) as uint,
+            decay: (instrument[4 + index] & 0x0f) as uint,
+            sustain_level: (instrument[6 + index] >> 4) as uint,
+            release: (instrument[6 + index] & 0x0f) as uint,
+        }
+    }
+}
+
+enum EnvelopePhase {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+impl Save for EnvelopePhase {
+    fn save(&mut self, fd: &mut Writer) {
+        let mut val: uint8_t = match *self {
+            Attack => 0, Decay => 1, Sustain => 2, Release => 3, Off => 4
+        };
+        val.save(fd);
+    }
+    fn load(&mut self, fd: &mut Reader) {
+        let mut val: uint8_t = 0;
+        val.load(fd);
+        *self = match val { 0 => Attack, 1 => Decay, 2 => Sustain, 3 => Release, _ => Off };
+    }
+}
+
+// The pitch a channel is playing, which sets the operators' speeds and key scaling.
+struct Pitch {
+    fnum: uint,     // 9 bits
+    block: uint,    // The octave
+}
+
+struct Operator {
+    phase: uint,                // In 1/PHASE_CYCLE of a cycle
+    envelope: EnvelopePhase,
+    // The envelope's attenuation in 1/65536 dB, so that it can be saved as an integer.
+    attenuation: uint,
+}
+
+save_struct!(Operator { phase, envelope, attenuation });
+
+impl Operator {
+    fn new() -> Operator {
+        Operator {
+            phase: 0,
+            envelope: Off,
+            attenuation: (ENVELOPE_RANGE * 65536.0) as uint,
+        }
+    }
+
+    fn key_on(&mut self) {
+        self.phase = 0;
+        self.envelope = Attack;
+    }
+
+    fn key_off(&mut self) {
+        match self.envelope {
+            Off => {}
+            _ => self.envelope = Release,
+        }
+    }
+
+    fn envelope_db(&self) -> f32 { self.attenuation as f32 / 65536.0 }
+    fn set_envelope_db(&mut self, db: f32) { self.attenuation = (db * 65536.0) as uint }
+
+    // The chip's rate, 0 to 63, from a 4-bit rate in the instrument. Higher notes go faster with
+    // key scale rate set, and a little faster without.
+    fn effective_rate(rate: uint, patch: &OperatorPatch, pitch: Pitch) -> uint {
+        if rate == 0 {
+            return 0;
+        }
+        let key = (pitch.block << 1) | (pitch.fnum >> 8);
+        let key = if patch.key_scale_rate { key } else { key >> 2 };
+        cmp::min(rate * 4 + key, 63)
+    }
+
+    // Runs the envelope for one sample. `release` is the rate to release at.
+    fn clock_envelope(&mut self, patch: &OperatorPatch, pitch: Pitch, release: uint) {
+        let db = self.envelope_db();
+        match self.envelope {
+            Attack => {
+                let rate = Operator::effective_rate(patch.attack, patch, pitch);
+                if rate >= 60 {
+                    self.set_envelope_db(0.0);
+                    self.envelope = Decay;
+                } else if rate > 0 {
+                    // The attack is exponential, quick at first, and then slowing into 0dB.
+                    let samples = ATTACK_SECONDS * SAMPLE_RATE / rate_scale(rate);
+                    let factor = (ATTACK_END / ENVELOPE_RANGE).powf(1.0 / samples);
+                    let db = db * factor;
+                    if db <= ATTACK_END {
+                        self.set_envelope_db(0.0);
+                        self.envelope = Decay;
+                    } else {
+                        self.set_envelope_db(db);
+                    }
+                }
+            }
+            Decay => {
+                let sustain_db = patch.sustain_level as f32 * 3.0;
+                let db = db + decay_step(Operator::effective_rate(patch.decay, patch, pitch));
+                if db >= sustain_db {
+                    self.set_envelope_db(sustain_db);
+                    self.envelope = Sustain;
+                } else {
+                    self.set_envelope_db(db);
+                }
+            }
+            Sustain if patch.sustained => {}
+            Sustain => {
+                // Percussive sounds fade away while the key is still down.
+                self.fade(db + decay_step(Operator::effective_rate(patch.release, patch, pitch)));
+            }
+            Release => {
+                self.fade(db + decay_step(Operator::effective_rate(release, patch, pitch)));
+            }
+            Off => {}
+        }
+    }
+
+    fn fade(&mut self, db: f32) {
+        if db >= ENVELOPE_RANGE {
+            self.set_envelope_db(ENVELOPE_RANGE);
+            self.envelope = Off;
+        } else {
+            self.set_envelope_db(db);
+        }
+    }
+
+    // Moves the phase on by one sample.
+    fn clock_phase(&mut self, patch: &OperatorPatch, pitch: Pitch, vibrato: f32) {
+        let step = ((pitch.fnum << pitch.block) * patch.multiplier) >> 1;
+        let step = if patch.vib { (step as f32 * (1.0 + vibrato)) as uint } else { step };
+        self.phase = (self.phase + step) % PHASE_CYCLE;
+    }
+
+    // The operator's output, from -1 to 1, given how far to move the phase, in cycles, and how
+    // much to attenuate it beyond the envelope.
+    fn output(&self, patch: &OperatorPatch, offset: f32, attenuation: f32) -> f32 {
+        let db = match self.envelope {
+            Off => return 0.0,
+            _ => self.envelope_db() + attenuation,
+        };
+        if db >= SILENT {
+            return 0.0;
+        }
+        let phase = self.phase as f32 / PHASE_CYCLE as f32 + offset;
+        let wave = (phase * 2.0 * PI).sin();
+        if patch.half_sine && wave < 0.0 {
+            return 0.0;
+        }
+        wave * 10.0f32.powf(-db / 20.0)
+    }
+}
+
+// How much faster than rate 4 a rate runs.
+fn rate_scale(rate: uint) -> f32 { 2.0f32.powf((rate as f32 - 4.0) / 4.0) }
+
+// How far a decay or release at the rate goes in one sample, in dB.
+fn decay_step(rate: uint) -> f32 {
+    if rate == 0 {
+        return 0.0;
+    }
+    ENVELOPE_RANGE * rate_scale(rate) / (DECAY_SECONDS * SAMPLE_RATE)
+}
+
+fn key_scale_attenuation(level: uint, pitch: Pitch) -> f32 {
+    let octave_7 = KEY_SCALE_LEVELS[pitch.fnum >> 5];
+    let db = octave_7 - 6.0 * (7 - pitch.block) as f32;
+    if db <= 0.0 { 0.0 } else { db * KEY_SCALE_FRACTIONS[level] }
+}
+
+struct FmChannel {
+    fnum: uint,             // $10-$15, and bit 0 of $20-$25
+    block: uint,            // $20-$25, bits 1-3
+    key_on: bool,           // $20-$25, bit 4
+    sustain: bool,          // $20-$25, bit 5: release slowly
+    instrument: uint,       // $30-$35, high bits
+    volume: uint,           // $30-$35, low bits: 3dB of attenuation each
+    modulator: Operator,
+    carrier: Operator,
+    feedback: [int; 2],     // The modulator's last two outputs, in 1/4096ths
+}
+
+save_struct!(FmChannel {
+    fnum,
+    block,
+    key_on,
+    sustain,
+    instrument,
+    volume,
+    modulator,
+    carrier,
+    feedback
+});
+
+impl FmChannel {
+    fn new() -> FmChannel {
+        FmChannel {
+            fnum: 0,
+            block: 0,
+            key_on: false,
+            sustain: false,
+            instrument: 0,
+            volume: 0,
+            modulator: Operator::new(),
+            carrier: Operator::new(),
+            feedback: [ 0, 0 ],
+        }
+    }
+
+    fn pitch(&self) -> Pitch { Pitch { fnum: self.fnum, block: self.block } }
+
+    fn store_key(&mut self, val: uint8_t) {
+        let key_on = (val & 0x10) != 0;
+        if key_on && !self.key_on {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key_on && self.key_on {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key_on = key_on;
+        self.sustain = (val & 0x20) != 0;
+        self.block = ((val >> 1) & 7) as uint;
+        self.fnum = (self.fnum & 0xff) | ((val as uint & 1) << 8);
+    }
+
+    // With the sustain bit set, notes ring on after the key comes up. Otherwise percussive
+    // sounds stop quickly and sustained ones release at their own rate.
+    fn release_rate(&self, patch: &OperatorPatch) -> uint {
+        if self.sustain { 5 } else if patch.sustained { patch.release } else { 7 }
+    }
+
+    // Makes one sample, from -1 to 1, and moves on.
+    fn clock(&mut self, instrument: &[uint8_t], tremolo: f32, vibrato: f32) -> f32 {
+        let pitch = self.pitch();
+        let modulator = OperatorPatch::new(instrument, 0);
+        let carrier = OperatorPatch::new(instrument, 1);
+
+        let modulator_release = self.release_rate(&modulator);
+        let carrier_release = self.release_rate(&carrier);
+
+        let feedback_level = instrument[3] & 7;
+        let feedback = if feedback_level == 0 {
+            0.0
+        } else {
+            let average = (self.feedback[0] + self.feedback[1]) as f32 / 2.0 / 4096.0;
+            average * 2.0f32.powf(feedback_level as f32 - 7.0)
+        };
+        let modulator_db = (instrument[2] & 0x3f) as f32 * 0.75 +
+            key_scale_attenuation(modulator.key_scale_level, pitch) +
+            if modulator.am { tremolo } else { 0.0 };
+        let modulation = self.modulator.output(&modulator, feedback, modulator_db);
+        self.feedback = [ self.feedback[1], (modulation * 4096.0) as int ];
+
+        let carrier_db = self.volume as f32 * 3.0 +
+            key_scale_attenuation(carrier.key_scale_level, pitch) +
+            if carrier.am { tremolo } else { 0.0 };
+        let output = self.carrier.output(&carrier, modulation * MODULATION_DEPTH, carrier_db);
+
+        self.modulator.clock_envelope(&modulator, pitch, modulator_release);
+        self.carrier.clock_envelope(&carrier, pitch, carrier_release);
+        self.modulator.clock_phase(&modulator, pitch, vibrato);
+        self.carrier.clock_phase(&carrier, pitch, vibrato);
+        output
+    }
+}
+
+struct Vrc7Audio {
+    select: uint8_t,                // $9010
+    custom: [uint8_t; 8],           // $00-$07: the custom instrument
+    channels: [FmChannel; 6],
+    cycles: uint,                   // Toward the next sample
+    lfo_samples: uint64_t,          // Samples since reset, for the LFOs
+    output: f32,                    // The last sample
+}
+
+save_struct!(Vrc7Audio { select, custom, channels, cycles, lfo_samples });
+
+impl Vrc7Audio {
+    fn new() -> Vrc7Audio {
+        Vrc7Audio {
+            select: 0,
+            custom: [ 0; 8 ],
+            channels: [
+                FmChannel::new(), FmChannel::new(), FmChannel::new(),
+                FmChannel::new(), FmChannel::new(), FmChannel::new(),
+            ],
+            cycles: 0,
+            lfo_samples: 0,
+            output: 0.0,
+        }
+    }
+
+    // $9030: stores to the register $9010 selected.
+    fn store_register(&mut self, val: uint8_t) {
+        let reg = self.select as uint;
+        let channel = reg & 0x0f;
+        match reg & 0xf0 {
+            0x00 if reg < 8 => self.custom[reg] = val,
+            0x10 if channel < CHANNEL_COUNT => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x100) | val as uint;
+            }
+            0x20 if channel < CHANNEL_COUNT => self.channels[channel].store_key(val),
+            0x30 if channel < CHANNEL_COUNT => {
+                self.channels[channel].instrument = (val >> 4) as uint;
+                self.channels[channel].volume = (val & 0x0f) as uint;
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Vrc7Audio::new();
+    }
+
+    fn clock(&mut self) {
+        self.cycles += 1;
+        if self.cycles < CYCLES_PER_SAMPLE {
+            return;
+        }
+        self.cycles = 0;
+
+        let time = self.lfo_samples as f32 / SAMPLE_RATE;
+        let tremolo = (1.0 - (time * AM_HZ * 2.0 * PI).cos()) / 2.0 * AM_DEPTH;
+        let vibrato = (time * VIB_HZ * 2.0 * PI).sin() * VIB_DEPTH;
+        self.lfo_samples += 1;
+
+        let mut output = 0.0;
+        for channel in self.channels.iter_mut() {
+            let instrument = if channel.instrument == 0 {
+                self.custom.as_slice()
+            } else {
+                INSTRUMENTS[channel.instrument - 1].as_slice()
+            };
+            output += channel.clock(instrument, tremolo, vibrato);
+        }
+        self.output = output * CHANNEL_LEVEL;
+    }
+
+    fn output(&self) -> f32 { self.output }
+}
+
+//
+// The mapper
+//
+
+pub struct Vrc7 {
+    rom: Box<Rom>,
+    prg_ram: Box<[uint8_t; 8192]>,
+    chr_ram: Box<[uint8_t; 8192]>,
+
+    prg_banks: [uint8_t; 3],    // $8000, $8010 and $9000
+    chr_banks: [uint8_t; 8],    // $A000-$D010: 1K banks
+    control: uint8_t,           // $E000: mirroring, audio reset and PRG-RAM enable
+    irq: VrcIrq,
+    audio: Vrc7Audio,
+}
+
+save_struct!(Vrc7 { prg_ram, chr_ram, prg_banks, chr_banks, control, irq, audio });
+
+impl Vrc7 {
+    pub fn new(rom: Box<Rom>) -> Vrc7 {
+        Vrc7 {
+            rom: rom,
+            prg_ram: Box::new([ 0; 8192 ]),
+            chr_ram: Box::new([ 0; 8192 ]),
+
+            prg_banks: [ 0, 0, 0 ],
+            chr_banks: [ 0, 0, 0, 0, 0, 0, 0, 0 ],
+            control: 0,
+            irq: VrcIrq::new(),
+            audio: Vrc7Audio::new(),
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool { (self.control & 0x80) != 0 }
+    fn audio_silenced(&self) -> bool { (self.control & 0x40) != 0 }
+
+    // The offset of the address into CHR, through the banks.
+    fn chr_offset(&self, addr: uint16_t) -> uint {
+        let bank = self.chr_banks[(addr as uint >> 10) & 7] as uint;
+        (bank * 1024) | (addr as uint & 0x3ff)
+    }
+}
+
+impl Mapper for Vrc7 {
+    fn prg_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if addr < 0x6000 {
+            0u8
+        } else if addr < 0x8000 {
+            if self.prg_ram_enabled() { self.prg_ram[addr as uint & 0x1fff] } else { 0u8 }
+        } else if addr < 0xe000 {
+            let bank = self.prg_banks[(addr as uint - 0x8000) >> 13] as uint;
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        } else {
+            let bank = last_bank(self.rom.prg.as_slice(), 8192);
+            bank_loadb(self.rom.prg.as_slice(), bank, 8192, addr)
+        }
+    }
+
+    fn prg_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if addr < 0x6000 {
+            return;
+        }
+        if addr < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[addr as uint & 0x1fff] = val;
+            }
+            return;
+        }
+
+        // Fold A3 onto A4, except at $9030, the audio data register.
+        let second = (addr & 0x18) != 0;
+        match (addr & 0xf000, second) {
+            (0x8000, false) => self.prg_banks[0] = val & 0x3f,
+            (0x8000, true) => self.prg_banks[1] = val & 0x3f,
+            (0x9000, false) => self.prg_banks[2] = val & 0x3f,
+            (0x9000, true) if (addr & 0x20) == 0 => self.audio.select = val,
+            (0x9000, true) => self.audio.store_register(val),
+            (0xa000 ... 0xd000, _) => {
+                let index = (((addr as uint >> 12) - 0xa) << 1) | second as uint;
+                self.chr_banks[index] = val;
+            }
+            (0xe000, false) => {
+                self.control = val;
+                if self.audio_silenced() {
+                    self.audio.reset();
+                }
+            }
+            (0xe000, true) => self.irq.store_latch(val),
+            (0xf000, false) => self.irq.store_control(val),
+            (0xf000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+
+    fn chr_loadb(&mut self, addr: uint16_t) -> uint8_t {
+        if self.rom.chr.len() == 0 {
+            return self.chr_ram[self.chr_offset(addr) & 0x1fff];
+        }
+        let bank = self.chr_banks[(addr as uint >> 10) & 7] as uint;
+        bank_loadb(self.rom.chr.as_slice(), bank, 1024, addr)
+    }
+    fn chr_storeb(&mut self, addr: uint16_t, val: uint8_t) {
+        if self.rom.chr.len() == 0 {
+            let offset = self.chr_offset(addr) & 0x1fff;
+            self.chr_ram[offset] = val;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 3 {
+            0 => Vertical,
+            1 => Horizontal,
+            2 => OneScreenLower,
+            _ => OneScreenUpper,
+        }
+    }
+
+    fn next_scanline(&mut self) { self.irq.next_scanline() }
+    fn irq_pending(&self) -> bool { self.irq.pending }
+
+    fn clock_audio(&mut self) {
+        if !self.audio_silenced() {
+            self.audio.clock();
+        }
+    }
+    fn audio_output(&self) -> f32 { self.audio.output() }
+
+    fn battery(&mut self) -> Option<&mut [uint8_t]> {
+        if self.rom.header.battery() { Some(self.prg_ram.as_mut_slice()) } else { None }
+    }
+}
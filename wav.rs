@@ -0,0 +1,83 @@
+//
+// sprocketnes/wav.rs
+//
+// Author: Patrick Walton
+//
+
+// Writes the APU's final mix to a WAV file, as 16-bit mono PCM at `OUTPUT_SAMPLE_RATE`.
+//
+// Like recordings, the audio follows emulated time, so the same ROM and input always produce the
+// same file. That makes it easy to compare against what other emulators produce.
+
+use apu::OUTPUT_SAMPLE_RATE;
+
+use libc::{int16_t, uint16_t, uint32_t, uint64_t};
+use std::io::{File, IoResult, SeekSet};
+
+const HEADER_SIZE: uint32_t = 44;
+
+// Where the sizes that are only known at the end go.
+const RIFF_SIZE_OFFSET: i64 = 4;
+const DATA_SIZE_OFFSET: i64 = 40;
+
+pub struct WavWriter {
+    file: File,
+    samples: uint64_t,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path) -> IoResult<WavWriter> {
+        let mut writer = WavWriter {
+            file: try!(File::create(path)),
+            samples: 0,
+        };
+        try!(writer.write_header());
+        Ok(writer)
+    }
+
+    fn write_header(&mut self) -> IoResult<()> {
+        let file = &mut self.file;
+        try!(file.write(b"RIFF"));
+        try!(file.write_le_u32(0));
+        try!(file.write(b"WAVE"));
+
+        try!(file.write(b"fmt "));
+        try!(file.write_le_u32(16));
+        try!(file.write_le_u16(1));                             // WAVE_FORMAT_PCM
+        try!(file.write_le_u16(1));                             // Channels
+        try!(file.write_le_u32(OUTPUT_SAMPLE_RATE));
+        try!(file.write_le_u32(OUTPUT_SAMPLE_RATE * 2));        // Bytes per second
+        try!(file.write_le_u16(2));                             // Block alignment
+        try!(file.write_le_u16(16));                            // Bits per sample
+
+        try!(file.write(b"data"));
+        file.write_le_u32(0)
+    }
+
+    pub fn add_samples(&mut self, samples: &[int16_t]) {
+        for &sample in samples.iter() {
+            self.file.write_le_i16(sample).unwrap();
+        }
+        self.samples += samples.len() as uint64_t;
+    }
+
+    /// Fills in the sizes in the header.
+    pub fn finish(mut self) {
+        let data_size = (self.samples * 2) as uint32_t;
+        self.file.seek(RIFF_SIZE_OFFSET, SeekSet).unwrap();
+        self.file.write_le_u32(HEADER_SIZE - 8 + data_size).unwrap();
+        self.file.seek(DATA_SIZE_OFFSET, SeekSet).unwrap();
+        self.file.write_le_u32(data_size).unwrap();
+    }
+}
+
+/// Reads back the samples of a file written by `WavWriter`. Other WAV files may have more in
+/// their headers, so this isn't for them.
+pub fn read_samples(path: &Path) -> IoResult<Vec<int16_t>> {
+    let mut file = try!(File::open(path));
+    try!(file.seek(HEADER_SIZE as i64, SeekSet));
+    let data = try!(file.read_to_end());
+    Ok(data.as_slice().chunks(2).filter(|pair| pair.len() == 2).map(|pair| {
+        (pair[0] as uint16_t | (pair[1] as uint16_t << 8)) as int16_t
+    }).collect())
+}